@@ -0,0 +1,175 @@
+use candid::Principal;
+use sha2::{Digest, Sha256};
+
+use crate::auth::require_admin;
+use crate::env::{random_bytes, CallerProvider, CanisterEnv, Clock};
+use crate::id_counter::{next_id, Entity};
+use crate::log;
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::{Error, API_KEY_STORAGE};
+
+// Prefix of every API key, so leaked keys are easy to recognise.
+const KEY_PREFIX: &str = "lib";
+
+// Longest name a key may be given.
+const MAX_NAME_LENGTH: usize = 100;
+
+// What a server-to-server integration may do with its key.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum ApiScope {
+    // The catalog HTTP routes.
+    ReadOnly,
+    // The circulation POST routes too.
+    Circulation,
+    // Every HTTP route.
+    Admin,
+}
+
+// Define the ApiKey struct to represent an issued key. Only a hash of the secret is stored.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct ApiKey {
+    pub id: u64,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub key_hash: String,
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub revoked_at: Option<u64>,
+}
+
+// Store ApiKey as Candid, bounded to 1024 bytes.
+candid_storable!(ApiKey, max_size = 1024);
+
+// Define the payload structure for issuing a key.
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub struct ApiKeyPayload {
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+}
+
+// A newly issued key; `key` is shown only once and cannot be recovered afterwards.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct IssuedApiKey {
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+// Issue a new API key for a backend system that has no Principal of its own.
+#[ic_cdk::update]
+async fn issue_api_key(payload: ApiKeyPayload) -> Result<IssuedApiKey, Error> {
+    let _perf = perf::measure("issue_api_key");
+    require_admin()?;
+    validate(&payload)?;
+    let secret = random_bytes().await?;
+    _issue_api_key(&CanisterEnv, &secret, payload)
+}
+
+// Internal function to store a key built from `secret` on behalf of `env`'s caller.
+pub(crate) fn _issue_api_key(
+    env: &(impl Clock + CallerProvider),
+    secret: &[u8],
+    payload: ApiKeyPayload,
+) -> Result<IssuedApiKey, Error> {
+    validate(&payload)?;
+    let id = next_id(Entity::ApiKey)?;
+    let key = format!("{}_{}_{}", KEY_PREFIX, id, hex::encode(secret));
+
+    let api_key = ApiKey {
+        id,
+        name: payload.name,
+        scopes: payload.scopes,
+        key_hash: hash(&key),
+        created_by: env.caller(),
+        created_at: env.now(),
+        revoked_at: None,
+    };
+    API_KEY_STORAGE.with(|service| service.borrow_mut().insert(id, api_key.clone()));
+    log::info(format!("{} issued API key id={}.", api_key.created_by, id));
+    Ok(IssuedApiKey { api_key, key })
+}
+
+// Retrieve every issued key, including revoked ones.
+#[ic_cdk::query]
+fn list_api_keys() -> Result<Vec<ApiKey>, Error> {
+    require_admin()?;
+    Ok(API_KEY_STORAGE.with(|keys| keys.borrow().iter().map(|(_, key)| key).collect()))
+}
+
+// Revoke a key so it is no longer accepted.
+#[ic_cdk::update]
+fn revoke_api_key(id: u64) -> Result<ApiKey, Error> {
+    let _perf = perf::measure("revoke_api_key");
+    require_admin()?;
+    _revoke_api_key(&CanisterEnv, id)
+}
+
+// Internal function to revoke a key at `clock`'s current time.
+pub(crate) fn _revoke_api_key(clock: &impl Clock, id: u64) -> Result<ApiKey, Error> {
+    match API_KEY_STORAGE.with(|service| service.borrow().get(&id)) {
+        Some(mut api_key) => {
+            if api_key.revoked_at.is_none() {
+                api_key.revoked_at = Some(clock.now());
+                API_KEY_STORAGE.with(|service| service.borrow_mut().insert(id, api_key.clone()));
+                log::info(format!("Revoked API key id={}.", id));
+            }
+            Ok(api_key)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Couldn't revoke an API key with id={}. Key not found.", id),
+        }),
+    }
+}
+
+// Resolve a presented key to its record, checking that it is live and grants `scope`.
+// An Admin key grants every scope and a Circulation key also grants ReadOnly.
+pub(crate) fn authorize_api_key(key: &str, scope: ApiScope) -> Result<ApiKey, Error> {
+    let invalid = || Error::Unauthorized {
+        msg: "Invalid API key.".to_string(),
+    };
+
+    let id = key
+        .strip_prefix(KEY_PREFIX)
+        .and_then(|rest| rest.strip_prefix('_'))
+        .and_then(|rest| rest.split('_').next())
+        .and_then(|id| id.parse::<u64>().ok())
+        .ok_or_else(invalid)?;
+    let api_key = API_KEY_STORAGE
+        .with(|service| service.borrow().get(&id))
+        .ok_or_else(invalid)?;
+    if api_key.revoked_at.is_some() || api_key.key_hash != hash(key) {
+        return Err(invalid());
+    }
+
+    let granted = api_key.scopes.iter().any(|granted| match granted {
+        ApiScope::Admin => true,
+        ApiScope::Circulation => scope != ApiScope::Admin,
+        ApiScope::ReadOnly => scope == ApiScope::ReadOnly,
+    });
+    if !granted {
+        return Err(Error::Unauthorized {
+            msg: format!("The API key id={} is not allowed to do this.", id),
+        });
+    }
+    Ok(api_key)
+}
+
+// Validate a key payload.
+fn validate(payload: &ApiKeyPayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_NAME_LENGTH {
+        return Err(Error::InvalidInput {
+            msg: format!("Name must be 1 to {} characters long.", MAX_NAME_LENGTH),
+        });
+    }
+    if payload.scopes.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "An API key needs at least one scope.".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Hex-encoded SHA-256 of a key, which is what gets stored.
+fn hash(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
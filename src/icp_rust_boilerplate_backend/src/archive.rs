@@ -0,0 +1,64 @@
+use candid::Principal;
+
+use crate::auth::require_admin;
+use crate::crud;
+use crate::env::CanisterEnv;
+use crate::loan::Loan;
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::student::require_student_access;
+use crate::{Error, ARCHIVE_CONFIG};
+
+// Where closed records are moved once they leave this canister's hot storage.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct ArchiveConfig {
+    pub canister: Option<Principal>,
+}
+
+// Store ArchiveConfig as Candid.
+candid_storable!(ArchiveConfig);
+
+// Retrieve the archive canister, if one is configured.
+#[ic_cdk::query]
+fn get_archive_canister() -> Option<Principal> {
+    ARCHIVE_CONFIG.with(|c| c.borrow().get().canister)
+}
+
+// Point loan history at an archive canister, or stop consulting one with None.
+#[ic_cdk::update]
+fn set_archive_canister(canister: Option<Principal>) -> Result<Option<Principal>, Error> {
+    let _perf = perf::measure("set_archive_canister");
+    require_admin()?;
+    ARCHIVE_CONFIG
+        .with(|c| c.borrow_mut().set(ArchiveConfig { canister }))
+        .expect("Cannot store the archive config");
+    Ok(canister)
+}
+
+// Retrieve every loan of a student, oldest first, including those moved to the archive
+// canister. The archive is asked within the same composite query, so clients don't need to
+// know where each loan is kept.
+#[ic_cdk::query(composite = true)]
+async fn get_loan_history(student_id: u64) -> Result<Vec<Loan>, Error> {
+    require_student_access(&CanisterEnv, student_id)?;
+    let mut loans: Vec<Loan> = crud::all::<Loan>()
+        .into_iter()
+        .filter(|loan| loan.student_id == student_id)
+        .collect();
+
+    if let Some(archive) = ARCHIVE_CONFIG.with(|c| c.borrow().get().canister) {
+        let archived: Result<(Vec<Loan>,), _> =
+            ic_cdk::call(archive, "get_archived_loans", (student_id,)).await;
+        match archived {
+            Ok((archived,)) => loans.extend(archived),
+            Err((code, msg)) => {
+                return Err(Error::Unavailable {
+                    msg: format!("Cannot reach the archive canister ({:?}): {}", code, msg),
+                })
+            }
+        }
+    }
+
+    loans.sort_by_key(|loan| (loan.loan_date, loan.id));
+    Ok(loans)
+}
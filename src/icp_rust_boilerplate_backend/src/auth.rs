@@ -0,0 +1,185 @@
+use candid::Principal;
+
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::log;
+use crate::perf;
+use crate::storable::{candid_storable, StorablePrincipal};
+use crate::{Error, ADMIN_STORAGE};
+
+// Define the Admin struct to record who may perform administrative actions.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct Admin {
+    pub principal: Principal,
+    pub added_by: Principal,
+    pub added_at: u64,
+}
+
+// Store Admin as Candid, bounded to 256 bytes.
+candid_storable!(Admin, max_size = 256);
+
+// Arguments accepted when the canister is installed.
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+pub struct InitArgs {
+    // Administrators in addition to the principal installing the canister.
+    pub admins: Vec<Principal>,
+}
+
+// Record the installer, plus any principals named in `args`, as the initial administrators.
+pub(crate) fn bootstrap(env: &(impl Clock + CallerProvider), args: Option<InitArgs>) {
+    let installer = env.caller();
+    let mut admins = vec![installer];
+    admins.extend(args.unwrap_or_default().admins);
+    for principal in admins {
+        if principal != Principal::anonymous() {
+            insert(Admin {
+                principal,
+                added_by: installer,
+                added_at: env.now(),
+            });
+        }
+    }
+}
+
+// Whether any administrator has been recorded yet.
+pub(crate) fn has_admins() -> bool {
+    ADMIN_STORAGE.with(|admins| !admins.borrow().is_empty())
+}
+
+// Retrieve every administrator.
+#[ic_cdk::query]
+fn list_admins() -> Result<Vec<Admin>, Error> {
+    require_admin()?;
+    Ok(ADMIN_STORAGE.with(|admins| admins.borrow().iter().map(|(_, admin)| admin).collect()))
+}
+
+// Grant administrator rights to a principal. While the canister has a single administrator,
+// they may add a second one directly, since approving a proposal takes two. After that only a
+// controller may; other administrators propose AddAdmin instead.
+#[ic_cdk::update]
+fn add_admin(principal: Principal) -> Result<Admin, Error> {
+    let _perf = perf::measure("add_admin");
+    require_admin()?;
+    let caller = ic_cdk::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        check_bootstrap()?;
+    }
+    _add_admin(&CanisterEnv, caller, principal)
+}
+
+// Revoke a principal's administrator rights. Only a controller may do this directly; other
+// administrators propose RemoveAdmin instead.
+#[ic_cdk::update]
+fn remove_admin(principal: Principal) -> Result<Admin, Error> {
+    let _perf = perf::measure("remove_admin");
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: "Only a controller can remove an administrator directly; propose RemoveAdmin \
+                  instead."
+                .to_string(),
+        });
+    }
+    _remove_admin(&principal)
+}
+
+// Check that an administrator may be added without a proposal: only while there is at most
+// one, who could otherwise never get a proposal approved.
+fn check_bootstrap() -> Result<(), Error> {
+    if ADMIN_STORAGE.with(|admins| admins.borrow().len()) >= 2 {
+        return Err(Error::Unauthorized {
+            msg: "The canister already has more than one administrator; propose AddAdmin instead."
+                .to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Check that a principal can be granted administrator rights.
+pub(crate) fn validate_addition(principal: &Principal) -> Result<(), Error> {
+    if *principal == Principal::anonymous() {
+        return Err(Error::InvalidInput {
+            msg: "The anonymous principal cannot be an administrator.".to_string(),
+        });
+    }
+    if get(principal).is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!("{} is already an administrator.", principal),
+        });
+    }
+    Ok(())
+}
+
+// Internal function to grant administrator rights to a principal, recording `added_by` as the
+// administrator who added or proposed it. A new administrator could approve proposals alone
+// with the one who added them, so past the first two this runs through a proposal.
+pub(crate) fn _add_admin(
+    clock: &impl Clock,
+    added_by: Principal,
+    principal: Principal,
+) -> Result<Admin, Error> {
+    validate_addition(&principal)?;
+    let admin = Admin {
+        principal,
+        added_by,
+        added_at: clock.now(),
+    };
+    insert(admin.clone());
+    log::info(format!(
+        "{} added {} as an administrator.",
+        admin.added_by, principal
+    ));
+    Ok(admin)
+}
+
+// Check that a principal's administrator rights can be revoked; the last administrator
+// cannot be removed.
+pub(crate) fn validate_removal(principal: &Principal) -> Result<Admin, Error> {
+    let admin = get(principal).ok_or_else(|| Error::NotFound {
+        msg: format!("{} is not an administrator.", principal),
+    })?;
+    if ADMIN_STORAGE.with(|admins| admins.borrow().len()) <= 1 {
+        return Err(Error::InvalidInput {
+            msg: "The last administrator cannot be removed.".to_string(),
+        });
+    }
+    Ok(admin)
+}
+
+// Internal function to revoke a principal's administrator rights. Removing an administrator is
+// destructive, so outside a controller's call it runs through an approved proposal.
+pub(crate) fn _remove_admin(principal: &Principal) -> Result<Admin, Error> {
+    let admin = validate_removal(principal)?;
+    ADMIN_STORAGE.with(|admins| admins.borrow_mut().remove(&StorablePrincipal(*principal)));
+    log::warn(format!("Removed {} as an administrator.", principal));
+    Ok(admin)
+}
+
+// Reject callers who may not perform administrative actions. Controllers always pass, so a
+// canister whose administrators were all lost can still be recovered.
+pub(crate) fn require_admin() -> Result<(), Error> {
+    if is_admin(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only an administrator can do this.".to_string(),
+        })
+    }
+}
+
+// Whether a principal may perform administrative actions.
+pub(crate) fn is_admin(principal: &Principal) -> bool {
+    get(principal).is_some() || ic_cdk::api::is_controller(principal)
+}
+
+// Internal function to fetch an administrator by principal.
+fn get(principal: &Principal) -> Option<Admin> {
+    ADMIN_STORAGE.with(|admins| admins.borrow().get(&StorablePrincipal(*principal)))
+}
+
+// Helper function to store an administrator.
+fn insert(admin: Admin) {
+    ADMIN_STORAGE.with(|admins| {
+        admins
+            .borrow_mut()
+            .insert(StorablePrincipal(admin.principal), admin)
+    });
+}
@@ -1,147 +1,818 @@
-use candid::{Decode, Encode};
-use ic_stable_structures::{BoundedStorable, Storable};
-use std::borrow::Cow;
+use ic_stable_structures::StableBTreeMap;
+use std::collections::BTreeSet;
 
-use crate::{time, Error, BOOK_STORAGE, ID_COUNTER};
+use crate::auth::require_admin;
+use crate::catalog::require_catalog_access;
+use crate::cover;
+use crate::crud::{self, crud_endpoints, library_entity, RecordKind};
+use crate::date::DAY;
+use crate::env::{CanisterEnv, Clock};
+use crate::id_counter::Entity;
+use crate::language;
+use crate::live_update::{self, LiveUpdateKind};
+use crate::loan::{is_available, release_book};
+use crate::log;
+use crate::outbox::{self, OutboxTask};
+use crate::pagination::{budget_exhausted, Page, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::perf;
+use crate::read_model;
+use crate::storable::{candid_storable, IndexString, MAX_INDEX_STRING_BYTES};
+use crate::translation;
+use crate::trending;
+use crate::wishlist;
+use crate::{
+    check_size, Error, Memory, ALT_TITLE_INDEX, BARCODE_INDEX, BOOK_ALT_TITLES, BOOK_CREATED_INDEX,
+    BOOK_STORAGE, TITLE_INDEX,
+};
 
 // Define the Book struct to represent a book in the system.
-#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Default)]
 pub struct Book {
     pub id: u64,
     pub title: String,
     pub author: String,
+    // Label scanned at the circulation desk; unique across books.
+    pub barcode: Option<String>,
+    // What a lost copy is billed at, in minor units of the library's currency.
+    pub replacement_cost: Option<u64>,
+    // Set when the copy leaves circulation, e.g. because it was lost; it can't be lent after.
+    pub withdrawn_at: Option<u64>,
+    // Where the cover image is published; the outbox fetches a copy into the canister whenever
+    // it changes, and cache_cover refreshes it on demand.
+    pub cover_url: Option<String>,
+    // Youngest age, in years, a student must be to borrow the book.
+    pub min_age: Option<u8>,
+    // Set while a librarian features the book, e.g. on the new arrivals shelf.
+    pub featured_at: Option<u64>,
+    // ISO 639-1 code of the language the book is written in, lower-cased.
+    pub language: Option<String>,
+    // Accessible formats this copy is in; None and an empty list both mean regular print.
+    pub formats: Option<Vec<BookFormat>>,
+    // School grade the book suits readers in, on the same scale as a student's grade.
+    pub reading_level: Option<u8>,
+    // Globally unique, time-sortable ID, set on records created while ULIDs are enabled.
+    pub ulid: Option<String>,
     pub created_at: u64,
     pub updated_at: Option<u64>,
 }
 
-// Implement serialization and deserialization for Book.
-impl Storable for Book {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// Define the AltTitles struct to hold the other titles a book is found under, e.g. its
+// original-language title or a common abbreviation. They are kept beside the book, under its
+// ID, so that they don't count against the bound on Book.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct AltTitles {
+    pub titles: Vec<String>,
+}
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Store AltTitles as Candid, bounded to 512 bytes to fit MAX_ALT_TITLES of the longest.
+candid_storable!(AltTitles, max_size = 512);
+
+// An accessible edition a copy can be in.
+#[derive(
+    candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum BookFormat {
+    Audiobook,
+    LargePrint,
+    Braille,
 }
 
-// Set limits for Book storage size and flexibility.
-impl BoundedStorable for Book {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+impl Book {
+    // Whether the copy is in an accessible format.
+    pub fn has_format(&self, format: BookFormat) -> bool {
+        self.formats.iter().flatten().any(|f| *f == format)
+    }
 }
 
+// Most edits a single bulk update may carry.
+const MAX_BULK_UPDATES: usize = 500;
+
+// Longest cover URL accepted.
+const MAX_COVER_URL_LENGTH: usize = 512;
+
+// Longest barcode accepted.
+const MAX_BARCODE_LENGTH: usize = 64;
+
+// Most alternate titles a book may have, and the longest accepted.
+const MAX_ALT_TITLES: usize = 4;
+const MAX_ALT_TITLE_LENGTH: usize = 100;
+
+// Highest reading level accepted: the last school grade.
+const MAX_READING_LEVEL: u8 = 12;
+
+// Furthest back, in days, new arrivals may be listed from.
+const MAX_NEW_ARRIVAL_DAYS: u32 = 366;
+
+// Store books as Candid in their own collection, indexed by creation time.
+library_entity!(Book {
+    max_size: 1024,
+    kind: "book",
+    name: "Book",
+    entity: Entity::Book,
+    storage: BOOK_STORAGE,
+    created_index: BOOK_CREATED_INDEX,
+    on_delete: release,
+    on_write: written,
+});
+
 // Define the payload structure for creating or updating a book.
 #[derive(candid::CandidType, Serialize, Deserialize, Default)]
 pub struct BookPayload {
     title: String,
     author: String,
+    barcode: Option<String>,
+    replacement_cost: Option<u64>,
+    cover_url: Option<String>,
+    min_age: Option<u8>,
+    alt_titles: Option<Vec<String>>,
+    language: Option<String>,
+    formats: Option<Vec<BookFormat>>,
+    reading_level: Option<u8>,
+}
+
+// Define the payload structure for changing some of a book's details; fields left as None keep
+// their current value. The optional details are cleared with `opt null`.
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub struct BookPatch {
+    title: Option<String>,
+    author: Option<String>,
+    barcode: Option<Option<String>>,
+    replacement_cost: Option<Option<u64>>,
+    cover_url: Option<Option<String>>,
+    min_age: Option<Option<u8>>,
+    // Replaces the whole list; an empty list removes every alternate title.
+    alt_titles: Option<Vec<String>>,
+    language: Option<Option<String>>,
+    // Replaces the whole list, like `alt_titles`.
+    formats: Option<Vec<BookFormat>>,
+    reading_level: Option<Option<u8>>,
+}
+
+// Expose the standard read and delete endpoints for books.
+crud_endpoints!(Book {
+    all: get_all_books,
+    list: list_books,
+    get: get_book,
+    delete: delete_book,
+    kind: RecordKind::Book,
+    read_guard: require_catalog_access,
+});
+
+// Retrieve the books added at or after a point in time, oldest first.
+#[ic_cdk::query]
+fn get_books_added_since(since: u64) -> Result<Vec<Book>, Error> {
+    require_catalog_access()?;
+    Ok(crud::created_between(since, u64::MAX))
 }
 
-// Retrieve all books from the storage.
+// Retrieve the books added in the last `days` days, newest first. Pass `next_cursor` back as
+// `cursor` to continue.
 #[ic_cdk::query]
-fn get_all_books() -> Result<Vec<Book>, Error> {
-    let books = _get_all_books();
-    Ok(books)
+fn get_new_arrivals(
+    days: u32,
+    featured_only: bool,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Page<Book>, Error> {
+    require_catalog_access()?;
+    _get_new_arrivals(&CanisterEnv, days, featured_only, cursor, limit)
 }
 
-// Internal function to fetch all books as a vector.
-fn _get_all_books() -> Vec<Book> {
-    BOOK_STORAGE.with(|books| {
-        books
+// Internal function to list new arrivals as of `clock`'s time. The window is read from the
+// creation index, so only the books added within it are loaded.
+pub(crate) fn _get_new_arrivals(
+    clock: &impl Clock,
+    days: u32,
+    featured_only: bool,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Page<Book>, Error> {
+    if days == 0 || days > MAX_NEW_ARRIVAL_DAYS {
+        return Err(Error::InvalidInput {
+            msg: format!("New arrivals can cover 1 to {} days.", MAX_NEW_ARRIVAL_DAYS),
+        });
+    }
+    let since = clock.now().saturating_sub(days as u64 * DAY);
+    let end = match cursor {
+        Some(id) => (crud::find::<Book>(id)?.created_at, id),
+        None => (u64::MAX, u64::MAX),
+    };
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+
+    // The index can only be walked oldest first, so the window's keys are gathered and reversed.
+    let ids: Vec<u64> = BOOK_CREATED_INDEX.with(|index| {
+        index
             .borrow()
-            .iter()
-            .map(|(_, value)| value.clone())
+            .range((since, 0)..=end)
+            .map(|((_, id), _)| id)
             .collect()
+    });
+    let mut items = Vec::new();
+    let mut next_cursor = None;
+    for id in ids.into_iter().rev() {
+        let Some(book) = crud::get::<Book>(&id) else {
+            continue;
+        };
+        if featured_only && book.featured_at.is_none() {
+            continue;
+        }
+        // Always return at least one book so that every call makes progress.
+        if items.len() >= limit || (!items.is_empty() && budget_exhausted()) {
+            next_cursor = Some(id);
+            break;
+        }
+        items.push(book);
+    }
+    Ok(Page { items, next_cursor })
+}
+
+// Feature a book, or stop featuring it.
+#[ic_cdk::update]
+fn set_book_featured(id: u64, featured: bool) -> Result<Book, Error> {
+    let _perf = perf::measure("set_book_featured");
+    require_admin()?;
+    let now = CanisterEnv.now();
+    crud::update(id, |book: &mut Book| {
+        book.featured_at = match (featured, book.featured_at) {
+            (false, _) => None,
+            (true, Some(since)) => Some(since),
+            (true, None) => Some(now),
+        };
+        book.updated_at = Some(now);
     })
 }
 
-// Retrieve a specific book by its ID.
+// Add a new book to the registry.
+#[ic_cdk::update]
+fn add_book(payload: BookPayload) -> Result<Book, Error> {
+    let _perf = perf::measure("add_book");
+    require_admin()?;
+    _add_book(&CanisterEnv, payload)
+}
+
+// Internal function to add a book, taking its timestamps from `clock`.
+pub(crate) fn _add_book(clock: &impl Clock, mut payload: BookPayload) -> Result<Book, Error> {
+    validate(&payload)?;
+    check_barcode(payload.barcode.as_deref(), None)?;
+    let alt_titles = payload.alt_titles.take().unwrap_or_default();
+    let book = crud::create(clock, |id, ulid, now| Book {
+        id,
+        title: payload.title,
+        author: payload.author,
+        barcode: payload.barcode,
+        replacement_cost: payload.replacement_cost,
+        withdrawn_at: None,
+        cover_url: payload.cover_url,
+        min_age: payload.min_age,
+        featured_at: None,
+        language: payload.language.map(|l| l.trim().to_lowercase()),
+        formats: formats(payload.formats),
+        reading_level: payload.reading_level,
+        ulid,
+        created_at: now,
+        updated_at: None,
+    })?;
+    index_barcode(None, &book);
+    index_title(None, &book);
+    set_alt_titles(book.id, alt_titles);
+    Ok(book)
+}
+
+// Update an existing book's details by ID.
+#[ic_cdk::update]
+fn update_book(id: u64, payload: BookPayload) -> Result<Book, Error> {
+    let _perf = perf::measure("update_book");
+    require_admin()?;
+    _update_book(&CanisterEnv, id, payload)
+}
+
+// Internal function to update a book, taking its timestamps from `clock`.
+pub(crate) fn _update_book(
+    clock: &impl Clock,
+    id: u64,
+    mut payload: BookPayload,
+) -> Result<Book, Error> {
+    validate(&payload)?;
+    check_barcode(payload.barcode.as_deref(), Some(id))?;
+    let previous = crud::find::<Book>(id)?;
+    let alt_titles = payload.alt_titles.take().unwrap_or_default();
+    let book = crud::update(id, |book: &mut Book| {
+        book.title = payload.title;
+        book.author = payload.author;
+        book.barcode = payload.barcode;
+        book.replacement_cost = payload.replacement_cost;
+        book.cover_url = payload.cover_url;
+        book.min_age = payload.min_age;
+        book.language = payload.language.map(|l| l.trim().to_lowercase());
+        book.formats = formats(payload.formats);
+        book.reading_level = payload.reading_level;
+        book.updated_at = Some(clock.now());
+    })?;
+    index_barcode(previous.barcode.as_deref(), &book);
+    index_title(Some(&previous.title), &book);
+    set_alt_titles(id, alt_titles);
+    Ok(book)
+}
+
+// Check a book payload without writing it, returning the error add_book would, or update_book
+// would for the book `id`, so a form can show it before submitting.
 #[ic_cdk::query]
-fn get_book(id: u64) -> Result<Book, Error> {
-    match _get_book(&id) {
-        Some(book) => Ok(book),
-        None => Err(Error::NotFound {
-            msg: format!("A book with id={} not found.", id),
-        }),
+fn validate_book_payload(payload: BookPayload, id: Option<u64>) -> Result<(), Error> {
+    require_admin()?;
+    validate(&payload)?;
+    check_barcode(payload.barcode.as_deref(), id)?;
+    if let Some(id) = id {
+        crud::find::<Book>(id)?;
     }
+    Ok(())
 }
 
-// Internal function to fetch a book by ID.
-fn _get_book(id: &u64) -> Option<Book> {
-    BOOK_STORAGE.with(|s| s.borrow().get(id))
+// Change only the given details of a book, leaving the rest as they are.
+#[ic_cdk::update]
+fn patch_book(id: u64, patch: BookPatch) -> Result<Book, Error> {
+    let _perf = perf::measure("patch_book");
+    require_admin()?;
+    _patch_book(&CanisterEnv, id, patch)
 }
 
-// Add a new book to the registry.
+// Internal function to patch a book by applying `patch` over its stored details.
+pub(crate) fn _patch_book(clock: &impl Clock, id: u64, patch: BookPatch) -> Result<Book, Error> {
+    let book = crud::find::<Book>(id)?;
+    let payload = BookPayload {
+        title: patch.title.unwrap_or(book.title),
+        author: patch.author.unwrap_or(book.author),
+        barcode: patch.barcode.unwrap_or(book.barcode),
+        replacement_cost: patch.replacement_cost.unwrap_or(book.replacement_cost),
+        cover_url: patch.cover_url.unwrap_or(book.cover_url),
+        min_age: patch.min_age.unwrap_or(book.min_age),
+        alt_titles: patch.alt_titles.or_else(|| Some(alt_titles(id))),
+        language: patch.language.unwrap_or(book.language),
+        formats: patch.formats.or(book.formats),
+        reading_level: patch.reading_level.unwrap_or(book.reading_level),
+    };
+    _update_book(clock, id, payload)
+}
+
+// Apply many book patches in one call. Each edit succeeds or fails on its own; the results are
+// returned in the order the edits were given.
 #[ic_cdk::update]
-fn add_book(payload: BookPayload) -> Result<Book, Error> {
-    // Validate the input payload.
-    if payload.title.trim().is_empty() || payload.author.trim().is_empty() {
+fn bulk_update_books(edits: Vec<(u64, BookPatch)>) -> Result<Vec<Result<Book, Error>>, Error> {
+    let _perf = perf::measure("bulk_update_books");
+    require_admin()?;
+    _bulk_update_books(&CanisterEnv, edits)
+}
+
+// Run a bulk update without keeping anything: this is a query, so every write is discarded
+// when it returns. The results are what bulk_update_books would return right now, including
+// barcode clashes between edits of the same batch.
+#[ic_cdk::query]
+fn preview_bulk_update_books(
+    edits: Vec<(u64, BookPatch)>,
+) -> Result<Vec<Result<Book, Error>>, Error> {
+    require_admin()?;
+    _bulk_update_books(&CanisterEnv, edits)
+}
+
+// Internal function to apply book patches in order, taking timestamps from `clock`.
+pub(crate) fn _bulk_update_books(
+    clock: &impl Clock,
+    edits: Vec<(u64, BookPatch)>,
+) -> Result<Vec<Result<Book, Error>>, Error> {
+    if edits.len() > MAX_BULK_UPDATES {
         return Err(Error::InvalidInput {
-            msg: "Title and author cannot be empty.".to_string(),
+            msg: format!("At most {} books can be updated at once.", MAX_BULK_UPDATES),
         });
     }
+    Ok(edits
+        .into_iter()
+        .map(|(id, patch)| _patch_book(clock, id, patch))
+        .collect())
+}
+
+// Change the author of every book by `from` to `to`, matching `from` the way copies are matched,
+// ignoring case and surrounding spaces. Merging a misspelled variant into the right name is a
+// rename to that name. Returns the books changed.
+#[ic_cdk::update]
+fn rename_author(from: String, to: String) -> Result<Vec<Book>, Error> {
+    let _perf = perf::measure("rename_author");
+    require_admin()?;
+    _rename_author(&CanisterEnv, &from, to)
+}
 
-    // Generate a new unique ID for the book.
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
+// Internal function to rename an author, taking timestamps from `clock`. Every book is checked
+// before any is changed, so the rename applies to all of them or none.
+pub(crate) fn _rename_author(
+    clock: &impl Clock,
+    from: &str,
+    to: String,
+) -> Result<Vec<Book>, Error> {
+    let to = to.trim().to_string();
+    if to.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "An author cannot be empty.".to_string(),
+        });
+    }
+    let key = from.trim().to_lowercase();
+    let books: Vec<Book> = crud::all::<Book>()
+        .into_iter()
+        .filter(|book| book.author.trim().to_lowercase() == key)
+        .collect();
+    if books.is_empty() {
+        return Err(Error::NotFound {
+            msg: format!("No book is by \"{}\".", from.trim()),
+        });
+    }
+    let now = clock.now();
+    let renamed: Vec<Book> = books
+        .into_iter()
+        .map(|book| Book {
+            author: to.clone(),
+            updated_at: Some(now),
+            ..book
         })
-        .expect("Cannot increment ID counter");
+        .collect();
+    for book in &renamed {
+        check_size("book", book)?;
+    }
+    for book in &renamed {
+        crud::insert(book)?;
+    }
+    log::info(format!(
+        "Renamed author \"{}\" to \"{}\" on {} books.",
+        from.trim(),
+        to,
+        renamed.len()
+    ));
+    Ok(renamed)
+}
 
-    // Create the new book with the provided payload.
-    let book = Book {
-        id,
-        title: payload.title,
-        author: payload.author,
-        created_at: time(),
-        updated_at: None,
+// Browse books alphabetically by title, starting at the first title at or after `starting_at`
+// (e.g. a single letter). Pass `next_cursor` back as `cursor` to continue; it takes precedence
+// over `starting_at`. Titles are given in `language` where the book has a translation into it;
+// books are still ordered by their own title.
+#[ic_cdk::query]
+fn browse_books(
+    starting_at: Option<String>,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+    language: Option<String>,
+) -> Result<Page<Book>, Error> {
+    require_catalog_access()?;
+    let start = match cursor {
+        Some(id) => (title_key(&crud::find::<Book>(id)?.title), id),
+        None => (title_key(starting_at.as_deref().unwrap_or_default()), 0),
     };
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
 
-    // Insert the book into storage.
-    do_insert(&book);
-    Ok(book)
+    TITLE_INDEX.with(|index| {
+        let mut items = Vec::new();
+        let mut next_cursor = None;
+        for ((key, id), _) in index.borrow().range(start..) {
+            // Skip entries left behind by books that were deleted while unreadable.
+            let Some(book) = crud::get::<Book>(&id).filter(|book| title_key(&book.title) == key)
+            else {
+                continue;
+            };
+            // Always return at least one book so that every call makes progress.
+            if items.len() >= limit || (!items.is_empty() && budget_exhausted()) {
+                next_cursor = Some(id);
+                break;
+            }
+            items.push(translation::localize(book, language.as_deref()));
+        }
+        Ok(Page { items, next_cursor })
+    })
 }
 
-// Helper function to insert a book into storage.
-fn do_insert(book: &Book) {
-    BOOK_STORAGE.with(|service| service.borrow_mut().insert(book.id, book.clone()));
+// Retrieve the other titles a book is found under.
+#[ic_cdk::query]
+fn get_book_alt_titles(book_id: u64) -> Result<Vec<String>, Error> {
+    require_catalog_access()?;
+    crud::find::<Book>(book_id)?;
+    Ok(alt_titles(book_id))
 }
 
-// Update an existing book's details by ID.
-#[ic_cdk::update]
-fn update_book(id: u64, payload: BookPayload) -> Result<Book, Error> {
-    // Validate the input payload.
-    if payload.title.trim().is_empty() || payload.author.trim().is_empty() {
+// Find the books whose title or one of whose alternate titles starts with `query`, ignoring
+// case and spacing the way titles are sorted. Books are listed once each, in ID order, with
+// their titles in `language` where they have a translation into it. With `format`, only copies
+// in that accessible format are listed.
+#[ic_cdk::query]
+fn find_books_by_title(
+    query: String,
+    limit: Option<u32>,
+    language: Option<String>,
+    format: Option<BookFormat>,
+) -> Result<Vec<Book>, Error> {
+    require_catalog_access()?;
+    let prefix = title_key(&query);
+    if prefix.0.is_empty() {
         return Err(Error::InvalidInput {
-            msg: "Title and author cannot be empty.".to_string(),
+            msg: "A title search cannot be empty.".to_string(),
         });
     }
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+    let matching = |index: &StableBTreeMap<(IndexString, u64), (), Memory>| -> Vec<u64> {
+        index
+            .range((prefix.clone(), 0)..)
+            .take_while(|((key, _), _)| key.0.starts_with(&prefix.0))
+            .map(|((_, id), _)| id)
+            .collect()
+    };
+    let mut ids = BTreeSet::new();
+    ids.extend(TITLE_INDEX.with(|index| matching(&index.borrow())));
+    ids.extend(ALT_TITLE_INDEX.with(|index| matching(&index.borrow())));
+    // Skip entries left behind by books whose titles have changed or that were deleted.
+    let title_matches = |title: &String| title_key(title).0.starts_with(&prefix.0);
+    Ok(ids
+        .into_iter()
+        .filter_map(|id| crud::get::<Book>(&id))
+        .filter(|book| title_matches(&book.title) || alt_titles(book.id).iter().any(title_matches))
+        .filter(|book| format.is_none_or(|format| book.has_format(format)))
+        .take(limit)
+        .map(|book| translation::localize(book, language.as_deref()))
+        .collect())
+}
 
-    // Fetch the book from storage and update its details.
-    match BOOK_STORAGE.with(|service| service.borrow().get(&id)) {
-        Some(mut book) => {
-            book.title = payload.title;
-            book.author = payload.author;
-            book.updated_at = Some(time());
-            do_insert(&book); // Save the updated book back to storage.
-            Ok(book)
+// Retrieve the books in circulation at a reading level, in ID order. Pass `next_cursor` back as
+// `cursor` to continue.
+#[ic_cdk::query]
+fn get_books_for_level(
+    level: u8,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Page<Book>, Error> {
+    require_catalog_access()?;
+    check_reading_level(Some(level))?;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+    BOOK_STORAGE.with(|s| {
+        let mut items = Vec::new();
+        let mut next_cursor = None;
+        for (id, book) in s.borrow().range(cursor.unwrap_or(0)..) {
+            if book.reading_level != Some(level) || book.withdrawn_at.is_some() {
+                continue;
+            }
+            // Always return at least one book so that every call makes progress.
+            if items.len() >= limit || (!items.is_empty() && budget_exhausted()) {
+                next_cursor = Some(id);
+                break;
+            }
+            items.push(book);
         }
-        None => Err(Error::NotFound {
-            msg: format!("Couldn't update a book with id={}. Book not found.", id),
+        Ok(Page { items, next_cursor })
+    })
+}
+
+// Retrieve the book carrying a barcode.
+#[ic_cdk::query]
+fn get_book_by_barcode(barcode: String) -> Result<Book, Error> {
+    require_catalog_access()?;
+    _get_book_by_barcode(&barcode).ok_or_else(|| Error::NotFound {
+        msg: format!("A book with barcode {} not found.", barcode.trim()),
+    })
+}
+
+// Internal function to resolve a barcode to its book. Index entries left behind by deleted
+// books are ignored, since the book they point to no longer carries the barcode.
+pub(crate) fn _get_book_by_barcode(barcode: &str) -> Option<Book> {
+    let barcode = barcode.trim();
+    let key = IndexString(barcode.to_string());
+    BARCODE_INDEX
+        .with(|index| index.borrow().get(&key))
+        .and_then(|id| crud::get::<Book>(&id))
+        .filter(|book| book.barcode.as_deref() == Some(barcode))
+}
+
+// Check that a barcode is well formed and not carried by a book other than `except`.
+fn check_barcode(barcode: Option<&str>, except: Option<u64>) -> Result<(), Error> {
+    let Some(barcode) = barcode else {
+        return Ok(());
+    };
+    if barcode.trim() != barcode
+        || barcode.is_empty()
+        || barcode.len() > MAX_BARCODE_LENGTH
+        || !barcode
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A barcode must be 1 to {} letters, digits or dashes.",
+                MAX_BARCODE_LENGTH
+            ),
+        });
+    }
+    match _get_book_by_barcode(barcode) {
+        Some(book) if Some(book.id) != except => Err(Error::InvalidInput {
+            msg: format!(
+                "The barcode {} is already used by book id={}.",
+                barcode, book.id
+            ),
         }),
+        _ => Ok(()),
     }
 }
 
-// Delete a book by ID from the registry.
-#[ic_cdk::update]
-fn delete_book(id: u64) -> Result<Book, Error> {
-    // Remove the book from storage.
-    match BOOK_STORAGE.with(|service| service.borrow_mut().remove(&id)) {
-        Some(book) => Ok(book),
-        None => Err(Error::NotFound {
-            msg: format!("Couldn't delete a book with id={}. Book not found.", id),
-        }),
+// Internal function to write back a book copied by a snapshot, moving its index entries from
+// whatever the book holds now.
+pub(crate) fn restore(book: Book) -> Result<(), Error> {
+    let previous = crud::get::<Book>(&book.id);
+    crud::insert(&book)?;
+    index_title(previous.as_ref().map(|p| p.title.as_str()), &book);
+    index_barcode(previous.as_ref().and_then(|p| p.barcode.as_deref()), &book);
+    Ok(())
+}
+
+// Helper function to point the barcode index at `book`, dropping its `previous` barcode.
+fn index_barcode(previous: Option<&str>, book: &Book) {
+    BARCODE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous) = previous {
+            index.remove(&IndexString(previous.to_string()));
+        }
+        if let Some(barcode) = &book.barcode {
+            index.insert(IndexString(barcode.clone()), book.id);
+        }
+    });
+}
+
+// Helper function to move `book` in the title index from its `previous` title.
+pub(crate) fn index_title(previous: Option<&str>, book: &Book) {
+    TITLE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous) = previous {
+            index.remove(&(title_key(previous), book.id));
+        }
+        index.insert((title_key(&book.title), book.id), ());
+    });
+}
+
+// Internal function to find every copy of `book`'s title, itself included: the books with the
+// same title and author, in ID order.
+pub(crate) fn copies_of(book: &Book) -> Vec<Book> {
+    let key = title_key(&book.title);
+    let author = book.author.trim().to_lowercase();
+    TITLE_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((key.clone(), 0)..=(key.clone(), u64::MAX))
+            .filter_map(|((_, id), _)| crud::get::<Book>(&id))
+            .filter(|copy| {
+                title_key(&copy.title) == key && copy.author.trim().to_lowercase() == author
+            })
+            .collect()
+    })
+}
+
+// Internal function to read a book's alternate titles.
+pub(crate) fn alt_titles(book_id: u64) -> Vec<String> {
+    BOOK_ALT_TITLES
+        .with(|s| s.borrow().get(&book_id))
+        .map(|alt_titles| alt_titles.titles)
+        .unwrap_or_default()
+}
+
+// Helper function to replace a book's alternate titles, moving them in the index too; an empty
+// list removes them.
+fn set_alt_titles(book_id: u64, titles: Vec<String>) {
+    ALT_TITLE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for title in alt_titles(book_id) {
+            index.remove(&(title_key(&title), book_id));
+        }
+        for title in &titles {
+            index.insert((title_key(title), book_id), ());
+        }
+    });
+    BOOK_ALT_TITLES.with(|s| {
+        if titles.is_empty() {
+            s.borrow_mut().remove(&book_id);
+        } else {
+            s.borrow_mut().insert(book_id, AltTitles { titles });
+        }
+    });
+}
+
+// The form titles are sorted by: lower-cased, with runs of whitespace collapsed and leading
+// punctuation dropped, cut to what an index key can hold.
+pub(crate) fn title_key(title: &str) -> IndexString {
+    let normalized = title
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    let mut key = normalized
+        .trim_start_matches(|c: char| !c.is_alphanumeric())
+        .to_string();
+    if key.len() > MAX_INDEX_STRING_BYTES {
+        let mut end = MAX_INDEX_STRING_BYTES;
+        while !key.is_char_boundary(end) {
+            end -= 1;
+        }
+        key.truncate(end);
+    }
+    IndexString(key)
+}
+
+// Delete policy for books: apply the loan rules, then drop the book from the lookup indexes,
+// wishlists and trending list and discard its cached cover.
+fn release(id: u64, cascade: bool) -> Result<(), Error> {
+    release_book(id, cascade)?;
+    if let Some(book) = crud::get::<Book>(&id) {
+        trending::remove_book(&book);
+        if let Some(barcode) = book.barcode {
+            BARCODE_INDEX.with(|index| index.borrow_mut().remove(&IndexString(barcode)));
+        }
+        TITLE_INDEX.with(|index| index.borrow_mut().remove(&(title_key(&book.title), id)));
+    }
+    set_alt_titles(id, Vec::new());
+    cover::remove_cover(id);
+    translation::remove_book(id);
+    wishlist::remove_book(id);
+    Ok(())
+}
+
+// Hook run on every book write: keep the open-loan summaries in step, publish a change of
+// availability, e.g. when the book is withdrawn, and queue a fetch of the cover whenever a book
+// gets a new cover_url.
+fn written(previous: Option<&Book>, current: Option<&Book>) {
+    read_model::book_written(previous, current);
+    let Some(book) = current else {
+        return;
+    };
+    if let Some(previous) = previous {
+        let now = CanisterEnv.now();
+        let available = is_available(book, now);
+        if is_available(previous, now) != available {
+            let kind = LiveUpdateKind::AvailabilityChanged {
+                book_id: book.id,
+                available,
+            };
+            live_update::publish(now, kind);
+        }
+    }
+    if book.cover_url.is_none()
+        || previous.is_some_and(|previous| previous.cover_url == book.cover_url)
+    {
+        return;
+    }
+    let task = OutboxTask::FetchCover { book_id: book.id };
+    if outbox::enqueue(CanisterEnv.now(), task).is_err() {
+        log::warn(format!(
+            "Could not queue a cover fetch for book id={}: no outbox IDs are left.",
+            book.id
+        ));
+    }
+}
+
+// Sort a list of formats and drop repeats, keeping None for a copy in regular print only.
+fn formats(formats: Option<Vec<BookFormat>>) -> Option<Vec<BookFormat>> {
+    let formats: BTreeSet<BookFormat> = formats.into_iter().flatten().collect();
+    (!formats.is_empty()).then(|| formats.into_iter().collect())
+}
+
+// Check that a reading level is a school grade.
+fn check_reading_level(level: Option<u8>) -> Result<(), Error> {
+    if level.is_some_and(|level| level > MAX_READING_LEVEL) {
+        return Err(Error::InvalidInput {
+            msg: format!("A reading level must be 0 to {}.", MAX_READING_LEVEL),
+        });
+    }
+    Ok(())
+}
+
+// Validate a book payload.
+fn validate(payload: &BookPayload) -> Result<(), Error> {
+    if payload.title.trim().is_empty() || payload.author.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Title and author cannot be empty.".to_string(),
+        });
+    }
+    let alt_titles = payload.alt_titles.as_deref().unwrap_or_default();
+    if alt_titles.len() > MAX_ALT_TITLES
+        || alt_titles
+            .iter()
+            .any(|title| title.trim().is_empty() || title.len() > MAX_ALT_TITLE_LENGTH)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A book can have at most {} alternate titles of 1 to {} characters.",
+                MAX_ALT_TITLES, MAX_ALT_TITLE_LENGTH
+            ),
+        });
+    }
+    if let Some(language) = &payload.language {
+        language::check_language(language)?;
+    }
+    check_reading_level(payload.reading_level)?;
+    if let Some(url) = &payload.cover_url {
+        if !url.starts_with("https://") || url.len() > MAX_COVER_URL_LENGTH {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "A cover URL must use https and be at most {} characters.",
+                    MAX_COVER_URL_LENGTH
+                ),
+            });
+        }
     }
+    Ok(())
 }
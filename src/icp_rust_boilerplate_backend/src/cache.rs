@@ -0,0 +1,89 @@
+use candid::{CandidType, Decode, Encode};
+use serde::de::DeserializeOwned;
+
+use crate::auth::require_admin;
+use crate::perf;
+use crate::storable::{candid_storable, IndexString};
+use crate::{check_size, Error, AGGREGATE_CACHE};
+
+// How long a cached result is served before it must be computed again, in nanoseconds.
+const CACHE_TTL: u64 = 10 * 60 * 1_000_000_000;
+
+// Most results kept; the oldest is dropped to make room for a new one.
+const MAX_CACHE_ENTRIES: u64 = 100;
+
+// Define the CachedResult struct to keep the Candid encoding of an expensive computed result,
+// such as a circulation report. Any write to the records it was computed from clears it.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct CachedResult {
+    pub value: Vec<u8>,
+    pub computed_at: u64,
+    pub expires_at: u64,
+}
+
+// Store CachedResult as Candid, bounded to 16 KiB.
+candid_storable!(CachedResult, max_size = 16 * 1024);
+
+// Drop every cached result, e.g. after changing records outside the canister's own endpoints.
+#[ic_cdk::update]
+fn clear_aggregate_cache() -> Result<u64, Error> {
+    let _perf = perf::measure("clear_aggregate_cache");
+    require_admin()?;
+    Ok(invalidate_all())
+}
+
+// Internal function to read a cached result that hasn't expired by `now`.
+pub(crate) fn get<T: CandidType + DeserializeOwned>(key: &str, now: u64) -> Option<T> {
+    let cached = AGGREGATE_CACHE.with(|c| c.borrow().get(&IndexString(key.to_string())))?;
+    if cached.expires_at <= now {
+        return None;
+    }
+    Decode!(&cached.value, T).ok()
+}
+
+// Internal function to cache a result computed at `now`. Queries can't keep what they write,
+// so only update calls populate the cache.
+pub(crate) fn put<T: CandidType>(key: &str, value: &T, now: u64) {
+    let Ok(value) = Encode!(value) else {
+        return;
+    };
+    let cached = CachedResult {
+        value,
+        computed_at: now,
+        expires_at: now.saturating_add(CACHE_TTL),
+    };
+    // A result too large to store is simply not cached.
+    if check_size("cached result", &cached).is_err() {
+        return;
+    }
+    AGGREGATE_CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        cache.insert(IndexString(key.to_string()), cached);
+        while cache.len() > MAX_CACHE_ENTRIES {
+            let oldest = cache
+                .iter()
+                .min_by_key(|(_, cached)| cached.computed_at)
+                .map(|(key, _)| key);
+            match oldest {
+                Some(key) => cache.remove(&key),
+                None => break,
+            };
+        }
+    });
+}
+
+// Hook run on every write to the records results are computed from: drop every cached
+// result, returning how many there were.
+pub(crate) fn invalidate_all() -> u64 {
+    AGGREGATE_CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        if cache.is_empty() {
+            return 0;
+        }
+        let keys: Vec<IndexString> = cache.iter().map(|(key, _)| key).collect();
+        for key in &keys {
+            cache.remove(key);
+        }
+        keys.len() as u64
+    })
+}
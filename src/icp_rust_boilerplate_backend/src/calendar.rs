@@ -0,0 +1,179 @@
+use sha2::{Digest, Sha256};
+
+use crate::book::Book;
+use crate::crud;
+use crate::date::civil;
+use crate::env::{random_bytes, CanisterEnv, Clock};
+use crate::loan::{Loan, LoanStatus};
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::student::{caller_student, Student};
+use crate::{Error, CALENDAR_TOKEN_STORAGE};
+
+// Prefix of every calendar token, so leaked tokens are easy to recognise.
+const TOKEN_PREFIX: &str = "cal";
+
+// Longest line an iCalendar document may have, in octets, before it must be folded.
+const MAX_ICAL_LINE: usize = 75;
+
+// Define the CalendarToken struct to record the token a student's due-date feed is served
+// under. Only a hash of the token is stored.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct CalendarToken {
+    pub student_id: u64,
+    pub token_hash: String,
+    pub issued_at: u64,
+}
+
+// Store CalendarToken as Candid, bounded to 256 bytes.
+candid_storable!(CalendarToken, max_size = 256);
+
+// A newly issued feed; `url_path` carries the token and is shown only once.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct IssuedCalendarFeed {
+    pub token: CalendarToken,
+    pub url_path: String,
+}
+
+// Issue a due-date calendar feed for the student linked to the caller, replacing any earlier
+// one so that its address stops working.
+#[ic_cdk::update]
+async fn issue_my_calendar_feed() -> Result<IssuedCalendarFeed, Error> {
+    let _perf = perf::measure("issue_my_calendar_feed");
+    let student = caller_student(&CanisterEnv)?;
+    let secret = random_bytes().await?;
+    Ok(_issue_calendar_feed(&CanisterEnv, student.id, &secret))
+}
+
+// Stop serving the caller's calendar feed.
+#[ic_cdk::update]
+fn revoke_my_calendar_feed() -> Result<CalendarToken, Error> {
+    let _perf = perf::measure("revoke_my_calendar_feed");
+    let student = caller_student(&CanisterEnv)?;
+    remove_student(student.id).ok_or_else(|| Error::NotFound {
+        msg: "You have no calendar feed.".to_string(),
+    })
+}
+
+// Internal function to store a feed token built from `secret` for a student.
+pub(crate) fn _issue_calendar_feed(
+    clock: &impl Clock,
+    student_id: u64,
+    secret: &[u8],
+) -> IssuedCalendarFeed {
+    let token = format!("{}_{}_{}", TOKEN_PREFIX, student_id, hex::encode(secret));
+    let record = CalendarToken {
+        student_id,
+        token_hash: hash(&token),
+        issued_at: clock.now(),
+    };
+    CALENDAR_TOKEN_STORAGE.with(|s| s.borrow_mut().insert(student_id, record.clone()));
+    IssuedCalendarFeed {
+        token: record,
+        url_path: format!("/students/{}/due-dates.ics", token),
+    }
+}
+
+// Resolve a presented feed token to its student, or None if it isn't live.
+pub(crate) fn student_for_token(token: &str) -> Option<Student> {
+    let student_id = token
+        .strip_prefix(TOKEN_PREFIX)
+        .and_then(|rest| rest.strip_prefix('_'))
+        .and_then(|rest| rest.split('_').next())
+        .and_then(|id| id.parse::<u64>().ok())?;
+    CALENDAR_TOKEN_STORAGE
+        .with(|s| s.borrow().get(&student_id))
+        .filter(|record| record.token_hash == hash(token))?;
+    crud::get::<Student>(&student_id)
+}
+
+// Build the iCalendar document of a student's due dates: one all-day event per book they have
+// out, on the day it is due.
+pub(crate) fn due_dates_ics(student: &Student, now: u64) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//Library canister//Due dates//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        format!(
+            "X-WR-CALNAME:{}",
+            escape(&format!("Library loans of {}", student.name))
+        ),
+    ];
+    let loans = crud::all::<Loan>().into_iter().filter(|loan| {
+        loan.student_id == student.id
+            && matches!(loan.status(), LoanStatus::Active | LoanStatus::Overdue)
+    });
+    for loan in loans {
+        let Some(due) = loan.due_date else {
+            continue;
+        };
+        let title = crud::get::<Book>(&loan.book_id)
+            .map_or_else(|| format!("book id={}", loan.book_id), |book| book.title);
+        lines.extend([
+            "BEGIN:VEVENT".to_string(),
+            format!("UID:loan-{}@{}", loan.id, ic_cdk::id()),
+            format!("DTSTAMP:{}", ical_timestamp(now)),
+            format!("DTSTART;VALUE=DATE:{}", ical_date(due)),
+            format!("SUMMARY:{}", escape(&format!("Return \"{}\"", title))),
+            "TRANSP:TRANSPARENT".to_string(),
+            "END:VEVENT".to_string(),
+        ]);
+    }
+    lines.push("END:VCALENDAR".to_string());
+    lines.iter().map(|line| fold(line)).collect()
+}
+
+// Helper function to drop a student's feed token, returning the token removed.
+pub(crate) fn remove_student(student_id: u64) -> Option<CalendarToken> {
+    CALENDAR_TOKEN_STORAGE.with(|s| s.borrow_mut().remove(&student_id))
+}
+
+// A timestamp's UTC date in iCalendar form, e.g. "20260301".
+fn ical_date(nanos: u64) -> String {
+    let (year, month, day, ..) = civil(nanos);
+    format!("{:04}{:02}{:02}", year, month, day)
+}
+
+// A timestamp in iCalendar's UTC form, e.g. "20260301T093000Z".
+fn ical_timestamp(nanos: u64) -> String {
+    let (_, _, _, hour, minute, second) = civil(nanos);
+    format!(
+        "{}T{:02}{:02}{:02}Z",
+        ical_date(nanos),
+        hour,
+        minute,
+        second
+    )
+}
+
+// Escape text for an iCalendar property value.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+        .replace('\r', "")
+}
+
+// A content line ended by CRLF, folded onto continuation lines so none exceeds MAX_ICAL_LINE
+// octets; folds never split a character.
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 2);
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > MAX_ICAL_LINE {
+            folded.push_str("\r\n ");
+            width = 1;
+        }
+        folded.push(c);
+        width += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+// Hex-encoded SHA-256 of a token, which is what gets stored.
+fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
@@ -0,0 +1,67 @@
+use candid::Principal;
+
+use crate::auth::require_admin;
+use crate::env::CanisterEnv;
+use crate::log;
+use crate::perf;
+use crate::proposal::{_propose_admin_action, AdminAction, Proposal};
+use crate::storable::candid_storable;
+use crate::{Error, CATALOG_CONFIG};
+
+// Who may browse the catalog.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, Default)]
+pub struct CatalogConfig {
+    // Let anonymous callers and HTTP visitors read books, arrivals and suggestions, so the
+    // catalog can back a public website. Student, loan and charge data stay behind sign-in
+    // and roles either way.
+    pub public: bool,
+}
+
+// Store CatalogConfig as Candid.
+candid_storable!(CatalogConfig);
+
+// Retrieve who may browse the catalog.
+#[ic_cdk::query]
+fn get_catalog_config() -> CatalogConfig {
+    CATALOG_CONFIG.with(|c| *c.borrow().get())
+}
+
+// Propose opening the catalog to anonymous callers, or restricting it to signed-in ones again.
+// It takes effect once another administrator approves.
+#[ic_cdk::update]
+fn set_catalog_config(config: CatalogConfig) -> Result<Proposal, Error> {
+    let _perf = perf::measure("set_catalog_config");
+    require_admin()?;
+    _propose_admin_action(&CanisterEnv, AdminAction::SetCatalogConfig { config })
+}
+
+// Store the catalog config, recording `approved_by` in the log. Opening the catalog exposes it
+// publicly, so this runs only through an approved proposal.
+pub(crate) fn set_config(approved_by: Principal, config: CatalogConfig) -> CatalogConfig {
+    CATALOG_CONFIG
+        .with(|c| c.borrow_mut().set(config))
+        .expect("Cannot store the catalog config");
+    log::info(format!(
+        "{} made the catalog {}.",
+        approved_by,
+        if config.public { "public" } else { "private" }
+    ));
+    config
+}
+
+// Whether anonymous callers may browse the catalog.
+pub(crate) fn is_public() -> bool {
+    CATALOG_CONFIG.with(|c| c.borrow().get().public)
+}
+
+// Check that the caller may browse the catalog: anyone while it is public, otherwise only
+// signed-in callers.
+pub(crate) fn require_catalog_access() -> Result<(), Error> {
+    if is_public() || ic_cdk::caller() != candid::Principal::anonymous() {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Sign in to browse the catalog.".to_string(),
+        })
+    }
+}
@@ -0,0 +1,159 @@
+use sha2::{Digest, Sha256};
+
+use crate::ledger;
+use crate::CERTIFIED_DIGEST;
+
+// Labels of the certified hash tree, in the sorted order the tree requires.
+const LAST_BLOCK_HASH: &[u8] = b"last_block_hash";
+const LAST_BLOCK_INDEX: &[u8] = b"last_block_index";
+const STATE_ROOT: &[u8] = b"state_root";
+
+// CBOR tag that marks the encoding as self-describing CBOR.
+const SELF_DESCRIBE_TAG: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+// An IC hash tree, the structure whose root hash the canister certifies.
+pub enum HashTree {
+    Empty,
+    Fork(Box<HashTree>, Box<HashTree>),
+    Labeled(Vec<u8>, Box<HashTree>),
+    Leaf(Vec<u8>),
+}
+
+impl HashTree {
+    // Root hash of the tree, as defined by the IC interface specification.
+    pub fn hash(&self) -> [u8; 32] {
+        match self {
+            HashTree::Empty => domain_hash("ic-hashtree-empty", &[]),
+            HashTree::Fork(left, right) => {
+                domain_hash("ic-hashtree-fork", &[&left.hash(), &right.hash()])
+            }
+            HashTree::Labeled(label, tree) => {
+                domain_hash("ic-hashtree-labeled", &[label, &tree.hash()])
+            }
+            HashTree::Leaf(value) => domain_hash("ic-hashtree-leaf", &[value]),
+        }
+    }
+
+    // Self-describing CBOR encoding of the tree, the form clients verify it in.
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut out = SELF_DESCRIBE_TAG.to_vec();
+        self.encode(&mut out);
+        out
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            HashTree::Empty => out.extend_from_slice(&[0x81, 0x00]),
+            HashTree::Fork(left, right) => {
+                out.extend_from_slice(&[0x83, 0x01]);
+                left.encode(out);
+                right.encode(out);
+            }
+            HashTree::Labeled(label, tree) => {
+                out.extend_from_slice(&[0x83, 0x02]);
+                encode_bytes(label, out);
+                tree.encode(out);
+            }
+            HashTree::Leaf(value) => {
+                out.extend_from_slice(&[0x82, 0x03]);
+                encode_bytes(value, out);
+            }
+        }
+    }
+}
+
+// The tree the canister certifies: the tip of the financial block log, once it has blocks,
+// and the last certified state root, once there is one.
+pub(crate) fn tree() -> HashTree {
+    let mut entries = Vec::new();
+    if let Some((index, hash)) = ledger::tip() {
+        let mut leb = Vec::new();
+        candid::Nat::from(index)
+            .encode(&mut leb)
+            .expect("Encoding a Nat cannot fail");
+        entries.push((LAST_BLOCK_HASH, hash.to_vec()));
+        entries.push((LAST_BLOCK_INDEX, leb));
+    }
+    let state_root = CERTIFIED_DIGEST.with(|c| c.borrow().get().root.clone());
+    if !state_root.is_empty() {
+        entries.push((STATE_ROOT, state_root));
+    }
+    entries
+        .into_iter()
+        .map(|(label, value)| HashTree::Labeled(label.to_vec(), Box::new(HashTree::Leaf(value))))
+        .reduce(|left, right| HashTree::Fork(Box::new(left), Box::new(right)))
+        .unwrap_or(HashTree::Empty)
+}
+
+// Publish the root of the current tree as the canister's certified data. Called whenever a
+// block is appended or the state is certified, and after an upgrade, which clears it. Native
+// unit tests have no system API, so they skip the publishing.
+pub(crate) fn publish() {
+    let root = tree().hash();
+    if cfg!(not(test)) {
+        ic_cdk::api::set_certified_data(&root);
+    }
+}
+
+// SHA-256 of a domain separator, its length byte first, followed by `parts`.
+fn domain_hash(separator: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([separator.len() as u8]);
+    hasher.update(separator.as_bytes());
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+// Append a CBOR byte string.
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    match bytes.len() {
+        len @ 0..=23 => out.push(0x40 | len as u8),
+        len @ 24..=0xff => out.extend_from_slice(&[0x58, len as u8]),
+        len => {
+            out.push(0x59);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_hashes_follow_the_interface_specification() {
+        let empty: [u8; 32] = Sha256::digest(b"\x11ic-hashtree-empty").into();
+        assert_eq!(HashTree::Empty.hash(), empty);
+
+        let leaf = HashTree::Leaf(b"hello".to_vec());
+        let expected: [u8; 32] = Sha256::digest(b"\x10ic-hashtree-leafhello").into();
+        assert_eq!(leaf.hash(), expected);
+
+        let labeled = HashTree::Labeled(b"a".to_vec(), Box::new(leaf));
+        let mut input = b"\x13ic-hashtree-labeleda".to_vec();
+        input.extend_from_slice(&expected);
+        let expected: [u8; 32] = Sha256::digest(&input).into();
+        assert_eq!(labeled.hash(), expected);
+    }
+
+    #[test]
+    fn tree_encodes_as_self_describing_cbor() {
+        let tree = HashTree::Fork(
+            Box::new(HashTree::Labeled(
+                b"a".to_vec(),
+                Box::new(HashTree::Leaf(vec![7])),
+            )),
+            Box::new(HashTree::Empty),
+        );
+        assert_eq!(
+            tree.to_cbor(),
+            vec![
+                0xd9, 0xd9, 0xf7, 0x83, 0x01, 0x83, 0x02, 0x41, b'a', 0x82, 0x03, 0x41, 7, 0x81,
+                0x00
+            ]
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use candid::Principal;
+
+use crate::auth::require_admin;
+use crate::cache;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::exchange_rate::{fetch_rate, Asset, AssetClass, Rate};
+use crate::id_counter::{next_id, Entity};
+use crate::ledger;
+use crate::live_update::{self, LiveUpdateKind};
+use crate::log;
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::student::caller_student;
+use crate::{Error, CHARGE_STORAGE};
+
+// Currency charges are set in, and the number of minor units per major unit.
+pub const CHARGE_CURRENCY: &str = "USD";
+const CHARGE_CURRENCY_DECIMALS: u32 = 2;
+
+// Token charges are paid in, and the number of decimals of its smallest unit.
+pub const PAYMENT_TOKEN: &str = "ICP";
+const PAYMENT_TOKEN_DECIMALS: u32 = 8;
+
+// Why a student is being charged.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum ChargeKind {
+    Replacement,
+}
+
+// Define the Charge struct to represent an amount a student owes the library.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct Charge {
+    pub id: u64,
+    pub student_id: u64,
+    pub loan_id: Option<u64>,
+    pub kind: ChargeKind,
+    // In minor units of the library's currency.
+    pub amount: u64,
+    pub created_at: u64,
+    pub paid_at: Option<u64>,
+    pub payment: Option<Payment>,
+}
+
+// How a charge was settled: the token amount taken for it and the exchange rate that amount
+// was converted at.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct Payment {
+    pub token: String,
+    // In the token's smallest unit.
+    pub token_amount: u64,
+    pub rate: Rate,
+    pub recorded_by: Principal,
+}
+
+// Store Charge as Candid, bounded to 512 bytes.
+candid_storable!(Charge, max_size = 512);
+
+// Retrieve a student's charges, oldest first.
+#[ic_cdk::query]
+fn get_student_charges(student_id: u64) -> Result<Vec<Charge>, Error> {
+    require_admin()?;
+    Ok(_get_charges(student_id))
+}
+
+// Retrieve the charges of the student linked to the caller.
+#[ic_cdk::query]
+fn get_my_charges() -> Result<Vec<Charge>, Error> {
+    _get_my_charges(&CanisterEnv)
+}
+
+// Internal function to fetch the charges of the student linked to `env`'s caller.
+pub(crate) fn _get_my_charges(env: &impl CallerProvider) -> Result<Vec<Charge>, Error> {
+    Ok(_get_charges(caller_student(env)?.id))
+}
+
+// Internal function to fetch a student's charges.
+pub(crate) fn _get_charges(student_id: u64) -> Vec<Charge> {
+    CHARGE_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, charge)| charge)
+            .filter(|charge| charge.student_id == student_id)
+            .collect()
+    })
+}
+
+// Move every charge of the student `from` to the student `to`. Returns how many were moved.
+pub(crate) fn move_charges(from: u64, to: u64) -> u64 {
+    let charges = _get_charges(from);
+    CHARGE_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        for charge in &charges {
+            service.insert(
+                charge.id,
+                Charge {
+                    student_id: to,
+                    ..charge.clone()
+                },
+            );
+        }
+    });
+    cache::invalidate_all();
+    charges.len() as u64
+}
+
+// Record that a student paid a charge in tokens. The charge's amount is converted at the
+// current exchange rate, which is kept on the charge with the token amount taken.
+#[ic_cdk::update]
+async fn pay_charge(id: u64) -> Result<Charge, Error> {
+    let _perf = perf::measure("pay_charge");
+    require_admin()?;
+    unpaid_charge(id)?;
+    let rate = fetch_rate(
+        Asset {
+            symbol: PAYMENT_TOKEN.to_string(),
+            class: AssetClass::Cryptocurrency,
+        },
+        Asset {
+            symbol: CHARGE_CURRENCY.to_string(),
+            class: AssetClass::FiatCurrency,
+        },
+    )
+    .await?;
+    _settle_charge(&CanisterEnv, id, rate)
+}
+
+// Internal function to mark a charge paid at `rate`, the price of one payment token in the
+// charge currency. The charge is checked again since it may have been paid while the rate was
+// being fetched.
+pub(crate) fn _settle_charge(
+    env: &(impl Clock + CallerProvider),
+    id: u64,
+    rate: Rate,
+) -> Result<Charge, Error> {
+    let mut charge = unpaid_charge(id)?;
+    let token_amount = to_token_amount(charge.amount, &rate)?;
+    charge.paid_at = Some(env.now());
+    charge.payment = Some(Payment {
+        token: PAYMENT_TOKEN.to_string(),
+        token_amount,
+        rate,
+        recorded_by: env.caller(),
+    });
+    CHARGE_STORAGE.with(|service| service.borrow_mut().insert(charge.id, charge.clone()));
+    cache::invalidate_all();
+    ledger::record_payment(&charge);
+    log::info(format!(
+        "Charge id={} of {} {} paid with {} {} (smallest units).",
+        charge.id, charge.amount, CHARGE_CURRENCY, token_amount, PAYMENT_TOKEN
+    ));
+    Ok(charge)
+}
+
+// Fetch a charge that has yet to be paid.
+fn unpaid_charge(id: u64) -> Result<Charge, Error> {
+    let charge = CHARGE_STORAGE
+        .with(|service| service.borrow().get(&id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("A charge with id={} not found.", id),
+        })?;
+    if charge.paid_at.is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!("The charge id={} has already been paid.", id),
+        });
+    }
+    Ok(charge)
+}
+
+// Convert an amount in minor units of the charge currency into the payment token's smallest
+// unit, rounding up so a charge is never underpaid.
+fn to_token_amount(amount: u64, rate: &Rate) -> Result<u64, Error> {
+    let unconvertible = || Error::Unavailable {
+        msg: format!(
+            "Cannot convert {} {} to {} at rate {}e-{}.",
+            amount, CHARGE_CURRENCY, PAYMENT_TOKEN, rate.rate, rate.decimals
+        ),
+    };
+    if rate.rate == 0 {
+        return Err(unconvertible());
+    }
+    let numerator = 10u128
+        .checked_pow(rate.decimals + PAYMENT_TOKEN_DECIMALS)
+        .and_then(|scale| scale.checked_mul(amount as u128))
+        .ok_or_else(unconvertible)?;
+    let denominator = rate.rate as u128 * 10u128.pow(CHARGE_CURRENCY_DECIMALS);
+    u64::try_from(numerator.div_ceil(denominator)).map_err(|_| unconvertible())
+}
+
+// Internal function to bill a student, timestamped by `clock`.
+pub(crate) fn _add_charge(
+    clock: &impl Clock,
+    student_id: u64,
+    loan_id: Option<u64>,
+    kind: ChargeKind,
+    amount: u64,
+) -> Result<Charge, Error> {
+    let charge = Charge {
+        id: next_id(Entity::Charge)?,
+        student_id,
+        loan_id,
+        kind,
+        amount,
+        created_at: clock.now(),
+        paid_at: None,
+        payment: None,
+    };
+    CHARGE_STORAGE.with(|service| service.borrow_mut().insert(charge.id, charge.clone()));
+    cache::invalidate_all();
+    ledger::record_charge(&charge);
+    let kind = LiveUpdateKind::ChargeIssued {
+        charge_id: charge.id,
+        student_id,
+        amount,
+    };
+    live_update::publish(charge.created_at, kind);
+    Ok(charge)
+}
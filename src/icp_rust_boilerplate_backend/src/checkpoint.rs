@@ -0,0 +1,167 @@
+use ic_stable_structures::{BoundedStorable, StableBTreeMap};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::auth::require_admin;
+use crate::env::{CanisterEnv, Clock};
+use crate::log;
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::upgrade::stored_schema_version;
+use crate::{
+    Error, Memory, ADMIN_STORAGE, BOOK_STORAGE, CHARGE_STORAGE, DIGITAL_ASSET_STORAGE,
+    EBOOK_HOLD_STORAGE, EBOOK_LOAN_STORAGE, EXTENSION_REQUEST_STORAGE, LOAN_STORAGE,
+    PROPOSAL_STORAGE, STUDENT_STORAGE, UPGRADE_STATE,
+};
+
+// Record count and content hash of one collection.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct CollectionFingerprint {
+    pub name: String,
+    pub records: u64,
+    // Hex-encoded SHA-256 over every key and value, in key order.
+    pub sha256: String,
+}
+
+// Fingerprints of the collections, taken just before an upgrade.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct UpgradeCheckpoint {
+    pub taken_at: u64,
+    pub schema_version: u32,
+    pub collections: Vec<CollectionFingerprint>,
+}
+
+// Outcome of checking the data against a checkpoint after an upgrade.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct UpgradeVerification {
+    pub verified_at: u64,
+    pub checkpoint_taken_at: u64,
+    // Whether content hashes were compared; they are skipped when migrations rewrote the data.
+    pub hashes_compared: bool,
+    pub discrepancies: Vec<String>,
+}
+
+// The pending checkpoint, if any, and the result of the last verification.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct UpgradeState {
+    pub checkpoint: Option<UpgradeCheckpoint>,
+    pub last_verification: Option<UpgradeVerification>,
+}
+
+// Store UpgradeState as Candid.
+candid_storable!(UpgradeState);
+
+// Record counts and content hashes of every collection so the next upgrade can be checked
+// against them. Call this right before upgrading.
+#[ic_cdk::update]
+fn prepare_upgrade() -> Result<UpgradeCheckpoint, Error> {
+    let _perf = perf::measure("prepare_upgrade");
+    require_admin()?;
+    let checkpoint = UpgradeCheckpoint {
+        taken_at: CanisterEnv.now(),
+        schema_version: stored_schema_version(),
+        collections: fingerprints(),
+    };
+    update_state(|state| state.checkpoint = Some(checkpoint.clone()));
+    Ok(checkpoint)
+}
+
+// Retrieve the result of checking the last upgrade against its checkpoint.
+#[ic_cdk::query]
+fn get_upgrade_verification() -> Result<Option<UpgradeVerification>, Error> {
+    require_admin()?;
+    Ok(UPGRADE_STATE.with(|s| s.borrow().get().last_verification.clone()))
+}
+
+// Compare the upgraded data with the pending checkpoint and consume it, logging a warning for
+// every discrepancy. Counts must always match; hashes only when no migration has run, since
+// migrations rewrite records on purpose.
+pub(crate) fn verify(migrated: bool) {
+    let Some(checkpoint) = UPGRADE_STATE.with(|s| s.borrow().get().checkpoint.clone()) else {
+        return;
+    };
+
+    let mut discrepancies = Vec::new();
+    for after in fingerprints() {
+        let Some(before) = checkpoint.collections.iter().find(|c| c.name == after.name) else {
+            continue;
+        };
+        if before.records != after.records {
+            discrepancies.push(format!(
+                "{} had {} records before the upgrade and {} after.",
+                after.name, before.records, after.records
+            ));
+        } else if !migrated && before.sha256 != after.sha256 {
+            discrepancies.push(format!(
+                "{} changed content during an upgrade without migrations.",
+                after.name
+            ));
+        }
+    }
+    for discrepancy in &discrepancies {
+        log::warn(format!("Upgrade check: {}", discrepancy));
+    }
+
+    let verification = UpgradeVerification {
+        verified_at: CanisterEnv.now(),
+        checkpoint_taken_at: checkpoint.taken_at,
+        hashes_compared: !migrated,
+        discrepancies,
+    };
+    update_state(|state| {
+        state.checkpoint = None;
+        state.last_verification = Some(verification);
+    });
+}
+
+// Fingerprint every collection holding library data.
+fn fingerprints() -> Vec<CollectionFingerprint> {
+    vec![
+        fingerprint("students", &STUDENT_STORAGE),
+        fingerprint("books", &BOOK_STORAGE),
+        fingerprint("loans", &LOAN_STORAGE),
+        fingerprint("digital_assets", &DIGITAL_ASSET_STORAGE),
+        fingerprint("ebook_loans", &EBOOK_LOAN_STORAGE),
+        fingerprint("ebook_holds", &EBOOK_HOLD_STORAGE),
+        fingerprint("admins", &ADMIN_STORAGE),
+        fingerprint("proposals", &PROPOSAL_STORAGE),
+        fingerprint("extension_requests", &EXTENSION_REQUEST_STORAGE),
+        fingerprint("charges", &CHARGE_STORAGE),
+    ]
+}
+
+// Count and hash a map's entries, in key order.
+fn fingerprint<K, V>(
+    name: &str,
+    storage: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+) -> CollectionFingerprint
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    storage.with(|map| {
+        let map = map.borrow();
+        let mut hasher = Sha256::new();
+        for (key, value) in map.iter() {
+            hasher.update(key.to_bytes());
+            hasher.update(value.to_bytes());
+        }
+        CollectionFingerprint {
+            name: name.to_string(),
+            records: map.len(),
+            sha256: hex::encode(hasher.finalize()),
+        }
+    })
+}
+
+// Helper function to change and persist the upgrade state.
+fn update_state(change: impl FnOnce(&mut UpgradeState)) {
+    UPGRADE_STATE.with(|s| {
+        let mut state = s.borrow().get().clone();
+        change(&mut state);
+        s.borrow_mut()
+            .set(state)
+            .expect("Cannot store the upgrade state");
+    });
+}
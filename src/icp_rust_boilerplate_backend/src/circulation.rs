@@ -0,0 +1,268 @@
+use crate::auth::require_admin;
+use crate::book::{_get_book_by_barcode, Book};
+use crate::charge::{_add_charge, Charge, ChargeKind};
+use crate::crud;
+use crate::date::DAY;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::feature::{is_enabled, Feature};
+use crate::loan::{_add_loan, _transition_loan, Loan, LoanPayload, LoanStatus};
+use crate::log;
+use crate::perf;
+use crate::student::caller_student;
+use crate::Error;
+
+// Most open loans a student may hold when borrowing at a kiosk.
+pub const MAX_OPEN_LOANS: usize = 5;
+
+// Replacement cost billed for a lost book that has none set, in minor currency units.
+pub const DEFAULT_REPLACEMENT_COST: u64 = 2_500;
+
+// What the returns desk needs to know after scanning a book.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct CheckinSummary {
+    pub book: Book,
+    pub loan: Loan,
+    // Whole days past the due date at check-in; 0 when returned on time.
+    pub days_overdue: u64,
+}
+
+// Check in a returned book by scanning its barcode: its open loan is marked Returned, which
+// makes the book available to borrow again.
+#[ic_cdk::update]
+fn checkin_by_barcode(barcode: String) -> Result<CheckinSummary, Error> {
+    let _perf = perf::measure("checkin_by_barcode");
+    require_admin()?;
+    _checkin_by_barcode(&CanisterEnv, &barcode)
+}
+
+// Internal function to check in a book, dating the return by `clock`.
+pub(crate) fn _checkin_by_barcode(
+    clock: &impl Clock,
+    barcode: &str,
+) -> Result<CheckinSummary, Error> {
+    let book = _get_book_by_barcode(barcode).ok_or_else(|| Error::BookNotFound {
+        msg: format!("A book with barcode {} not found.", barcode.trim()),
+    })?;
+    let loan = crud::all::<Loan>()
+        .into_iter()
+        .find(|loan| loan.book_id == book.id && loan.status().is_open())
+        .ok_or_else(|| Error::InvalidInput {
+            msg: format!("The book id={} is not on loan.", book.id),
+        })?;
+    if loan.status() == LoanStatus::Requested {
+        return Err(Error::InvalidInput {
+            msg: format!("The loan id={} was requested but never started.", loan.id),
+        });
+    }
+
+    let now = clock.now();
+    let days_overdue = match loan.due_date {
+        Some(due) if now > due => (now - due) / DAY,
+        _ => 0,
+    };
+    let loan = _transition_loan(clock, loan.id, LoanStatus::Returned)?;
+    Ok(CheckinSummary {
+        book,
+        loan,
+        days_overdue,
+    })
+}
+
+// Borrow a book at a self-service kiosk by scanning its barcode. The caller must be linked to a
+// student, below the open-loan limit and without overdue loans.
+#[ic_cdk::update]
+fn self_checkout(copy_barcode: String) -> Result<Loan, Error> {
+    let _perf = perf::measure("self_checkout");
+    _self_checkout(&CanisterEnv, &copy_barcode)
+}
+
+// Internal function to lend a book to `env`'s caller.
+pub(crate) fn _self_checkout(
+    env: &(impl Clock + CallerProvider),
+    barcode: &str,
+) -> Result<Loan, Error> {
+    let student = caller_student(env)?;
+    let book = _get_book_by_barcode(barcode).ok_or_else(|| Error::BookNotFound {
+        msg: format!("A book with barcode {} not found.", barcode.trim()),
+    })?;
+
+    let open: Vec<Loan> = crud::all::<Loan>()
+        .into_iter()
+        .filter(|loan| loan.student_id == student.id && loan.status().is_open())
+        .collect();
+    let now = env.now();
+    if open.iter().any(|loan| {
+        loan.status() == LoanStatus::Overdue || loan.due_date.is_some_and(|due| due < now)
+    }) {
+        return Err(Error::InvalidInput {
+            msg: "Return your overdue books before borrowing more.".to_string(),
+        });
+    }
+    if open.len() >= MAX_OPEN_LOANS {
+        return Err(Error::InvalidInput {
+            msg: format!("You can have at most {} books on loan.", MAX_OPEN_LOANS),
+        });
+    }
+
+    _add_loan(
+        env,
+        LoanPayload {
+            student_id: student.id,
+            book_id: book.id,
+            loan_date: now,
+        },
+    )
+}
+
+// What happened when a loan was marked lost.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct LostBookSummary {
+    pub loan: Loan,
+    pub book: Book,
+    // None while fines are turned off.
+    pub charge: Option<Charge>,
+}
+
+// Record that a loan's book has been lost: the loan closes as Lost, the copy is withdrawn from
+// circulation and, if fines are on, the student is charged the book's replacement cost.
+#[ic_cdk::update]
+fn mark_loan_lost(loan_id: u64) -> Result<LostBookSummary, Error> {
+    let _perf = perf::measure("mark_loan_lost");
+    require_admin()?;
+    _mark_loan_lost(&CanisterEnv, loan_id)
+}
+
+// Internal function to process a lost book, timestamped by `clock`.
+pub(crate) fn _mark_loan_lost(clock: &impl Clock, loan_id: u64) -> Result<LostBookSummary, Error> {
+    let loan = _transition_loan(clock, loan_id, LoanStatus::Lost)?;
+    let book = crud::update(loan.book_id, |book: &mut Book| {
+        book.withdrawn_at = Some(clock.now());
+    })?;
+    if !is_enabled(Feature::Fines) {
+        log::info(format!(
+            "Loan id={} marked lost; book id={} withdrawn.",
+            loan.id, book.id
+        ));
+        return Ok(LostBookSummary {
+            loan,
+            book,
+            charge: None,
+        });
+    }
+    let amount = book.replacement_cost.unwrap_or(DEFAULT_REPLACEMENT_COST);
+    let charge = _add_charge(
+        clock,
+        loan.student_id,
+        Some(loan.id),
+        ChargeKind::Replacement,
+        amount,
+    )?;
+    log::info(format!(
+        "Loan id={} marked lost; book id={} withdrawn and student id={} charged {}.",
+        loan.id, book.id, loan.student_id, amount
+    ));
+    Ok(LostBookSummary {
+        loan,
+        book,
+        charge: Some(charge),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::{_add_book, BookPayload};
+    use crate::charge::_get_charges;
+    use crate::env::TestEnv;
+    use crate::feature::_set_feature_flag;
+    use crate::loan::{is_available, LOAN_PERIOD};
+    use crate::student::{_add_student, _link_student_principal, StudentPayload};
+
+    // 2023-11-14, an arbitrary day to start the clock on.
+    const START: u64 = 1_700_000_000_000_000_000;
+
+    // Register a student linked to `env`'s caller and add a book with barcode LP-001, with
+    // fines turned on. Payload fields are private to their modules, so payloads are read from
+    // JSON the way a client would send them.
+    fn library(env: &TestEnv) -> Book {
+        _set_feature_flag(env, Feature::Fines, true);
+        let student: StudentPayload = serde_json::from_str(
+            r#"{"name": "Siti Rahma", "contacts": [
+                {"kind": "Email", "value": "siti@example.com", "preferred": true}]}"#,
+        )
+        .unwrap();
+        let student = _add_student(env, student).unwrap();
+        _link_student_principal(env, student.id, Some(env.caller)).unwrap();
+        let book: BookPayload = serde_json::from_str(
+            r#"{"title": "Laskar Pelangi", "author": "Andrea Hirata", "barcode": "LP-001",
+                "replacement_cost": 4000}"#,
+        )
+        .unwrap();
+        _add_book(env, book).unwrap()
+    }
+
+    #[test]
+    fn late_return_reports_the_days_overdue() {
+        let env = TestEnv::new(START, 1);
+        let book = library(&env);
+        let loan = _self_checkout(&env, "LP-001").unwrap();
+        assert_eq!(loan.book_id, book.id);
+        assert!(!is_available(
+            &crud::find::<Book>(book.id).unwrap(),
+            env.now()
+        ));
+
+        env.advance(LOAN_PERIOD + 3 * DAY);
+        let checkin = _checkin_by_barcode(&env, " LP-001 ").unwrap();
+        assert_eq!(checkin.days_overdue, 3);
+        assert_eq!(checkin.loan.status(), LoanStatus::Returned);
+        assert!(is_available(
+            &crud::find::<Book>(book.id).unwrap(),
+            env.now()
+        ));
+        assert!(_get_charges(loan.student_id).is_empty());
+    }
+
+    #[test]
+    fn overdue_loan_blocks_self_checkout() {
+        let env = TestEnv::new(START, 1);
+        library(&env);
+        _self_checkout(&env, "LP-001").unwrap();
+        let book: BookPayload = serde_json::from_str(
+            r#"{"title": "Ronggeng", "author": "Ahmad Tohari", "barcode": "R-1"}"#,
+        )
+        .unwrap();
+        _add_book(&env, book).unwrap();
+
+        env.advance(LOAN_PERIOD + 1);
+        assert!(matches!(
+            _self_checkout(&env, "R-1"),
+            Err(Error::InvalidInput { .. })
+        ));
+    }
+
+    #[test]
+    fn lost_book_is_withdrawn_and_billed() {
+        let env = TestEnv::new(START, 1);
+        let book = library(&env);
+        let loan = _self_checkout(&env, "LP-001").unwrap();
+
+        let lost = _mark_loan_lost(&env, loan.id).unwrap();
+        assert_eq!(lost.loan.status(), LoanStatus::Lost);
+        assert_eq!(lost.book.withdrawn_at, Some(START));
+        let charge = lost.charge.unwrap();
+        assert_eq!(charge.amount, 4000);
+        assert_eq!(charge.loan_id, Some(loan.id));
+        assert_eq!(_get_charges(loan.student_id).len(), 1);
+
+        // A withdrawn copy can't be lent again.
+        assert!(matches!(
+            _self_checkout(&env, "LP-001"),
+            Err(Error::BookUnavailable { .. })
+        ));
+        assert!(!is_available(
+            &crud::find::<Book>(book.id).unwrap(),
+            env.now()
+        ));
+    }
+}
@@ -0,0 +1,303 @@
+use candid::Principal;
+
+use crate::auth::{is_admin, require_admin};
+use crate::class_set;
+use crate::crud;
+use crate::env::{CanisterEnv, Clock};
+use crate::id_counter::{next_id, Entity};
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::student::{require_student_access, Student};
+use crate::{Error, CLASS_STORAGE};
+
+// Most students a class may have.
+pub const MAX_CLASS_SIZE: usize = 100;
+
+// Longest name, teacher name or academic year accepted.
+const MAX_TEXT_LENGTH: usize = 100;
+
+// Define the Class struct to represent a class or course and the students in it.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct Class {
+    pub id: u64,
+    pub name: String,
+    pub teacher_name: String,
+    // Principal the teacher signs in with, if they use the canister themselves.
+    pub teacher: Option<Principal>,
+    // E.g. "2025/2026".
+    pub academic_year: String,
+    // IDs of the students in the class, in the order they joined.
+    pub members: Vec<u64>,
+    pub created_at: u64,
+    pub updated_at: Option<u64>,
+    // When the class finished. Its memberships have expired then: the members are kept for
+    // reports, but no longer count as belonging to it.
+    pub archived_at: Option<u64>,
+}
+
+impl Class {
+    // Whether the class is still running.
+    pub fn is_current(&self) -> bool {
+        self.archived_at.is_none()
+    }
+}
+
+// Store Class as Candid, bounded to 2048 bytes to fit a full class.
+candid_storable!(Class, max_size = 2048);
+
+// Define the payload structure for creating or updating a class.
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub struct ClassPayload {
+    name: String,
+    teacher_name: String,
+    teacher: Option<Principal>,
+    academic_year: String,
+}
+
+// Retrieve every class, in ID order.
+#[ic_cdk::query]
+fn get_classes() -> Result<Vec<Class>, Error> {
+    Ok(CLASS_STORAGE.with(|s| s.borrow().iter().map(|(_, class)| class).collect()))
+}
+
+// Retrieve a class by ID.
+#[ic_cdk::query]
+fn get_class(id: u64) -> Result<Class, Error> {
+    _get_class(id)
+}
+
+// Retrieve the students in a class. Available to administrators and to the class's teacher.
+#[ic_cdk::query]
+fn get_class_members(id: u64) -> Result<Vec<Student>, Error> {
+    let class = _get_class(id)?;
+    let caller = ic_cdk::caller();
+    if !is_admin(&caller) && class.teacher != Some(caller) {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "Only an administrator or the teacher of class id={} can do this.",
+                id
+            ),
+        });
+    }
+    Ok(class
+        .members
+        .iter()
+        .filter_map(crud::get::<Student>)
+        .collect())
+}
+
+// Retrieve the current classes a student belongs to.
+#[ic_cdk::query]
+fn get_student_classes(student_id: u64) -> Result<Vec<Class>, Error> {
+    require_student_access(&CanisterEnv, student_id)?;
+    Ok(classes_where(|class| {
+        class.is_current() && class.members.contains(&student_id)
+    }))
+}
+
+// Add a new class without members.
+#[ic_cdk::update]
+fn add_class(payload: ClassPayload) -> Result<Class, Error> {
+    let _perf = perf::measure("add_class");
+    require_admin()?;
+    _add_class(&CanisterEnv, payload)
+}
+
+// Internal function to add a class, taking its timestamps from `clock`.
+pub(crate) fn _add_class(clock: &impl Clock, payload: ClassPayload) -> Result<Class, Error> {
+    validate(&payload)?;
+    let class = Class {
+        id: next_id(Entity::Class)?,
+        name: payload.name,
+        teacher_name: payload.teacher_name,
+        teacher: payload.teacher,
+        academic_year: payload.academic_year,
+        members: Vec::new(),
+        created_at: clock.now(),
+        updated_at: None,
+        archived_at: None,
+    };
+    do_insert(&class);
+    Ok(class)
+}
+
+// Update a class's details, keeping its members.
+#[ic_cdk::update]
+fn update_class(id: u64, payload: ClassPayload) -> Result<Class, Error> {
+    let _perf = perf::measure("update_class");
+    require_admin()?;
+    validate(&payload)?;
+    let mut class = _get_class(id)?;
+    class.name = payload.name;
+    class.teacher_name = payload.teacher_name;
+    class.teacher = payload.teacher;
+    class.academic_year = payload.academic_year;
+    class.updated_at = Some(CanisterEnv.now());
+    do_insert(&class);
+    Ok(class)
+}
+
+// Delete a class, releasing its set reservations. Its students are not affected.
+#[ic_cdk::update]
+fn delete_class(id: u64) -> Result<Class, Error> {
+    let _perf = perf::measure("delete_class");
+    require_admin()?;
+    let class = CLASS_STORAGE
+        .with(|s| s.borrow_mut().remove(&id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Couldn't delete a class with id={}. Class not found.", id),
+        })?;
+    class_set::release_class(id, CanisterEnv.now());
+    Ok(class)
+}
+
+// Add students to a class; students already in it are skipped.
+#[ic_cdk::update]
+fn add_class_members(id: u64, student_ids: Vec<u64>) -> Result<Class, Error> {
+    let _perf = perf::measure("add_class_members");
+    require_admin()?;
+    _add_class_members(&CanisterEnv, id, student_ids)
+}
+
+// Internal function to add students to a class, taking the timestamp from `clock`.
+pub(crate) fn _add_class_members(
+    clock: &impl Clock,
+    id: u64,
+    student_ids: Vec<u64>,
+) -> Result<Class, Error> {
+    let mut class = _get_class(id)?;
+    if !class.is_current() {
+        return Err(Error::InvalidInput {
+            msg: format!("The class id={} has been archived.", id),
+        });
+    }
+    for student_id in student_ids {
+        if class.members.contains(&student_id) {
+            continue;
+        }
+        if !crud::exists::<Student>(student_id) {
+            return Err(Error::StudentNotFound {
+                msg: format!("A student with id={} not found.", student_id),
+            });
+        }
+        class.members.push(student_id);
+    }
+    if class.members.len() > MAX_CLASS_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!("A class can have at most {} students.", MAX_CLASS_SIZE),
+        });
+    }
+    class.updated_at = Some(clock.now());
+    do_insert(&class);
+    Ok(class)
+}
+
+// Remove a student from a class.
+#[ic_cdk::update]
+fn remove_class_member(id: u64, student_id: u64) -> Result<Class, Error> {
+    let _perf = perf::measure("remove_class_member");
+    require_admin()?;
+    let mut class = _get_class(id)?;
+    let Some(position) = class.members.iter().position(|&m| m == student_id) else {
+        return Err(Error::NotFound {
+            msg: format!("The student id={} is not in class id={}.", student_id, id),
+        });
+    };
+    class.members.remove(position);
+    class.updated_at = Some(CanisterEnv.now());
+    do_insert(&class);
+    Ok(class)
+}
+
+// Internal function to fetch a class by ID.
+pub(crate) fn _get_class(id: u64) -> Result<Class, Error> {
+    CLASS_STORAGE
+        .with(|s| s.borrow().get(&id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("A class with id={} not found.", id),
+        })
+}
+
+// Internal function to fetch the classes matching `filter`, in ID order.
+pub(crate) fn classes_where(filter: impl Fn(&Class) -> bool) -> Vec<Class> {
+    CLASS_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, class)| class)
+            .filter(|class| filter(class))
+            .collect()
+    })
+}
+
+// Whether a principal teaches any current class.
+pub(crate) fn is_teacher(principal: &Principal) -> bool {
+    !classes_where(|class| class.is_current() && class.teacher == Some(*principal)).is_empty()
+}
+
+// Whether a principal teaches a current class the student belongs to.
+pub(crate) fn teaches(principal: &Principal, student_id: u64) -> bool {
+    !classes_where(|class| {
+        class.is_current()
+            && class.teacher == Some(*principal)
+            && class.members.contains(&student_id)
+    })
+    .is_empty()
+}
+
+// Helper function to archive a finished class, releasing its set reservations.
+pub(crate) fn archive(mut class: Class, now: u64) -> Class {
+    class.archived_at = Some(now);
+    class.updated_at = Some(now);
+    do_insert(&class);
+    class_set::release_class(class.id, now);
+    class
+}
+
+// Helper function to take a deleted student out of every class.
+pub(crate) fn remove_student(student_id: u64) {
+    for mut class in classes_where(|class| class.members.contains(&student_id)) {
+        class.members.retain(|&m| m != student_id);
+        do_insert(&class);
+    }
+}
+
+// Helper function to put the student `to` in every class of the student `from`, in place of
+// `from`, returning how many classes changed.
+pub(crate) fn move_student(from: u64, to: u64) -> u64 {
+    let classes = classes_where(|class| class.members.contains(&from));
+    for mut class in classes.iter().cloned() {
+        if class.members.contains(&to) {
+            class.members.retain(|&m| m != from);
+        } else {
+            class
+                .members
+                .iter_mut()
+                .filter(|m| **m == from)
+                .for_each(|m| *m = to);
+        }
+        do_insert(&class);
+    }
+    classes.len() as u64
+}
+
+// Helper function to store a class.
+fn do_insert(class: &Class) {
+    CLASS_STORAGE.with(|s| s.borrow_mut().insert(class.id, class.clone()));
+}
+
+// Validate a class payload.
+fn validate(payload: &ClassPayload) -> Result<(), Error> {
+    let texts = [&payload.name, &payload.teacher_name, &payload.academic_year];
+    if texts
+        .iter()
+        .any(|text| text.trim().is_empty() || text.len() > MAX_TEXT_LENGTH)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Name, teacher name and academic year must be 1 to {} characters.",
+                MAX_TEXT_LENGTH
+            ),
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,260 @@
+use candid::Principal;
+
+use crate::auth::is_admin;
+use crate::book::{copies_of, Book};
+use crate::class::{_get_class, Class, MAX_CLASS_SIZE};
+use crate::crud;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::id_counter::{next_id, Entity};
+use crate::loan;
+use crate::log;
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::wishlist;
+use crate::{Error, CLASS_SET_SCHEDULE, CLASS_SET_STORAGE};
+
+// Most reservations a single run of the periodic jobs releases; the rest wait for the next one.
+const MAX_RELEASE_BATCH: usize = 50;
+
+// Define the ClassSetReservation struct to represent copies of a title set aside for a class.
+// The copies can't be checked out individually between `from` and `to` (inclusive); after
+// that, or once the reservation is cancelled, they circulate again.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct ClassSetReservation {
+    pub id: u64,
+    pub class_id: u64,
+    // IDs of the reserved copies.
+    pub book_ids: Vec<u64>,
+    pub from: u64,
+    pub to: u64,
+    pub reserved_by: Principal,
+    pub created_at: u64,
+    // When the copies were handed back, by cancellation or once the window ended.
+    pub released_at: Option<u64>,
+}
+
+// Store ClassSetReservation as Candid, bounded to 2048 bytes to fit a set for a full class.
+candid_storable!(ClassSetReservation, max_size = 2048);
+
+impl ClassSetReservation {
+    // Whether the reservation keeps its copies from circulating at `now`.
+    pub fn is_active_at(&self, now: u64) -> bool {
+        self.released_at.is_none() && self.from <= now && now <= self.to
+    }
+
+    // Whether the reservation holds its copies at any time between `from` and `to`.
+    fn overlaps(&self, from: u64, to: u64) -> bool {
+        self.released_at.is_none() && self.from <= to && from <= self.to
+    }
+}
+
+// Retrieve a class's set reservations, oldest first. Available to administrators and to the
+// class's teacher.
+#[ic_cdk::query]
+fn get_class_set_reservations(class_id: u64) -> Result<Vec<ClassSetReservation>, Error> {
+    require_teacher(&CanisterEnv, &_get_class(class_id)?)?;
+    Ok(reservations_where(|r| r.class_id == class_id))
+}
+
+// Reserve `qty` copies of a book's title for a class between `from` and `to`. Administrators
+// and the class's teacher can reserve.
+#[ic_cdk::update]
+fn reserve_class_set(
+    book_id: u64,
+    class_id: u64,
+    qty: u32,
+    from: u64,
+    to: u64,
+) -> Result<ClassSetReservation, Error> {
+    let _perf = perf::measure("reserve_class_set");
+    _reserve_class_set(&CanisterEnv, book_id, class_id, qty, from, to)
+}
+
+// Internal function to reserve copies on behalf of `env`'s caller. Copies are picked in ID
+// order among those in circulation, not on loan and not reserved during the window.
+pub(crate) fn _reserve_class_set(
+    env: &(impl Clock + CallerProvider),
+    book_id: u64,
+    class_id: u64,
+    qty: u32,
+    from: u64,
+    to: u64,
+) -> Result<ClassSetReservation, Error> {
+    let class = _get_class(class_id)?;
+    require_teacher(env, &class)?;
+    if !class.is_current() {
+        return Err(Error::InvalidInput {
+            msg: format!("The class id={} has been archived.", class_id),
+        });
+    }
+    let book = crud::find::<Book>(book_id)?;
+    let now = env.now();
+    if qty == 0 || qty as usize > MAX_CLASS_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!("A class set must have 1 to {} copies.", MAX_CLASS_SIZE),
+        });
+    }
+    if from > to || to < now {
+        return Err(Error::InvalidInput {
+            msg: "The reservation must end after it starts and in the future.".to_string(),
+        });
+    }
+
+    let reserved: Vec<u64> = reservations_where(|r| r.overlaps(from, to))
+        .into_iter()
+        .flat_map(|r| r.book_ids)
+        .collect();
+    let book_ids: Vec<u64> = copies_of(&book)
+        .iter()
+        .filter(|copy| loan::is_available(copy, now) && !reserved.contains(&copy.id))
+        .map(|copy| copy.id)
+        .take(qty as usize)
+        .collect();
+    if book_ids.len() < qty as usize {
+        return Err(Error::BookUnavailable {
+            msg: format!(
+                "Only {} copies of \"{}\" are free for the whole window.",
+                book_ids.len(),
+                book.title
+            ),
+        });
+    }
+
+    let reservation = ClassSetReservation {
+        id: next_id(Entity::ClassSet)?,
+        class_id,
+        book_ids,
+        from,
+        to,
+        reserved_by: env.caller(),
+        created_at: now,
+        released_at: None,
+    };
+    do_insert(&reservation);
+    log::info(format!(
+        "{} reserved {} copies of book id={} for class id={}.",
+        reservation.reserved_by, qty, book_id, class_id
+    ));
+    Ok(reservation)
+}
+
+// Cancel a reservation, handing its copies back to circulation.
+#[ic_cdk::update]
+fn cancel_class_set(id: u64) -> Result<ClassSetReservation, Error> {
+    let _perf = perf::measure("cancel_class_set");
+    _cancel_class_set(&CanisterEnv, id)
+}
+
+// Internal function to cancel a reservation on behalf of `env`'s caller.
+pub(crate) fn _cancel_class_set(
+    env: &(impl Clock + CallerProvider),
+    id: u64,
+) -> Result<ClassSetReservation, Error> {
+    let reservation = get(id)?;
+    require_teacher(env, &_get_class(reservation.class_id)?)?;
+    if reservation.released_at.is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!("The reservation id={} was already released.", id),
+        });
+    }
+    Ok(release(reservation, env.now()))
+}
+
+// The active reservation holding a book at `now`, if any.
+pub(crate) fn reservation_of(book_id: u64, now: u64) -> Option<ClassSetReservation> {
+    reservations_where(|r| r.is_active_at(now) && r.book_ids.contains(&book_id))
+        .into_iter()
+        .next()
+}
+
+// Hook run from the periodic jobs: release the reservations whose window has ended, reading only
+// those off the schedule.
+pub(crate) fn release_expired(now: u64) {
+    let due: Vec<u64> = CLASS_SET_SCHEDULE.with(|s| {
+        s.borrow()
+            .range(..(now, 0))
+            .take(MAX_RELEASE_BATCH)
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    for id in due {
+        if let Ok(reservation) = get(id) {
+            release(reservation, now);
+        }
+    }
+}
+
+// Helper function to release the reservations of a deleted or archived class.
+pub(crate) fn release_class(class_id: u64, now: u64) {
+    for reservation in reservations_where(|r| r.released_at.is_none() && r.class_id == class_id) {
+        release(reservation, now);
+    }
+}
+
+// Mark a reservation released and tell wishlists about the copies that can now be borrowed.
+fn release(mut reservation: ClassSetReservation, now: u64) -> ClassSetReservation {
+    let started = reservation.from <= now;
+    reservation.released_at = Some(now);
+    do_insert(&reservation);
+    if started {
+        for book in reservation.book_ids.iter().filter_map(crud::get::<Book>) {
+            if loan::is_available(&book, now) {
+                wishlist::book_available(book.id, now);
+            }
+        }
+    }
+    reservation
+}
+
+// Reject callers who are neither administrators nor the class's teacher.
+fn require_teacher(env: &impl CallerProvider, class: &Class) -> Result<(), Error> {
+    let caller = env.caller();
+    if is_admin(&caller) || class.teacher == Some(caller) {
+        return Ok(());
+    }
+    Err(Error::Unauthorized {
+        msg: "Only an administrator or the class's teacher can manage its reservations."
+            .to_string(),
+    })
+}
+
+// Internal function to fetch a reservation by ID.
+fn get(id: u64) -> Result<ClassSetReservation, Error> {
+    CLASS_SET_STORAGE
+        .with(|s| s.borrow().get(&id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("A class set reservation with id={} not found.", id),
+        })
+}
+
+// The reservations matching `filter`, in ID order.
+fn reservations_where(filter: impl Fn(&ClassSetReservation) -> bool) -> Vec<ClassSetReservation> {
+    CLASS_SET_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, reservation)| reservation)
+            .filter(|reservation| filter(reservation))
+            .collect()
+    })
+}
+
+// Helper function to store a reservation and keep its place in the schedule while it is
+// unreleased.
+fn do_insert(reservation: &ClassSetReservation) {
+    CLASS_SET_STORAGE.with(|s| s.borrow_mut().insert(reservation.id, reservation.clone()));
+    let key = (reservation.to, reservation.id);
+    CLASS_SET_SCHEDULE.with(|s| {
+        if reservation.released_at.is_none() {
+            s.borrow_mut().insert(key, ());
+        } else {
+            s.borrow_mut().remove(&key);
+        }
+    });
+}
+
+// Helper function to schedule the unreleased reservations stored before the schedule existed.
+pub(crate) fn build_schedule() {
+    for reservation in reservations_where(|r| r.released_at.is_none()) {
+        CLASS_SET_SCHEDULE.with(|s| s.borrow_mut().insert((reservation.to, reservation.id), ()));
+    }
+}
@@ -0,0 +1,213 @@
+use candid::Principal;
+
+use crate::auth::require_admin;
+use crate::crud;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::id_counter::{next_id, Entity};
+use crate::log;
+use crate::perf;
+use crate::private_notes;
+use crate::storable::candid_storable;
+use crate::student::{caller_student, Student};
+use crate::{Error, CONSENT_STORAGE};
+
+// Longest guardian name accepted.
+const MAX_GUARDIAN_NAME_LENGTH: usize = 100;
+
+// A kind of data processing a student can agree to.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConsentKind {
+    // Processing the student's records for lending at all.
+    DataProcessing,
+    // Contacting the student, e.g. by email.
+    Communication,
+    // Keeping sensitive notes, such as accessibility needs.
+    SensitiveNotes,
+}
+
+// Who gave or withdrew a consent.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub enum ConsentGiver {
+    Student,
+    Guardian { name: String },
+}
+
+// Define the Consent struct to record one grant or withdrawal. Records are never changed; the
+// latest one per kind is the student's current choice.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct Consent {
+    pub id: u64,
+    pub student_id: u64,
+    pub kind: ConsentKind,
+    pub granted: bool,
+    // Version of the consent text shown when the choice was made.
+    pub text_version: u32,
+    pub given_by: ConsentGiver,
+    pub recorded_by: Principal,
+    pub recorded_at: u64,
+}
+
+// Store Consent as Candid, bounded to 512 bytes.
+candid_storable!(Consent, max_size = 512);
+
+// Define the payload structure for recording a consent choice.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct ConsentPayload {
+    pub kind: ConsentKind,
+    pub granted: bool,
+    pub text_version: u32,
+    pub given_by: ConsentGiver,
+}
+
+// Record a consent choice on behalf of a student or their guardian.
+#[ic_cdk::update]
+fn record_consent(student_id: u64, payload: ConsentPayload) -> Result<Consent, Error> {
+    let _perf = perf::measure("record_consent");
+    require_admin()?;
+    _record_consent(&CanisterEnv, student_id, payload)
+}
+
+// Grant or withdraw a consent for the student linked to the caller.
+#[ic_cdk::update]
+fn set_my_consent(kind: ConsentKind, granted: bool, text_version: u32) -> Result<Consent, Error> {
+    let _perf = perf::measure("set_my_consent");
+    let student = caller_student(&CanisterEnv)?;
+    let payload = ConsentPayload {
+        kind,
+        granted,
+        text_version,
+        given_by: ConsentGiver::Student,
+    };
+    _record_consent(&CanisterEnv, student.id, payload)
+}
+
+// Retrieve a student's current consent choices, one per kind recorded.
+#[ic_cdk::query]
+fn get_student_consents(student_id: u64) -> Result<Vec<Consent>, Error> {
+    require_admin()?;
+    Ok(current(student_id))
+}
+
+// Retrieve every consent choice recorded for a student, oldest first.
+#[ic_cdk::query]
+fn get_consent_history(student_id: u64) -> Result<Vec<Consent>, Error> {
+    require_admin()?;
+    Ok(history(student_id))
+}
+
+// Retrieve the current consent choices of the student linked to the caller.
+#[ic_cdk::query]
+fn get_my_consents() -> Result<Vec<Consent>, Error> {
+    let student = caller_student(&CanisterEnv)?;
+    Ok(current(student.id))
+}
+
+// Internal function to record a choice made through `env`'s caller.
+pub(crate) fn _record_consent(
+    env: &(impl Clock + CallerProvider),
+    student_id: u64,
+    payload: ConsentPayload,
+) -> Result<Consent, Error> {
+    crud::find::<Student>(student_id)?;
+    if let ConsentGiver::Guardian { name } = &payload.given_by {
+        if name.trim().is_empty() || name.len() > MAX_GUARDIAN_NAME_LENGTH {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "A guardian's name must be 1 to {} characters.",
+                    MAX_GUARDIAN_NAME_LENGTH
+                ),
+            });
+        }
+    }
+
+    let consent = Consent {
+        id: next_id(Entity::Consent)?,
+        student_id,
+        kind: payload.kind,
+        granted: payload.granted,
+        text_version: payload.text_version,
+        given_by: payload.given_by,
+        recorded_by: env.caller(),
+        recorded_at: env.now(),
+    };
+    CONSENT_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert((student_id, consent.id), consent.clone())
+    });
+    // Withdrawing consent for sensitive notes means they may no longer be kept.
+    if !consent.granted && consent.kind == ConsentKind::SensitiveNotes {
+        private_notes::remove_private_notes(student_id);
+    }
+    log::info(format!(
+        "{} {} {:?} consent (text v{}) for student id={}.",
+        consent.recorded_by,
+        if consent.granted {
+            "granted"
+        } else {
+            "withdrew"
+        },
+        consent.kind,
+        consent.text_version,
+        student_id
+    ));
+    Ok(consent)
+}
+
+// Whether a student's latest choice for `kind` grants it. Without any choice recorded, the
+// consent has not been given.
+pub(crate) fn has_consent(student_id: u64, kind: ConsentKind) -> bool {
+    history(student_id)
+        .into_iter()
+        .rev()
+        .find(|consent| consent.kind == kind)
+        .is_some_and(|consent| consent.granted)
+}
+
+// Reject processing a student has not agreed to.
+pub(crate) fn require_consent(student_id: u64, kind: ConsentKind) -> Result<(), Error> {
+    if has_consent(student_id, kind) {
+        return Ok(());
+    }
+    Err(Error::Unauthorized {
+        msg: format!(
+            "The student id={} has not given {:?} consent.",
+            student_id, kind
+        ),
+    })
+}
+
+// Helper function to drop every consent record of a student.
+pub(crate) fn remove_consents(student_id: u64) {
+    CONSENT_STORAGE.with(|s| {
+        let mut consents = s.borrow_mut();
+        let keys: Vec<_> = consents
+            .range((student_id, 0)..=(student_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            consents.remove(&key);
+        }
+    });
+}
+
+// Every consent record of a student, oldest first.
+fn history(student_id: u64) -> Vec<Consent> {
+    CONSENT_STORAGE.with(|s| {
+        s.borrow()
+            .range((student_id, 0)..=(student_id, u64::MAX))
+            .map(|(_, consent)| consent)
+            .collect()
+    })
+}
+
+// The latest record per kind, in the order the kinds were first recorded.
+fn current(student_id: u64) -> Vec<Consent> {
+    let mut latest: Vec<Consent> = Vec::new();
+    for consent in history(student_id) {
+        match latest.iter_mut().find(|c| c.kind == consent.kind) {
+            Some(slot) => *slot = consent,
+            None => latest.push(consent),
+        }
+    }
+    latest
+}
@@ -0,0 +1,209 @@
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod,
+    HttpResponse as OutcallResponse, TransformArgs, TransformContext,
+};
+use sha2::{Digest, Sha256};
+
+use crate::auth::require_admin;
+use crate::book::Book;
+use crate::crud;
+use crate::digital_asset::{EbookChunk, CHUNK_SIZE};
+use crate::env::{CanisterEnv, Clock};
+use crate::log;
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::{Error, COVER_CHUNK_STORAGE, COVER_STORAGE};
+
+// Largest cover image the canister will fetch and keep.
+pub const MAX_COVER_SIZE: u64 = 1024 * 1024;
+
+// Room left in an outcall response for the status line and headers.
+const RESPONSE_HEADER_ALLOWANCE: u64 = 16 * 1024;
+
+// Cycles attached to a cover fetch, enough for a maximum-size response on a 13-node subnet;
+// whatever the call doesn't use is refunded.
+const COVER_FETCH_CYCLES: u128 = 15_000_000_000;
+
+// Image formats accepted as covers.
+const COVER_CONTENT_TYPES: &[&str] = &["image/jpeg", "image/png", "image/webp", "image/gif"];
+
+// Define the Cover struct describing the cached cover image of a book. The image itself is kept
+// in CHUNK_SIZE pieces alongside it.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct Cover {
+    pub book_id: u64,
+    pub content_type: String,
+    pub size: u64,
+    pub chunk_count: u32,
+    // Hex-encoded SHA-256 of the image.
+    pub sha256: String,
+    // Address the image was fetched from; differs from the book's cover_url once that changes.
+    pub source_url: String,
+    pub fetched_at: u64,
+}
+
+// Store Cover as Candid, bounded to 1024 bytes.
+candid_storable!(Cover, max_size = 1024);
+
+// Retrieve the description of a book's cached cover.
+#[ic_cdk::query]
+fn get_cover(book_id: u64) -> Result<Cover, Error> {
+    _get_cover(&book_id).ok_or_else(|| Error::NotFound {
+        msg: format!("No cached cover for book id={}.", book_id),
+    })
+}
+
+// Retrieve one piece of a book's cached cover image.
+#[ic_cdk::query]
+fn get_cover_chunk(book_id: u64, index: u32) -> Result<Vec<u8>, Error> {
+    _get_cover_chunk(book_id, index).ok_or_else(|| Error::NotFound {
+        msg: format!("Cover chunk {} for book id={} not found.", index, book_id),
+    })
+}
+
+// Internal function to read one piece of a book's cached cover image.
+pub(crate) fn _get_cover_chunk(book_id: u64, index: u32) -> Option<Vec<u8>> {
+    COVER_CHUNK_STORAGE
+        .with(|s| s.borrow().get(&(book_id, index)))
+        .map(|chunk| chunk.0)
+}
+
+// Internal function to look up a book's cached cover.
+pub(crate) fn _get_cover(book_id: &u64) -> Option<Cover> {
+    COVER_STORAGE.with(|s| s.borrow().get(book_id))
+}
+
+// Fetch a book's cover from its cover_url and cache it in the canister, replacing any earlier
+// copy, so catalogs don't depend on the external host.
+#[ic_cdk::update]
+async fn cache_cover(book_id: u64) -> Result<Cover, Error> {
+    let _perf = perf::measure("cache_cover");
+    require_admin()?;
+    _fetch_cover(book_id).await
+}
+
+// Internal function to fetch a book's cover from its cover_url and store it. A failed call or
+// a bad answer from the host is reported as Unavailable, which is worth retrying.
+pub(crate) async fn _fetch_cover(book_id: u64) -> Result<Cover, Error> {
+    let book = crud::find::<Book>(book_id)?;
+    let url = book.cover_url.ok_or_else(|| Error::InvalidInput {
+        msg: format!("The book id={} has no cover_url.", book_id),
+    })?;
+
+    let request = CanisterHttpRequestArgument {
+        url: url.clone(),
+        max_response_bytes: Some(MAX_COVER_SIZE + RESPONSE_HEADER_ALLOWANCE),
+        method: HttpMethod::GET,
+        headers: vec![HttpHeader {
+            name: "Accept".to_string(),
+            value: COVER_CONTENT_TYPES.join(", "),
+        }],
+        body: None,
+        transform: Some(TransformContext::from_name(
+            "transform_cover_response".to_string(),
+            Vec::new(),
+        )),
+    };
+    let response = match http_request(request, COVER_FETCH_CYCLES).await {
+        Ok((response,)) => response,
+        Err((code, msg)) => {
+            return Err(Error::Unavailable {
+                msg: format!("Cannot fetch the cover from {} ({:?}): {}", url, code, msg),
+            })
+        }
+    };
+    _store_cover(&CanisterEnv, book_id, url, response)
+}
+
+// Keep only the parts of a cover response every replica agrees on, so the outcall reaches
+// consensus despite per-request headers such as Date.
+#[ic_cdk::query]
+fn transform_cover_response(args: TransformArgs) -> OutcallResponse {
+    OutcallResponse {
+        status: args.response.status,
+        headers: args
+            .response
+            .headers
+            .into_iter()
+            .filter(|header| header.name.eq_ignore_ascii_case("content-type"))
+            .collect(),
+        body: args.response.body,
+    }
+}
+
+// Internal function to validate a fetched cover and store it, replacing any earlier copy. The
+// book is looked up again since it may have been deleted while the image was being fetched.
+pub(crate) fn _store_cover(
+    clock: &impl Clock,
+    book_id: u64,
+    source_url: String,
+    response: OutcallResponse,
+) -> Result<Cover, Error> {
+    crud::find::<Book>(book_id)?;
+    if response.status != 200u32 {
+        return Err(Error::Unavailable {
+            msg: format!("{} answered with status {}.", source_url, response.status),
+        });
+    }
+    let content_type = response
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("content-type"))
+        .and_then(|header| header.value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    if !COVER_CONTENT_TYPES.contains(&content_type.as_str()) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "{} is not a cover image; expected one of {}.",
+                source_url,
+                COVER_CONTENT_TYPES.join(", ")
+            ),
+        });
+    }
+    let size = response.body.len() as u64;
+    if size == 0 || size > MAX_COVER_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A cover must be between 1 and {} bytes, got {}.",
+                MAX_COVER_SIZE, size
+            ),
+        });
+    }
+
+    remove_cover(book_id);
+    let chunks: Vec<&[u8]> = response.body.chunks(CHUNK_SIZE as usize).collect();
+    COVER_CHUNK_STORAGE.with(|s| {
+        let mut storage = s.borrow_mut();
+        for (index, chunk) in chunks.iter().enumerate() {
+            storage.insert((book_id, index as u32), EbookChunk(chunk.to_vec()));
+        }
+    });
+    let cover = Cover {
+        book_id,
+        content_type,
+        size,
+        chunk_count: chunks.len() as u32,
+        sha256: hex::encode(Sha256::digest(&response.body)),
+        source_url,
+        fetched_at: clock.now(),
+    };
+    COVER_STORAGE.with(|s| s.borrow_mut().insert(book_id, cover.clone()));
+    log::info(format!(
+        "Cached a {} byte cover for book id={} from {}.",
+        size, book_id, cover.source_url
+    ));
+    Ok(cover)
+}
+
+// Helper function to drop a book's cached cover and its image chunks.
+pub(crate) fn remove_cover(book_id: u64) {
+    if let Some(cover) = COVER_STORAGE.with(|s| s.borrow_mut().remove(&book_id)) {
+        COVER_CHUNK_STORAGE.with(|s| {
+            let mut chunks = s.borrow_mut();
+            for index in 0..cover.chunk_count {
+                chunks.remove(&(book_id, index));
+            }
+        });
+    }
+}
@@ -0,0 +1,31 @@
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::CreatedIndex;
+
+// A collection's (created_at, id) index.
+pub type IndexKey = &'static LocalKey<RefCell<CreatedIndex>>;
+
+// Record that the entity `id` was created at `created_at`.
+pub(crate) fn insert(index: IndexKey, created_at: u64, id: u64) {
+    index.with(|index| index.borrow_mut().insert((created_at, id), ()));
+}
+
+// Drop an entity from the index.
+pub(crate) fn remove(index: IndexKey, created_at: u64, id: u64) {
+    index.with(|index| index.borrow_mut().remove(&(created_at, id)));
+}
+
+// IDs of the entities created within `from..=to`, oldest first.
+pub(crate) fn ids_between(index: IndexKey, from: u64, to: u64) -> Vec<u64> {
+    if from > to {
+        return Vec::new();
+    }
+    index.with(|index| {
+        index
+            .borrow()
+            .range((from, 0)..=(to, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
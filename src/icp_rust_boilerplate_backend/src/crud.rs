@@ -0,0 +1,314 @@
+use ic_stable_structures::{BoundedStorable, StableBTreeMap};
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::cache;
+use crate::created_index::{self, IndexKey};
+use crate::env::Clock;
+use crate::id_counter::{self, Entity};
+use crate::pagination::{self, Page};
+use crate::snapshot;
+use crate::ulid::next_ulid;
+use crate::{check_size, Error, Memory};
+
+// A collection's primary storage, keyed by ID.
+pub type StorageKey<R> = &'static LocalKey<RefCell<StableBTreeMap<u64, R, Memory>>>;
+
+// The library collections, for calls that target one of them by kind.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+pub enum RecordKind {
+    Student,
+    Book,
+    Loan,
+}
+
+// A record kept in its own ID-keyed collection with a creation-time index. Implementing this
+// gives an entity the shared storage access below and, through `crud_endpoints!`, the standard
+// read and delete endpoints.
+pub trait Record: BoundedStorable + Clone + 'static {
+    // Lower-case name used in error messages, e.g. "book".
+    const KIND: &'static str;
+    // Capitalised name used in error messages, e.g. "Book".
+    const NAME: &'static str;
+    // ID sequence the collection draws from.
+    const ENTITY: Entity;
+
+    fn storage() -> StorageKey<Self>;
+    fn created_index() -> IndexKey;
+    fn id(&self) -> u64;
+    fn created_at(&self) -> u64;
+
+    // Whether this is the placeholder read in place of a record that failed to decode. Real
+    // records never have ID 0, since ID sequences start at 1.
+    fn is_corrupted(&self) -> bool {
+        self.id() == 0
+    }
+
+    // Deal with whatever still references the record `id` before it is deleted: refuse while
+    // anything does, or with `cascade` close those references first.
+    fn release_references(_id: u64, _cascade: bool) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Keep read models in step with the collection: called after every write with the stored
+    // version it replaced and the version now stored, None when the record was just created or
+    // has been deleted.
+    fn on_write(_previous: Option<&Self>, _current: Option<&Self>) {}
+}
+
+// Fetch every readable record of a collection, skipping corrupted ones.
+pub(crate) fn all<R: Record>() -> Vec<R> {
+    R::storage().with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, value)| value)
+            .filter(|record| !record.is_corrupted())
+            .collect()
+    })
+}
+
+// Fetch one page of a collection, starting at the `cursor` ID and skipping corrupted records.
+pub(crate) fn list<R: Record>(cursor: Option<u64>, limit: Option<u32>) -> Page<R> {
+    let mut page = pagination::paginate(R::storage(), cursor, limit);
+    page.items.retain(|record| !record.is_corrupted());
+    page
+}
+
+// Fetch a readable record by ID.
+pub(crate) fn get<R: Record>(id: &u64) -> Option<R> {
+    R::storage()
+        .with(|s| s.borrow().get(id))
+        .filter(|record| !record.is_corrupted())
+}
+
+// Whether a record is stored under `id`, even one that can't be read.
+pub(crate) fn exists<R: Record>(id: u64) -> bool {
+    R::storage().with(|s| s.borrow().contains_key(&id))
+}
+
+// Fetch a record by ID, reporting a missing one as NotFound and an unreadable one as
+// StorageCorrupted.
+pub(crate) fn find<R: Record>(id: u64) -> Result<R, Error> {
+    match R::storage().with(|s| s.borrow().get(&id)) {
+        Some(record) if record.is_corrupted() => Err(corrupted::<R>(id)),
+        Some(record) => Ok(record),
+        None => Err(Error::NotFound {
+            msg: format!("A {} with id={} not found.", R::KIND, id),
+        }),
+    }
+}
+
+// Error for a stored record that cannot be decoded; deleting it is the way to clear it.
+fn corrupted<R: Record>(id: u64) -> Error {
+    Error::StorageCorrupted {
+        msg: format!(
+            "The stored {} with id={} cannot be read and can only be deleted.",
+            R::KIND,
+            id
+        ),
+    }
+}
+
+// Fetch the records created within `from..=to`, oldest first.
+pub(crate) fn created_between<R: Record>(from: u64, to: u64) -> Vec<R> {
+    created_index::ids_between(R::created_index(), from, to)
+        .iter()
+        .filter_map(get)
+        .collect()
+}
+
+// Allocate an ID and ULID and build a new record from them with `build(id, ulid, now)`,
+// then store it.
+pub(crate) fn create<R: Record>(
+    clock: &impl Clock,
+    build: impl FnOnce(u64, Option<String>, u64) -> R,
+) -> Result<R, Error> {
+    let now = clock.now();
+    let record = build(id_counter::next_id(R::ENTITY)?, next_ulid(now), now);
+    insert(&record)?;
+    Ok(record)
+}
+
+// Apply `change` to a stored record and save it back.
+pub(crate) fn update<R: Record>(id: u64, change: impl FnOnce(&mut R)) -> Result<R, Error> {
+    match R::storage().with(|s| s.borrow().get(&id)) {
+        Some(record) if record.is_corrupted() => Err(corrupted::<R>(id)),
+        Some(mut record) => {
+            change(&mut record);
+            insert(&record)?;
+            Ok(record)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't update a {} with id={}. {} not found.",
+                R::KIND,
+                id,
+                R::NAME
+            ),
+        }),
+    }
+}
+
+// Store a record and index it, rejecting records too large to store.
+pub(crate) fn insert<R: Record>(record: &R) -> Result<(), Error> {
+    snapshot::check_writable()?;
+    check_size(R::KIND, record)?;
+    let previous = R::storage().with(|s| s.borrow_mut().insert(record.id(), record.clone()));
+    created_index::insert(R::created_index(), record.created_at(), record.id());
+    R::on_write(previous.as_ref(), Some(record));
+    cache::invalidate_all();
+    Ok(())
+}
+
+// Remove a record and its index entry, applying its delete policy to records that reference it.
+// Corrupted records can be deleted too, though their index entry is left behind because its
+// creation time is unknown.
+pub(crate) fn delete<R: Record>(id: u64, cascade: bool) -> Result<R, Error> {
+    snapshot::check_writable()?;
+    if R::storage().with(|s| s.borrow().contains_key(&id)) {
+        R::release_references(id, cascade)?;
+    }
+    match R::storage().with(|s| s.borrow_mut().remove(&id)) {
+        Some(record) => {
+            created_index::remove(R::created_index(), record.created_at(), id);
+            R::on_write(Some(&record), None);
+            cache::invalidate_all();
+            Ok(record)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't delete a {} with id={}. {} not found.",
+                R::KIND,
+                id,
+                R::NAME
+            ),
+        }),
+    }
+}
+
+// Generate the standard query and delete endpoints of a `Record` type. The read endpoints
+// first call `read_guard`, which rejects callers who may not see the collection. With
+// `get_guard`, reading a single record is instead allowed to whoever it accepts for that record.
+// `kind` names the collection in the delete proposals.
+macro_rules! crud_endpoints {
+    ($record:ty {
+        all: $all:ident,
+        list: $list:ident,
+        get: $get:ident,
+        delete: $delete:ident,
+        kind: $kind:expr,
+        read_guard: $guard:path
+        $(, get_guard: $get_guard:path)? $(,)?
+    }) => {
+        // Retrieve every record of the collection.
+        #[ic_cdk::query]
+        fn $all() -> Result<Vec<$record>, crate::Error> {
+            $guard()?;
+            Ok(crate::crud::all())
+        }
+
+        // Retrieve records one page at a time, starting at the `cursor` ID.
+        #[ic_cdk::query]
+        fn $list(
+            cursor: Option<u64>,
+            limit: Option<u32>,
+        ) -> Result<crate::pagination::Page<$record>, crate::Error> {
+            $guard()?;
+            Ok(crate::crud::list(cursor, limit))
+        }
+
+        // Retrieve a specific record by its ID.
+        #[ic_cdk::query]
+        fn $get(id: u64) -> Result<$record, crate::Error> {
+            crate::crud::crud_endpoints!(@get_guard $guard $(, $get_guard)?);
+            let record = crate::crud::find(id)?;
+            $( $get_guard(&record)?; )?
+            Ok(record)
+        }
+
+        // Propose deleting a record by ID; it is deleted once another administrator approves.
+        // Deleting a record that others still reference is refused unless `cascade` is set,
+        // which closes those references first.
+        #[ic_cdk::update]
+        fn $delete(
+            id: u64,
+            cascade: Option<bool>,
+        ) -> Result<crate::proposal::Proposal, crate::Error> {
+            let _perf = crate::perf::measure(stringify!($delete));
+            crate::auth::require_admin()?;
+            crate::proposal::_propose_admin_action(
+                &crate::env::CanisterEnv,
+                crate::proposal::AdminAction::DeleteRecords {
+                    kind: $kind,
+                    ids: vec![id],
+                    cascade: cascade.unwrap_or(false),
+                },
+            )
+        }
+    };
+    // Without a record guard, a single record needs the collection guard.
+    (@get_guard $guard:path) => {
+        $guard()?
+    };
+    (@get_guard $guard:path, $get_guard:path) => {};
+}
+pub(crate) use crud_endpoints;
+
+// Make a struct a library entity: Candid storage bounded by `max_size`, plus the `Record` impl
+// tying it to its collection. The struct needs `id` and `created_at` fields and a Default,
+// which is what a record that fails to decode is read as. `on_delete` names the entity's
+// `Record::release_references` and `on_write` its `Record::on_write`.
+macro_rules! library_entity {
+    ($record:ty {
+        max_size: $max_size:expr,
+        kind: $kind:literal,
+        name: $name:literal,
+        entity: $entity:expr,
+        storage: $storage:ident,
+        created_index: $index:ident
+        $(, on_delete: $on_delete:path)?
+        $(, on_write: $on_write:path)? $(,)?
+    }) => {
+        crate::storable::candid_storable!(
+            $record,
+            max_size = $max_size,
+            fallback = <$record>::default()
+        );
+
+        // Tie the entity to its storage and ID sequence.
+        impl crate::crud::Record for $record {
+            const KIND: &'static str = $kind;
+            const NAME: &'static str = $name;
+            const ENTITY: crate::id_counter::Entity = $entity;
+
+            fn storage() -> crate::crud::StorageKey<Self> {
+                &crate::$storage
+            }
+
+            fn created_index() -> crate::created_index::IndexKey {
+                &crate::$index
+            }
+
+            fn id(&self) -> u64 {
+                self.id
+            }
+
+            fn created_at(&self) -> u64 {
+                self.created_at
+            }
+
+            $(
+                fn release_references(id: u64, cascade: bool) -> Result<(), crate::Error> {
+                    $on_delete(id, cascade)
+                }
+            )?
+
+            $(
+                fn on_write(previous: Option<&Self>, current: Option<&Self>) {
+                    $on_write(previous, current)
+                }
+            )?
+        }
+    };
+}
+pub(crate) use library_entity;
@@ -0,0 +1,48 @@
+const SECOND: u64 = 1_000_000_000;
+const DAY_SECONDS: u64 = 24 * 60 * 60;
+
+// One day in nanoseconds.
+pub const DAY: u64 = DAY_SECONDS * SECOND;
+
+// The UTC calendar date and time of a timestamp in nanoseconds since the Unix epoch, as
+// (year, month, day, hour, minute, second).
+pub(crate) fn civil(nanos: u64) -> (u64, u32, u32, u32, u32, u32) {
+    let seconds = nanos / SECOND;
+    let (days, time) = (seconds / DAY_SECONDS, seconds % DAY_SECONDS);
+    // Howard Hinnant's days-to-civil algorithm, counting eras of 400 years from 0000-03-01.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (
+        year,
+        month,
+        day,
+        (time / 3600) as u32,
+        (time % 3600 / 60) as u32,
+        (time % 60) as u32,
+    )
+}
+
+// A timestamp's UTC date, e.g. "2026-03-01".
+pub(crate) fn format_date(nanos: u64) -> String {
+    let (year, month, day, ..) = civil(nanos);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// A timestamp in RFC 3339 UTC form, e.g. "2026-03-01T09:30:00Z".
+pub(crate) fn format_timestamp(nanos: u64) -> String {
+    let (_, _, _, hour, minute, second) = civil(nanos);
+    format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        format_date(nanos),
+        hour,
+        minute,
+        second
+    )
+}
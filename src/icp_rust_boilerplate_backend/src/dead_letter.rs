@@ -0,0 +1,118 @@
+use crate::auth::require_admin;
+use crate::book::Book;
+use crate::crud;
+use crate::env::{CanisterEnv, Clock};
+use crate::log;
+use crate::outbox::{self, OutboxItem, OutboxTask};
+use crate::pagination::{self, Page};
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::{Error, DEAD_LETTER_STORAGE};
+
+// Define the DeadLetter struct to represent an outbox item the worker gave up on, kept under
+// the item's ID until an admin replays or discards it.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct DeadLetter {
+    // The item as it was when the worker gave up, with the error that stopped it.
+    pub item: OutboxItem,
+    pub failed_at: u64,
+    // Set when an admin last changed the item's task.
+    pub edited_at: Option<u64>,
+}
+
+// Store DeadLetter as Candid, bounded to 2560 bytes.
+candid_storable!(DeadLetter, max_size = 2560);
+
+// Retrieve the dead letters one page at a time, oldest item first.
+#[ic_cdk::query]
+fn get_dead_letters(cursor: Option<u64>, limit: Option<u32>) -> Result<Page<DeadLetter>, Error> {
+    require_admin()?;
+    Ok(pagination::paginate(&DEAD_LETTER_STORAGE, cursor, limit))
+}
+
+// Retrieve a dead letter by the ID of its outbox item.
+#[ic_cdk::query]
+fn get_dead_letter(id: u64) -> Result<DeadLetter, Error> {
+    require_admin()?;
+    find(id)
+}
+
+// Replace the task of a dead letter, e.g. to point it at another record, before replaying it.
+#[ic_cdk::update]
+fn update_dead_letter(id: u64, task: OutboxTask) -> Result<DeadLetter, Error> {
+    let _perf = perf::measure("update_dead_letter");
+    require_admin()?;
+    _update_dead_letter(&CanisterEnv, id, task)
+}
+
+// Move a dead letter back into the outbox with a fresh set of attempts.
+#[ic_cdk::update]
+fn replay_dead_letter(id: u64) -> Result<OutboxItem, Error> {
+    let _perf = perf::measure("replay_dead_letter");
+    require_admin()?;
+    _replay_dead_letter(&CanisterEnv, id)
+}
+
+// Delete a dead letter without replaying it.
+#[ic_cdk::update]
+fn discard_dead_letter(id: u64) -> Result<DeadLetter, Error> {
+    let _perf = perf::measure("discard_dead_letter");
+    require_admin()?;
+    let dead_letter = take(id)?;
+    log::info(format!("Discarded dead letter id={}.", id));
+    Ok(dead_letter)
+}
+
+// Internal function to replace a dead letter's task, timestamped by `clock`.
+pub(crate) fn _update_dead_letter(
+    clock: &impl Clock,
+    id: u64,
+    task: OutboxTask,
+) -> Result<DeadLetter, Error> {
+    let mut dead_letter = find(id)?;
+    match &task {
+        OutboxTask::FetchCover { book_id } => {
+            crud::find::<Book>(*book_id)?;
+        }
+    }
+    dead_letter.item.task = task;
+    dead_letter.edited_at = Some(clock.now());
+    DEAD_LETTER_STORAGE.with(|s| s.borrow_mut().insert(id, dead_letter.clone()));
+    Ok(dead_letter)
+}
+
+// Internal function to put a dead letter back in the outbox, due as of `clock`.
+pub(crate) fn _replay_dead_letter(clock: &impl Clock, id: u64) -> Result<OutboxItem, Error> {
+    let dead_letter = take(id)?;
+    let item = outbox::requeue(clock.now(), dead_letter.item);
+    log::info(format!("Replayed dead letter id={} into the outbox.", id));
+    Ok(item)
+}
+
+// Hook run when the outbox worker gives up on an item.
+pub(crate) fn bury(now: u64, item: OutboxItem) {
+    let dead_letter = DeadLetter {
+        item,
+        failed_at: now,
+        edited_at: None,
+    };
+    DEAD_LETTER_STORAGE.with(|s| s.borrow_mut().insert(dead_letter.item.id, dead_letter));
+}
+
+// Fetch a dead letter by ID.
+fn find(id: u64) -> Result<DeadLetter, Error> {
+    DEAD_LETTER_STORAGE
+        .with(|s| s.borrow().get(&id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("A dead letter with id={} not found.", id),
+        })
+}
+
+// Remove a dead letter and return it.
+fn take(id: u64) -> Result<DeadLetter, Error> {
+    DEAD_LETTER_STORAGE
+        .with(|s| s.borrow_mut().remove(&id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("A dead letter with id={} not found.", id),
+        })
+}
@@ -0,0 +1,291 @@
+use crate::auth::require_admin;
+use crate::book::Book;
+use crate::crud;
+use crate::date::DAY;
+use crate::env::{CanisterEnv, Clock};
+use crate::loan::{Loan, LoanStatus, LOAN_PERIOD};
+use crate::perf;
+use crate::student::{with_email, Student};
+use crate::Error;
+
+// Largest number of records of each kind a single call may create.
+const MAX_DEMO_RECORDS: u32 = 1_000;
+
+// Largest number of records of each kind a single fake-data call may create.
+const MAX_FAKE_RECORDS: u32 = 10_000;
+
+const FIRST_NAMES: &[&str] = &[
+    "Ayu", "Budi", "Citra", "Dimas", "Eka", "Fajar", "Gita", "Hendra", "Indah", "Joko",
+];
+
+const LAST_NAMES: &[&str] = &[
+    "Pratama", "Santoso", "Wijaya", "Saputra", "Lestari", "Hidayat", "Kusuma", "Nugroho",
+];
+
+const BOOKS: &[(&str, &str)] = &[
+    ("Laskar Pelangi", "Andrea Hirata"),
+    ("Bumi Manusia", "Pramoedya Ananta Toer"),
+    ("Cantik Itu Luka", "Eka Kurniawan"),
+    ("Ronggeng Dukuh Paruk", "Ahmad Tohari"),
+    ("Pulang", "Leila S. Chudori"),
+    ("The Hobbit", "J. R. R. Tolkien"),
+    ("To Kill a Mockingbird", "Harper Lee"),
+    ("A Brief History of Time", "Stephen Hawking"),
+    ("The Little Prince", "Antoine de Saint-Exupery"),
+    ("Sapiens", "Yuval Noah Harari"),
+];
+
+const TITLE_WORDS: &[&str] = &[
+    "River",
+    "Night",
+    "Garden",
+    "Stone",
+    "Letters",
+    "Island",
+    "Silent",
+    "Forgotten",
+    "Red",
+    "Journey",
+    "Winter",
+    "City",
+    "Lost",
+    "Golden",
+    "Memory",
+    "Storm",
+    "Light",
+    "Paper",
+];
+
+// How many records of each kind to create.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default)]
+pub struct DemoCounts {
+    pub students: u32,
+    pub books: u32,
+    pub loans: u32,
+}
+
+// Populate the canister with sample students, books and loans for local development.
+// Loans are spread over the past weeks, so roughly a third of them are overdue.
+#[ic_cdk::update]
+fn seed_demo_data(counts: DemoCounts) -> Result<DemoCounts, Error> {
+    let _perf = perf::measure("seed_demo_data");
+    require_admin()?;
+    _seed_demo_data(&CanisterEnv, counts)
+}
+
+// Internal function to create the sample records, dating them relative to `clock`.
+pub(crate) fn _seed_demo_data(clock: &impl Clock, counts: DemoCounts) -> Result<DemoCounts, Error> {
+    validate(&counts, MAX_DEMO_RECORDS)?;
+
+    let mut students = Vec::new();
+    for i in 0..counts.students as usize {
+        let first = FIRST_NAMES[i % FIRST_NAMES.len()];
+        let last = LAST_NAMES[(i / FIRST_NAMES.len()) % LAST_NAMES.len()];
+        let student = crud::create(clock, |id, ulid, now| Student {
+            id,
+            name: format!("{} {}", first, last),
+            email: None,
+            contacts: Some(with_email(
+                Vec::new(),
+                format!("{}.{}{}@school.example", first, last, i).to_lowercase(),
+            )),
+            address: None,
+            principal: None,
+            birth_date: None,
+            grade: None,
+            external_id: None,
+            notification_preferences: None,
+            ulid,
+            created_at: now,
+            updated_at: None,
+        })?;
+        students.push(student.id);
+    }
+
+    let mut books = Vec::new();
+    for i in 0..counts.books as usize {
+        let (title, author) = BOOKS[i % BOOKS.len()];
+        let copy = i / BOOKS.len();
+        let book = crud::create(clock, |id, ulid, now| Book {
+            id,
+            title: match copy {
+                0 => title.to_string(),
+                _ => format!("{} (copy {})", title, copy + 1),
+            },
+            author: author.to_string(),
+            barcode: None,
+            replacement_cost: None,
+            withdrawn_at: None,
+            cover_url: None,
+            min_age: None,
+            featured_at: None,
+            language: None,
+            formats: None,
+            reading_level: None,
+            ulid,
+            created_at: now,
+            updated_at: None,
+        })?;
+        books.push(book.id);
+    }
+
+    for i in 0..counts.loans as usize {
+        // Lent between 0 and 20 days ago; anything older than the loan period is overdue.
+        let loan_date = clock.now().saturating_sub((i as u64 % 21) * DAY).max(1);
+        crud::create(clock, |id, ulid, now| Loan {
+            id,
+            student_id: students[i % students.len()],
+            book_id: books[i % books.len()],
+            loan_date,
+            due_date: Some(loan_date.saturating_add(LOAN_PERIOD)),
+            status: Some(LoanStatus::Active),
+            status_changed_at: Some(now),
+            age_override: None,
+            ulid,
+            created_at: now,
+            updated_at: None,
+        })?;
+    }
+
+    Ok(counts)
+}
+
+// Generate synthetic records for load testing. The same `seed` always yields the same names,
+// titles and loan dates, so pagination, search and memory usage can be measured reproducibly.
+// Call repeatedly with different seeds to build up larger volumes.
+#[ic_cdk::update]
+fn generate_fake_data(seed: u64, counts: DemoCounts) -> Result<DemoCounts, Error> {
+    let _perf = perf::measure("generate_fake_data");
+    require_admin()?;
+    _generate_fake_data(&CanisterEnv, seed, counts)
+}
+
+// Internal function to create the synthetic records, dating them relative to `clock`.
+pub(crate) fn _generate_fake_data(
+    clock: &impl Clock,
+    seed: u64,
+    counts: DemoCounts,
+) -> Result<DemoCounts, Error> {
+    validate(&counts, MAX_FAKE_RECORDS)?;
+    let mut rng = SplitMix64(seed);
+
+    let mut students = Vec::new();
+    for i in 0..counts.students {
+        let first = rng.pick(FIRST_NAMES);
+        let last = rng.pick(LAST_NAMES);
+        let student = crud::create(clock, |id, ulid, now| Student {
+            id,
+            name: format!("{} {}", first, last),
+            email: None,
+            contacts: Some(with_email(
+                Vec::new(),
+                format!("{}.{}.{}.{}@load.example", first, last, seed, i).to_lowercase(),
+            )),
+            address: None,
+            principal: None,
+            birth_date: None,
+            grade: None,
+            external_id: None,
+            notification_preferences: None,
+            ulid,
+            created_at: now,
+            updated_at: None,
+        })?;
+        students.push(student.id);
+    }
+
+    let mut books = Vec::new();
+    for _ in 0..counts.books {
+        let title = format!("The {} {}", rng.pick(TITLE_WORDS), rng.pick(TITLE_WORDS));
+        let author = format!("{} {}", rng.pick(FIRST_NAMES), rng.pick(LAST_NAMES));
+        let book = crud::create(clock, |id, ulid, now| Book {
+            id,
+            title,
+            author,
+            barcode: None,
+            replacement_cost: None,
+            withdrawn_at: None,
+            cover_url: None,
+            min_age: None,
+            featured_at: None,
+            language: None,
+            formats: None,
+            reading_level: None,
+            ulid,
+            created_at: now,
+            updated_at: None,
+        })?;
+        books.push(book.id);
+    }
+
+    // Shuffle the books so each loan gets a different, randomly chosen one.
+    for i in (1..books.len()).rev() {
+        books.swap(i, rng.below(i as u64 + 1) as usize);
+    }
+    for &book_id in books.iter().take(counts.loans as usize) {
+        let student_id = students[rng.below(students.len() as u64) as usize];
+        // Lent at some point in the past 60 days.
+        let loan_date = clock.now().saturating_sub(rng.below(60 * DAY)).max(1);
+        crud::create(clock, |id, ulid, now| Loan {
+            id,
+            student_id,
+            book_id,
+            loan_date,
+            due_date: Some(loan_date.saturating_add(LOAN_PERIOD)),
+            status: Some(LoanStatus::Active),
+            status_changed_at: Some(now),
+            age_override: None,
+            ulid,
+            created_at: now,
+            updated_at: None,
+        })?;
+    }
+
+    Ok(counts)
+}
+
+// Reject counts above `max`, and loans with no students or free books to attach them to.
+fn validate(counts: &DemoCounts, max: u32) -> Result<(), Error> {
+    if counts.students > max || counts.books > max || counts.loans > max {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "At most {} records of each kind can be created at once.",
+                max
+            ),
+        });
+    }
+    if counts.loans > 0 && counts.students == 0 {
+        return Err(Error::InvalidInput {
+            msg: "Loans need at least one student.".to_string(),
+        });
+    }
+    // A book can only be on one loan at a time.
+    if counts.loans > counts.books {
+        return Err(Error::InvalidInput {
+            msg: "There must be at least as many books as loans.".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Small, fast PRNG whose output depends only on the seed; not for anything security related.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // A number in `0..bound`; `bound` must be non-zero.
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[self.below(items.len() as u64) as usize]
+    }
+}
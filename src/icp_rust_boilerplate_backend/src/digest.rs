@@ -0,0 +1,340 @@
+use ic_stable_structures::{BoundedStorable, StableBTreeMap};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::auth::require_admin;
+use crate::certification;
+use crate::env::{CanisterEnv, Clock};
+use crate::log;
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::{
+    Error, Memory, BOOK_STORAGE, CERTIFIED_DIGEST, CHARGE_STORAGE, DIGITAL_ASSET_STORAGE,
+    EBOOK_HOLD_STORAGE, EBOOK_LOAN_STORAGE, EXTENSION_REQUEST_STORAGE, LOAN_STORAGE,
+    STUDENT_STORAGE,
+};
+
+// Domain separators, so a leaf can never be passed off as an inner node or the other way round.
+const LEAF_TAG: u8 = 0x00;
+const NODE_TAG: u8 = 0x01;
+
+// Root of a tree without leaves.
+const EMPTY_ROOT: [u8; 32] = [0; 32];
+
+// The collections covered by the digest.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum Collection {
+    Students,
+    Books,
+    Loans,
+    DigitalAssets,
+    EbookLoans,
+    EbookHolds,
+    ExtensionRequests,
+    Charges,
+}
+
+// Order of the collections' leaves in the overall tree.
+const COLLECTIONS: &[Collection] = &[
+    Collection::Students,
+    Collection::Books,
+    Collection::Loans,
+    Collection::DigitalAssets,
+    Collection::EbookLoans,
+    Collection::EbookHolds,
+    Collection::ExtensionRequests,
+    Collection::Charges,
+];
+
+impl Collection {
+    fn name(self) -> &'static str {
+        match self {
+            Collection::Students => "students",
+            Collection::Books => "books",
+            Collection::Loans => "loans",
+            Collection::DigitalAssets => "digital_assets",
+            Collection::EbookLoans => "ebook_loans",
+            Collection::EbookHolds => "ebook_holds",
+            Collection::ExtensionRequests => "extension_requests",
+            Collection::Charges => "charges",
+        }
+    }
+
+    // Leaf hashes of the collection's records, in ID order.
+    fn leaves(self) -> Vec<[u8; 32]> {
+        match self {
+            Collection::Students => leaves(&STUDENT_STORAGE),
+            Collection::Books => leaves(&BOOK_STORAGE),
+            Collection::Loans => leaves(&LOAN_STORAGE),
+            Collection::DigitalAssets => leaves(&DIGITAL_ASSET_STORAGE),
+            Collection::EbookLoans => leaves(&EBOOK_LOAN_STORAGE),
+            Collection::EbookHolds => leaves(&EBOOK_HOLD_STORAGE),
+            Collection::ExtensionRequests => leaves(&EXTENSION_REQUEST_STORAGE),
+            Collection::Charges => leaves(&CHARGE_STORAGE),
+        }
+    }
+
+    // Position and stored encoding of record `id`.
+    fn locate(self, id: u64) -> Option<(usize, Vec<u8>)> {
+        match self {
+            Collection::Students => locate(&STUDENT_STORAGE, id),
+            Collection::Books => locate(&BOOK_STORAGE, id),
+            Collection::Loans => locate(&LOAN_STORAGE, id),
+            Collection::DigitalAssets => locate(&DIGITAL_ASSET_STORAGE, id),
+            Collection::EbookLoans => locate(&EBOOK_LOAN_STORAGE, id),
+            Collection::EbookHolds => locate(&EBOOK_HOLD_STORAGE, id),
+            Collection::ExtensionRequests => locate(&EXTENSION_REQUEST_STORAGE, id),
+            Collection::Charges => locate(&CHARGE_STORAGE, id),
+        }
+    }
+}
+
+// Merkle root and record count of one collection.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct CollectionDigest {
+    pub name: String,
+    pub records: u64,
+    // Hex-encoded root of the tree over the collection's records, in ID order.
+    pub root: String,
+}
+
+// Merkle roots of the library's collections and the root over all of them.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct StateDigest {
+    pub computed_at: u64,
+    // Hex-encoded root of the tree whose leaves are the collections, in the order listed.
+    pub root: String,
+    pub collections: Vec<CollectionDigest>,
+}
+
+// The state root last published in the canister's certified data.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct CertifiedDigest {
+    pub root: Vec<u8>,
+    pub certified_at: u64,
+}
+
+// Store CertifiedDigest as Candid.
+candid_storable!(CertifiedDigest);
+
+// One step up a Merkle path: the sibling to hash with, and on which side it sits. Levels where
+// the node had no sibling and was carried up are skipped.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct ProofStep {
+    pub sibling: Vec<u8>,
+    pub sibling_on_left: bool,
+}
+
+// Evidence that a record is part of the certified state: hash `record` into its leaf, follow
+// `record_path` to the collection root, then `collection_path` to `state_root`, which is the
+// state_root leaf of `hash_tree`. `certificate` is the IC's signature over the certified data,
+// the root hash of `hash_tree`; it is only present when the proof is fetched as a plain query.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct RecordProof {
+    pub entity: Collection,
+    pub id: u64,
+    // The record's Candid encoding, as stored.
+    pub record: Vec<u8>,
+    pub record_path: Vec<ProofStep>,
+    pub collection_root: Vec<u8>,
+    pub collection_path: Vec<ProofStep>,
+    pub state_root: Vec<u8>,
+    pub certified_at: u64,
+    // CBOR-encoded IC hash tree, as described in the interface specification.
+    pub hash_tree: Vec<u8>,
+    pub certificate: Option<Vec<u8>>,
+}
+
+// Compute a Merkle root per collection so an export can be checked against what the canister
+// holds. A record's leaf is SHA-256(0x00 || 8-byte big-endian ID || Candid-encoded record);
+// an inner node is SHA-256(0x01 || left || right), and an odd node is carried up unchanged.
+// A collection's leaf in the overall tree hashes its name and root the same way.
+#[ic_cdk::query]
+fn get_state_digest() -> StateDigest {
+    _get_state_digest(&CanisterEnv)
+}
+
+// Internal function to compute the digest, timestamped by `clock`.
+pub(crate) fn _get_state_digest(clock: &impl Clock) -> StateDigest {
+    let roots = collection_roots();
+    StateDigest {
+        computed_at: clock.now(),
+        root: hex::encode(state_root(&roots)),
+        collections: roots
+            .into_iter()
+            .map(|(collection, records, root)| CollectionDigest {
+                name: collection.name().to_string(),
+                records,
+                root: hex::encode(root),
+            })
+            .collect(),
+    }
+}
+
+// Publish the current state root in the canister's certified data, so record proofs can be
+// verified against it. Proofs are only issued while the state still matches, so certify again
+// after changes that should be provable.
+#[ic_cdk::update]
+fn certify_state() -> Result<StateDigest, Error> {
+    let _perf = perf::measure("certify_state");
+    require_admin()?;
+    let digest = _get_state_digest(&CanisterEnv);
+    let root = hex::decode(&digest.root).expect("A digest root is valid hex");
+    CERTIFIED_DIGEST
+        .with(|c| {
+            c.borrow_mut().set(CertifiedDigest {
+                root,
+                certified_at: digest.computed_at,
+            })
+        })
+        .expect("Cannot store the certified digest");
+    certification::publish();
+    log::info(format!("Certified state root {}.", digest.root));
+    Ok(digest)
+}
+
+// Prove that a record is part of the certified state.
+#[ic_cdk::query]
+fn get_record_proof(entity: Collection, id: u64) -> Result<RecordProof, Error> {
+    let certified = CERTIFIED_DIGEST.with(|c| c.borrow().get().clone());
+    if certified.root.is_empty() {
+        return Err(Error::Unavailable {
+            msg: "No state root has been certified yet.".to_string(),
+        });
+    }
+    let (position, record) = entity.locate(id).ok_or_else(|| Error::NotFound {
+        msg: format!("A record with id={} not found in {}.", id, entity.name()),
+    })?;
+
+    let roots = collection_roots();
+    if state_root(&roots).as_slice() != certified.root {
+        return Err(Error::Unavailable {
+            msg: "The state changed since it was last certified; certify it again.".to_string(),
+        });
+    }
+    let collection_position = COLLECTIONS
+        .iter()
+        .position(|&c| c == entity)
+        .expect("Every collection is listed");
+    let collection_leaves = roots
+        .iter()
+        .map(|(collection, _, root)| leaf_hash(collection.name().as_bytes(), root))
+        .collect();
+
+    Ok(RecordProof {
+        entity,
+        id,
+        record,
+        record_path: merkle_path(entity.leaves(), position),
+        collection_root: roots[collection_position].2.to_vec(),
+        collection_path: merkle_path(collection_leaves, collection_position),
+        state_root: certified.root,
+        certified_at: certified.certified_at,
+        hash_tree: certification::tree().to_cbor(),
+        certificate: ic_cdk::api::data_certificate(),
+    })
+}
+
+// Each collection with its record count and Merkle root, in tree order.
+fn collection_roots() -> Vec<(Collection, u64, [u8; 32])> {
+    COLLECTIONS
+        .iter()
+        .map(|&collection| {
+            let leaves = collection.leaves();
+            (collection, leaves.len() as u64, merkle_root(leaves))
+        })
+        .collect()
+}
+
+// Root of the tree over the collections' roots.
+fn state_root(roots: &[(Collection, u64, [u8; 32])]) -> [u8; 32] {
+    merkle_root(
+        roots
+            .iter()
+            .map(|(collection, _, root)| leaf_hash(collection.name().as_bytes(), root))
+            .collect(),
+    )
+}
+
+// Leaf hashes of a map's records, in key order.
+fn leaves<V: BoundedStorable>(
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+) -> Vec<[u8; 32]> {
+    storage.with(|map| {
+        map.borrow()
+            .iter()
+            .map(|(id, value)| leaf_hash(&id.to_be_bytes(), &value.to_bytes()))
+            .collect()
+    })
+}
+
+// Position of `id` among a map's keys, and its value's encoding.
+fn locate<V: BoundedStorable>(
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+    id: u64,
+) -> Option<(usize, Vec<u8>)> {
+    storage.with(|map| {
+        let map = map.borrow();
+        let value = map.get(&id)?;
+        Some((map.range(..id).count(), value.to_bytes().into_owned()))
+    })
+}
+
+// Hash of a leaf holding `value` under `key`.
+fn leaf_hash(key: &[u8], value: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_TAG]);
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize().into()
+}
+
+// Hash of an inner node over its two children.
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_TAG]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// Root of the tree over `leaves`, pairing neighbours level by level.
+fn merkle_root(mut level: Vec<[u8; 32]>) -> [u8; 32] {
+    if level.is_empty() {
+        return EMPTY_ROOT;
+    }
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+// Siblings on the way from leaf `position` up to the root of the tree over `leaves`.
+fn merkle_path(mut level: Vec<[u8; 32]>, mut position: usize) -> Vec<ProofStep> {
+    let mut path = Vec::new();
+    while level.len() > 1 {
+        let sibling = position ^ 1;
+        if sibling < level.len() {
+            path.push(ProofStep {
+                sibling: level[sibling].to_vec(),
+                sibling_on_left: sibling < position,
+            });
+        }
+        level = next_level(&level);
+        position /= 2;
+    }
+    path
+}
+
+// The level above `level`: neighbours hashed in pairs, an odd last node carried up.
+fn next_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node_hash(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
@@ -0,0 +1,322 @@
+use ic_stable_structures::{BoundedStorable, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+use crate::auth::require_admin;
+use crate::catalog::require_catalog_access;
+use crate::env::{CanisterEnv, Clock};
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::{Error, BOOK_STORAGE, DIGITAL_ASSET_STORAGE, EBOOK_CHUNK_STORAGE};
+
+// Size of a single upload chunk; every chunk except the last must be exactly this long. Every
+// B-tree node reserves room for a full chunk per entry, so chunks are kept small.
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+
+// Largest e-book file accepted by the canister.
+pub const MAX_ASSET_SIZE: u64 = 64 * 1024 * 1024;
+
+// Number of simultaneous borrowers a newly uploaded e-book allows.
+pub const DEFAULT_LICENSES: u32 = 1;
+
+// Supported digital file formats.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum DigitalFormat {
+    Epub,
+    Pdf,
+}
+
+// Define the DigitalAsset struct describing the e-book file attached to a book.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct DigitalAsset {
+    pub book_id: u64,
+    pub format: DigitalFormat,
+    pub size: u64,
+    pub chunk_count: u32,
+    // Number of digital loans that may be active at the same time.
+    pub licenses: u32,
+    // Hex-encoded SHA-256 of the file, set once the upload has been finished.
+    pub sha256: Option<String>,
+    pub created_at: u64,
+    pub updated_at: Option<u64>,
+}
+
+// Store DigitalAsset as Candid, bounded to 1024 bytes.
+candid_storable!(DigitalAsset, max_size = 1024);
+
+// Raw bytes of one uploaded chunk, stored without any encoding overhead.
+pub struct EbookChunk(pub Vec<u8>);
+
+// Implement serialization and deserialization for EbookChunk.
+impl Storable for EbookChunk {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<'_, [u8]>) -> Self {
+        EbookChunk(bytes.into_owned())
+    }
+}
+
+// Set limits for EbookChunk storage size and flexibility.
+impl BoundedStorable for EbookChunk {
+    const MAX_SIZE: u32 = CHUNK_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define the payload structure for starting an e-book upload.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct DigitalAssetPayload {
+    format: DigitalFormat,
+    size: u64,
+}
+
+// Retrieve all digital assets from the storage.
+#[ic_cdk::query]
+fn get_all_digital_assets() -> Result<Vec<DigitalAsset>, Error> {
+    require_admin()?;
+    let assets = DIGITAL_ASSET_STORAGE.with(|assets| {
+        assets
+            .borrow()
+            .iter()
+            .map(|(_, value)| value.clone())
+            .collect()
+    });
+    Ok(assets)
+}
+
+// Retrieve the digital asset attached to a book.
+#[ic_cdk::query]
+fn get_digital_asset(book_id: u64) -> Result<DigitalAsset, Error> {
+    require_catalog_access()?;
+    match _get_digital_asset(&book_id) {
+        Some(asset) => Ok(asset),
+        None => Err(Error::NotFound {
+            msg: format!("A digital asset for book id={} not found.", book_id),
+        }),
+    }
+}
+
+// Internal function to fetch a digital asset by book ID.
+pub(crate) fn _get_digital_asset(book_id: &u64) -> Option<DigitalAsset> {
+    DIGITAL_ASSET_STORAGE.with(|s| s.borrow().get(book_id))
+}
+
+// Internal function to fetch a single stored chunk of a book's e-book file.
+pub(crate) fn _get_chunk(book_id: u64, index: u32) -> Option<Vec<u8>> {
+    EBOOK_CHUNK_STORAGE.with(|s| s.borrow().get(&(book_id, index)).map(|chunk| chunk.0))
+}
+
+// Start (or restart) the upload of an e-book file for a book, discarding any previous file.
+#[ic_cdk::update]
+fn start_ebook_upload(book_id: u64, payload: DigitalAssetPayload) -> Result<DigitalAsset, Error> {
+    let _perf = perf::measure("start_ebook_upload");
+    require_admin()?;
+    _start_ebook_upload(&CanisterEnv, book_id, payload)
+}
+
+// Internal function to start an e-book upload, taking its timestamps from `clock`.
+pub(crate) fn _start_ebook_upload(
+    clock: &impl Clock,
+    book_id: u64,
+    payload: DigitalAssetPayload,
+) -> Result<DigitalAsset, Error> {
+    // Validate the input payload.
+    if payload.size == 0 || payload.size > MAX_ASSET_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!("File size must be between 1 and {} bytes.", MAX_ASSET_SIZE),
+        });
+    }
+
+    if !BOOK_STORAGE.with(|books| books.borrow().contains_key(&book_id)) {
+        return Err(Error::NotFound {
+            msg: format!("A book with id={} not found.", book_id),
+        });
+    }
+
+    // Drop the chunks of any previously stored file, keeping its license count.
+    let (licenses, created_at) = match _get_digital_asset(&book_id) {
+        Some(previous) => {
+            remove_chunks(&previous);
+            (previous.licenses, previous.created_at)
+        }
+        None => (DEFAULT_LICENSES, clock.now()),
+    };
+
+    let asset = DigitalAsset {
+        book_id,
+        format: payload.format,
+        size: payload.size,
+        chunk_count: payload.size.div_ceil(CHUNK_SIZE) as u32,
+        licenses,
+        sha256: None,
+        created_at,
+        updated_at: Some(clock.now()),
+    };
+
+    do_insert(&asset);
+    Ok(asset)
+}
+
+// Store one chunk of an in-progress e-book upload.
+#[ic_cdk::update]
+fn upload_ebook_chunk(book_id: u64, index: u32, data: Vec<u8>) -> Result<(), Error> {
+    let _perf = perf::measure("upload_ebook_chunk");
+    require_admin()?;
+    let asset = match _get_digital_asset(&book_id) {
+        Some(asset) if asset.sha256.is_none() => asset,
+        Some(_) => {
+            return Err(Error::InvalidInput {
+                msg: format!("The upload for book id={} is already finished.", book_id),
+            })
+        }
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("No upload in progress for book id={}.", book_id),
+            })
+        }
+    };
+
+    // Validate the chunk position and length against the declared file size.
+    if index >= asset.chunk_count {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Chunk index {} is out of range; the file has {} chunks.",
+                index, asset.chunk_count
+            ),
+        });
+    }
+
+    let expected_len = expected_chunk_len(&asset, index);
+    if data.len() as u64 != expected_len {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Chunk {} must be {} bytes long, got {}.",
+                index,
+                expected_len,
+                data.len()
+            ),
+        });
+    }
+
+    EBOOK_CHUNK_STORAGE.with(|s| s.borrow_mut().insert((book_id, index), EbookChunk(data)));
+    Ok(())
+}
+
+// Finish an e-book upload once every chunk is present and record the file's content hash.
+#[ic_cdk::update]
+fn finish_ebook_upload(book_id: u64) -> Result<DigitalAsset, Error> {
+    let _perf = perf::measure("finish_ebook_upload");
+    require_admin()?;
+    _finish_ebook_upload(&CanisterEnv, book_id)
+}
+
+// Internal function to finish an e-book upload, taking its timestamps from `clock`.
+pub(crate) fn _finish_ebook_upload(
+    clock: &impl Clock,
+    book_id: u64,
+) -> Result<DigitalAsset, Error> {
+    let mut asset = match _get_digital_asset(&book_id) {
+        Some(asset) => asset,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("No upload in progress for book id={}.", book_id),
+            })
+        }
+    };
+
+    // Hash the chunks in order, failing on the first missing one.
+    let mut hasher = Sha256::new();
+    for index in 0..asset.chunk_count {
+        match _get_chunk(book_id, index) {
+            Some(chunk) => hasher.update(&chunk),
+            None => {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Chunk {} for book id={} has not been uploaded.",
+                        index, book_id
+                    ),
+                })
+            }
+        }
+    }
+
+    asset.sha256 = Some(hex::encode(hasher.finalize()));
+    asset.updated_at = Some(clock.now());
+    do_insert(&asset);
+    Ok(asset)
+}
+
+// Set how many digital loans of a book may be active at the same time.
+#[ic_cdk::update]
+fn set_ebook_licenses(book_id: u64, licenses: u32) -> Result<DigitalAsset, Error> {
+    let _perf = perf::measure("set_ebook_licenses");
+    require_admin()?;
+    _set_ebook_licenses(&CanisterEnv, book_id, licenses)
+}
+
+// Internal function to set an e-book's license count, taking its timestamps from `clock`.
+pub(crate) fn _set_ebook_licenses(
+    clock: &impl Clock,
+    book_id: u64,
+    licenses: u32,
+) -> Result<DigitalAsset, Error> {
+    if licenses == 0 {
+        return Err(Error::InvalidInput {
+            msg: "License count must be at least 1.".to_string(),
+        });
+    }
+
+    match _get_digital_asset(&book_id) {
+        Some(mut asset) => {
+            asset.licenses = licenses;
+            asset.updated_at = Some(clock.now());
+            do_insert(&asset);
+            Ok(asset)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("A digital asset for book id={} not found.", book_id),
+        }),
+    }
+}
+
+// Delete the digital asset of a book together with all of its chunks.
+#[ic_cdk::update]
+fn delete_digital_asset(book_id: u64) -> Result<DigitalAsset, Error> {
+    let _perf = perf::measure("delete_digital_asset");
+    require_admin()?;
+    remove_digital_asset(book_id).ok_or_else(|| Error::NotFound {
+        msg: format!(
+            "Couldn't delete a digital asset for book id={}. Digital asset not found.",
+            book_id
+        ),
+    })
+}
+
+// Helper function to drop a book's digital asset and its chunks, returning the asset removed.
+pub(crate) fn remove_digital_asset(book_id: u64) -> Option<DigitalAsset> {
+    let asset = DIGITAL_ASSET_STORAGE.with(|service| service.borrow_mut().remove(&book_id))?;
+    remove_chunks(&asset);
+    Some(asset)
+}
+
+// Helper function to insert a digital asset into storage.
+fn do_insert(asset: &DigitalAsset) {
+    DIGITAL_ASSET_STORAGE.with(|service| service.borrow_mut().insert(asset.book_id, asset.clone()));
+}
+
+// Helper function to remove every stored chunk of a digital asset.
+fn remove_chunks(asset: &DigitalAsset) {
+    EBOOK_CHUNK_STORAGE.with(|service| {
+        let mut chunks = service.borrow_mut();
+        for index in 0..asset.chunk_count {
+            chunks.remove(&(asset.book_id, index));
+        }
+    });
+}
+
+// Length a chunk at the given index must have for the declared file size.
+fn expected_chunk_len(asset: &DigitalAsset, index: u32) -> u64 {
+    let offset = index as u64 * CHUNK_SIZE;
+    (asset.size - offset).min(CHUNK_SIZE)
+}
@@ -0,0 +1,247 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::auth::require_admin;
+use crate::charge;
+use crate::class;
+use crate::crud;
+use crate::env::{CanisterEnv, Clock};
+use crate::guardian;
+use crate::loan;
+use crate::log;
+use crate::perf;
+use crate::student::{self, ContactKind, Student};
+use crate::wishlist;
+use crate::Error;
+
+// What two student records have in common that makes them look like the same person.
+#[derive(
+    candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum DuplicateReason {
+    // An email contact, ignoring case and surrounding spaces.
+    Email,
+    // The name, ignoring case and spacing.
+    Name,
+    // The ID in the school information system, which is printed on library cards.
+    ExternalId,
+}
+
+// Two students that are probably the same person, lower ID first.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub struct DuplicatePair {
+    pub first_id: u64,
+    pub second_id: u64,
+    pub reasons: Vec<DuplicateReason>,
+}
+
+// What merging moved onto the surviving student.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub struct StudentMerge {
+    pub student: Student,
+    pub merged_id: u64,
+    pub loans: u64,
+    pub charges: u64,
+    pub classes: u64,
+    pub guardians: u64,
+    pub wishlist: u64,
+}
+
+// List pairs of students sharing an email, name or external ID, ordered by their IDs. A match
+// is only a hint: two students can share a name.
+#[ic_cdk::query]
+fn find_duplicate_students() -> Result<Vec<DuplicatePair>, Error> {
+    require_admin()?;
+    Ok(duplicate_pairs(&crud::all::<Student>()))
+}
+
+// Fold the student `merge_id` into `keep_id`: their loans, charges, class places, guardians and
+// wishlist move to `keep_id`, which also takes their principal if it has none, and `merge_id`
+// is deleted.
+#[ic_cdk::update]
+fn merge_students(keep_id: u64, merge_id: u64) -> Result<StudentMerge, Error> {
+    let _perf = perf::measure("merge_students");
+    require_admin()?;
+    _merge_students(&CanisterEnv, keep_id, merge_id)
+}
+
+// Internal function to merge two students, taking timestamps from `clock`. Everything that can
+// be refused is checked before the first write; a failure after that traps, so a merge is
+// never left half done.
+pub(crate) fn _merge_students(
+    clock: &impl Clock,
+    keep_id: u64,
+    merge_id: u64,
+) -> Result<StudentMerge, Error> {
+    if keep_id == merge_id {
+        return Err(Error::InvalidInput {
+            msg: "A student cannot be merged into themselves.".to_string(),
+        });
+    }
+    let keep = crud::find::<Student>(keep_id)?;
+    let merged = crud::find::<Student>(merge_id)?;
+
+    let merge = move_records(clock, &keep, &merged).unwrap_or_else(|error| {
+        ic_cdk::trap(&format!(
+            "Merging student id={} into id={} failed part-way: {:?}",
+            merge_id, keep_id, error
+        ))
+    });
+    log::warn(format!(
+        "Merged student id={} into id={}, moving {} loans and {} charges.",
+        merge_id, keep_id, merge.loans, merge.charges
+    ));
+    Ok(merge)
+}
+
+// Helper function to move everything of `merged` onto `keep` and delete `merged`.
+fn move_records(
+    clock: &impl Clock,
+    keep: &Student,
+    merged: &Student,
+) -> Result<StudentMerge, Error> {
+    let loans = loan::move_loans(clock, merged.id, keep.id)?;
+    let charges = charge::move_charges(merged.id, keep.id);
+    let classes = class::move_student(merged.id, keep.id);
+    let guardians = guardian::move_student(merged.id, keep.id);
+    let wishlist = wishlist::move_student(merged.id, keep.id);
+    // With no open loans left, deleting the student only drops the records that belong to
+    // them alone, such as notes and consents.
+    crud::delete::<Student>(merged.id, false)?;
+    let student = match (keep.principal, merged.principal) {
+        (None, Some(principal)) => {
+            student::_link_student_principal(clock, keep.id, Some(principal))?
+        }
+        _ => crud::find::<Student>(keep.id)?,
+    };
+    Ok(StudentMerge {
+        student,
+        merged_id: merged.id,
+        loans,
+        charges,
+        classes,
+        guardians,
+        wishlist,
+    })
+}
+
+// Group students by each normalized key and pair up the students in every group.
+fn duplicate_pairs(students: &[Student]) -> Vec<DuplicatePair> {
+    let mut groups: BTreeMap<(DuplicateReason, String), Vec<u64>> = BTreeMap::new();
+    for student in students {
+        for key in keys(student) {
+            groups.entry(key).or_default().push(student.id);
+        }
+    }
+    let mut pairs: BTreeMap<(u64, u64), BTreeSet<DuplicateReason>> = BTreeMap::new();
+    for ((reason, _), ids) in groups {
+        for (i, first) in ids.iter().enumerate() {
+            for second in &ids[i + 1..] {
+                let pair = (*first.min(second), *first.max(second));
+                pairs.entry(pair).or_default().insert(reason);
+            }
+        }
+    }
+    pairs
+        .into_iter()
+        .map(|((first_id, second_id), reasons)| DuplicatePair {
+            first_id,
+            second_id,
+            reasons: reasons.into_iter().collect(),
+        })
+        .collect()
+}
+
+// The normalized values a student is matched on. Empty values match nothing.
+fn keys(student: &Student) -> BTreeSet<(DuplicateReason, String)> {
+    let emails = student
+        .contacts()
+        .iter()
+        .filter(|c| c.kind == ContactKind::Email)
+        .map(|c| (DuplicateReason::Email, c.value.trim().to_lowercase()));
+    let name = student
+        .name
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    let external_id = student
+        .external_id
+        .as_deref()
+        .map(|id| (DuplicateReason::ExternalId, id.trim().to_lowercase()));
+    emails
+        .chain(Some((DuplicateReason::Name, name)))
+        .chain(external_id)
+        .filter(|(_, value)| !value.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::{_add_book, BookPayload};
+    use crate::class::{_add_class, _add_class_members, ClassPayload};
+    use crate::env::TestEnv;
+    use crate::guardian::_link_guardian;
+    use crate::student::{_add_student, _link_student_principal, StudentPayload};
+    use crate::wishlist::_add_to_wishlist;
+    use crate::{GUARDIAN_STORAGE, WISHLIST_STORAGE};
+    use candid::Principal;
+
+    // 2023-11-14, an arbitrary day to start the clock on.
+    const START: u64 = 1_700_000_000_000_000_000;
+
+    fn student(env: &TestEnv, name: &str) -> Student {
+        let payload: StudentPayload = serde_json::from_str(&format!(
+            r#"{{"name": "{}", "contacts": [
+                {{"kind": "Email", "value": "rahma@example.com", "preferred": true}}]}}"#,
+            name
+        ))
+        .unwrap();
+        _add_student(env, payload).unwrap()
+    }
+
+    #[test]
+    fn merge_moves_classes_guardians_and_wishlist() {
+        let env = TestEnv::new(START, 1);
+        let keep = student(&env, "Siti Rahma");
+        let merged = student(&env, "Siti  Rahma");
+        _link_student_principal(&env, merged.id, Some(env.caller)).unwrap();
+        let class: ClassPayload = serde_json::from_str(
+            r#"{"name": "7A", "teacher_name": "Bu Ani", "academic_year": "2025/2026"}"#,
+        )
+        .unwrap();
+        let class = _add_class(&env, class).unwrap();
+        _add_class_members(&env, class.id, vec![merged.id]).unwrap();
+        let guardian = Principal::from_slice(&[9]);
+        _link_guardian(&env, merged.id, guardian, "Pak Rahmat".to_string()).unwrap();
+        let book: BookPayload =
+            serde_json::from_str(r#"{"title": "Laskar Pelangi", "author": "Andrea Hirata"}"#)
+                .unwrap();
+        let book = _add_book(&env, book).unwrap();
+        _add_to_wishlist(&env, book.id, true).unwrap();
+
+        let merge = _merge_students(&env, keep.id, merged.id).unwrap();
+        assert_eq!((merge.classes, merge.guardians, merge.wishlist), (1, 1, 1));
+        assert_eq!(merge.student.principal, Some(env.caller));
+        assert!(!crud::exists::<Student>(merged.id));
+        assert_eq!(
+            crate::class::_get_class(class.id).unwrap().members,
+            vec![keep.id]
+        );
+        let students: Vec<u64> =
+            GUARDIAN_STORAGE.with(|s| s.borrow().iter().map(|((_, student), _)| student).collect());
+        assert_eq!(students, vec![keep.id]);
+        let wished: Vec<(u64, u64)> =
+            WISHLIST_STORAGE.with(|s| s.borrow().iter().map(|(key, _)| key).collect());
+        assert_eq!(wished, vec![(keep.id, book.id)]);
+    }
+
+    #[test]
+    fn merge_refuses_before_writing() {
+        let env = TestEnv::new(START, 1);
+        let keep = student(&env, "Siti Rahma");
+        assert!(_merge_students(&env, keep.id, keep.id).is_err());
+        assert!(_merge_students(&env, keep.id, keep.id + 100).is_err());
+        assert!(crud::exists::<Student>(keep.id));
+    }
+}
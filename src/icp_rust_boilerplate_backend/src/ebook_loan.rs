@@ -0,0 +1,588 @@
+use candid::Principal;
+use sha2::{Digest, Sha256};
+
+use crate::auth::require_admin;
+use crate::date::DAY;
+use crate::digital_asset::{_get_chunk, _get_digital_asset, DigitalAsset};
+use crate::env::{random_bytes, CallerProvider, CanisterEnv, Clock};
+use crate::feature::{require_feature, Feature};
+use crate::id_counter::{next_id, Entity};
+use crate::log;
+use crate::perf;
+use crate::repository::Repository;
+use crate::storable::candid_storable;
+use crate::{Error, EBOOK_HOLD_STORAGE, EBOOK_LOAN_STORAGE, EBOOK_TOKEN_SECRET};
+
+// How long a digital loan (and its access token) stays valid: 14 days in nanoseconds.
+pub const EBOOK_LOAN_DURATION: u64 = 14 * DAY;
+
+// How long a hold keeps its license once one is free for it: 3 days in nanoseconds. After that
+// the hold is dropped and the license passes to the next in the queue.
+pub const HOLD_CLAIM_PERIOD: u64 = 3 * DAY;
+
+// Define the EbookLoan struct to represent a time-limited digital loan.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct EbookLoan {
+    pub id: u64,
+    pub book_id: u64,
+    pub borrower: Principal,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+// Store EbookLoan as Candid, bounded to 1024 bytes.
+candid_storable!(EbookLoan, max_size = 1024);
+
+impl EbookLoan {
+    // A digital loan ends by itself once its expiry time has passed.
+    pub fn is_active(&self, now: u64) -> bool {
+        now < self.expires_at
+    }
+}
+
+// Define the EbookHold struct to represent a place in the queue for a fully borrowed e-book.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct EbookHold {
+    pub id: u64,
+    pub book_id: u64,
+    pub holder: Principal,
+    pub created_at: u64,
+    // When a license was first seen free for this hold; it must be claimed within
+    // HOLD_CLAIM_PERIOD of that.
+    pub ready_at: Option<u64>,
+}
+
+// Store EbookHold as Candid, bounded to 1024 bytes.
+candid_storable!(EbookHold, max_size = 1024);
+
+// Access granted by borrow_ebook: the loan plus the token needed to fetch chunks.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct EbookAccess {
+    pub loan: EbookLoan,
+    pub token: String,
+    pub chunk_count: u32,
+}
+
+// Retrieve all digital loans, including expired ones.
+#[ic_cdk::query]
+fn get_all_ebook_loans() -> Result<Vec<EbookLoan>, Error> {
+    require_admin()?;
+    let loans = EBOOK_LOAN_STORAGE.with(|loans| {
+        loans
+            .borrow()
+            .iter()
+            .map(|(_, value)| value.clone())
+            .collect()
+    });
+    Ok(loans)
+}
+
+// Retrieve the caller's digital loans that have not expired yet.
+#[ic_cdk::query]
+fn get_my_ebook_loans() -> Result<Vec<EbookLoan>, Error> {
+    _get_my_ebook_loans(&CanisterEnv)
+}
+
+// Internal function to fetch the unexpired digital loans of `env`'s caller.
+pub(crate) fn _get_my_ebook_loans(
+    env: &(impl Clock + CallerProvider),
+) -> Result<Vec<EbookLoan>, Error> {
+    let caller = env.caller();
+    let now = env.now();
+    let loans = EBOOK_LOAN_STORAGE.with(|loans| {
+        loans
+            .borrow()
+            .iter()
+            .map(|(_, value)| value)
+            .filter(|loan| loan.borrower == caller && loan.is_active(now))
+            .collect()
+    });
+    Ok(loans)
+}
+
+// Borrow the digital edition of a book, receiving a signed token that expires with the loan.
+#[ic_cdk::update]
+async fn borrow_ebook(book_id: u64) -> Result<EbookAccess, Error> {
+    let _perf = perf::measure("borrow_ebook");
+    require_feature(Feature::DigitalLending)?;
+    let asset = match _get_digital_asset(&book_id) {
+        Some(asset) if asset.sha256.is_some() => asset,
+        _ => {
+            return Err(Error::NotFound {
+                msg: format!("No digital edition available for book id={}.", book_id),
+            })
+        }
+    };
+
+    let secret = token_secret().await?;
+    _borrow_ebook(&CanisterEnv, &secret, asset)
+}
+
+// Internal function to lend an e-book to `env`'s caller once the signing key is available.
+// License availability is checked here, after the await, so concurrent borrows see each other.
+pub(crate) fn _borrow_ebook(
+    env: &(impl Clock + CallerProvider),
+    secret: &[u8; 32],
+    asset: DigitalAsset,
+) -> Result<EbookAccess, Error> {
+    let book_id = asset.book_id;
+    let caller = signed_in_caller(env)?;
+    let now = env.now();
+    let active = _get_active_loans(book_id, now);
+    if active.iter().any(|loan| loan.borrower == caller) {
+        return Err(Error::InvalidInput {
+            msg: format!("You already have an active loan for book id={}.", book_id),
+        });
+    }
+
+    // Free licenses go to the front of the holds queue first.
+    let free = asset.licenses.saturating_sub(active.len() as u32) as usize;
+    let holds = advance_holds(&asset, now);
+    match holds.iter().position(|hold| hold.holder == caller) {
+        Some(position) if position < free => {
+            EBOOK_HOLD_STORAGE.with(|s| s.borrow_mut().remove(&holds[position].id));
+        }
+        Some(position) => {
+            return Err(Error::Unavailable {
+                msg: format!(
+                    "All licenses for book id={} are in use. You are number {} in the holds queue.",
+                    book_id,
+                    position + 1
+                ),
+            })
+        }
+        None if holds.len() < free => {}
+        None => {
+            return Err(Error::Unavailable {
+                msg: format!(
+                    "All licenses for book id={} are in use. Place a hold to join the queue.",
+                    book_id
+                ),
+            })
+        }
+    }
+
+    let loan = EbookLoan {
+        id: next_id(Entity::EbookLoan)?,
+        book_id,
+        borrower: caller,
+        created_at: now,
+        expires_at: now + EBOOK_LOAN_DURATION,
+    };
+
+    EBOOK_LOAN_STORAGE.with(|service| service.borrow_mut().insert(loan.id, loan.clone()));
+    Ok(EbookAccess {
+        token: format!("{}.{}", loan.id, hex::encode(sign(secret, &loan))),
+        loan,
+        chunk_count: asset.chunk_count,
+    })
+}
+
+// Join the holds queue for an e-book whose licenses are all in use.
+#[ic_cdk::update]
+fn place_ebook_hold(book_id: u64) -> Result<EbookHold, Error> {
+    let _perf = perf::measure("place_ebook_hold");
+    require_feature(Feature::DigitalLending)?;
+    _place_ebook_hold(&CanisterEnv, book_id)
+}
+
+// Internal function to place a hold for `env`'s caller.
+pub(crate) fn _place_ebook_hold(
+    env: &(impl Clock + CallerProvider),
+    book_id: u64,
+) -> Result<EbookHold, Error> {
+    let asset = match _get_digital_asset(&book_id) {
+        Some(asset) if asset.sha256.is_some() => asset,
+        _ => {
+            return Err(Error::NotFound {
+                msg: format!("No digital edition available for book id={}.", book_id),
+            })
+        }
+    };
+
+    let caller = signed_in_caller(env)?;
+    let now = env.now();
+    let active = _get_active_loans(book_id, now);
+    let holds = advance_holds(&asset, now);
+    if holds.iter().any(|hold| hold.holder == caller) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "You already hold a place in the queue for book id={}.",
+                book_id
+            ),
+        });
+    }
+    if active.iter().any(|loan| loan.borrower == caller) {
+        return Err(Error::InvalidInput {
+            msg: format!("You already have an active loan for book id={}.", book_id),
+        });
+    }
+    if (active.len() + holds.len()) < asset.licenses as usize {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A license for book id={} is free; borrow it directly.",
+                book_id
+            ),
+        });
+    }
+
+    let hold = EbookHold {
+        id: next_id(Entity::EbookHold)?,
+        book_id,
+        holder: caller,
+        created_at: now,
+        ready_at: None,
+    };
+    EBOOK_HOLD_STORAGE.with(|service| service.borrow_mut().insert(hold.id, hold.clone()));
+    Ok(hold)
+}
+
+// Leave the holds queue; only the holder can cancel their hold.
+#[ic_cdk::update]
+fn cancel_ebook_hold(id: u64) -> Result<EbookHold, Error> {
+    let _perf = perf::measure("cancel_ebook_hold");
+    EBOOK_HOLD_STORAGE
+        .with(|service| _cancel_ebook_hold(&CanisterEnv, &mut *service.borrow_mut(), id))
+}
+
+// Internal function to cancel a hold in `holds` on behalf of `env`'s caller.
+pub(crate) fn _cancel_ebook_hold(
+    env: &impl CallerProvider,
+    holds: &mut impl Repository<u64, EbookHold>,
+    id: u64,
+) -> Result<EbookHold, Error> {
+    match holds.get(&id) {
+        Some(hold) if hold.holder == env.caller() => {
+            holds.remove(&id);
+            Ok(hold)
+        }
+        Some(_) => Err(Error::Unauthorized {
+            msg: format!("The hold id={} belongs to another user.", id),
+        }),
+        None => Err(Error::NotFound {
+            msg: format!("Couldn't cancel a hold with id={}. Hold not found.", id),
+        }),
+    }
+}
+
+// Retrieve the holds queue of an e-book in the order licenses will be handed out, as it stands
+// now. Queries can't store anything, so the queue is only moved on by updates and the periodic jobs.
+#[ic_cdk::query]
+fn get_ebook_holds(book_id: u64) -> Result<Vec<EbookHold>, Error> {
+    match _get_digital_asset(&book_id) {
+        Some(asset) => Ok(project_holds(&asset, CanisterEnv.now()).0),
+        None => Ok(_get_holds(book_id)),
+    }
+}
+
+// Hook run from the periodic jobs: move on the hold queue of every e-book that has holds.
+pub(crate) fn run_pending(clock: &impl Clock) {
+    let mut book_ids: Vec<u64> =
+        EBOOK_HOLD_STORAGE.with(|s| s.borrow().iter().map(|(_, hold)| hold.book_id).collect());
+    book_ids.sort_unstable();
+    book_ids.dedup();
+    for asset in book_ids.iter().filter_map(_get_digital_asset) {
+        advance_holds(&asset, clock.now());
+    }
+}
+
+// The holds of a book as they stand at `now`, oldest first, without storing anything: holds a
+// license is free for have their claim deadline started, and those whose deadline has passed
+// are dropped so the queue moves on. Also returns the IDs of the dropped holds.
+fn project_holds(asset: &DigitalAsset, now: u64) -> (Vec<EbookHold>, Vec<u64>) {
+    let active = _get_active_loans(asset.book_id, now).len() as u32;
+    let free = asset.licenses.saturating_sub(active) as usize;
+    let mut holds = _get_holds(asset.book_id);
+    let mut dropped = Vec::new();
+    let mut index = 0;
+    while index < holds.len().min(free) {
+        match holds[index].ready_at {
+            None => {
+                holds[index].ready_at = Some(now);
+                index += 1;
+            }
+            Some(ready_at) if now >= ready_at.saturating_add(HOLD_CLAIM_PERIOD) => {
+                dropped.push(holds.remove(index).id);
+            }
+            Some(_) => index += 1,
+        }
+    }
+    (holds, dropped)
+}
+
+// Store the hold queue of a book as it stands at `now`. Returns the holds left, oldest first.
+fn advance_holds(asset: &DigitalAsset, now: u64) -> Vec<EbookHold> {
+    let (holds, dropped) = project_holds(asset, now);
+    for id in dropped {
+        remove_hold(id);
+        log::info(format!(
+            "Dropped e-book hold id={} on book id={}: it was not claimed in time.",
+            id, asset.book_id
+        ));
+    }
+    for hold in holds.iter().filter(|hold| hold.ready_at == Some(now)) {
+        EBOOK_HOLD_STORAGE.with(|s| s.borrow_mut().insert(hold.id, hold.clone()));
+    }
+    holds
+}
+
+// Internal function to fetch the unexpired loans of a book.
+fn _get_active_loans(book_id: u64, now: u64) -> Vec<EbookLoan> {
+    EBOOK_LOAN_STORAGE.with(|loans| {
+        loans
+            .borrow()
+            .iter()
+            .map(|(_, value)| value)
+            .filter(|loan| loan.book_id == book_id && loan.is_active(now))
+            .collect()
+    })
+}
+
+// Internal function to fetch the holds of a book, oldest first.
+pub(crate) fn _get_holds(book_id: u64) -> Vec<EbookHold> {
+    EBOOK_HOLD_STORAGE.with(|holds| {
+        holds
+            .borrow()
+            .iter()
+            .map(|(_, value)| value)
+            .filter(|hold| hold.book_id == book_id)
+            .collect()
+    })
+}
+
+// Helper function to drop a hold from the queue.
+pub(crate) fn remove_hold(id: u64) {
+    EBOOK_HOLD_STORAGE.with(|service| service.borrow_mut().remove(&id));
+}
+
+// Serve one chunk of a borrowed e-book after validating the access token and its expiry.
+#[ic_cdk::query]
+fn get_ebook_chunk(token: String, chunk: u32) -> Result<Vec<u8>, Error> {
+    require_feature(Feature::DigitalLending)?;
+    let loan = validate_token(&CanisterEnv, &token)?;
+    match _get_chunk(loan.book_id, chunk) {
+        Some(bytes) => Ok(bytes),
+        None => Err(Error::NotFound {
+            msg: format!("Chunk {} of book id={} not found.", chunk, loan.book_id),
+        }),
+    }
+}
+
+// Resolve a token to its loan, rejecting forged, unknown or expired tokens.
+pub(crate) fn validate_token(clock: &impl Clock, token: &str) -> Result<EbookLoan, Error> {
+    let invalid = || Error::Unauthorized {
+        msg: "Invalid e-book access token.".to_string(),
+    };
+
+    let (id, signature) = token.split_once('.').ok_or_else(invalid)?;
+    let id: u64 = id.parse().map_err(|_| invalid())?;
+    let signature = hex::decode(signature).map_err(|_| invalid())?;
+    let loan = EBOOK_LOAN_STORAGE
+        .with(|s| s.borrow().get(&id))
+        .ok_or_else(invalid)?;
+
+    let secret = EBOOK_TOKEN_SECRET.with(|s| *s.borrow().get());
+    if !constant_time_eq(&sign(&secret, &loan), &signature) {
+        return Err(invalid());
+    }
+
+    if !loan.is_active(clock.now()) {
+        return Err(Error::Unauthorized {
+            msg: format!("The digital loan id={} has expired.", loan.id),
+        });
+    }
+    Ok(loan)
+}
+
+// Return the token signing key, drawing it from the management canister's randomness on first use.
+async fn token_secret() -> Result<[u8; 32], Error> {
+    let secret = EBOOK_TOKEN_SECRET.with(|s| *s.borrow().get());
+    if secret != [0; 32] {
+        return Ok(secret);
+    }
+
+    let bytes = random_bytes().await?;
+    let mut secret = [0; 32];
+    secret.copy_from_slice(&bytes[..32]);
+
+    // Another call may have initialized the secret while this one was waiting.
+    EBOOK_TOKEN_SECRET.with(|s| {
+        let mut cell = s.borrow_mut();
+        if *cell.get() == [0; 32] {
+            cell.set(secret).expect("Cannot store the token secret");
+        }
+        Ok(*cell.get())
+    })
+}
+
+// HMAC-SHA256 over the loan fields that the token grants access to.
+fn sign(secret: &[u8; 32], loan: &EbookLoan) -> [u8; 32] {
+    let message = format!("{}.{}.{}", loan.id, loan.book_id, loan.expires_at);
+
+    let mut inner_pad = [0x36u8; 64];
+    let mut outer_pad = [0x5cu8; 64];
+    for (i, byte) in secret.iter().enumerate() {
+        inner_pad[i] ^= byte;
+        outer_pad[i] ^= byte;
+    }
+
+    let inner = Sha256::new()
+        .chain_update(inner_pad)
+        .chain_update(message.as_bytes())
+        .finalize();
+    Sha256::new()
+        .chain_update(outer_pad)
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+// Compare two byte strings without short-circuiting on the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// `env`'s caller, refusing the anonymous principal: everyone who isn't signed in shares it, so
+// a loan or hold it took would be open to all of them.
+fn signed_in_caller(env: &impl CallerProvider) -> Result<Principal, Error> {
+    let caller = env.caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Sign in to borrow e-books or place holds.".to_string(),
+        });
+    }
+    Ok(caller)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digital_asset::DigitalFormat;
+    use crate::env::TestEnv;
+    use crate::DIGITAL_ASSET_STORAGE;
+    use std::collections::HashMap;
+
+    // 2023-11-14, an arbitrary day to start the clock on.
+    const START: u64 = 1_700_000_000_000_000_000;
+
+    const SECRET: [u8; 32] = [7; 32];
+
+    // Store a finished e-book for book 1 with `licenses` licenses, and the token signing key.
+    fn ebook(licenses: u32) -> DigitalAsset {
+        let asset = DigitalAsset {
+            book_id: 1,
+            format: DigitalFormat::Epub,
+            size: 1,
+            chunk_count: 1,
+            licenses,
+            sha256: Some("00".to_string()),
+            created_at: START,
+            updated_at: None,
+        };
+        DIGITAL_ASSET_STORAGE.with(|s| s.borrow_mut().insert(asset.book_id, asset.clone()));
+        EBOOK_TOKEN_SECRET.with(|s| s.borrow_mut().set(SECRET).unwrap());
+        asset
+    }
+
+    #[test]
+    fn token_is_accepted_until_the_loan_expires() {
+        let env = TestEnv::new(START, 1);
+        let access = _borrow_ebook(&env, &SECRET, ebook(1)).unwrap();
+        assert_eq!(access.loan.expires_at, START + EBOOK_LOAN_DURATION);
+
+        env.advance(EBOOK_LOAN_DURATION - 1);
+        assert!(validate_token(&env, &access.token).is_ok());
+        env.advance(1);
+        assert!(matches!(
+            validate_token(&env, &access.token),
+            Err(Error::Unauthorized { .. })
+        ));
+    }
+
+    #[test]
+    fn anonymous_caller_cannot_borrow_or_hold() {
+        let env = TestEnv {
+            now: std::cell::Cell::new(START),
+            caller: Principal::anonymous(),
+        };
+        assert!(matches!(
+            _borrow_ebook(&env, &SECRET, ebook(1)),
+            Err(Error::Unauthorized { .. })
+        ));
+        assert!(matches!(
+            _place_ebook_hold(&env, 1),
+            Err(Error::Unauthorized { .. })
+        ));
+    }
+
+    #[test]
+    fn forged_token_is_rejected() {
+        let env = TestEnv::new(START, 1);
+        let access = _borrow_ebook(&env, &SECRET, ebook(1)).unwrap();
+        let forged = format!("{}.{}", access.loan.id, hex::encode([0u8; 32]));
+        assert!(validate_token(&env, &forged).is_err());
+    }
+
+    #[test]
+    fn expired_loan_frees_its_license() {
+        let env = TestEnv::new(START, 1);
+        let asset = ebook(1);
+        _borrow_ebook(&env, &SECRET, asset.clone()).unwrap();
+        let other = env.as_caller(2);
+        assert!(_borrow_ebook(&other, &SECRET, asset.clone()).is_err());
+
+        other.advance(EBOOK_LOAN_DURATION);
+        assert!(_borrow_ebook(&other, &SECRET, asset).is_ok());
+    }
+
+    #[test]
+    fn only_the_holder_can_cancel_a_hold() {
+        let hold = EbookHold {
+            id: 1,
+            book_id: 1,
+            holder: TestEnv::new(START, 1).caller,
+            created_at: START,
+            ready_at: None,
+        };
+        let mut holds = HashMap::from([(hold.id, hold)]);
+
+        let other = TestEnv::new(START, 2);
+        assert!(matches!(
+            _cancel_ebook_hold(&other, &mut holds, 1),
+            Err(Error::Unauthorized { .. })
+        ));
+        let holder = TestEnv::new(START, 1);
+        assert!(_cancel_ebook_hold(&holder, &mut holds, 1).is_ok());
+        assert!(holds.is_empty());
+        assert!(matches!(
+            _cancel_ebook_hold(&holder, &mut holds, 1),
+            Err(Error::NotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn unclaimed_hold_is_dropped_after_the_claim_period() {
+        let env = TestEnv::new(START, 1);
+        let asset = ebook(1);
+        _borrow_ebook(&env, &SECRET, asset.clone()).unwrap();
+        let holder = env.as_caller(2);
+        let hold = _place_ebook_hold(&holder, 1).unwrap();
+
+        // The license comes free when the loan expires. Reading the queue doesn't start the
+        // claim deadline; moving it on does.
+        holder.advance(EBOOK_LOAN_DURATION);
+        let (holds, _) = project_holds(&asset, holder.now());
+        assert_eq!(holds[0].ready_at, Some(holder.now()));
+        assert_eq!(_get_holds(1)[0].ready_at, None);
+        let holds = advance_holds(&asset, holder.now());
+        assert_eq!(holds[0].ready_at, Some(START + EBOOK_LOAN_DURATION));
+
+        holder.advance(HOLD_CLAIM_PERIOD - 1);
+        assert_eq!(advance_holds(&asset, holder.now()).len(), 1);
+        holder.advance(1);
+        assert!(advance_holds(&asset, holder.now()).is_empty());
+        assert!(EBOOK_HOLD_STORAGE
+            .with(|s| s.borrow().get(&hold.id))
+            .is_none());
+    }
+}
@@ -0,0 +1,97 @@
+use candid::Principal;
+use ic_cdk::api::management_canister::main::raw_rand;
+
+use crate::Error;
+
+// Source of the current time, in nanoseconds since the Unix epoch.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+// Source of the principal that made the current call.
+pub trait CallerProvider {
+    fn caller(&self) -> Principal;
+}
+
+// The environment of a running canister, backed by the system API.
+pub struct CanisterEnv;
+
+impl Clock for CanisterEnv {
+    #[cfg(not(test))]
+    fn now(&self) -> u64 {
+        ic_cdk::api::time()
+    }
+
+    // Native unit tests have no system API. Code that reads the time through CanisterEnv, e.g.
+    // for log timestamps, sees the epoch; tests that depend on time pass a TestEnv instead.
+    #[cfg(test)]
+    fn now(&self) -> u64 {
+        0
+    }
+}
+
+impl CallerProvider for CanisterEnv {
+    #[cfg(not(test))]
+    fn caller(&self) -> Principal {
+        ic_cdk::caller()
+    }
+
+    // Likewise, native unit tests see the anonymous principal as the caller.
+    #[cfg(test)]
+    fn caller(&self) -> Principal {
+        Principal::anonymous()
+    }
+}
+
+// Fetch 32 random bytes from the management canister, reporting a failed call as Unavailable
+// so the caller can retry.
+pub(crate) async fn random_bytes() -> Result<Vec<u8>, Error> {
+    match raw_rand().await {
+        Ok((bytes,)) => Ok(bytes),
+        Err((code, msg)) => Err(Error::Unavailable {
+            msg: format!("Cannot obtain randomness ({:?}): {}", code, msg),
+        }),
+    }
+}
+
+// A stand-in environment for native unit tests, with a clock that only moves when told to.
+#[cfg(test)]
+pub(crate) struct TestEnv {
+    pub now: std::cell::Cell<u64>,
+    pub caller: Principal,
+}
+
+#[cfg(test)]
+impl TestEnv {
+    // An environment at `now` whose calls come from the principal with the one-byte ID `caller`.
+    pub fn new(now: u64, caller: u8) -> Self {
+        TestEnv {
+            now: std::cell::Cell::new(now),
+            caller: Principal::from_slice(&[caller]),
+        }
+    }
+
+    // The same moment, seen from another caller.
+    pub fn as_caller(&self, caller: u8) -> Self {
+        TestEnv::new(self.now(), caller)
+    }
+
+    // Move the clock forward by `nanos`.
+    pub fn advance(&self, nanos: u64) {
+        self.now.set(self.now.get() + nanos);
+    }
+}
+
+#[cfg(test)]
+impl Clock for TestEnv {
+    fn now(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+#[cfg(test)]
+impl CallerProvider for TestEnv {
+    fn caller(&self) -> Principal {
+        self.caller
+    }
+}
@@ -0,0 +1,106 @@
+use candid::Principal;
+use ic_cdk::api::call::call_with_payment128;
+
+use crate::Error;
+
+// The IC exchange rate canister (XRC) on the NNS subnet.
+const XRC_CANISTER_ID: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai";
+
+// Cycles the XRC charges per rate request; whatever isn't used is refunded.
+const XRC_REQUEST_CYCLES: u128 = 1_000_000_000;
+
+// Whether an asset is a token or a national currency, as the XRC classifies them.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub enum AssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct Asset {
+    pub symbol: String,
+    pub class: AssetClass,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct GetExchangeRateRequest {
+    base_asset: Asset,
+    quote_asset: Asset,
+    timestamp: Option<u64>,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct ExchangeRateMetadata {
+    decimals: u32,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct ExchangeRate {
+    timestamp: u64,
+    rate: u64,
+    metadata: ExchangeRateMetadata,
+}
+
+#[derive(candid::CandidType, Deserialize, Debug)]
+enum ExchangeRateError {
+    AnonymousPrincipalNotAllowed,
+    Pending,
+    CryptoBaseAssetNotFound,
+    CryptoQuoteAssetNotFound,
+    StablecoinRateNotFound,
+    StablecoinRateTooFewRates,
+    StablecoinRateZeroRate,
+    ForexInvalidTimestamp,
+    ForexBaseAssetNotFound,
+    ForexQuoteAssetNotFound,
+    ForexAssetsNotFound,
+    RateLimited,
+    NotEnoughCycles,
+    FailedToAcceptCycles,
+    InconsistentRatesReceived,
+    Other { code: u32, description: String },
+}
+
+#[derive(candid::CandidType, Deserialize)]
+enum GetExchangeRateResult {
+    Ok(ExchangeRate),
+    Err(ExchangeRateError),
+}
+
+// How many units of the quote asset one unit of the base asset is worth, as `rate` scaled by
+// 10^`decimals`, observed at `timestamp` (seconds).
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct Rate {
+    pub rate: u64,
+    pub decimals: u32,
+    pub timestamp: u64,
+}
+
+// Ask the XRC for the current price of `base` in `quote`, reporting a failed call or a rate
+// the XRC couldn't determine as Unavailable so the caller can retry.
+pub(crate) async fn fetch_rate(base: Asset, quote: Asset) -> Result<Rate, Error> {
+    let xrc = Principal::from_text(XRC_CANISTER_ID).expect("Invalid XRC canister ID");
+    let request = GetExchangeRateRequest {
+        base_asset: base,
+        quote_asset: quote,
+        timestamp: None,
+    };
+    let result: Result<(GetExchangeRateResult,), _> =
+        call_with_payment128(xrc, "get_exchange_rate", (request,), XRC_REQUEST_CYCLES).await;
+    match result {
+        Ok((GetExchangeRateResult::Ok(rate),)) => Ok(Rate {
+            rate: rate.rate,
+            decimals: rate.metadata.decimals,
+            timestamp: rate.timestamp,
+        }),
+        Ok((GetExchangeRateResult::Err(err),)) => Err(Error::Unavailable {
+            msg: format!("The exchange rate canister returned {:?}.", err),
+        }),
+        Err((code, msg)) => Err(Error::Unavailable {
+            msg: format!(
+                "Cannot reach the exchange rate canister ({:?}): {}",
+                code, msg
+            ),
+        }),
+    }
+}
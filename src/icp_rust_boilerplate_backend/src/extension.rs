@@ -0,0 +1,200 @@
+use candid::Principal;
+
+use crate::auth::require_admin;
+use crate::crud;
+use crate::date::DAY;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::id_counter::{next_id, Entity};
+use crate::loan::{_transition_loan, Loan, LoanStatus};
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::student::caller_student;
+use crate::{Error, EXTENSION_REQUEST_STORAGE};
+
+// Longest extension a student may ask for, in days.
+pub const MAX_EXTENSION_DAYS: u32 = 14;
+
+// Longest reason or note accepted, in bytes.
+const MAX_TEXT_LENGTH: usize = 500;
+
+// Where an extension request is in its lifecycle.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum ExtensionStatus {
+    Pending,
+    Approved,
+    Denied,
+}
+
+// Define the ExtensionRequest struct to represent a student asking to keep a book longer.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct ExtensionRequest {
+    pub id: u64,
+    pub loan_id: u64,
+    pub student_id: u64,
+    pub days: u32,
+    pub reason: String,
+    pub status: ExtensionStatus,
+    // The librarian's explanation, shown to the student with the decision.
+    pub note: Option<String>,
+    pub created_at: u64,
+    pub decided_by: Option<Principal>,
+    pub decided_at: Option<u64>,
+}
+
+// Store ExtensionRequest as Candid, bounded to 2048 bytes to fit the reason and note.
+candid_storable!(ExtensionRequest, max_size = 2048);
+
+// Ask for more time on one of the caller's loans.
+#[ic_cdk::update]
+fn request_extension(loan_id: u64, days: u32, reason: String) -> Result<ExtensionRequest, Error> {
+    let _perf = perf::measure("request_extension");
+    _request_extension(&CanisterEnv, loan_id, days, reason)
+}
+
+// Internal function to file an extension request for `env`'s caller.
+pub(crate) fn _request_extension(
+    env: &(impl Clock + CallerProvider),
+    loan_id: u64,
+    days: u32,
+    reason: String,
+) -> Result<ExtensionRequest, Error> {
+    let student = caller_student(env)?;
+    let loan = crud::find::<Loan>(loan_id)?;
+    if loan.student_id != student.id {
+        return Err(Error::Unauthorized {
+            msg: format!("The loan id={} belongs to another student.", loan_id),
+        });
+    }
+    if !matches!(loan.status(), LoanStatus::Active | LoanStatus::Overdue) {
+        return Err(Error::InvalidInput {
+            msg: format!("The loan id={} is not in progress.", loan_id),
+        });
+    }
+    if days == 0 || days > MAX_EXTENSION_DAYS {
+        return Err(Error::InvalidInput {
+            msg: format!("An extension must be 1 to {} days.", MAX_EXTENSION_DAYS),
+        });
+    }
+    if reason.trim().is_empty() || reason.len() > MAX_TEXT_LENGTH {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A reason of 1 to {} characters is required.",
+                MAX_TEXT_LENGTH
+            ),
+        });
+    }
+    if !_get_requests(|r| r.loan_id == loan_id && r.status == ExtensionStatus::Pending).is_empty() {
+        return Err(Error::InvalidInput {
+            msg: format!("The loan id={} already has a pending request.", loan_id),
+        });
+    }
+
+    let request = ExtensionRequest {
+        id: next_id(Entity::ExtensionRequest)?,
+        loan_id,
+        student_id: student.id,
+        days,
+        reason,
+        status: ExtensionStatus::Pending,
+        note: None,
+        created_at: env.now(),
+        decided_by: None,
+        decided_at: None,
+    };
+    do_insert(&request);
+    Ok(request)
+}
+
+// Retrieve the caller's extension requests, including the decisions made on them.
+#[ic_cdk::query]
+fn get_my_extension_requests() -> Result<Vec<ExtensionRequest>, Error> {
+    let student = caller_student(&CanisterEnv)?;
+    Ok(_get_requests(|r| r.student_id == student.id))
+}
+
+// Retrieve the queue of requests waiting for a librarian, oldest first.
+#[ic_cdk::query]
+fn get_pending_extension_requests() -> Result<Vec<ExtensionRequest>, Error> {
+    require_admin()?;
+    Ok(_get_requests(|r| r.status == ExtensionStatus::Pending))
+}
+
+// Approve a request, pushing the loan's due date out by the days asked for.
+#[ic_cdk::update]
+fn approve_extension(id: u64, note: Option<String>) -> Result<ExtensionRequest, Error> {
+    let _perf = perf::measure("approve_extension");
+    require_admin()?;
+    _decide_extension(&CanisterEnv, id, ExtensionStatus::Approved, note)
+}
+
+// Deny a request, explaining why to the student.
+#[ic_cdk::update]
+fn deny_extension(id: u64, note: String) -> Result<ExtensionRequest, Error> {
+    let _perf = perf::measure("deny_extension");
+    require_admin()?;
+    _decide_extension(&CanisterEnv, id, ExtensionStatus::Denied, Some(note))
+}
+
+// Internal function to record a decision by `env`'s caller, applying an approval to the loan.
+pub(crate) fn _decide_extension(
+    env: &(impl Clock + CallerProvider),
+    id: u64,
+    decision: ExtensionStatus,
+    note: Option<String>,
+) -> Result<ExtensionRequest, Error> {
+    let mut request = EXTENSION_REQUEST_STORAGE
+        .with(|service| service.borrow().get(&id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("An extension request with id={} not found.", id),
+        })?;
+    if request.status != ExtensionStatus::Pending {
+        return Err(Error::InvalidInput {
+            msg: format!("The extension request id={} was already decided.", id),
+        });
+    }
+    if note
+        .as_ref()
+        .is_some_and(|note| note.len() > MAX_TEXT_LENGTH)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!("A note can be at most {} characters.", MAX_TEXT_LENGTH),
+        });
+    }
+
+    if decision == ExtensionStatus::Approved {
+        let now = env.now();
+        let loan = crud::update(request.loan_id, |loan: &mut Loan| {
+            let due = loan.due_date.unwrap_or(loan.loan_date);
+            loan.due_date = Some(due.saturating_add(request.days as u64 * DAY));
+            loan.updated_at = Some(now);
+        })?;
+        if loan.status() == LoanStatus::Overdue && loan.due_date.is_some_and(|due| due >= now) {
+            _transition_loan(env, loan.id, LoanStatus::Active)?;
+        }
+    }
+
+    request.status = decision;
+    request.note = note;
+    request.decided_by = Some(env.caller());
+    request.decided_at = Some(env.now());
+    do_insert(&request);
+    Ok(request)
+}
+
+// Internal function to fetch the extension requests matching `filter`, oldest first.
+fn _get_requests(filter: impl Fn(&ExtensionRequest) -> bool) -> Vec<ExtensionRequest> {
+    EXTENSION_REQUEST_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, request)| request)
+            .filter(|request| filter(request))
+            .collect()
+    })
+}
+
+// Helper function to store an extension request.
+fn do_insert(request: &ExtensionRequest) {
+    EXTENSION_REQUEST_STORAGE
+        .with(|service| service.borrow_mut().insert(request.id, request.clone()));
+}
@@ -0,0 +1,220 @@
+use candid::{Encode, Principal};
+use ic_cdk::api::management_canister::main::{
+    create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+    InstallCodeArgument,
+};
+use sha2::{Digest, Sha256};
+
+use crate::auth::{require_admin, InitArgs};
+use crate::digital_asset::{EbookChunk, CHUNK_SIZE};
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::id_counter::{next_id, Entity};
+use crate::log;
+use crate::perf;
+use crate::proposal::{_propose_admin_action, AdminAction, Proposal};
+use crate::storable::candid_storable;
+use crate::{Error, SCHOOL_STORAGE, SCHOOL_WASM_CHUNKS};
+
+// Largest library wasm the factory accepts, the most install_code takes in one message.
+pub const MAX_WASM_SIZE: u64 = 2 * 1024 * 1024;
+
+// Fewest cycles a new school canister may be created with: the creation fee plus a reserve
+// to run on.
+pub const MIN_SCHOOL_CYCLES: u64 = 500_000_000_000;
+
+// Longest school name accepted.
+const MAX_SCHOOL_NAME_LENGTH: usize = 100;
+
+// Define the School struct to record a library canister created by this factory.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct School {
+    pub id: u64,
+    pub name: String,
+    pub canister_id: Principal,
+    // The school's first library administrator.
+    pub admin: Principal,
+    // Hex-encoded SHA-256 of the wasm installed.
+    pub wasm_sha256: String,
+    pub created_by: Principal,
+    pub created_at: u64,
+    // None when the canister was created but installing the wasm failed.
+    pub installed_at: Option<u64>,
+}
+
+// Store School as Candid, bounded to 1024 bytes.
+candid_storable!(School, max_size = 1024);
+
+// Retrieve every school canister created by this factory.
+#[ic_cdk::query]
+fn list_schools() -> Result<Vec<School>, Error> {
+    require_admin()?;
+    Ok(SCHOOL_STORAGE.with(|s| s.borrow().iter().map(|(_, school)| school).collect()))
+}
+
+// Store one CHUNK_SIZE piece of the library wasm that new schools are installed with.
+#[ic_cdk::update]
+fn upload_school_wasm_chunk(index: u32, data: Vec<u8>) -> Result<(), Error> {
+    let _perf = perf::measure("upload_school_wasm_chunk");
+    require_admin()?;
+    if data.is_empty() || data.len() as u64 > CHUNK_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!("A chunk must be between 1 and {} bytes.", CHUNK_SIZE),
+        });
+    }
+    if index as u64 * CHUNK_SIZE + data.len() as u64 > MAX_WASM_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!("The wasm cannot be larger than {} bytes.", MAX_WASM_SIZE),
+        });
+    }
+    SCHOOL_WASM_CHUNKS.with(|s| s.borrow_mut().insert(index, EbookChunk(data)));
+    Ok(())
+}
+
+// Propose discarding the uploaded library wasm, e.g. before uploading a new release. It is
+// discarded once another administrator approves.
+#[ic_cdk::update]
+fn clear_school_wasm() -> Result<Proposal, Error> {
+    let _perf = perf::measure("clear_school_wasm");
+    require_admin()?;
+    _propose_admin_action(&CanisterEnv, AdminAction::ClearSchoolWasm)
+}
+
+// Discard the uploaded library wasm. Schools can't be created without it, so this runs only
+// through an approved proposal.
+pub(crate) fn clear_wasm() {
+    SCHOOL_WASM_CHUNKS.with(|s| {
+        let mut chunks = s.borrow_mut();
+        let indexes: Vec<u32> = chunks.iter().map(|(index, _)| index).collect();
+        for index in indexes {
+            chunks.remove(&index);
+        }
+    });
+    log::info("Discarded the uploaded library wasm.".to_string());
+}
+
+// Create a library canister for a new school, funded with `cycles`, and install the uploaded
+// wasm with `admin` as its first administrator. This canister stays its controller, so each
+// school's data is isolated in its own canister but can still be upgraded from here.
+#[ic_cdk::update]
+async fn create_school(name: String, admin: Principal, cycles: u64) -> Result<School, Error> {
+    let _perf = perf::measure("create_school");
+    require_admin()?;
+    let name = name.trim().to_string();
+    if name.is_empty() || name.len() > MAX_SCHOOL_NAME_LENGTH {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A school name must be 1 to {} characters.",
+                MAX_SCHOOL_NAME_LENGTH
+            ),
+        });
+    }
+    if admin == Principal::anonymous() {
+        return Err(Error::InvalidInput {
+            msg: "The anonymous principal cannot administer a school.".to_string(),
+        });
+    }
+    if cycles < MIN_SCHOOL_CYCLES || cycles as u128 > ic_cdk::api::canister_balance128() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A school needs at least {} cycles, and no more than this canister holds.",
+                MIN_SCHOOL_CYCLES
+            ),
+        });
+    }
+    let wasm = school_wasm()?;
+    let wasm_sha256 = hex::encode(Sha256::digest(&wasm));
+    let init_args = Encode!(&Some(InitArgs {
+        admins: vec![admin]
+    }))
+    .expect("Candid encoding of InitArgs cannot fail");
+
+    let settings = CanisterSettings {
+        controllers: Some(vec![ic_cdk::id()]),
+        compute_allocation: None,
+        memory_allocation: None,
+        freezing_threshold: None,
+    };
+    let created = create_canister(
+        CreateCanisterArgument {
+            settings: Some(settings),
+        },
+        cycles as u128,
+    )
+    .await;
+    let canister_id = match created {
+        Ok((record,)) => record.canister_id,
+        Err((code, msg)) => {
+            return Err(Error::Unavailable {
+                msg: format!("Cannot create the school canister ({:?}): {}", code, msg),
+            })
+        }
+    };
+
+    let mut school = School {
+        id: next_id(Entity::School)?,
+        name,
+        canister_id,
+        admin,
+        wasm_sha256,
+        created_by: CanisterEnv.caller(),
+        created_at: CanisterEnv.now(),
+        installed_at: None,
+    };
+    let installed = install_code(InstallCodeArgument {
+        mode: CanisterInstallMode::Install,
+        canister_id,
+        wasm_module: wasm,
+        arg: init_args,
+    })
+    .await;
+    // Record the canister even when installing fails, so its cycles aren't lost track of.
+    if let Ok(()) = installed {
+        school.installed_at = Some(CanisterEnv.now());
+    }
+    SCHOOL_STORAGE.with(|s| s.borrow_mut().insert(school.id, school.clone()));
+
+    match installed {
+        Ok(()) => {
+            log::info(format!(
+                "Created school id={} ({}) in canister {}.",
+                school.id, school.name, canister_id
+            ));
+            Ok(school)
+        }
+        Err((code, msg)) => {
+            log::warn(format!(
+                "Created canister {} for school id={} but could not install it.",
+                canister_id, school.id
+            ));
+            Err(Error::Unavailable {
+                msg: format!(
+                    "Created canister {} but cannot install the library ({:?}): {}",
+                    canister_id, code, msg
+                ),
+            })
+        }
+    }
+}
+
+// Helper function to put the uploaded wasm chunks back together, failing when none were
+// uploaded or one is missing.
+fn school_wasm() -> Result<Vec<u8>, Error> {
+    SCHOOL_WASM_CHUNKS.with(|s| {
+        let chunks = s.borrow();
+        let mut wasm = Vec::new();
+        for (expected, (index, chunk)) in chunks.iter().enumerate() {
+            if index as usize != expected {
+                return Err(Error::InvalidInput {
+                    msg: format!("Wasm chunk {} has not been uploaded.", expected),
+                });
+            }
+            wasm.extend_from_slice(&chunk.0);
+        }
+        if wasm.is_empty() {
+            return Err(Error::InvalidInput {
+                msg: "Upload the library wasm before creating a school.".to_string(),
+            });
+        }
+        Ok(wasm)
+    })
+}
@@ -0,0 +1,139 @@
+use candid::Principal;
+
+use crate::auth::require_admin;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::log;
+use crate::perf;
+use crate::proposal::{_propose_admin_action, AdminAction, Proposal};
+use crate::storable::candid_storable;
+use crate::{Error, FEATURE_FLAGS};
+
+// A subsystem a deployment can switch on or off at runtime.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Feature {
+    // Billing students, e.g. the replacement cost of a lost book.
+    Fines,
+    // Lending e-books and queueing holds for them.
+    DigitalLending,
+}
+
+impl Feature {
+    // Every feature, in the order they are listed.
+    pub const ALL: [Feature; 2] = [Feature::Fines, Feature::DigitalLending];
+
+    // Whether a deployment that never set the flag has the feature. Features that predate
+    // flags default to on, so upgrading changes nothing.
+    fn default_enabled(self) -> bool {
+        match self {
+            Feature::Fines | Feature::DigitalLending => true,
+        }
+    }
+
+    // How the feature is named in messages.
+    fn label(self) -> &'static str {
+        match self {
+            Feature::Fines => "Fines",
+            Feature::DigitalLending => "Digital lending",
+        }
+    }
+}
+
+// A feature's current setting. `changed_by` and `changed_at` are None while it has its default.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct FeatureFlag {
+    pub feature: Feature,
+    pub enabled: bool,
+    pub changed_by: Option<Principal>,
+    pub changed_at: Option<u64>,
+}
+
+// The flags an administrator has set; features without one have their default.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct FeatureFlags {
+    pub flags: Vec<FeatureFlag>,
+}
+
+// Store FeatureFlags as Candid.
+candid_storable!(FeatureFlags);
+
+// Retrieve the setting of every feature, so clients can hide what is switched off.
+#[ic_cdk::query]
+fn get_feature_flags() -> Vec<FeatureFlag> {
+    Feature::ALL.into_iter().map(flag).collect()
+}
+
+// Propose switching a feature on or off; it switches once another administrator approves.
+#[ic_cdk::update]
+fn set_feature_flag(feature: Feature, enabled: bool) -> Result<Proposal, Error> {
+    let _perf = perf::measure("set_feature_flag");
+    require_admin()?;
+    _propose_admin_action(
+        &CanisterEnv,
+        AdminAction::SetFeatureFlag { feature, enabled },
+    )
+}
+
+// Internal function to set a flag on behalf of `env`'s caller. Outside tests this runs only
+// through an approved proposal.
+pub(crate) fn _set_feature_flag(
+    env: &(impl Clock + CallerProvider),
+    feature: Feature,
+    enabled: bool,
+) -> FeatureFlag {
+    let changed = FeatureFlag {
+        feature,
+        enabled,
+        changed_by: Some(env.caller()),
+        changed_at: Some(env.now()),
+    };
+    FEATURE_FLAGS
+        .with(|c| {
+            let mut state = c.borrow().get().clone();
+            state.flags.retain(|flag| flag.feature != feature);
+            state.flags.push(changed.clone());
+            c.borrow_mut().set(state)
+        })
+        .expect("Cannot store the feature flags");
+    log::warn(format!(
+        "{} turned {} {}.",
+        env.caller(),
+        feature.label().to_lowercase(),
+        if enabled { "on" } else { "off" }
+    ));
+    changed
+}
+
+// Whether a feature is switched on.
+pub(crate) fn is_enabled(feature: Feature) -> bool {
+    flag(feature).enabled
+}
+
+// Reject calls into a feature that is switched off.
+pub(crate) fn require_feature(feature: Feature) -> Result<(), Error> {
+    if is_enabled(feature) {
+        Ok(())
+    } else {
+        Err(Error::Unavailable {
+            msg: format!("{} is turned off in this library.", feature.label()),
+        })
+    }
+}
+
+// A feature's current setting, falling back to its default.
+fn flag(feature: Feature) -> FeatureFlag {
+    FEATURE_FLAGS
+        .with(|c| {
+            c.borrow()
+                .get()
+                .flags
+                .iter()
+                .find(|flag| flag.feature == feature)
+                .cloned()
+        })
+        .unwrap_or(FeatureFlag {
+            feature,
+            enabled: feature.default_enabled(),
+            changed_by: None,
+            changed_at: None,
+        })
+}
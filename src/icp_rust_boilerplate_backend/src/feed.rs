@@ -0,0 +1,90 @@
+use crate::book::{_get_new_arrivals, Book};
+use crate::date::format_timestamp;
+use crate::env::Clock;
+
+// How far back, in days, the new arrivals feed reaches.
+const FEED_DAYS: u32 = 30;
+
+// Most entries the new arrivals feed carries.
+const FEED_ENTRIES: u32 = 50;
+
+// Path the new arrivals feed is served at.
+pub const NEW_ARRIVALS_PATH: &str = "/feeds/new-arrivals.xml";
+
+// Build the Atom document listing the books added in the last FEED_DAYS days, newest first.
+// `host` is the host the feed was requested from, used for its self link.
+pub(crate) fn new_arrivals_atom(clock: &impl Clock, host: Option<&str>) -> String {
+    let books = _get_new_arrivals(clock, FEED_DAYS, false, None, Some(FEED_ENTRIES))
+        .map(|page| page.items)
+        .unwrap_or_default();
+    let canister = ic_cdk::id();
+    // The feed changes when a book is added or edited, so that is when it was last updated.
+    let updated = books
+        .iter()
+        .map(|book| book.updated_at.unwrap_or(book.created_at))
+        .max()
+        .unwrap_or(0);
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str(&format!(
+        "  <id>urn:library:{}:new-arrivals</id>\n",
+        canister
+    ));
+    xml.push_str("  <title>New arrivals</title>\n");
+    xml.push_str(&format!(
+        "  <updated>{}</updated>\n",
+        format_timestamp(updated)
+    ));
+    if let Some(host) = host {
+        xml.push_str(&format!(
+            "  <link rel=\"self\" href=\"https://{}{}\"/>\n",
+            escape(host),
+            NEW_ARRIVALS_PATH
+        ));
+    }
+    for book in &books {
+        xml.push_str(&entry(book, &canister.to_string()));
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+// One book as an Atom entry.
+fn entry(book: &Book, canister: &str) -> String {
+    let mut xml = String::from("  <entry>\n");
+    xml.push_str(&format!(
+        "    <id>urn:library:{}:book:{}</id>\n",
+        canister, book.id
+    ));
+    xml.push_str(&format!("    <title>{}</title>\n", escape(&book.title)));
+    xml.push_str(&format!(
+        "    <author><name>{}</name></author>\n",
+        escape(&book.author)
+    ));
+    xml.push_str(&format!(
+        "    <published>{}</published>\n",
+        format_timestamp(book.created_at)
+    ));
+    xml.push_str(&format!(
+        "    <updated>{}</updated>\n",
+        format_timestamp(book.updated_at.unwrap_or(book.created_at))
+    ));
+    if let Some(cover_url) = &book.cover_url {
+        xml.push_str(&format!(
+            "    <link rel=\"enclosure\" href=\"{}\"/>\n",
+            escape(cover_url)
+        ));
+    }
+    xml.push_str("  </entry>\n");
+    xml
+}
+
+// Escape text for XML content and attribute values.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -0,0 +1,257 @@
+use candid::Principal;
+
+use crate::auth::require_admin;
+use crate::book::Book;
+use crate::charge::{_get_charges, Charge};
+use crate::crud;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::loan::{Loan, LoanStatus};
+use crate::log;
+use crate::perf;
+use crate::storable::{candid_storable, StorablePrincipal};
+use crate::student::Student;
+use crate::{Error, GUARDIAN_STORAGE};
+
+// Longest guardian name accepted.
+const MAX_GUARDIAN_NAME_LENGTH: usize = 100;
+
+// Most students one principal may be the guardian of.
+const MAX_DEPENDENTS: usize = 10;
+
+// Define the Guardianship struct to represent a parent or guardian who may follow a student's
+// borrowing. It grants read access only.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct Guardianship {
+    pub guardian: Principal,
+    pub student_id: u64,
+    pub name: String,
+    pub linked_by: Principal,
+    pub linked_at: u64,
+}
+
+// Store Guardianship as Candid, bounded to 512 bytes.
+candid_storable!(Guardianship, max_size = 512);
+
+// A book a dependent currently has out.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub struct DependentLoan {
+    pub loan_id: u64,
+    pub book_id: u64,
+    pub title: String,
+    pub status: LoanStatus,
+    pub due_date: Option<u64>,
+}
+
+// What a guardian sees of one dependent: their open loans and unpaid charges.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub struct DependentOverview {
+    pub student_id: u64,
+    pub name: String,
+    pub loans: Vec<DependentLoan>,
+    pub unpaid_charges: Vec<Charge>,
+    // Sum of the unpaid charges, in minor units of the library's currency.
+    pub amount_owed: u64,
+}
+
+// Link a guardian's principal to a student.
+#[ic_cdk::update]
+fn link_guardian(
+    student_id: u64,
+    guardian: Principal,
+    name: String,
+) -> Result<Guardianship, Error> {
+    let _perf = perf::measure("link_guardian");
+    require_admin()?;
+    _link_guardian(&CanisterEnv, student_id, guardian, name)
+}
+
+// Internal function to link a guardian on behalf of `env`'s caller.
+pub(crate) fn _link_guardian(
+    env: &(impl Clock + CallerProvider),
+    student_id: u64,
+    guardian: Principal,
+    name: String,
+) -> Result<Guardianship, Error> {
+    crud::find::<Student>(student_id)?;
+    if guardian == Principal::anonymous() {
+        return Err(Error::InvalidInput {
+            msg: "A guardian cannot be the anonymous principal.".to_string(),
+        });
+    }
+    if name.trim().is_empty() || name.len() > MAX_GUARDIAN_NAME_LENGTH {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A guardian's name must be 1 to {} characters.",
+                MAX_GUARDIAN_NAME_LENGTH
+            ),
+        });
+    }
+    let dependents = dependents_of(&guardian);
+    if !dependents.contains(&student_id) && dependents.len() >= MAX_DEPENDENTS {
+        return Err(Error::InvalidInput {
+            msg: format!("A guardian can follow at most {} students.", MAX_DEPENDENTS),
+        });
+    }
+
+    let guardianship = Guardianship {
+        guardian,
+        student_id,
+        name,
+        linked_by: env.caller(),
+        linked_at: env.now(),
+    };
+    GUARDIAN_STORAGE.with(|s| {
+        s.borrow_mut().insert(
+            (StorablePrincipal(guardian), student_id),
+            guardianship.clone(),
+        )
+    });
+    log::info(format!(
+        "{} linked guardian {} to student id={}.",
+        guardianship.linked_by, guardian, student_id
+    ));
+    Ok(guardianship)
+}
+
+// Unlink a guardian from a student.
+#[ic_cdk::update]
+fn unlink_guardian(student_id: u64, guardian: Principal) -> Result<Guardianship, Error> {
+    let _perf = perf::measure("unlink_guardian");
+    require_admin()?;
+    GUARDIAN_STORAGE
+        .with(|s| {
+            s.borrow_mut()
+                .remove(&(StorablePrincipal(guardian), student_id))
+        })
+        .ok_or_else(|| Error::NotFound {
+            msg: format!(
+                "{} is not a guardian of student id={}.",
+                guardian, student_id
+            ),
+        })
+}
+
+// Retrieve a student's guardians.
+#[ic_cdk::query]
+fn get_student_guardians(student_id: u64) -> Result<Vec<Guardianship>, Error> {
+    require_admin()?;
+    Ok(guardianships_where(|g| g.student_id == student_id))
+}
+
+// Retrieve the open loans, due dates and unpaid charges of every student the caller is a
+// guardian of.
+#[ic_cdk::query]
+fn get_dependents_overview() -> Result<Vec<DependentOverview>, Error> {
+    _get_dependents_overview(&CanisterEnv)
+}
+
+// Internal function to build the overview for `env`'s caller.
+pub(crate) fn _get_dependents_overview(
+    env: &impl CallerProvider,
+) -> Result<Vec<DependentOverview>, Error> {
+    let caller = env.caller();
+    let dependents = dependents_of(&caller);
+    if dependents.is_empty() {
+        return Err(Error::Unauthorized {
+            msg: format!("{} is not a guardian of any student.", caller),
+        });
+    }
+    Ok(dependents
+        .into_iter()
+        .filter_map(|id| crud::get::<Student>(&id))
+        .map(overview)
+        .collect())
+}
+
+// Helper function to drop the guardians of a deleted student.
+pub(crate) fn remove_student(student_id: u64) {
+    GUARDIAN_STORAGE.with(|s| {
+        let mut guardians = s.borrow_mut();
+        let keys: Vec<_> = guardians
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|&(_, id)| id == student_id)
+            .collect();
+        for key in keys {
+            guardians.remove(&key);
+        }
+    });
+}
+
+// Helper function to give the student `to` the guardians of the student `from`, returning how
+// many links moved. A guardian already linked to `to` keeps that link. A guardian's number of
+// dependents can only drop, so MAX_DEPENDENTS still holds.
+pub(crate) fn move_student(from: u64, to: u64) -> u64 {
+    let moved = guardianships_where(|g| g.student_id == from);
+    GUARDIAN_STORAGE.with(|s| {
+        let mut guardians = s.borrow_mut();
+        for guardianship in &moved {
+            let guardian = StorablePrincipal(guardianship.guardian);
+            guardians.remove(&(guardian, from));
+            if !guardians.contains_key(&(guardian, to)) {
+                let moved = Guardianship {
+                    student_id: to,
+                    ..guardianship.clone()
+                };
+                guardians.insert((guardian, to), moved);
+            }
+        }
+    });
+    moved.len() as u64
+}
+
+// A student's open loans and unpaid charges.
+fn overview(student: Student) -> DependentOverview {
+    let loans = crud::all::<Loan>()
+        .into_iter()
+        .filter(|loan| loan.student_id == student.id && loan.status().is_open())
+        .map(|loan| DependentLoan {
+            loan_id: loan.id,
+            book_id: loan.book_id,
+            title: crud::get::<Book>(&loan.book_id).map_or_else(String::new, |book| book.title),
+            status: loan.status(),
+            due_date: loan.due_date,
+        })
+        .collect();
+    let unpaid_charges: Vec<Charge> = _get_charges(student.id)
+        .into_iter()
+        .filter(|charge| charge.paid_at.is_none())
+        .collect();
+    DependentOverview {
+        student_id: student.id,
+        name: student.name,
+        loans,
+        amount_owed: unpaid_charges.iter().map(|charge| charge.amount).sum(),
+        unpaid_charges,
+    }
+}
+
+// IDs of the students `guardian` is linked to.
+fn dependents_of(guardian: &Principal) -> Vec<u64> {
+    let key = StorablePrincipal(*guardian);
+    GUARDIAN_STORAGE.with(|s| {
+        s.borrow()
+            .range((key, 0)..=(key, u64::MAX))
+            .map(|((_, student_id), _)| student_id)
+            .collect()
+    })
+}
+
+// Whether a principal is a guardian of a student.
+pub(crate) fn is_guardian(guardian: &Principal, student_id: u64) -> bool {
+    GUARDIAN_STORAGE.with(|s| {
+        s.borrow()
+            .contains_key(&(StorablePrincipal(*guardian), student_id))
+    })
+}
+
+// The guardianships matching `filter`.
+fn guardianships_where(filter: impl Fn(&Guardianship) -> bool) -> Vec<Guardianship> {
+    GUARDIAN_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, guardianship)| guardianship)
+            .filter(|guardianship| filter(guardianship))
+            .collect()
+    })
+}
@@ -0,0 +1,326 @@
+use sha2::{Digest, Sha256};
+
+use crate::api_key::{authorize_api_key, ApiScope};
+use crate::calendar::{due_dates_ics, student_for_token};
+use crate::catalog;
+use crate::circulation::{_checkin_by_barcode, _mark_loan_lost};
+use crate::cover::{_get_cover, _get_cover_chunk};
+use crate::env::CanisterEnv;
+use crate::feed::{new_arrivals_atom, NEW_ARRIVALS_PATH};
+use crate::loan::_mark_overdue_loans;
+use crate::perf;
+use crate::Error;
+
+// Every route `http_request` serves, as (method, path, description), for the API schema.
+pub const ROUTES: &[(&str, &str, &str)] = &[
+    (
+        "GET",
+        "/covers/{book_id}",
+        "The book's cached cover image, while the catalog is public or for a ReadOnly API key; \
+         honours If-None-Match.",
+    ),
+    (
+        "GET",
+        "/students/{token}/due-dates.ics",
+        "iCalendar feed of the due dates of the student holding the calendar token.",
+    ),
+    (
+        "GET",
+        NEW_ARRIVALS_PATH,
+        "Atom feed of the books added in the last 30 days, while the catalog is public or for a \
+         ReadOnly API key; honours If-None-Match.",
+    ),
+    (
+        "POST",
+        "/circulation/checkin",
+        "Check in the book whose barcode is the request body, for a Circulation API key. Answers \
+         with the check-in summary as JSON.",
+    ),
+    (
+        "POST",
+        "/circulation/loans/{loan_id}/lost",
+        "Mark a loan's book lost, for a Circulation API key. Answers with the lost-book summary \
+         as JSON.",
+    ),
+    (
+        "POST",
+        "/admin/loans/mark-overdue",
+        "Mark every loan past its due date as overdue, for an Admin API key. Answers with the \
+         loans changed as JSON.",
+    ),
+];
+
+// A request for an HTTP route, as the HTTP gateway hands it to the canister.
+#[derive(candid::CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+// The canister's answer to an HTTP request. `upgrade` asks the gateway to send the request
+// again as an update call, to http_request_update.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub upgrade: Option<bool>,
+}
+
+// Serve the canister's HTTP routes, listed in ROUTES. Responses carry an ETag; a request whose
+// If-None-Match already names it gets a bodiless 304. Visitors are anonymous, so the catalog
+// routes answer only while the catalog is public, or to backend systems sending an API key as
+// "Authorization: Bearer <key>". POST requests change state, so they are upgraded to update
+// calls.
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method == "POST" {
+        return HttpResponse {
+            status_code: 200,
+            headers: Vec::new(),
+            body: Vec::new(),
+            upgrade: Some(true),
+        };
+    }
+    if request.method != "GET" && request.method != "HEAD" {
+        return text_response(405, "Method not allowed.");
+    }
+    let path = request.url.split(['?', '#']).next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["covers", _] | ["feeds", _] if !catalog::is_public() => match bearer_key(&request) {
+            None => text_response(403, "The catalog is not public."),
+            Some(key) if authorize_api_key(key, ApiScope::ReadOnly).is_err() => {
+                text_response(401, "Invalid API key.")
+            }
+            Some(_) => serve_catalog(&request, &segments),
+        },
+        ["covers", _] | ["feeds", _] => serve_catalog(&request, &segments),
+        ["students", token, "due-dates.ics"] => serve_due_dates(token),
+        _ => not_found(),
+    }
+}
+
+// Serve the POST routes listed in ROUTES. They are for backend systems: each needs an API key
+// with the route's scope, where an Admin key also grants Circulation.
+#[ic_cdk::update]
+fn http_request_update(request: HttpRequest) -> HttpResponse {
+    let _perf = perf::measure("http_request_update");
+    if request.method != "POST" {
+        return text_response(405, "Method not allowed.");
+    }
+    let path = request.url.split(['?', '#']).next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let scope = match segments.as_slice() {
+        ["circulation", "checkin"] | ["circulation", "loans", _, "lost"] => ApiScope::Circulation,
+        ["admin", "loans", "mark-overdue"] => ApiScope::Admin,
+        _ => return not_found(),
+    };
+    let Some(key) = bearer_key(&request) else {
+        return text_response(401, "An API key is required.");
+    };
+    if let Err(error) = authorize_api_key(key, scope) {
+        return json_response(401, &error);
+    }
+
+    match segments.as_slice() {
+        ["circulation", "checkin"] => {
+            let barcode = String::from_utf8_lossy(&request.body);
+            json_result(_checkin_by_barcode(&CanisterEnv, &barcode))
+        }
+        ["circulation", "loans", id, "lost"] => id.parse().map_or_else(
+            |_| not_found(),
+            |id| json_result(_mark_loan_lost(&CanisterEnv, id)),
+        ),
+        ["admin", "loans", "mark-overdue"] => json_result(_mark_overdue_loans(&CanisterEnv)),
+        _ => not_found(),
+    }
+}
+
+// Helper function to respond on the catalog routes, once the request may see the catalog.
+fn serve_catalog(request: &HttpRequest, segments: &[&str]) -> HttpResponse {
+    match segments {
+        ["covers", id] => id
+            .parse()
+            .map_or_else(|_| not_found(), |id| serve_cover(request, id)),
+        ["feeds", "new-arrivals.xml"] => serve_new_arrivals(request),
+        _ => not_found(),
+    }
+}
+
+// The API key in a request's "Authorization: Bearer" header, if it has one.
+fn bearer_key(request: &HttpRequest) -> Option<&str> {
+    request
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| value.trim().strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+// Helper function to respond with a book's cached cover image.
+fn serve_cover(request: &HttpRequest, book_id: u64) -> HttpResponse {
+    let Some(cover) = _get_cover(&book_id) else {
+        return not_found();
+    };
+    let etag = format!("\"{}\"", cover.sha256);
+    if is_fresh(request, &etag) {
+        return not_modified(etag);
+    }
+
+    let mut body = Vec::with_capacity(cover.size as usize);
+    for index in 0..cover.chunk_count {
+        match _get_cover_chunk(book_id, index) {
+            Some(chunk) => body.extend_from_slice(&chunk),
+            None => return text_response(500, "The cover is incomplete."),
+        }
+    }
+    image_response(cover.content_type, etag, body)
+}
+
+// Helper function to respond with the due-date calendar of the student holding `token`. Unknown
+// and revoked tokens get a 404, so feeds can't be told apart from missing ones.
+fn serve_due_dates(token: &str) -> HttpResponse {
+    let Some(student) = student_for_token(token) else {
+        return not_found();
+    };
+    let body = due_dates_ics(&student, ic_cdk::api::time()).into_bytes();
+    HttpResponse {
+        status_code: 200,
+        headers: vec![
+            (
+                "Content-Type".to_string(),
+                "text/calendar; charset=utf-8".to_string(),
+            ),
+            ("Content-Length".to_string(), body.len().to_string()),
+            ("Cache-Control".to_string(), "private, no-store".to_string()),
+        ],
+        body,
+        upgrade: None,
+    }
+}
+
+// Helper function to respond with the Atom feed of new arrivals, tagged with its hash.
+fn serve_new_arrivals(request: &HttpRequest) -> HttpResponse {
+    let host = request
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.as_str());
+    let body = new_arrivals_atom(&CanisterEnv, host).into_bytes();
+    let etag = format!("\"{}\"", hex::encode(Sha256::digest(&body)));
+    if is_fresh(request, &etag) {
+        return not_modified(etag);
+    }
+    HttpResponse {
+        status_code: 200,
+        headers: vec![
+            (
+                "Content-Type".to_string(),
+                "application/atom+xml; charset=utf-8".to_string(),
+            ),
+            ("Content-Length".to_string(), body.len().to_string()),
+            ("ETag".to_string(), etag),
+            ("Cache-Control".to_string(), "no-cache".to_string()),
+        ],
+        body,
+        upgrade: None,
+    }
+}
+
+// Helper function to build a response carrying an image tagged `etag`.
+fn image_response(content_type: String, etag: String, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code: 200,
+        headers: vec![
+            ("Content-Type".to_string(), content_type),
+            ("Content-Length".to_string(), body.len().to_string()),
+            ("ETag".to_string(), etag),
+            ("Cache-Control".to_string(), "no-cache".to_string()),
+        ],
+        body,
+        upgrade: None,
+    }
+}
+
+// Whether the client's If-None-Match already names `etag`, so its cached copy is current. Weak
+// tags match too, as If-None-Match uses weak comparison.
+fn is_fresh(request: &HttpRequest, etag: &str) -> bool {
+    request
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("if-none-match"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+// Helper function to tell the client its cached copy tagged `etag` is still current.
+fn not_modified(etag: String) -> HttpResponse {
+    HttpResponse {
+        status_code: 304,
+        headers: vec![
+            ("ETag".to_string(), etag),
+            ("Cache-Control".to_string(), "no-cache".to_string()),
+        ],
+        body: Vec::new(),
+        upgrade: None,
+    }
+}
+
+// Helper function to respond with the outcome of a write as JSON: the result when it succeeded,
+// otherwise the error with a status matching its kind.
+fn json_result<T: serde::Serialize>(result: Result<T, Error>) -> HttpResponse {
+    match result {
+        Ok(value) => json_response(200, &value),
+        Err(error) => {
+            let status_code = match error {
+                Error::NotFound { .. }
+                | Error::StudentNotFound { .. }
+                | Error::BookNotFound { .. } => 404,
+                Error::Unauthorized { .. } => 403,
+                Error::Unavailable { .. } => 503,
+                _ => 400,
+            };
+            json_response(status_code, &error)
+        }
+    }
+}
+
+// Helper function to build a JSON response.
+fn json_response<T: serde::Serialize>(status_code: u16, value: &T) -> HttpResponse {
+    let body = serde_json::to_vec(value).expect("Library records always serialize");
+    HttpResponse {
+        status_code,
+        headers: vec![
+            (
+                "Content-Type".to_string(),
+                "application/json; charset=utf-8".to_string(),
+            ),
+            ("Content-Length".to_string(), body.len().to_string()),
+            ("Cache-Control".to_string(), "no-store".to_string()),
+        ],
+        body,
+        upgrade: None,
+    }
+}
+
+// Helper function to respond that nothing is served at the requested path.
+fn not_found() -> HttpResponse {
+    text_response(404, "Not found.")
+}
+
+// Helper function to build a plain-text response.
+fn text_response(status_code: u16, message: &str) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![(
+            "Content-Type".to_string(),
+            "text/plain; charset=utf-8".to_string(),
+        )],
+        body: message.as_bytes().to_vec(),
+        upgrade: None,
+    }
+}
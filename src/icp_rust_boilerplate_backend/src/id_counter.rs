@@ -0,0 +1,44 @@
+use crate::{Error, ID_COUNTERS};
+
+// Entities with their own ID sequence; the discriminant is the counter's key in storage.
+#[derive(Clone, Copy)]
+pub enum Entity {
+    Student = 0,
+    Book = 1,
+    Loan = 2,
+    EbookLoan = 3,
+    EbookHold = 4,
+    ApiKey = 5,
+    Proposal = 6,
+    ExtensionRequest = 7,
+    Charge = 8,
+    School = 9,
+    Consent = 10,
+    Class = 11,
+    ReadingList = 12,
+    ClassSet = 13,
+    Notification = 14,
+    OutboxItem = 15,
+    Snapshot = 16,
+}
+
+// Allocate the next ID for an entity. Sequences start at 1.
+pub(crate) fn next_id(entity: Entity) -> Result<u64, Error> {
+    ID_COUNTERS.with(|counters| {
+        let mut counters = counters.borrow_mut();
+        let id = counters
+            .get(&(entity as u8))
+            .unwrap_or(0)
+            .checked_add(1)
+            .ok_or_else(|| Error::CounterUnavailable {
+                msg: "The ID sequence is exhausted.".to_string(),
+            })?;
+        counters.insert(entity as u8, id);
+        Ok(id)
+    })
+}
+
+// Set the last ID issued for an entity, so the next allocation continues after it.
+pub(crate) fn seed(entity: Entity, last_id: u64) {
+    ID_COUNTERS.with(|counters| counters.borrow_mut().insert(entity as u8, last_id));
+}
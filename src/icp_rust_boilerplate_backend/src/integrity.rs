@@ -0,0 +1,386 @@
+use candid::Principal;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap};
+use std::cell::RefCell;
+use std::ops::Bound;
+use std::thread::LocalKey;
+
+use crate::auth::require_admin;
+use crate::book::{title_key, Book};
+use crate::charge::Charge;
+use crate::crud::{self, Record, RecordKind};
+use crate::ebook_loan::EbookHold;
+use crate::extension::ExtensionRequest;
+use crate::loan::Loan;
+use crate::pagination::budget_exhausted;
+use crate::storable::{IndexString, StorablePrincipal};
+use crate::student::Student;
+use crate::{
+    Error, Memory, BARCODE_INDEX, BOOK_CREATED_INDEX, CHARGE_STORAGE, COVER_STORAGE,
+    DIGITAL_ASSET_STORAGE, EBOOK_HOLD_STORAGE, EXTENSION_REQUEST_STORAGE, LOAN_CREATED_INDEX,
+    LOAN_STORAGE, STUDENT_CREATED_INDEX, STUDENT_PRINCIPAL_INDEX, TITLE_INDEX,
+};
+
+// The checks a scan runs, in order.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityCheck {
+    Loans,
+    EbookHolds,
+    ExtensionRequests,
+    Charges,
+    DigitalAssets,
+    Covers,
+    StudentCreatedIndex,
+    BookCreatedIndex,
+    LoanCreatedIndex,
+    BarcodeIndex,
+    TitleIndex,
+    StudentPrincipalIndex,
+}
+
+const CHECKS: &[IntegrityCheck] = &[
+    IntegrityCheck::Loans,
+    IntegrityCheck::EbookHolds,
+    IntegrityCheck::ExtensionRequests,
+    IntegrityCheck::Charges,
+    IntegrityCheck::DigitalAssets,
+    IntegrityCheck::Covers,
+    IntegrityCheck::StudentCreatedIndex,
+    IntegrityCheck::BookCreatedIndex,
+    IntegrityCheck::LoanCreatedIndex,
+    IntegrityCheck::BarcodeIndex,
+    IntegrityCheck::TitleIndex,
+    IntegrityCheck::StudentPrincipalIndex,
+];
+
+// A reference that points at a record which no longer exists. Closed loans and paid charges
+// are history and may outlive the records they name, so only open loans and unpaid charges are
+// reported.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub enum Violation {
+    OrphanLoan {
+        loan_id: u64,
+        student_missing: bool,
+        book_missing: bool,
+    },
+    OrphanEbookHold {
+        hold_id: u64,
+        book_id: u64,
+    },
+    OrphanExtensionRequest {
+        request_id: u64,
+        loan_id: u64,
+    },
+    OrphanCharge {
+        charge_id: u64,
+        student_id: u64,
+    },
+    OrphanDigitalAsset {
+        book_id: u64,
+    },
+    OrphanCover {
+        book_id: u64,
+    },
+    StaleCreatedIndexEntry {
+        kind: RecordKind,
+        created_at: u64,
+        id: u64,
+    },
+    StaleBarcodeEntry {
+        barcode: String,
+        book_id: u64,
+    },
+    StaleTitleEntry {
+        title_key: String,
+        book_id: u64,
+    },
+    StalePrincipalEntry {
+        principal: Principal,
+        student_id: u64,
+    },
+}
+
+// Where a scan stopped: the check it was running and the encoded key of the last entry it
+// inspected there.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct IntegrityCursor {
+    pub check: IntegrityCheck,
+    pub after: Option<Vec<u8>>,
+}
+
+// Violations found by one scan batch. Pass `next` back to continue; it is None once every
+// check has finished.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub struct IntegrityReport {
+    pub violations: Vec<Violation>,
+    pub next: Option<IntegrityCursor>,
+}
+
+// Scan for references to missing records, as many as fit in one query. Start without a cursor
+// and keep passing the returned one back until it is None.
+#[ic_cdk::query]
+fn run_integrity_check(resume: Option<IntegrityCursor>) -> Result<IntegrityReport, Error> {
+    require_admin()?;
+    let resume = resume.unwrap_or(IntegrityCursor {
+        check: CHECKS[0],
+        after: None,
+    });
+    let first = CHECKS
+        .iter()
+        .position(|&check| check == resume.check)
+        .unwrap_or(0);
+
+    let mut violations = Vec::new();
+    let mut after = resume.after;
+    for &check in &CHECKS[first..] {
+        if let Some(stopped_at) = run_check(check, after.take(), &mut violations) {
+            return Ok(IntegrityReport {
+                violations,
+                next: Some(IntegrityCursor {
+                    check,
+                    after: Some(stopped_at),
+                }),
+            });
+        }
+    }
+    Ok(IntegrityReport {
+        violations,
+        next: None,
+    })
+}
+
+// Run one check from just after the `after` key, returning the key it stopped at when the
+// instruction budget ran out.
+fn run_check(
+    check: IntegrityCheck,
+    after: Option<Vec<u8>>,
+    found: &mut Vec<Violation>,
+) -> Option<Vec<u8>> {
+    let after = after.as_deref();
+    match check {
+        IntegrityCheck::Loans => scan(&LOAN_STORAGE, after, found, |&id, loan| {
+            loan_violation(id, loan)
+        }),
+        IntegrityCheck::EbookHolds => scan(&EBOOK_HOLD_STORAGE, after, found, |&id, hold| {
+            hold_violation(id, hold)
+        }),
+        IntegrityCheck::ExtensionRequests => {
+            scan(&EXTENSION_REQUEST_STORAGE, after, found, |&id, request| {
+                request_violation(id, request)
+            })
+        }
+        IntegrityCheck::Charges => scan(&CHARGE_STORAGE, after, found, |&id, charge| {
+            charge_violation(id, charge)
+        }),
+        IntegrityCheck::DigitalAssets => scan(&DIGITAL_ASSET_STORAGE, after, found, |&id, _| {
+            (!crud::exists::<Book>(id)).then_some(Violation::OrphanDigitalAsset { book_id: id })
+        }),
+        IntegrityCheck::Covers => scan(&COVER_STORAGE, after, found, |&id, _| {
+            (!crud::exists::<Book>(id)).then_some(Violation::OrphanCover { book_id: id })
+        }),
+        IntegrityCheck::StudentCreatedIndex => scan(
+            &STUDENT_CREATED_INDEX,
+            after,
+            found,
+            |&(created_at, id), _| created_violation(RecordKind::Student, created_at, id),
+        ),
+        IntegrityCheck::BookCreatedIndex => {
+            scan(&BOOK_CREATED_INDEX, after, found, |&(created_at, id), _| {
+                created_violation(RecordKind::Book, created_at, id)
+            })
+        }
+        IntegrityCheck::LoanCreatedIndex => {
+            scan(&LOAN_CREATED_INDEX, after, found, |&(created_at, id), _| {
+                created_violation(RecordKind::Loan, created_at, id)
+            })
+        }
+        IntegrityCheck::BarcodeIndex => scan(&BARCODE_INDEX, after, found, |barcode, &id| {
+            barcode_violation(&barcode.0, id)
+        }),
+        IntegrityCheck::TitleIndex => scan(&TITLE_INDEX, after, found, |(key, id), _| {
+            title_violation(&key.0, *id)
+        }),
+        IntegrityCheck::StudentPrincipalIndex => {
+            scan(&STUDENT_PRINCIPAL_INDEX, after, found, |principal, &id| {
+                principal_violation(principal.0, id)
+            })
+        }
+    }
+}
+
+// Whether a reported violation still holds, judged against the current state. Repairs check
+// this first so that they never touch something that was fixed in the meantime.
+pub(crate) fn still_holds(violation: &Violation) -> bool {
+    match violation {
+        Violation::OrphanLoan { loan_id, .. } => {
+            crud::get::<Loan>(loan_id).is_some_and(|loan| loan_violation(*loan_id, &loan).is_some())
+        }
+        Violation::OrphanEbookHold { hold_id, .. } => EBOOK_HOLD_STORAGE
+            .with(|s| s.borrow().get(hold_id))
+            .is_some_and(|hold| hold_violation(*hold_id, &hold).is_some()),
+        Violation::OrphanExtensionRequest { request_id, .. } => EXTENSION_REQUEST_STORAGE
+            .with(|s| s.borrow().get(request_id))
+            .is_some_and(|request| request_violation(*request_id, &request).is_some()),
+        Violation::OrphanCharge { charge_id, .. } => CHARGE_STORAGE
+            .with(|s| s.borrow().get(charge_id))
+            .is_some_and(|charge| charge_violation(*charge_id, &charge).is_some()),
+        Violation::OrphanDigitalAsset { book_id } => {
+            DIGITAL_ASSET_STORAGE.with(|s| s.borrow().contains_key(book_id))
+                && !crud::exists::<Book>(*book_id)
+        }
+        Violation::OrphanCover { book_id } => {
+            COVER_STORAGE.with(|s| s.borrow().contains_key(book_id))
+                && !crud::exists::<Book>(*book_id)
+        }
+        Violation::StaleCreatedIndexEntry {
+            kind,
+            created_at,
+            id,
+        } => {
+            let index = match kind {
+                RecordKind::Student => &STUDENT_CREATED_INDEX,
+                RecordKind::Book => &BOOK_CREATED_INDEX,
+                RecordKind::Loan => &LOAN_CREATED_INDEX,
+            };
+            index.with(|index| index.borrow().contains_key(&(*created_at, *id)))
+                && created_violation(*kind, *created_at, *id).is_some()
+        }
+        Violation::StaleBarcodeEntry { barcode, book_id } => {
+            let key = IndexString(barcode.clone());
+            BARCODE_INDEX.with(|index| index.borrow().get(&key)) == Some(*book_id)
+                && barcode_violation(barcode, *book_id).is_some()
+        }
+        Violation::StaleTitleEntry { title_key, book_id } => {
+            let key = (IndexString(title_key.clone()), *book_id);
+            TITLE_INDEX.with(|index| index.borrow().contains_key(&key))
+                && title_violation(title_key, *book_id).is_some()
+        }
+        Violation::StalePrincipalEntry {
+            principal,
+            student_id,
+        } => {
+            let key = StorablePrincipal(*principal);
+            STUDENT_PRINCIPAL_INDEX.with(|index| index.borrow().get(&key)) == Some(*student_id)
+                && principal_violation(*principal, *student_id).is_some()
+        }
+    }
+}
+
+// An open loan whose student or book is gone.
+fn loan_violation(id: u64, loan: &Loan) -> Option<Violation> {
+    if !loan.status().is_open() {
+        return None;
+    }
+    let student_missing = !crud::exists::<Student>(loan.student_id);
+    let book_missing = !crud::exists::<Book>(loan.book_id);
+    (student_missing || book_missing).then_some(Violation::OrphanLoan {
+        loan_id: id,
+        student_missing,
+        book_missing,
+    })
+}
+
+// An e-book hold on a book that is gone.
+fn hold_violation(id: u64, hold: &EbookHold) -> Option<Violation> {
+    (!crud::exists::<Book>(hold.book_id)).then_some(Violation::OrphanEbookHold {
+        hold_id: id,
+        book_id: hold.book_id,
+    })
+}
+
+// An extension request for a loan that is gone.
+fn request_violation(id: u64, request: &ExtensionRequest) -> Option<Violation> {
+    (!crud::exists::<Loan>(request.loan_id)).then_some(Violation::OrphanExtensionRequest {
+        request_id: id,
+        loan_id: request.loan_id,
+    })
+}
+
+// An unpaid charge billed to a student who is gone.
+fn charge_violation(id: u64, charge: &Charge) -> Option<Violation> {
+    let orphan = charge.paid_at.is_none() && !crud::exists::<Student>(charge.student_id);
+    orphan.then_some(Violation::OrphanCharge {
+        charge_id: id,
+        student_id: charge.student_id,
+    })
+}
+
+// A creation-time index entry whose record is gone or was created at another time. Records
+// that exist but can't be read are left alone.
+fn created_violation(kind: RecordKind, created_at: u64, id: u64) -> Option<Violation> {
+    let stale = match kind {
+        RecordKind::Student => created_at_differs::<Student>(created_at, id),
+        RecordKind::Book => created_at_differs::<Book>(created_at, id),
+        RecordKind::Loan => created_at_differs::<Loan>(created_at, id),
+    };
+    stale.then_some(Violation::StaleCreatedIndexEntry {
+        kind,
+        created_at,
+        id,
+    })
+}
+
+// Whether record `id` is missing or was created at a time other than `created_at`.
+fn created_at_differs<R: Record>(created_at: u64, id: u64) -> bool {
+    match crud::find::<R>(id) {
+        Ok(record) => record.created_at() != created_at,
+        Err(Error::NotFound { .. }) => true,
+        Err(_) => false,
+    }
+}
+
+// A barcode index entry naming a book that no longer carries the barcode.
+fn barcode_violation(barcode: &str, id: u64) -> Option<Violation> {
+    let current =
+        crud::get::<Book>(&id).is_some_and(|book| book.barcode.as_deref() == Some(barcode));
+    (!current).then_some(Violation::StaleBarcodeEntry {
+        barcode: barcode.to_string(),
+        book_id: id,
+    })
+}
+
+// A title index entry naming a book that no longer has the title.
+fn title_violation(key: &str, id: u64) -> Option<Violation> {
+    let current = crud::get::<Book>(&id).is_some_and(|book| title_key(&book.title).0 == key);
+    (!current).then_some(Violation::StaleTitleEntry {
+        title_key: key.to_string(),
+        book_id: id,
+    })
+}
+
+// A principal index entry naming a student who is no longer linked to the principal.
+fn principal_violation(principal: Principal, id: u64) -> Option<Violation> {
+    let current =
+        crud::get::<Student>(&id).is_some_and(|student| student.principal == Some(principal));
+    (!current).then_some(Violation::StalePrincipalEntry {
+        principal,
+        student_id: id,
+    })
+}
+
+// Inspect a map's entries after the `after` key, collecting violations, until the end or the
+// instruction budget runs out. Returns the encoded key of the last entry inspected when it
+// stopped early.
+fn scan<K, V>(
+    storage: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+    after: Option<&[u8]>,
+    found: &mut Vec<Violation>,
+    inspect: impl Fn(&K, &V) -> Option<Violation>,
+) -> Option<Vec<u8>>
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    let start = match after {
+        Some(bytes) => Bound::Excluded(K::from_bytes(bytes.to_vec().into())),
+        None => Bound::Unbounded,
+    };
+    storage.with(|s| {
+        for (key, value) in s.borrow().range((start, Bound::Unbounded)) {
+            found.extend(inspect(&key, &value));
+            if budget_exhausted() {
+                return Some(key.to_bytes().into_owned());
+            }
+        }
+        None
+    })
+}
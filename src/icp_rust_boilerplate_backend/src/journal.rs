@@ -0,0 +1,227 @@
+use candid::{Encode, Principal};
+use sha2::{Digest, Sha256};
+
+use crate::auth::require_admin;
+use crate::crud;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::loan::{Loan, LoanStatus};
+use crate::pagination::{self, budget_exhausted, Page};
+use crate::storable::candid_storable;
+use crate::{Error, CIRCULATION_JOURNAL, JOURNAL_LOAN_INDEX};
+
+// A circulation desk action recorded in the journal.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CirculationAction {
+    Checkout,
+    // The due date of an open loan was moved later.
+    Renewal,
+    Return,
+    Lost,
+}
+
+// Define the JournalEntry struct to represent one circulation action. Entries are never changed
+// or removed, and each one's hash covers the hash of the entry before it, so editing or
+// dropping an entry breaks the chain from there on.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct JournalEntry {
+    pub seq: u64,
+    pub action: CirculationAction,
+    pub loan_id: u64,
+    pub student_id: u64,
+    pub book_id: u64,
+    // The loan's due date after the action.
+    pub due_date: Option<u64>,
+    pub recorded_by: Principal,
+    pub at: u64,
+    // Hex-encoded SHA-256 of the previous entry; empty on the first one.
+    pub prev_hash: String,
+    // Hex-encoded SHA-256 of this entry's other fields.
+    pub hash: String,
+}
+
+// Store JournalEntry as Candid, bounded to 1024 bytes.
+candid_storable!(JournalEntry, max_size = 1024);
+
+// Outcome of checking part of the hash chain. Call again from `next_cursor` until it is None.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub struct JournalVerification {
+    pub checked: u64,
+    // The first entry whose hash or link to the previous entry doesn't match.
+    pub broken_at: Option<u64>,
+    pub next_cursor: Option<u64>,
+}
+
+// A loan rebuilt from its journal entries alone, next to what its record says now.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub struct ReplayedLoan {
+    pub loan_id: u64,
+    pub status: LoanStatus,
+    pub due_date: Option<u64>,
+    pub entries: Vec<JournalEntry>,
+    // None when the loan record has been deleted.
+    pub record: Option<Loan>,
+    // Whether the record agrees with the replay. An overdue record agrees with an active
+    // replay, since loans going overdue isn't a desk action.
+    pub matches_record: bool,
+}
+
+// Retrieve the journal one page at a time, oldest entry first.
+#[ic_cdk::query]
+fn get_circulation_journal(
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Page<JournalEntry>, Error> {
+    require_admin()?;
+    Ok(pagination::paginate(&CIRCULATION_JOURNAL, cursor, limit))
+}
+
+// Check the hash chain from `cursor`, as far as the instruction budget allows.
+#[ic_cdk::query]
+fn verify_circulation_journal(cursor: Option<u64>) -> Result<JournalVerification, Error> {
+    require_admin()?;
+    let start = cursor.unwrap_or(0);
+    let mut prev_hash = match start.checked_sub(1) {
+        Some(before) => CIRCULATION_JOURNAL
+            .with(|s| s.borrow().get(&before))
+            .map(|entry| entry.hash)
+            .unwrap_or_default(),
+        None => String::new(),
+    };
+    let mut verification = JournalVerification {
+        checked: 0,
+        broken_at: None,
+        next_cursor: None,
+    };
+    CIRCULATION_JOURNAL.with(|s| {
+        for (seq, entry) in s.borrow().range(start..) {
+            if verification.checked > 0 && budget_exhausted() {
+                verification.next_cursor = Some(seq);
+                break;
+            }
+            verification.checked += 1;
+            if entry.prev_hash != prev_hash || entry.hash != hash(&entry) {
+                verification.broken_at = Some(seq);
+                break;
+            }
+            prev_hash = entry.hash;
+        }
+    });
+    Ok(verification)
+}
+
+// Rebuild a loan's state from its journal entries and compare it with the stored record.
+#[ic_cdk::query]
+fn replay_loan(loan_id: u64) -> Result<ReplayedLoan, Error> {
+    require_admin()?;
+    let entries: Vec<JournalEntry> = JOURNAL_LOAN_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((loan_id, 0)..=(loan_id, u64::MAX))
+            .filter_map(|((_, seq), _)| CIRCULATION_JOURNAL.with(|s| s.borrow().get(&seq)))
+            .collect()
+    });
+    if entries.is_empty() {
+        return Err(Error::NotFound {
+            msg: format!("The journal has no entries for loan id={}.", loan_id),
+        });
+    }
+    let (mut status, mut due_date) = (LoanStatus::Active, None);
+    for entry in &entries {
+        status = match entry.action {
+            CirculationAction::Checkout | CirculationAction::Renewal => LoanStatus::Active,
+            CirculationAction::Return => LoanStatus::Returned,
+            CirculationAction::Lost => LoanStatus::Lost,
+        };
+        due_date = entry.due_date;
+    }
+    let record = crud::get::<Loan>(&loan_id);
+    let matches_record = record.as_ref().is_some_and(|loan| {
+        let recorded = match loan.status() {
+            LoanStatus::Overdue => LoanStatus::Active,
+            other => other,
+        };
+        recorded == status && loan.due_date == due_date
+    });
+    Ok(ReplayedLoan {
+        loan_id,
+        status,
+        due_date,
+        entries,
+        record,
+        matches_record,
+    })
+}
+
+// Hook run on every loan write: journal the desk action the change amounts to, if any.
+pub(crate) fn loan_written(previous: Option<&Loan>, current: Option<&Loan>) {
+    let Some(loan) = current else {
+        return;
+    };
+    if let Some(action) = action(previous, loan) {
+        _append(&CanisterEnv, action, loan);
+    }
+}
+
+// Which desk action turned `previous` into `current`. Creating a loan that is already active
+// is a checkout; edits that don't change the status or push the due date back aren't actions.
+fn action(previous: Option<&Loan>, current: &Loan) -> Option<CirculationAction> {
+    use LoanStatus::*;
+    let before = previous.map(Loan::status);
+    match (before, current.status()) {
+        (None | Some(Requested), Active) => Some(CirculationAction::Checkout),
+        (Some(Active | Overdue), Returned) => Some(CirculationAction::Return),
+        (Some(Active | Overdue), Lost) => Some(CirculationAction::Lost),
+        (Some(Active | Overdue), Active | Overdue)
+            if current.due_date > previous.and_then(|loan| loan.due_date) =>
+        {
+            Some(CirculationAction::Renewal)
+        }
+        _ => None,
+    }
+}
+
+// Internal function to append an entry for `loan`, recorded by `env`'s caller at its time.
+pub(crate) fn _append(
+    env: &(impl Clock + CallerProvider),
+    action: CirculationAction,
+    loan: &Loan,
+) -> JournalEntry {
+    let (seq, prev_hash) = CIRCULATION_JOURNAL.with(|s| {
+        s.borrow()
+            .last_key_value()
+            .map_or((0, String::new()), |(seq, entry)| (seq + 1, entry.hash))
+    });
+    let mut entry = JournalEntry {
+        seq,
+        action,
+        loan_id: loan.id,
+        student_id: loan.student_id,
+        book_id: loan.book_id,
+        due_date: loan.due_date,
+        recorded_by: env.caller(),
+        at: env.now(),
+        prev_hash,
+        hash: String::new(),
+    };
+    entry.hash = hash(&entry);
+    CIRCULATION_JOURNAL.with(|s| s.borrow_mut().insert(seq, entry.clone()));
+    JOURNAL_LOAN_INDEX.with(|index| index.borrow_mut().insert((loan.id, seq), ()));
+    entry
+}
+
+// Hash every field of an entry except the hash itself.
+fn hash(entry: &JournalEntry) -> String {
+    let fields = Encode!(
+        &entry.seq,
+        &entry.action,
+        &entry.loan_id,
+        &entry.student_id,
+        &entry.book_id,
+        &entry.due_date,
+        &entry.recorded_by,
+        &entry.at,
+        &entry.prev_hash
+    )
+    .expect("Candid encoding of a journal entry cannot fail");
+    hex::encode(Sha256::digest(fields))
+}
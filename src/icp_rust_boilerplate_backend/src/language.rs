@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+
+use crate::book::Book;
+use crate::catalog::require_catalog_access;
+use crate::crud;
+use crate::Error;
+
+// The ISO 639-1 language codes, in order.
+const ISO_639_1: [&str; 183] = [
+    "aa", "ab", "ae", "af", "ak", "am", "an", "ar", "as", "av", "ay", "az", "ba", "be", "bg", "bi",
+    "bm", "bn", "bo", "br", "bs", "ca", "ce", "ch", "co", "cr", "cs", "cu", "cv", "cy", "da", "de",
+    "dv", "dz", "ee", "el", "en", "eo", "es", "et", "eu", "fa", "ff", "fi", "fj", "fo", "fr", "fy",
+    "ga", "gd", "gl", "gn", "gu", "gv", "ha", "he", "hi", "ho", "hr", "ht", "hu", "hy", "hz", "ia",
+    "id", "ie", "ig", "ii", "ik", "io", "is", "it", "iu", "ja", "jv", "ka", "kg", "ki", "kj", "kk",
+    "kl", "km", "kn", "ko", "kr", "ks", "ku", "kv", "kw", "ky", "la", "lb", "lg", "li", "ln", "lo",
+    "lt", "lu", "lv", "mg", "mh", "mi", "mk", "ml", "mn", "mr", "ms", "mt", "my", "na", "nb", "nd",
+    "ne", "ng", "nl", "nn", "no", "nr", "nv", "ny", "oc", "oj", "om", "or", "os", "pa", "pi", "pl",
+    "ps", "pt", "qu", "rm", "rn", "ro", "ru", "rw", "sa", "sc", "sd", "se", "sg", "si", "sk", "sl",
+    "sm", "sn", "so", "sq", "sr", "ss", "st", "su", "sv", "sw", "ta", "te", "tg", "th", "ti", "tk",
+    "tl", "tn", "to", "tr", "ts", "tt", "tw", "ty", "ug", "uk", "ur", "uz", "ve", "vi", "vo", "wa",
+    "wo", "xh", "yi", "yo", "za", "zh", "zu",
+];
+
+// How many books are written in one language; `language` is None for books without one.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub struct LanguageFacet {
+    pub language: Option<String>,
+    pub books: u64,
+}
+
+// Retrieve the books written in a language, given as its ISO 639-1 code.
+#[ic_cdk::query]
+fn get_books_by_language(language: String) -> Result<Vec<Book>, Error> {
+    require_catalog_access()?;
+    let language = check_language(&language)?;
+    Ok(crud::all::<Book>()
+        .into_iter()
+        .filter(|book| book.language.as_deref() == Some(language.as_str()))
+        .collect())
+}
+
+// Count the books in each language, ordered by language with books of unknown language first.
+#[ic_cdk::query]
+fn get_book_language_facets() -> Result<Vec<LanguageFacet>, Error> {
+    require_catalog_access()?;
+    let mut counts: BTreeMap<Option<String>, u64> = BTreeMap::new();
+    for book in crud::all::<Book>() {
+        *counts.entry(book.language).or_default() += 1;
+    }
+    Ok(counts
+        .into_iter()
+        .map(|(language, books)| LanguageFacet { language, books })
+        .collect())
+}
+
+// Check that `language` is an ISO 639-1 code, ignoring case and surrounding spaces, and return
+// it lower-cased.
+pub(crate) fn check_language(language: &str) -> Result<String, Error> {
+    let code = language.trim().to_lowercase();
+    if ISO_639_1.binary_search(&code.as_str()).is_err() {
+        return Err(Error::InvalidInput {
+            msg: format!("\"{}\" is not an ISO 639-1 language code.", language.trim()),
+        });
+    }
+    Ok(code)
+}
@@ -0,0 +1,322 @@
+use candid::{Int, Nat, Principal};
+use sha2::{Digest, Sha256};
+
+use crate::certification;
+use crate::charge::{Charge, ChargeKind, CHARGE_CURRENCY};
+use crate::storable::candid_storable;
+use crate::{BLOCK_STORAGE, CHARGE_STORAGE};
+
+// Block types the log writes; their fields are described at BLOCK_SCHEMA_URL.
+const CHARGE_BLOCK: &str = "libcharge";
+const PAYMENT_BLOCK: &str = "libpay";
+const BLOCK_SCHEMA_URL: &str =
+    "https://github.com/thisisbintang/icp-101-rust-boilerplate/blob/main/docs/ledger_blocks.md";
+
+// Most blocks a single icrc3_get_blocks call returns, over all requested ranges.
+const MAX_BLOCKS_PER_CALL: u64 = 100;
+
+// ICRC-3 generic value, the form every block is stored and served in.
+#[derive(candid::CandidType, Deserialize, Clone)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+// Store a block as Candid, bounded to 2048 bytes.
+candid_storable!(Value, max_size = 2048);
+
+#[derive(candid::CandidType, Deserialize)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Value,
+}
+
+candid::define_function!(pub GetBlocksCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(candid::CandidType, Deserialize)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: GetBlocksCallback,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    // Always empty: every block is kept in this canister.
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+pub struct GetArchivesArgs {
+    pub from: Option<Principal>,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+pub struct ArchiveInfo {
+    pub canister_id: Principal,
+    pub start: Nat,
+    pub end: Nat,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+pub struct DataCertificate {
+    pub certificate: Vec<u8>,
+    pub hash_tree: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+pub struct SupportedBlockType {
+    pub block_type: String,
+    pub url: String,
+}
+
+// Retrieve ranges of the financial block log, oldest block first. Block IDs start at 0.
+#[ic_cdk::query]
+fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    let log_length = BLOCK_STORAGE.with(|s| s.borrow().len());
+    let mut budget = MAX_BLOCKS_PER_CALL;
+    let mut blocks = Vec::new();
+    for range in args {
+        let (Ok(start), Ok(length)) = (u64::try_from(range.start.0), u64::try_from(range.length.0))
+        else {
+            continue;
+        };
+        let length = length.min(budget);
+        BLOCK_STORAGE.with(|s| {
+            for (id, block) in s.borrow().range(start..start.saturating_add(length)) {
+                blocks.push(BlockWithId {
+                    id: Nat::from(id),
+                    block,
+                });
+            }
+        });
+        budget -= length;
+    }
+    GetBlocksResult {
+        log_length: Nat::from(log_length),
+        blocks,
+        archived_blocks: Vec::new(),
+    }
+}
+
+// List the archive canisters holding older blocks; there are none.
+#[ic_cdk::query]
+fn icrc3_get_archives(_args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+    Vec::new()
+}
+
+// Certify the tip of the block log: the certificate signs a hash tree holding last_block_index
+// and last_block_hash, next to the state digest's root. None while the log is empty.
+#[ic_cdk::query]
+fn icrc3_get_tip_certificate() -> Option<DataCertificate> {
+    tip()?;
+    Some(DataCertificate {
+        certificate: ic_cdk::api::data_certificate()?,
+        hash_tree: certification::tree().to_cbor(),
+    })
+}
+
+// List the block types the log writes.
+#[ic_cdk::query]
+fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    [CHARGE_BLOCK, PAYMENT_BLOCK]
+        .into_iter()
+        .map(|block_type| SupportedBlockType {
+            block_type: block_type.to_string(),
+            url: BLOCK_SCHEMA_URL.to_string(),
+        })
+        .collect()
+}
+
+// Hook run when a student is charged: append a charge block.
+pub(crate) fn record_charge(charge: &Charge) {
+    let kind = match charge.kind {
+        ChargeKind::Replacement => "replacement",
+    };
+    let mut tx = vec![
+        ("charge_id", nat(charge.id)),
+        ("student_id", nat(charge.student_id)),
+        ("kind", Value::Text(kind.to_string())),
+        ("amt", nat(charge.amount)),
+        ("currency", Value::Text(CHARGE_CURRENCY.to_string())),
+    ];
+    if let Some(loan_id) = charge.loan_id {
+        tx.push(("loan_id", nat(loan_id)));
+    }
+    append(CHARGE_BLOCK, charge.created_at, tx);
+}
+
+// Hook run when a charge is paid: append a payment block.
+pub(crate) fn record_payment(charge: &Charge) {
+    let (Some(paid_at), Some(payment)) = (charge.paid_at, &charge.payment) else {
+        return;
+    };
+    let tx = vec![
+        ("charge_id", nat(charge.id)),
+        ("student_id", nat(charge.student_id)),
+        ("amt", nat(charge.amount)),
+        ("currency", Value::Text(CHARGE_CURRENCY.to_string())),
+        ("token", Value::Text(payment.token.clone())),
+        ("token_amt", nat(payment.token_amount)),
+        ("rate", nat(payment.rate.rate)),
+        ("rate_decimals", nat(payment.rate.decimals as u64)),
+        ("by", Value::Blob(payment.recorded_by.as_slice().to_vec())),
+    ];
+    append(PAYMENT_BLOCK, paid_at, tx);
+}
+
+// Helper function to write blocks for the charges and payments recorded before the log
+// existed, in the order they happened.
+pub(crate) fn backfill() {
+    let charges: Vec<Charge> = CHARGE_STORAGE.with(|s| s.borrow().iter().map(|(_, c)| c).collect());
+    let mut events: Vec<(u64, u64, bool)> = Vec::new();
+    for charge in &charges {
+        events.push((charge.created_at, charge.id, false));
+        if let Some(paid_at) = charge.paid_at {
+            events.push((paid_at, charge.id, true));
+        }
+    }
+    events.sort();
+    for (_, id, paid) in events {
+        let Some(charge) = charges.iter().find(|charge| charge.id == id) else {
+            continue;
+        };
+        if paid {
+            record_payment(charge);
+        } else {
+            record_charge(charge);
+        }
+    }
+}
+
+// ID and hash of the last block, None while the log is empty.
+pub(crate) fn tip() -> Option<(u64, [u8; 32])> {
+    BLOCK_STORAGE.with(|s| {
+        s.borrow()
+            .last_key_value()
+            .map(|(id, block)| (id, hash(&block)))
+    })
+}
+
+// Append a block of type `btype` made at `ts`, chained to the hash of the previous block, and
+// certify the new tip.
+fn append(btype: &str, ts: u64, tx: Vec<(&str, Value)>) {
+    let (id, phash) = tip().map_or((0, None), |(id, hash)| (id + 1, Some(hash)));
+    let mut fields = Vec::new();
+    if let Some(phash) = phash {
+        fields.push(("phash".to_string(), Value::Blob(phash.to_vec())));
+    }
+    fields.push(("btype".to_string(), Value::Text(btype.to_string())));
+    fields.push(("ts".to_string(), nat(ts)));
+    let tx = tx
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect();
+    fields.push(("tx".to_string(), Value::Map(tx)));
+    BLOCK_STORAGE.with(|s| s.borrow_mut().insert(id, Value::Map(fields)));
+    certification::publish();
+}
+
+// Wrap a number as an ICRC-3 Nat value.
+fn nat(n: u64) -> Value {
+    Value::Nat(Nat::from(n))
+}
+
+// ICRC-3 representation-independent hash of a value.
+fn hash(value: &Value) -> [u8; 32] {
+    match value {
+        Value::Blob(bytes) => Sha256::digest(bytes).into(),
+        Value::Text(text) => Sha256::digest(text.as_bytes()).into(),
+        Value::Nat(n) => {
+            let mut leb = Vec::new();
+            n.encode(&mut leb).expect("Encoding a Nat cannot fail");
+            Sha256::digest(&leb).into()
+        }
+        Value::Int(i) => {
+            let mut sleb = Vec::new();
+            i.encode(&mut sleb).expect("Encoding an Int cannot fail");
+            Sha256::digest(&sleb).into()
+        }
+        Value::Array(items) => {
+            let mut hasher = Sha256::new();
+            for item in items {
+                hasher.update(hash(item));
+            }
+            hasher.finalize().into()
+        }
+        Value::Map(entries) => {
+            let mut pairs: Vec<Vec<u8>> = entries
+                .iter()
+                .map(|(key, value)| {
+                    [Sha256::digest(key.as_bytes()).as_slice(), &hash(value)].concat()
+                })
+                .collect();
+            pairs.sort();
+            let mut hasher = Sha256::new();
+            for pair in pairs {
+                hasher.update(pair);
+            }
+            hasher.finalize().into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The examples of the representation-independent hash given in the ICRC-3 standard.
+    #[test]
+    fn hash_matches_the_icrc3_examples() {
+        let cases = [
+            (
+                nat(42),
+                "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1",
+            ),
+            (
+                Value::Int(Int::from(-42)),
+                "de5a6f78116eca62d7fc5ce159d23ae6b889b365a1739ad2cf36f925a140d0cc",
+            ),
+            (
+                Value::Text("Hello, World!".to_string()),
+                "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f",
+            ),
+            (
+                Value::Blob(vec![1, 2, 3, 4]),
+                "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a",
+            ),
+            (
+                Value::Array(vec![
+                    nat(3),
+                    Value::Text("foo".to_string()),
+                    Value::Blob(vec![5, 6]),
+                ]),
+                "514a04011caa503990d446b7dec5d79e19c221ae607fb08b2848c67734d468d6",
+            ),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(hex::encode(hash(&value)), expected);
+        }
+    }
+
+    #[test]
+    fn map_hash_ignores_entry_order() {
+        let entries = vec![
+            ("amount".to_string(), nat(42)),
+            ("memo".to_string(), Value::Text("fine".to_string())),
+        ];
+        let reversed = entries.iter().rev().cloned().collect();
+        assert_eq!(hash(&Value::Map(entries)), hash(&Value::Map(reversed)));
+    }
+}
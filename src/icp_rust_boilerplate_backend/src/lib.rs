@@ -1,32 +1,165 @@
+mod api_key;
+mod archive;
+mod auth;
 mod book;
+mod cache;
+mod calendar;
+mod catalog;
+mod certification;
+mod charge;
+mod checkpoint;
+mod circulation;
+mod class;
+mod class_set;
+mod consent;
+mod cover;
+mod created_index;
+mod crud;
+mod date;
+mod dead_letter;
+#[cfg(feature = "demo")]
+mod demo;
+mod digest;
+mod digital_asset;
+mod duplicate;
+mod ebook_loan;
+mod env;
+mod exchange_rate;
+mod extension;
+mod factory;
+mod feature;
+mod feed;
+mod guardian;
+mod http;
+mod id_counter;
+mod integrity;
+mod journal;
+mod language;
+mod ledger;
+mod live_update;
 mod loan;
+mod log;
+mod migrations;
+mod notification;
+mod outbox;
+mod pagination;
+mod perf;
+mod photo;
+mod private_notes;
+mod proposal;
+mod query;
+mod read_model;
+mod reading_list;
+mod receipt;
+mod repair;
+mod report;
+mod repository;
+mod retention;
+mod ring_buffer;
+mod rollover;
+mod roster;
+mod scheduler;
+mod schema;
+mod signing;
+mod snapshot;
+mod storable;
+mod storage_report;
 mod student;
+mod suggestion;
+mod translation;
+mod trending;
+mod ulid;
+mod upgrade;
+mod vetkd;
+mod wishlist;
 
 #[macro_use]
 extern crate serde;
 
+use candid::Principal;
+use ic_cdk::api::management_canister::http_request::{
+    HttpResponse as OutcallResponse, TransformArgs,
+};
 use ic_cdk::api::time;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{Cell, DefaultMemoryImpl, StableBTreeMap};
+use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap};
 
 use std::cell::RefCell;
 
-use book::{Book, BookPayload};
-use loan::{Loan, LoanPayload};
-use student::{Student, StudentPayload};
+use api_key::{ApiKey, ApiKeyPayload, IssuedApiKey};
+use archive::ArchiveConfig;
+use auth::{Admin, InitArgs};
+use book::{AltTitles, Book, BookFormat, BookPatch, BookPayload};
+use cache::CachedResult;
+use calendar::{CalendarToken, IssuedCalendarFeed};
+use catalog::CatalogConfig;
+use charge::Charge;
+use checkpoint::{UpgradeCheckpoint, UpgradeState, UpgradeVerification};
+use circulation::{CheckinSummary, LostBookSummary};
+use class::{Class, ClassPayload};
+use class_set::ClassSetReservation;
+use consent::{Consent, ConsentKind, ConsentPayload};
+use cover::Cover;
+use dead_letter::DeadLetter;
+#[cfg(feature = "demo")]
+use demo::DemoCounts;
+use digest::{CertifiedDigest, Collection, RecordProof, StateDigest};
+use digital_asset::{DigitalAsset, DigitalAssetPayload, EbookChunk};
+use duplicate::{DuplicatePair, StudentMerge};
+use ebook_loan::{EbookAccess, EbookHold, EbookLoan};
+use extension::ExtensionRequest;
+use factory::School;
+use feature::{Feature, FeatureFlag, FeatureFlags};
+use guardian::{DependentOverview, Guardianship};
+use http::{HttpRequest, HttpResponse};
+use integrity::{IntegrityCursor, IntegrityReport};
+use journal::{JournalEntry, JournalVerification, ReplayedLoan};
+use language::LanguageFacet;
+use ledger::{
+    ArchiveInfo, DataCertificate, GetArchivesArgs, GetBlocksArgs, GetBlocksResult,
+    SupportedBlockType, Value,
+};
+use live_update::LiveUpdate;
+use loan::{Loan, LoanPayload, LoanStatus};
+use log::{LogConfig, LogEntry, LogLevel};
+use migrations::MigrationRecord;
+use notification::{Notification, NotificationPreferences};
+use outbox::{OutboxItem, OutboxRun, OutboxTask};
+use pagination::Page;
+use perf::{EndpointPerformance, EndpointStats};
+use photo::StudentPhoto;
+use private_notes::PrivateNotes;
+use proposal::{AdminAction, Proposal};
+use query::{QueryRequest, QueryResult};
+use read_model::{BookAvailability, LoanSummary};
+use reading_list::{ReadingList, ReadingListEntry, ReadingListPayload};
+use receipt::SignedReceipt;
+use repair::RepairAction;
+use report::{CirculationReport, ClassReadingReport, FormatCoverage, Period, SignedReport};
+use retention::{CleanupReport, RetentionPolicy};
+use rollover::{Rollover, RolloverPayload, RolloverState};
+use roster::{RosterFormat, RosterImport};
+use schema::ApiSchema;
+use signing::{SigningConfig, SigningPurpose};
+use snapshot::{Snapshot, SnapshotJob, SnapshotJobState};
+use storable::{IndexString, StorablePrincipal};
+use storage_report::StorageReport;
+use student::{Student, StudentPatch, StudentPayload};
+use suggestion::RandomBookFilter;
+use translation::{BookTranslation, BookTranslationPayload};
+use trending::{TrendScore, TrendingBook};
+use ulid::UlidGenerator;
+use wishlist::WishlistItem;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
-type IdCell = Cell<u64, Memory>;
+type CreatedIndex = StableBTreeMap<(u64, u64), (), Memory>;
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
         MemoryManager::init(DefaultMemoryImpl::default())
     );
 
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a counter")
-    );
+    // MemoryId 0 held the single ID counter shared by all entities before per-entity counters.
 
     static STUDENT_STORAGE: RefCell<StableBTreeMap<u64, Student, Memory>> =
         RefCell::new(StableBTreeMap::init(
@@ -35,19 +168,470 @@ thread_local! {
 
     static BOOK_STORAGE: RefCell<StableBTreeMap<u64, Book, Memory>> =
         RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
     ));
 
     static LOAN_STORAGE: RefCell<StableBTreeMap<u64, Loan, Memory>> =
         RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    static DIGITAL_ASSET_STORAGE: RefCell<StableBTreeMap<u64, DigitalAsset, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+
+    static EBOOK_CHUNK_STORAGE: RefCell<StableBTreeMap<(u64, u32), EbookChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+
+    static EBOOK_LOAN_STORAGE: RefCell<StableBTreeMap<u64, EbookLoan, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    static EBOOK_TOKEN_SECRET: RefCell<Cell<[u8; 32], Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))), [0; 32])
+            .expect("Cannot create the token secret")
+    );
+
+    static EBOOK_HOLD_STORAGE: RefCell<StableBTreeMap<u64, EbookHold, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    static SCHEMA_VERSION: RefCell<Cell<u32, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))), 0)
+            .expect("Cannot create the schema version")
+    );
+
+    static MIGRATION_LOG: RefCell<StableBTreeMap<u32, MigrationRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+
+    static STORAGE_CAPACITY: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+            storage_report::MAX_CAPACITY_PAGES,
+        )
+        .expect("Cannot create the storage capacity")
+    );
+
+    static ID_COUNTERS: RefCell<StableBTreeMap<u8, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    static ULID_GENERATOR: RefCell<Cell<UlidGenerator, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+            UlidGenerator::default(),
+        )
+        .expect("Cannot create the ULID generator")
+    );
+
+    static STUDENT_CREATED_INDEX: RefCell<CreatedIndex> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    static BOOK_CREATED_INDEX: RefCell<CreatedIndex> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    static LOAN_CREATED_INDEX: RefCell<CreatedIndex> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    static API_KEY_STORAGE: RefCell<StableBTreeMap<u64, ApiKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    static ADMIN_STORAGE: RefCell<StableBTreeMap<StorablePrincipal, Admin, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    static PROPOSAL_STORAGE: RefCell<StableBTreeMap<u64, Proposal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+
+    static LOG_STORAGE: RefCell<StableBTreeMap<u64, LogEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
     ));
+
+    static LOG_CONFIG: RefCell<Cell<LogConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+            LogConfig::default(),
+        )
+        .expect("Cannot create the log config")
+    );
+
+    static BARCODE_INDEX: RefCell<StableBTreeMap<IndexString, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+
+    static STUDENT_PRINCIPAL_INDEX: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    static EXTENSION_REQUEST_STORAGE: RefCell<StableBTreeMap<u64, ExtensionRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+
+    static CHARGE_STORAGE: RefCell<StableBTreeMap<u64, Charge, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+
+    static TITLE_INDEX: RefCell<StableBTreeMap<(IndexString, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+
+    static COVER_STORAGE: RefCell<StableBTreeMap<u64, Cover, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    // Cover images share the e-book chunk format.
+    static COVER_CHUNK_STORAGE: RefCell<StableBTreeMap<(u64, u32), EbookChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    static ARCHIVE_CONFIG: RefCell<Cell<ArchiveConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))),
+            ArchiveConfig::default(),
+        )
+        .expect("Cannot create the archive config")
+    );
+
+    // The library wasm new school canisters are installed with, in CHUNK_SIZE pieces.
+    static SCHOOL_WASM_CHUNKS: RefCell<StableBTreeMap<u32, EbookChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    static SCHOOL_STORAGE: RefCell<StableBTreeMap<u64, School, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    static UPGRADE_STATE: RefCell<Cell<UpgradeState, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))),
+            UpgradeState::default(),
+        )
+        .expect("Cannot create the upgrade state")
+    );
+
+    static RETENTION_POLICY: RefCell<Cell<RetentionPolicy, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))),
+            RetentionPolicy::default(),
+        )
+        .expect("Cannot create the retention policy")
+    );
+
+    static CERTIFIED_DIGEST: RefCell<Cell<CertifiedDigest, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))),
+            CertifiedDigest::default(),
+        )
+        .expect("Cannot create the certified digest")
+    );
+
+    static SIGNING_CONFIG: RefCell<Cell<SigningConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35))),
+            SigningConfig::default(),
+        )
+        .expect("Cannot create the signing config")
+    );
+
+    static CONSENT_STORAGE: RefCell<StableBTreeMap<(u64, u64), Consent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+
+    static CLASS_STORAGE: RefCell<StableBTreeMap<u64, Class, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+
+    static PRIVATE_NOTES_STORAGE: RefCell<StableBTreeMap<u64, PrivateNotes, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+    ));
+
+    static READING_LIST_STORAGE: RefCell<StableBTreeMap<u64, ReadingList, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+    ));
+
+    static WISHLIST_STORAGE: RefCell<StableBTreeMap<(u64, u64), WishlistItem, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+    ));
+
+    static CLASS_SET_STORAGE: RefCell<StableBTreeMap<u64, ClassSetReservation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
+    ));
+
+    static ROLLOVER_STATE: RefCell<Cell<RolloverState, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42))),
+            RolloverState::default(),
+        )
+        .expect("Cannot create the rollover state")
+    );
+
+    static STUDENT_EXTERNAL_ID_INDEX: RefCell<StableBTreeMap<IndexString, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
+    ));
+
+    static GUARDIAN_STORAGE: RefCell<StableBTreeMap<(StorablePrincipal, u64), Guardianship, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+    ));
+
+    static NOTIFICATION_STORAGE: RefCell<StableBTreeMap<u64, Notification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
+    ));
+
+    static STUDENT_PHOTO_STORAGE: RefCell<StableBTreeMap<u64, StudentPhoto, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+    ));
+
+    // Student photos share the e-book chunk format.
+    static STUDENT_PHOTO_CHUNK_STORAGE: RefCell<StableBTreeMap<(u64, u32), EbookChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
+    ));
+
+    static TRENDING_STORAGE: RefCell<StableBTreeMap<IndexString, TrendScore, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+    ));
+
+    static CALENDAR_TOKEN_STORAGE: RefCell<StableBTreeMap<u64, CalendarToken, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49)))
+    ));
+
+    static CATALOG_CONFIG: RefCell<Cell<CatalogConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50))),
+            CatalogConfig::default(),
+        )
+        .expect("Cannot create the catalog config")
+    );
+
+    static FEATURE_FLAGS: RefCell<Cell<FeatureFlags, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51))),
+            FeatureFlags::default(),
+        )
+        .expect("Cannot create the feature flags")
+    );
+
+    static PERF_STATS: RefCell<StableBTreeMap<IndexString, EndpointStats, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52)))
+    ));
+
+    static AGGREGATE_CACHE: RefCell<StableBTreeMap<IndexString, CachedResult, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53)))
+    ));
+
+    static OPEN_LOANS_BY_STUDENT: RefCell<StableBTreeMap<(u64, u64), LoanSummary, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54)))
+    ));
+
+    static OPEN_LOANS_BY_BOOK: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55)))
+    ));
+
+    static OUTBOX_STORAGE: RefCell<StableBTreeMap<u64, OutboxItem, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56)))
+    ));
+
+    // Outbox items waiting for an attempt, keyed by (next attempt time, item ID).
+    static OUTBOX_SCHEDULE: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57)))
+    ));
+
+    static DEAD_LETTER_STORAGE: RefCell<StableBTreeMap<u64, DeadLetter, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58)))
+    ));
+
+    static LIVE_UPDATE_STORAGE: RefCell<StableBTreeMap<u64, LiveUpdate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59)))
+    ));
+
+    // The ICRC-3 block log of charges and payments, keyed by block ID from 0.
+    static BLOCK_STORAGE: RefCell<StableBTreeMap<u64, Value, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60)))
+    ));
+
+    static CIRCULATION_JOURNAL: RefCell<StableBTreeMap<u64, JournalEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61)))
+    ));
+
+    // Journal entries of each loan, keyed by (loan ID, entry sequence number).
+    static JOURNAL_LOAN_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62)))
+    ));
+
+    static SNAPSHOT_STORAGE: RefCell<StableBTreeMap<u64, Snapshot, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63)))
+    ));
+
+    // Copies taken by snapshots, keyed by (snapshot ID, record ID).
+    static SNAPSHOT_STUDENTS: RefCell<StableBTreeMap<(u64, u64), Student, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64)))
+    ));
+
+    static SNAPSHOT_BOOKS: RefCell<StableBTreeMap<(u64, u64), Book, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65)))
+    ));
+
+    static SNAPSHOT_LOANS: RefCell<StableBTreeMap<(u64, u64), Loan, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66)))
+    ));
+
+    static ALT_TITLE_INDEX: RefCell<StableBTreeMap<(IndexString, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67)))
+    ));
+
+    static BOOK_TRANSLATIONS: RefCell<StableBTreeMap<(u64, IndexString), BookTranslation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(68)))
+    ));
+
+    static BOOK_ALT_TITLES: RefCell<StableBTreeMap<u64, AltTitles, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69)))
+    ));
+
+    // Unreleased class set reservations, keyed by (end of the window, reservation ID).
+    static CLASS_SET_SCHEDULE: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70)))
+    ));
+
+    static SNAPSHOT_JOB: RefCell<Cell<SnapshotJobState, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(71))),
+            SnapshotJobState::default(),
+        )
+        .expect("Cannot create the snapshot job state")
+    );
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Debug)]
 enum Error {
     NotFound { msg: String },
+    InvalidInput { msg: String },
+    Unauthorized { msg: String },
+    Unavailable { msg: String },
+    RecordTooLarge { msg: String },
+    StorageCorrupted { msg: String },
+    CounterUnavailable { msg: String },
+    StudentNotFound { msg: String },
+    BookNotFound { msg: String },
+    BookUnavailable { msg: String },
+    InUse { msg: String },
+}
+
+// Check that a record fits its storage bound, since inserting an oversized one traps.
+fn check_size<T: BoundedStorable>(kind: &str, record: &T) -> Result<(), Error> {
+    let size = record.to_bytes().len();
+    if size > T::MAX_SIZE as usize {
+        return Err(Error::RecordTooLarge {
+            msg: format!(
+                "The {} is {} bytes when stored, more than the {} bytes allowed.",
+                kind,
+                size,
+                T::MAX_SIZE
+            ),
+        });
+    }
+    Ok(())
 }
 
 // need this to generate candid
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    // Check the exported interface against the committed .did file. Removed or changed lines
+    // would break existing clients, so any difference fails until it is accepted by rerunning
+    // with UPDATE_CANDID=1, which rewrites the committed file.
+    #[test]
+    #[cfg_attr(
+        feature = "demo",
+        ignore = "the demo endpoints are not part of the interface"
+    )]
+    fn candid_interface_matches_committed_file() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/icp_rust_boilerplate_backend.did"
+        );
+        let exported = format!("{}\n", super::__export_service().trim_end());
+
+        if std::env::var_os("UPDATE_CANDID").is_some() {
+            std::fs::write(path, exported).expect("Cannot write the .did file");
+            return;
+        }
+
+        let committed = std::fs::read_to_string(path).expect("Cannot read the .did file");
+        let removed: Vec<_> = committed
+            .lines()
+            .filter(|line| !exported.lines().any(|l| l == *line))
+            .collect();
+        let added: Vec<_> = exported
+            .lines()
+            .filter(|line| !committed.lines().any(|l| l == *line))
+            .collect();
+        assert!(
+            removed.is_empty() && added.is_empty(),
+            "The Candid interface changed.\nRemoved or changed (breaks clients):\n{}\nAdded:\n{}\n\
+             Rerun with UPDATE_CANDID=1 to accept the new interface.",
+            removed.join("\n"),
+            added.join("\n"),
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use crate::auth::require_admin;
+use crate::book::Book;
+use crate::crud;
+use crate::loan::is_available;
+use crate::pagination::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::ring_buffer;
+use crate::storable::candid_storable;
+use crate::{Error, LIVE_UPDATE_STORAGE};
+
+// Most updates kept; older ones are evicted, so a client that falls further behind reloads.
+const LIVE_UPDATE_CAPACITY: u64 = 1000;
+
+// A change a circulation desk screen reacts to.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub enum LiveUpdateKind {
+    // A book could be lent before and can't now, or the other way round.
+    AvailabilityChanged {
+        book_id: u64,
+        available: bool,
+    },
+    ChargeIssued {
+        charge_id: u64,
+        student_id: u64,
+        amount: u64,
+    },
+}
+
+// Define the LiveUpdate struct to represent one change, numbered in the order it happened so
+// clients can ask for everything after the last one they saw.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct LiveUpdate {
+    pub seq: u64,
+    pub kind: LiveUpdateKind,
+    pub at: u64,
+}
+
+// Store LiveUpdate as Candid, bounded to 256 bytes.
+candid_storable!(LiveUpdate, max_size = 256);
+
+// Retrieve the updates after sequence number `after`, oldest first, so a screen can follow
+// changes with one cheap call instead of reloading listings. Without `after`, starts from the
+// oldest update kept.
+#[ic_cdk::query]
+fn get_live_updates(after: Option<u64>, limit: Option<u32>) -> Result<Vec<LiveUpdate>, Error> {
+    require_admin()?;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+    let start = after.map_or(0, |seq| seq.saturating_add(1));
+    Ok(LIVE_UPDATE_STORAGE.with(|s| {
+        s.borrow()
+            .range(start..)
+            .take(limit)
+            .map(|(_, update)| update)
+            .collect()
+    }))
+}
+
+// Record a change, numbered after the latest one kept.
+pub(crate) fn publish(now: u64, kind: LiveUpdateKind) {
+    let seq =
+        LIVE_UPDATE_STORAGE.with(|s| s.borrow().last_key_value().map_or(1, |(seq, _)| seq + 1));
+    let update = LiveUpdate { seq, kind, at: now };
+    ring_buffer::push(&LIVE_UPDATE_STORAGE, seq, update, LIVE_UPDATE_CAPACITY);
+}
+
+// Whether each of `book_ids` can be lent at `now`, for comparing before and after a write.
+pub(crate) fn availability(book_ids: &[u64], now: u64) -> Vec<bool> {
+    book_ids
+        .iter()
+        .map(|id| crud::get::<Book>(id).is_some_and(|book| is_available(&book, now)))
+        .collect()
+}
+
+// Publish an update for each book whose availability differs from `before`.
+pub(crate) fn publish_availability(now: u64, book_ids: &[u64], before: &[bool]) {
+    for ((book_id, was), available) in book_ids.iter().zip(before).zip(availability(book_ids, now))
+    {
+        if *was != available {
+            let kind = LiveUpdateKind::AvailabilityChanged {
+                book_id: *book_id,
+                available,
+            };
+            publish(now, kind);
+        }
+    }
+}
@@ -1,35 +1,131 @@
-use candid::{Decode, Encode};
-use ic_stable_structures::{BoundedStorable, Storable};
-use std::borrow::Cow;
+use candid::Principal;
 
-use crate::{time, Error, ID_COUNTER, LOAN_STORAGE};
+use crate::auth::require_admin;
+use crate::book::Book;
+use crate::class_set;
+use crate::crud::{self, crud_endpoints, library_entity, RecordKind};
+use crate::date::DAY;
+use crate::ebook_loan;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::id_counter::Entity;
+use crate::journal;
+use crate::live_update;
+use crate::log;
+use crate::notification;
+use crate::perf;
+use crate::read_model;
+use crate::student::{caller_student, require_student_access, Student};
+use crate::trending;
+use crate::wishlist;
+use crate::Error;
+
+// How long a book may be kept: 14 days in nanoseconds.
+pub const LOAN_PERIOD: u64 = 14 * DAY;
+
+// Longest age override reason accepted.
+const MAX_OVERRIDE_REASON_LENGTH: usize = 200;
 
 // Define the Loan struct to represent a loan in the system.
-#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Default)]
 pub struct Loan {
-    id: u64,
-    student_id: u64,
-    book_id: u64,
-    loan_date: u64,
-    created_at: u64,
-    updated_at: Option<u64>,
+    pub id: u64,
+    pub student_id: u64,
+    pub book_id: u64,
+    pub loan_date: u64,
+    // Optional so that loans stored before due dates existed still decode.
+    pub due_date: Option<u64>,
+    // Optional so that loans stored before statuses existed still decode; read it through
+    // `status()`, which treats a missing status as Active.
+    pub status: Option<LoanStatus>,
+    pub status_changed_at: Option<u64>,
+    // Set when a librarian lent an age-rated book to a student too young for it.
+    pub age_override: Option<AgeOverride>,
+    // Globally unique, time-sortable ID, set on records created while ULIDs are enabled.
+    pub ulid: Option<String>,
+    pub created_at: u64,
+    pub updated_at: Option<u64>,
+}
+
+// A librarian's decision to lend a book despite its age rating, and why.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct AgeOverride {
+    pub reason: String,
+    pub approved_by: Principal,
+    pub approved_at: u64,
 }
 
-// Implement serialization and deserialization for Loan.
-impl Storable for Loan {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+// Where a loan is in its lifecycle.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LoanStatus {
+    Requested,
+    Active,
+    Returned,
+    Overdue,
+    Lost,
+    Cancelled,
+}
+
+impl LoanStatus {
+    // Whether a loan can move from this status to `to`. An overdue loan becomes active again
+    // when its due date is extended.
+    pub fn can_become(self, to: LoanStatus) -> bool {
+        use LoanStatus::*;
+        matches!(
+            (self, to),
+            (Requested, Active)
+                | (Requested, Cancelled)
+                | (Active, Returned)
+                | (Active, Overdue)
+                | (Active, Lost)
+                | (Active, Cancelled)
+                | (Overdue, Active)
+                | (Overdue, Returned)
+                | (Overdue, Lost)
+                | (Overdue, Cancelled)
+        )
     }
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+    // Whether the loan still holds its book.
+    pub fn is_open(self) -> bool {
+        matches!(
+            self,
+            LoanStatus::Requested | LoanStatus::Active | LoanStatus::Overdue
+        )
+    }
+}
+
+impl Loan {
+    // The loan's status; loans stored before statuses existed are Active.
+    pub fn status(&self) -> LoanStatus {
+        self.status.unwrap_or(LoanStatus::Active)
     }
 }
 
-// Set limits for Loan storage size and flexibility.
-impl BoundedStorable for Loan {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+// Store loans as Candid in their own collection, indexed by creation time.
+library_entity!(Loan {
+    max_size: 1024,
+    kind: "loan",
+    name: "Loan",
+    entity: Entity::Loan,
+    storage: LOAN_STORAGE,
+    created_index: LOAN_CREATED_INDEX,
+    on_write: written,
+});
+
+// Hook run on every loan write: keep the read models in step, journal the desk action it
+// records and publish the availability changes it causes.
+fn written(previous: Option<&Loan>, current: Option<&Loan>) {
+    let mut books: Vec<u64> = [previous, current]
+        .into_iter()
+        .flatten()
+        .map(|loan| loan.book_id)
+        .collect();
+    books.dedup();
+    let now = CanisterEnv.now();
+    let before = live_update::availability(&books, now);
+    read_model::loan_written(previous, current);
+    journal::loan_written(previous, current);
+    live_update::publish_availability(now, &books, &before);
 }
 
 // Define the payload structure for creating or updating a loan.
@@ -40,112 +136,596 @@ pub struct LoanPayload {
     pub loan_date: u64,
 }
 
-// Retrieve all loans from the storage.
-#[ic_cdk::query]
-fn get_all_loans() -> Result<Vec<Loan>, Error> {
-    let loans = _get_all_loans();
-    Ok(loans)
-}
-
-// Internal function to fetch all loans as a vector.
-fn _get_all_loans() -> Vec<Loan> {
-    LOAN_STORAGE.with(|loans| {
-        loans
-            .borrow()
-            .iter()
-            .map(|(_, value)| value.clone())
-            .collect()
-    })
-}
+// Expose the standard read and delete endpoints for loans.
+crud_endpoints!(Loan {
+    all: get_all_loans,
+    list: list_loans,
+    get: get_loan,
+    delete: delete_loan,
+    kind: RecordKind::Loan,
+    read_guard: require_admin,
+    get_guard: can_read,
+});
 
-// Retrieve a specific loan by its ID.
-#[ic_cdk::query]
-fn get_loan(id: u64) -> Result<Loan, Error> {
-    match _get_loan(&id) {
-        Some(loan) => Ok(loan),
-        None => Err(Error::NotFound {
-            msg: format!("A loan with id={} not found.", id),
-        }),
-    }
+// Record guard of get_loan: a loan is visible to whoever may see its student's records.
+fn can_read(loan: &Loan) -> Result<(), Error> {
+    require_student_access(&CanisterEnv, loan.student_id)
 }
 
-// Internal function to fetch a loan by ID.
-fn _get_loan(id: &u64) -> Option<Loan> {
-    LOAN_STORAGE.with(|s| s.borrow().get(id))
+// Retrieve the loans created within a time range (inclusive), oldest first.
+#[ic_cdk::query]
+fn get_loans_between(from: u64, to: u64) -> Result<Vec<Loan>, Error> {
+    require_admin()?;
+    Ok(crud::created_between(from, to))
 }
 
 // Add a new loan to the registry.
 #[ic_cdk::update]
 fn add_loan(payload: LoanPayload) -> Result<Loan, Error> {
-    // Validate the input payload.
-    if payload.student_id == 0 || payload.book_id == 0 || payload.loan_date == 0 {
+    let _perf = perf::measure("add_loan");
+    require_admin()?;
+    _add_loan(&CanisterEnv, payload)
+}
+
+// Internal function to add an Active loan, taking its timestamps from `clock`.
+pub(crate) fn _add_loan(clock: &impl Clock, payload: LoanPayload) -> Result<Loan, Error> {
+    create(clock, payload, LoanStatus::Active, None)
+}
+
+// Lend a book to a student below its age rating. A librarian must give the reason, which is
+// kept on the loan and logged.
+#[ic_cdk::update]
+fn add_loan_with_override(payload: LoanPayload, reason: String) -> Result<Loan, Error> {
+    let _perf = perf::measure("add_loan_with_override");
+    require_admin()?;
+    _add_loan_with_override(&CanisterEnv, payload, reason)
+}
+
+// Internal function to add an Active loan that skips the age check on behalf of `env`'s
+// caller.
+pub(crate) fn _add_loan_with_override(
+    env: &(impl Clock + CallerProvider),
+    payload: LoanPayload,
+    reason: String,
+) -> Result<Loan, Error> {
+    if reason.trim().is_empty() || reason.len() > MAX_OVERRIDE_REASON_LENGTH {
         return Err(Error::InvalidInput {
-            msg: "Student ID, Book ID, and Loan Date must be non-zero.".to_string(),
+            msg: format!(
+                "An override reason must be 1 to {} characters.",
+                MAX_OVERRIDE_REASON_LENGTH
+            ),
         });
     }
+    let age_override = AgeOverride {
+        reason,
+        approved_by: env.caller(),
+        approved_at: env.now(),
+    };
+    let loan = create(env, payload, LoanStatus::Active, Some(age_override.clone()))?;
+    log::warn(format!(
+        "{} lent age-rated book id={} to student id={} in loan id={}: {}",
+        age_override.approved_by, loan.book_id, loan.student_id, loan.id, age_override.reason
+    ));
+    Ok(loan)
+}
 
-    // Generate a new unique ID for the loan.
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+// Ask to borrow a book for the student linked to the caller; the loan starts once a librarian
+// activates it.
+#[ic_cdk::update]
+fn request_loan(payload: LoanPayload) -> Result<Loan, Error> {
+    let _perf = perf::measure("request_loan");
+    _request_loan(&CanisterEnv, payload)
+}
 
-    // Create the new loan with the provided payload.
-    let loan = Loan {
+// Internal function to request a loan on behalf of `env`'s caller.
+pub(crate) fn _request_loan(
+    env: &(impl Clock + CallerProvider),
+    payload: LoanPayload,
+) -> Result<Loan, Error> {
+    if caller_student(env)?.id != payload.student_id {
+        return Err(Error::Unauthorized {
+            msg: "You can only request loans for yourself.".to_string(),
+        });
+    }
+    create(env, payload, LoanStatus::Requested, None)
+}
+
+// Helper function to create a loan in the given status; only loans with an age override may
+// lend an age-rated book to a younger student.
+fn create(
+    clock: &impl Clock,
+    payload: LoanPayload,
+    status: LoanStatus,
+    age_override: Option<AgeOverride>,
+) -> Result<Loan, Error> {
+    validate(&payload)?;
+    check_references(clock, &payload, None, age_override.is_some())?;
+    let loan = crud::create(clock, |id, ulid, now| Loan {
         id,
         student_id: payload.student_id,
         book_id: payload.book_id,
         loan_date: payload.loan_date,
-        created_at: time(),
+        due_date: Some(payload.loan_date.saturating_add(LOAN_PERIOD)),
+        status: Some(status),
+        status_changed_at: Some(now),
+        age_override,
+        ulid,
+        created_at: now,
         updated_at: None,
-    };
-
-    // Insert the loan into storage.
-    do_insert(&loan);
+    })?;
+    if status == LoanStatus::Active {
+        lent(&loan, clock.now());
+    }
     Ok(loan)
 }
 
-// Helper function to insert a loan into storage.
-fn do_insert(loan: &Loan) {
-    LOAN_STORAGE.with(|service| service.borrow_mut().insert(loan.id, loan.clone()));
-}
-
 // Update an existing loan's details by ID.
 #[ic_cdk::update]
 fn update_loan(id: u64, payload: LoanPayload) -> Result<Loan, Error> {
-    // Validate the input payload.
+    let _perf = perf::measure("update_loan");
+    require_admin()?;
+    _update_loan(&CanisterEnv, id, payload)
+}
+
+// Internal function to update a loan, taking its timestamps from `clock`.
+pub(crate) fn _update_loan(
+    clock: &impl Clock,
+    id: u64,
+    payload: LoanPayload,
+) -> Result<Loan, Error> {
+    check_update(clock, id, &payload)?;
+    crud::update(id, |loan: &mut Loan| {
+        loan.student_id = payload.student_id;
+        loan.book_id = payload.book_id;
+        // A due date set by an approved extension stands unless the loan's start moves.
+        if loan.loan_date != payload.loan_date || loan.due_date.is_none() {
+            loan.due_date = Some(payload.loan_date.saturating_add(LOAN_PERIOD));
+        }
+        loan.loan_date = payload.loan_date;
+        loan.updated_at = Some(clock.now());
+    })
+}
+
+// Check a loan payload without writing it, returning the error add_loan would, or update_loan
+// would for the loan `id`.
+#[ic_cdk::query]
+fn validate_loan_payload(payload: LoanPayload, id: Option<u64>) -> Result<(), Error> {
+    require_admin()?;
+    match id {
+        Some(id) => check_update(&CanisterEnv, id, &payload),
+        None => {
+            validate(&payload)?;
+            check_references(&CanisterEnv, &payload, None, false)
+        }
+    }
+}
+
+// Check that the loan `id` may be changed to `payload`.
+fn check_update(clock: &impl Clock, id: u64, payload: &LoanPayload) -> Result<(), Error> {
+    validate(payload)?;
+    // An override only covers the student and book it was given for.
+    let loan = crud::find::<Loan>(id)?;
+    let overridden = loan.age_override.is_some()
+        && loan.student_id == payload.student_id
+        && loan.book_id == payload.book_id;
+    check_references(clock, payload, Some(id), overridden)
+}
+
+// Retrieve the loans in a given status.
+#[ic_cdk::query]
+fn get_loans_by_status(status: LoanStatus) -> Result<Vec<Loan>, Error> {
+    require_admin()?;
+    Ok(crud::all::<Loan>()
+        .into_iter()
+        .filter(|loan| loan.status() == status)
+        .collect())
+}
+
+// Start a requested loan.
+#[ic_cdk::update]
+fn activate_loan(id: u64) -> Result<Loan, Error> {
+    let _perf = perf::measure("activate_loan");
+    require_admin()?;
+    _transition_loan(&CanisterEnv, id, LoanStatus::Active)
+}
+
+// Record that a loan's book came back.
+#[ic_cdk::update]
+fn return_loan(id: u64) -> Result<Loan, Error> {
+    let _perf = perf::measure("return_loan");
+    require_admin()?;
+    _transition_loan(&CanisterEnv, id, LoanStatus::Returned)
+}
+
+// Mark an active loan as overdue.
+#[ic_cdk::update]
+fn mark_loan_overdue(id: u64) -> Result<Loan, Error> {
+    let _perf = perf::measure("mark_loan_overdue");
+    require_admin()?;
+    _transition_loan(&CanisterEnv, id, LoanStatus::Overdue)
+}
+
+// Call off a loan, e.g. a request that should not start.
+#[ic_cdk::update]
+fn cancel_loan(id: u64) -> Result<Loan, Error> {
+    let _perf = perf::measure("cancel_loan");
+    require_admin()?;
+    _transition_loan(&CanisterEnv, id, LoanStatus::Cancelled)
+}
+
+// Mark every active loan past its due date as overdue, returning the loans changed.
+#[ic_cdk::update]
+fn mark_overdue_loans() -> Result<Vec<Loan>, Error> {
+    let _perf = perf::measure("mark_overdue_loans");
+    require_admin()?;
+    _mark_overdue_loans(&CanisterEnv)
+}
+
+// Internal function to mark the loans overdue at `clock`'s current time.
+pub(crate) fn _mark_overdue_loans(clock: &impl Clock) -> Result<Vec<Loan>, Error> {
+    let now = clock.now();
+    crud::all::<Loan>()
+        .into_iter()
+        .filter(|loan| {
+            loan.status() == LoanStatus::Active && loan.due_date.is_some_and(|due| due < now)
+        })
+        .map(|loan| _transition_loan(clock, loan.id, LoanStatus::Overdue))
+        .collect()
+}
+
+// Internal function to move a loan to status `to`, rejecting transitions the lifecycle forbids.
+pub(crate) fn _transition_loan(clock: &impl Clock, id: u64, to: LoanStatus) -> Result<Loan, Error> {
+    let loan = crud::find::<Loan>(id)?;
+    let from = loan.status();
+    if !from.can_become(to) {
+        return Err(Error::InvalidInput {
+            msg: format!("A loan cannot go from {:?} to {:?}.", from, to),
+        });
+    }
+    if from == LoanStatus::Requested && to == LoanStatus::Active {
+        let payload = LoanPayload {
+            student_id: loan.student_id,
+            book_id: loan.book_id,
+            loan_date: loan.loan_date,
+        };
+        check_references(clock, &payload, Some(id), false)?;
+    }
+
+    let loan = crud::update(id, |loan: &mut Loan| {
+        loan.status = Some(to);
+        loan.status_changed_at = Some(clock.now());
+    })?;
+    if from == LoanStatus::Requested && to == LoanStatus::Active {
+        lent(&loan, clock.now());
+    }
+    if !to.is_open() && to != LoanStatus::Lost {
+        let now = clock.now();
+        if let Some(book) = crud::get::<Book>(&loan.book_id).filter(|book| is_available(book, now))
+        {
+            wishlist::book_available(book.id, clock.now());
+        }
+    }
+    Ok(loan)
+}
+
+// Helper function to count a loan that just started towards its book's trending score and
+// send the student a receipt if they asked for one.
+fn lent(loan: &Loan, now: u64) {
+    if let Some(book) = crud::get::<Book>(&loan.book_id) {
+        trending::record_loan(&book, now);
+        notification::checkout_receipt(now, loan, &book);
+    }
+}
+
+// Validate a loan payload.
+fn validate(payload: &LoanPayload) -> Result<(), Error> {
     if payload.student_id == 0 || payload.book_id == 0 || payload.loan_date == 0 {
         return Err(Error::InvalidInput {
             msg: "Student ID, Book ID, and Loan Date must be non-zero.".to_string(),
         });
     }
+    Ok(())
+}
 
-    // Fetch the loan from storage and update its details.
-    match LOAN_STORAGE.with(|service| service.borrow().get(&id)) {
-        Some(mut loan) => {
-            loan.student_id = payload.student_id;
-            loan.book_id = payload.book_id;
-            loan.loan_date = payload.loan_date;
-            loan.updated_at = Some(time());
-            do_insert(&loan); // Save the updated loan back to storage.
-            Ok(loan)
+// Check that the loan's student and book exist, that the book is in circulation, that the
+// student is old enough for it unless `age_override` is set, that no class set reservation
+// holds it, and that no other open loan (`except` the one being updated) holds it, so orphan
+// and double loans can't be created, all as of `clock`'s current time.
+pub(crate) fn check_references(
+    clock: &impl Clock,
+    payload: &LoanPayload,
+    except: Option<u64>,
+    age_override: bool,
+) -> Result<(), Error> {
+    let Some(student) = crud::get::<Student>(&payload.student_id) else {
+        return Err(Error::StudentNotFound {
+            msg: format!("A student with id={} not found.", payload.student_id),
+        });
+    };
+    let book = match crud::get::<Book>(&payload.book_id) {
+        None => {
+            return Err(Error::BookNotFound {
+                msg: format!("A book with id={} not found.", payload.book_id),
+            })
+        }
+        Some(book) if book.withdrawn_at.is_some() => {
+            return Err(Error::BookUnavailable {
+                msg: format!("The book id={} has been withdrawn.", payload.book_id),
+            })
         }
-        None => Err(Error::NotFound {
-            msg: format!("Couldn't update a loan with id={}. Loan not found.", id),
-        }),
+        Some(book) => book,
+    };
+    if !age_override {
+        check_age(clock, &student, &book)?;
+    }
+    if let Some(reservation) = class_set::reservation_of(book.id, clock.now()) {
+        return Err(Error::BookUnavailable {
+            msg: format!(
+                "The book id={} is reserved for class id={} until {}.",
+                book.id, reservation.class_id, reservation.to
+            ),
+        });
+    }
+
+    let on_loan = read_model::open_loans_of_book(payload.book_id)
+        .into_iter()
+        .any(|id| Some(id) != except);
+    if on_loan {
+        return Err(Error::BookUnavailable {
+            msg: format!("The book id={} is already on loan.", payload.book_id),
+        });
     }
+    Ok(())
 }
 
-// Delete a loan by ID from the registry.
-#[ic_cdk::update]
-fn delete_loan(id: u64) -> Result<Loan, Error> {
-    // Remove the loan from storage.
-    match LOAN_STORAGE.with(|service| service.borrow_mut().remove(&id)) {
-        Some(loan) => Ok(loan),
-        None => Err(Error::NotFound {
-            msg: format!("Couldn't delete a loan with id={}. Loan not found.", id),
-        }),
+// Whether a book can be borrowed at `now`: it is in circulation, not on loan and not reserved
+// for a class.
+pub(crate) fn is_available(book: &Book, now: u64) -> bool {
+    book.withdrawn_at.is_none()
+        && read_model::open_loans_of_book(book.id).is_empty()
+        && class_set::reservation_of(book.id, now).is_none()
+}
+
+// Check that a student is old enough for a book's age rating at `clock`'s current time.
+// Students of unknown age can't borrow rated books, since they might be too young.
+fn check_age(clock: &impl Clock, student: &Student, book: &Book) -> Result<(), Error> {
+    let Some(min_age) = book.min_age else {
+        return Ok(());
+    };
+    let age = student.age_at(clock.now());
+    if age.is_some_and(|age| age >= min_age as u64) {
+        return Ok(());
+    }
+    let age = age.map_or("of unknown age".to_string(), |age| {
+        format!("{} years old", age)
+    });
+    Err(Error::BookUnavailable {
+        msg: format!(
+            "The book id={} is rated {}+ and the student id={} is {}.",
+            book.id, min_age, student.id, age
+        ),
+    })
+}
+
+// Delete policy for students: refuse while they have open loans, or with `cascade` close them.
+pub(crate) fn release_student(id: u64, cascade: bool) -> Result<(), Error> {
+    let loans = read_model::student_open_loans(id)
+        .into_iter()
+        .map(|summary| summary.loan_id)
+        .collect();
+    release("student", id, loans, cascade)
+}
+
+// Move every loan of the student `from` to the student `to`, taking the timestamp from `clock`.
+// Returns how many were moved.
+pub(crate) fn move_loans(clock: &impl Clock, from: u64, to: u64) -> Result<u64, Error> {
+    let loans: Vec<u64> = crud::all::<Loan>()
+        .into_iter()
+        .filter(|loan| loan.student_id == from)
+        .map(|loan| loan.id)
+        .collect();
+    for &id in &loans {
+        crud::update(id, |loan: &mut Loan| {
+            loan.student_id = to;
+            loan.updated_at = Some(clock.now());
+        })?;
+    }
+    Ok(loans.len() as u64)
+}
+
+// Delete policy for books: refuse while they are on loan or have e-book holds, or with
+// `cascade` close the loan and cancel the holds.
+pub(crate) fn release_book(id: u64, cascade: bool) -> Result<(), Error> {
+    let holds = ebook_loan::_get_holds(id);
+    if !holds.is_empty() && !cascade {
+        return Err(Error::InUse {
+            msg: format!(
+                "The book id={} has {} e-book hold(s); pass cascade to cancel them.",
+                id,
+                holds.len()
+            ),
+        });
+    }
+
+    let loans = read_model::open_loans_of_book(id);
+    release("book", id, loans, cascade)?;
+    for hold in holds {
+        ebook_loan::remove_hold(hold.id);
+        log::warn(format!(
+            "Cancelled e-book hold id={} because book id={} was deleted.",
+            hold.id, id
+        ));
+    }
+    Ok(())
+}
+
+// Refuse to delete the `kind` record `id` while open `loans` reference it, or with `cascade`
+// cancel them, recording each in the log.
+fn release(kind: &str, id: u64, loans: Vec<u64>, cascade: bool) -> Result<(), Error> {
+    if loans.is_empty() {
+        return Ok(());
+    }
+    if !cascade {
+        return Err(Error::InUse {
+            msg: format!(
+                "The {} id={} has {} open loan(s); pass cascade to cancel them.",
+                kind,
+                id,
+                loans.len()
+            ),
+        });
+    }
+
+    for loan_id in &loans {
+        let status = crud::find::<Loan>(*loan_id)?.status();
+        if !status.can_become(LoanStatus::Cancelled) {
+            return Err(Error::InvalidInput {
+                msg: format!("A loan cannot go from {:?} to Cancelled.", status),
+            });
+        }
+    }
+    for loan_id in loans {
+        crud::update(loan_id, |loan: &mut Loan| {
+            loan.status = Some(LoanStatus::Cancelled);
+            loan.status_changed_at = Some(CanisterEnv.now());
+        })?;
+        log::warn(format!(
+            "Cancelled loan id={} because {} id={} was deleted.",
+            loan_id, kind, id
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::TestEnv;
+
+    // 2023-11-14, an arbitrary day to start the clock on.
+    const START: u64 = 1_700_000_000_000_000_000;
+
+    // Lend a new book to a new student at `env`'s current time.
+    fn lend(env: &TestEnv) -> Loan {
+        crud::insert(&Student {
+            id: 1,
+            name: "Siti Rahma".to_string(),
+            created_at: env.now(),
+            ..Default::default()
+        })
+        .unwrap();
+        crud::insert(&Book {
+            id: 1,
+            title: "Laskar Pelangi".to_string(),
+            author: "Andrea Hirata".to_string(),
+            created_at: env.now(),
+            ..Default::default()
+        })
+        .unwrap();
+        let payload = LoanPayload {
+            student_id: 1,
+            book_id: 1,
+            loan_date: env.now(),
+        };
+        _add_loan(env, payload).unwrap()
+    }
+
+    #[test]
+    fn loan_is_due_one_loan_period_after_it_starts() {
+        let env = TestEnv::new(START, 1);
+        let loan = lend(&env);
+        assert_eq!(loan.status(), LoanStatus::Active);
+        assert_eq!(loan.due_date, Some(START + LOAN_PERIOD));
+    }
+
+    #[test]
+    fn loan_becomes_overdue_only_after_its_due_date() {
+        let env = TestEnv::new(START, 1);
+        let loan = lend(&env);
+
+        env.advance(LOAN_PERIOD);
+        assert!(_mark_overdue_loans(&env).unwrap().is_empty());
+
+        env.advance(1);
+        let marked = _mark_overdue_loans(&env).unwrap();
+        assert_eq!(marked.len(), 1);
+        assert_eq!(marked[0].id, loan.id);
+        assert_eq!(marked[0].status(), LoanStatus::Overdue);
+        assert_eq!(marked[0].status_changed_at, Some(START + LOAN_PERIOD + 1));
+
+        // Overdue loans are not marked again.
+        env.advance(LOAN_PERIOD);
+        assert!(_mark_overdue_loans(&env).unwrap().is_empty());
+    }
+
+    #[test]
+    fn returned_loan_is_never_overdue() {
+        let env = TestEnv::new(START, 1);
+        let loan = lend(&env);
+        _transition_loan(&env, loan.id, LoanStatus::Returned).unwrap();
+
+        env.advance(2 * LOAN_PERIOD);
+        assert!(_mark_overdue_loans(&env).unwrap().is_empty());
+    }
+
+    #[test]
+    fn students_request_loans_only_for_themselves() {
+        let env = TestEnv::new(START, 1);
+        let loan = lend(&env);
+        _transition_loan(&env, loan.id, LoanStatus::Returned).unwrap();
+        let payload = || LoanPayload {
+            student_id: 1,
+            book_id: 1,
+            loan_date: START,
+        };
+        assert!(matches!(
+            _request_loan(&env, payload()),
+            Err(Error::Unauthorized { .. })
+        ));
+
+        crate::student::_link_student_principal(&env, 1, Some(env.caller)).unwrap();
+        let requested = _request_loan(&env, payload()).unwrap();
+        assert_eq!(requested.status(), LoanStatus::Requested);
+    }
+
+    #[test]
+    fn age_rating_is_checked_at_the_clock_time() {
+        let env = TestEnv::new(START, 1);
+        // Born 4,300 days, about 11.8 years, before the clock starts.
+        let student = Student {
+            birth_date: Some(START - 4_300 * DAY),
+            ..Default::default()
+        };
+        let book = Book {
+            id: 1,
+            min_age: Some(12),
+            ..Default::default()
+        };
+        assert!(matches!(
+            check_age(&env, &student, &book),
+            Err(Error::BookUnavailable { .. })
+        ));
+
+        env.advance(100 * DAY);
+        assert!(check_age(&env, &student, &book).is_ok());
+    }
+
+    #[test]
+    fn update_keeps_an_extended_due_date() {
+        let env = TestEnv::new(START, 1);
+        let loan = lend(&env);
+        let extended = START + 2 * LOAN_PERIOD;
+        crud::update(loan.id, |loan: &mut Loan| loan.due_date = Some(extended)).unwrap();
+        let payload = |loan_date| LoanPayload {
+            student_id: 1,
+            book_id: 1,
+            loan_date,
+        };
+
+        let updated = _update_loan(&env, loan.id, payload(START)).unwrap();
+        assert_eq!(updated.due_date, Some(extended));
+
+        let updated = _update_loan(&env, loan.id, payload(START + DAY)).unwrap();
+        assert_eq!(updated.due_date, Some(START + DAY + LOAN_PERIOD));
     }
 }
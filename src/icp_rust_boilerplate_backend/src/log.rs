@@ -0,0 +1,199 @@
+use crate::auth::require_admin;
+use crate::env::{CanisterEnv, Clock};
+use crate::perf;
+use crate::proposal::{_propose_admin_action, AdminAction, Proposal};
+use crate::ring_buffer;
+use crate::storable::candid_storable;
+use crate::{Error, LOG_CONFIG, LOG_STORAGE};
+
+// Number of entries kept unless configured otherwise; the oldest entry is dropped when a new
+// one would exceed the capacity.
+pub const LOG_CAPACITY: u64 = 10_000;
+
+// Bounds of the configurable capacity. At the upper bound the log takes about 100 MiB.
+const MIN_LOG_CAPACITY: u64 = 100;
+const MAX_LOG_CAPACITY: u64 = 100_000;
+
+// Longest message kept, in bytes; longer ones are truncated so the entry fits its bound.
+const MAX_MESSAGE_BYTES: usize = 900;
+
+// Largest number of entries a single get_logs call returns.
+const MAX_LOGS_PER_CALL: u32 = 1_000;
+
+// Severity of a log entry, from least to most severe.
+#[derive(
+    candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+// Define the LogEntry struct to represent one line of the canister's log.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct LogEntry {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub level: LogLevel,
+    pub message: String,
+}
+
+// Store LogEntry as Candid, bounded to 1024 bytes.
+candid_storable!(LogEntry, max_size = 1024);
+
+// The minimum level recorded, the sequence number of the next entry and how many entries are
+// kept.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    pub next_seq: u64,
+    // Optional so that configs stored before it existed still decode; None is LOG_CAPACITY.
+    pub capacity: Option<u64>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: LogLevel::Info,
+            next_seq: 0,
+            capacity: None,
+        }
+    }
+}
+
+// Store LogConfig as Candid.
+candid_storable!(LogConfig);
+
+// Record a message if `level` is at or above the configured minimum. Entries written during a
+// query are discarded with the rest of the query's state changes.
+pub(crate) fn log(level: LogLevel, message: impl Into<String>) {
+    _log(&CanisterEnv, level, message.into());
+}
+
+// Record a message at Info level.
+pub(crate) fn info(message: impl Into<String>) {
+    log(LogLevel::Info, message);
+}
+
+// Record a message at Warn level.
+pub(crate) fn warn(message: impl Into<String>) {
+    log(LogLevel::Warn, message);
+}
+
+// Record a message at Error level.
+pub(crate) fn error(message: impl Into<String>) {
+    log(LogLevel::Error, message);
+}
+
+// Internal function to append an entry timestamped by `clock`, evicting the oldest when full.
+pub(crate) fn _log(clock: &impl Clock, level: LogLevel, mut message: String) {
+    let mut config = LOG_CONFIG.with(|c| c.borrow().get().clone());
+    if level < config.level {
+        return;
+    }
+
+    if message.len() > MAX_MESSAGE_BYTES {
+        let mut end = MAX_MESSAGE_BYTES;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+
+    let entry = LogEntry {
+        seq: config.next_seq,
+        timestamp: clock.now(),
+        level,
+        message,
+    };
+    let capacity = config.capacity.unwrap_or(LOG_CAPACITY);
+    ring_buffer::push(&LOG_STORAGE, entry.seq, entry, capacity);
+
+    config.next_seq += 1;
+    LOG_CONFIG
+        .with(|c| c.borrow_mut().set(config))
+        .expect("Cannot store the log config");
+}
+
+// Retrieve up to `limit` entries at or above `level` written at or after `since`, oldest first.
+#[ic_cdk::query]
+fn get_logs(
+    level: Option<LogLevel>,
+    since: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Vec<LogEntry>, Error> {
+    require_admin()?;
+    let level = level.unwrap_or(LogLevel::Debug);
+    let since = since.unwrap_or(0);
+    let limit = limit.unwrap_or(MAX_LOGS_PER_CALL).min(MAX_LOGS_PER_CALL) as usize;
+    Ok(LOG_STORAGE.with(|logs| {
+        logs.borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.level >= level && entry.timestamp >= since)
+            .take(limit)
+            .collect()
+    }))
+}
+
+// Retrieve the minimum level currently recorded.
+#[ic_cdk::query]
+fn get_log_level() -> LogLevel {
+    LOG_CONFIG.with(|c| c.borrow().get().level)
+}
+
+// Change the minimum level recorded, without redeploying.
+#[ic_cdk::update]
+fn set_log_level(level: LogLevel) -> Result<LogLevel, Error> {
+    let _perf = perf::measure("set_log_level");
+    require_admin()?;
+    let mut config = LOG_CONFIG.with(|c| c.borrow().get().clone());
+    config.level = level;
+    LOG_CONFIG
+        .with(|c| c.borrow_mut().set(config))
+        .expect("Cannot store the log config");
+    Ok(level)
+}
+
+// Retrieve how many log entries are kept.
+#[ic_cdk::query]
+fn get_log_capacity() -> u64 {
+    LOG_CONFIG.with(|c| c.borrow().get().capacity.unwrap_or(LOG_CAPACITY))
+}
+
+// Propose changing how many log entries are kept. It takes effect once another administrator
+// approves the proposal, evicting the oldest entries at once when lowered.
+#[ic_cdk::update]
+fn set_log_capacity(capacity: u64) -> Result<Proposal, Error> {
+    let _perf = perf::measure("set_log_capacity");
+    require_admin()?;
+    _propose_admin_action(&CanisterEnv, AdminAction::SetLogCapacity { capacity })
+}
+
+// Check that a log capacity is within bounds.
+pub(crate) fn validate_capacity(capacity: u64) -> Result<(), Error> {
+    if !(MIN_LOG_CAPACITY..=MAX_LOG_CAPACITY).contains(&capacity) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Log capacity must be between {} and {} entries.",
+                MIN_LOG_CAPACITY, MAX_LOG_CAPACITY
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Change how many log entries are kept. Lowering it evicts entries, so it runs only through an
+// approved proposal.
+pub(crate) fn set_capacity(capacity: u64) -> Result<u64, Error> {
+    validate_capacity(capacity)?;
+    let mut config = LOG_CONFIG.with(|c| c.borrow().get().clone());
+    config.capacity = Some(capacity);
+    LOG_CONFIG
+        .with(|c| c.borrow_mut().set(config))
+        .expect("Cannot store the log config");
+    ring_buffer::evict_to(&LOG_STORAGE, capacity);
+    Ok(capacity)
+}
@@ -0,0 +1,275 @@
+use ic_stable_structures::{BoundedStorable, StableBTreeMap};
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::book;
+use crate::class_set;
+use crate::created_index;
+use crate::id_counter::{self, Entity};
+use crate::ledger;
+use crate::loan::{Loan, LoanStatus, LOAN_PERIOD};
+use crate::log;
+use crate::read_model;
+use crate::repository::Repository;
+use crate::storable::candid_storable;
+use crate::student::{with_email, Student};
+use crate::{
+    time, Memory, BOOK_CREATED_INDEX, BOOK_STORAGE, EBOOK_HOLD_STORAGE, EBOOK_LOAN_STORAGE,
+    LOAN_CREATED_INDEX, LOAN_STORAGE, MIGRATION_LOG, STUDENT_CREATED_INDEX, STUDENT_STORAGE,
+};
+
+// A single step that rewrites stored data from `from_version` to `from_version + 1`.
+struct Migration {
+    from_version: u32,
+    description: &'static str,
+    run: fn(),
+}
+
+// Registry of all migrations, ordered by the version they upgrade from.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from_version: 1,
+        description: "Add due_date to loans",
+        run: add_loan_due_dates,
+    },
+    Migration {
+        from_version: 2,
+        description: "Seed per-entity ID counters from existing records",
+        run: seed_id_counters,
+    },
+    Migration {
+        from_version: 3,
+        description: "Build the created_at indexes",
+        run: build_created_indexes,
+    },
+    Migration {
+        from_version: 4,
+        description: "Give existing loans the Active status",
+        run: add_loan_statuses,
+    },
+    Migration {
+        from_version: 5,
+        description: "Build the title index",
+        run: build_title_index,
+    },
+    Migration {
+        from_version: 6,
+        description: "Move student emails into contact lists",
+        run: move_emails_to_contacts,
+    },
+    Migration {
+        from_version: 7,
+        description: "Build the open loan read models",
+        run: read_model::build,
+    },
+    Migration {
+        from_version: 8,
+        description: "Record existing charges and payments in the block log",
+        run: ledger::backfill,
+    },
+    Migration {
+        from_version: 9,
+        description: "Build the class set schedule",
+        run: class_set::build_schedule,
+    },
+];
+
+// Define the MigrationRecord struct to log a migration that has been applied.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct MigrationRecord {
+    pub from_version: u32,
+    pub to_version: u32,
+    pub description: String,
+    pub ran_at: u64,
+}
+
+// Store MigrationRecord as Candid, bounded to 1024 bytes.
+candid_storable!(MigrationRecord, max_size = 1024);
+
+// Retrieve every migration applied to this canister, oldest first.
+#[ic_cdk::query]
+fn get_migration_log() -> Vec<MigrationRecord> {
+    MIGRATION_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .map(|(_, value)| value.clone())
+            .collect()
+    })
+}
+
+// Run every migration newer than `stored_version` in order, logging each one.
+pub(crate) fn run_pending(stored_version: u32) {
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.from_version >= stored_version)
+    {
+        (migration.run)();
+
+        let record = MigrationRecord {
+            from_version: migration.from_version,
+            to_version: migration.from_version + 1,
+            description: migration.description.to_string(),
+            ran_at: time(),
+        };
+        log::info(format!(
+            "Migrated stored data to schema version {}: {}",
+            record.to_version, record.description
+        ));
+        MIGRATION_LOG.with(|log| log.borrow_mut().insert(record.to_version, record));
+    }
+}
+
+// v1 -> v2: give every existing loan the default due date.
+fn add_loan_due_dates() {
+    LOAN_STORAGE.with(|service| fill_due_dates(&mut *service.borrow_mut()));
+}
+
+// Set the default due date on every loan in `loans` that has none.
+fn fill_due_dates(loans: &mut impl Repository<u64, Loan>) {
+    for (id, mut loan) in loans.entries() {
+        if loan.due_date.is_none() {
+            loan.due_date = Some(loan.loan_date.saturating_add(LOAN_PERIOD));
+            loans.insert(id, loan);
+        }
+    }
+}
+
+// v2 -> v3: continue each entity's ID sequence after its largest existing key.
+fn seed_id_counters() {
+    id_counter::seed(Entity::Student, last_id(&STUDENT_STORAGE));
+    id_counter::seed(Entity::Book, last_id(&BOOK_STORAGE));
+    id_counter::seed(Entity::Loan, last_id(&LOAN_STORAGE));
+    id_counter::seed(Entity::EbookLoan, last_id(&EBOOK_LOAN_STORAGE));
+    id_counter::seed(Entity::EbookHold, last_id(&EBOOK_HOLD_STORAGE));
+}
+
+// v3 -> v4: index every existing student, book and loan by creation time.
+fn build_created_indexes() {
+    STUDENT_STORAGE.with(|s| {
+        for (id, student) in s.borrow().iter() {
+            created_index::insert(&STUDENT_CREATED_INDEX, student.created_at, id);
+        }
+    });
+    BOOK_STORAGE.with(|s| {
+        for (id, book) in s.borrow().iter() {
+            created_index::insert(&BOOK_CREATED_INDEX, book.created_at, id);
+        }
+    });
+    LOAN_STORAGE.with(|s| {
+        for (id, loan) in s.borrow().iter() {
+            created_index::insert(&LOAN_CREATED_INDEX, loan.created_at, id);
+        }
+    });
+}
+
+// v4 -> v5: record the implicit Active status on every existing loan.
+fn add_loan_statuses() {
+    LOAN_STORAGE.with(|service| fill_statuses(&mut *service.borrow_mut()));
+}
+
+// Set the Active status on every loan in `loans` that has none.
+fn fill_statuses(loans: &mut impl Repository<u64, Loan>) {
+    for (id, mut loan) in loans.entries() {
+        if loan.status.is_none() {
+            loan.status = Some(LoanStatus::Active);
+            loan.status_changed_at = Some(loan.created_at);
+            loans.insert(id, loan);
+        }
+    }
+}
+
+// v5 -> v6: index every existing book by title for alphabetical browsing.
+fn build_title_index() {
+    BOOK_STORAGE.with(|s| {
+        for (_, book) in s.borrow().iter() {
+            book::index_title(None, &book);
+        }
+    });
+}
+
+// v6 -> v7: make each student's single email their preferred email contact.
+fn move_emails_to_contacts() {
+    STUDENT_STORAGE.with(|service| fill_contacts(&mut *service.borrow_mut()));
+}
+
+// Move the legacy email of every student in `students` into their contacts.
+fn fill_contacts(students: &mut impl Repository<u64, Student>) {
+    for (id, mut student) in students.entries() {
+        if let Some(email) = student.email.take() {
+            student.contacts = Some(with_email(student.contacts().to_vec(), email));
+            students.insert(id, student);
+        }
+    }
+}
+
+// Largest key in a u64-keyed collection, or 0 when it is empty.
+fn last_id<V: BoundedStorable>(
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+) -> u64 {
+    storage.with(|s| s.borrow().last_key_value().map(|(id, _)| id).unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::student::{Contact, ContactKind};
+    use std::collections::HashMap;
+
+    // 2023-11-14, an arbitrary day loans were made on.
+    const START: u64 = 1_700_000_000_000_000_000;
+
+    fn loan(id: u64) -> Loan {
+        Loan {
+            id,
+            student_id: 1,
+            book_id: id,
+            loan_date: START,
+            created_at: START,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn due_dates_are_filled_only_where_missing() {
+        let mut loans = HashMap::from([(1, loan(1)), (2, loan(2))]);
+        loans.get_mut(&2).unwrap().due_date = Some(START + 1);
+        fill_due_dates(&mut loans);
+        assert_eq!(loans[&1].due_date, Some(START + LOAN_PERIOD));
+        assert_eq!(loans[&2].due_date, Some(START + 1));
+    }
+
+    #[test]
+    fn statuses_are_filled_only_where_missing() {
+        let mut loans = HashMap::from([(1, loan(1)), (2, loan(2))]);
+        loans.get_mut(&2).unwrap().status = Some(LoanStatus::Returned);
+        fill_statuses(&mut loans);
+        assert_eq!(loans[&1].status, Some(LoanStatus::Active));
+        assert_eq!(loans[&1].status_changed_at, Some(START));
+        assert_eq!(loans[&2].status, Some(LoanStatus::Returned));
+    }
+
+    #[test]
+    fn legacy_email_becomes_the_preferred_email_contact() {
+        let phone = Contact {
+            kind: ContactKind::Phone,
+            value: "+62 812 0000 0000".to_string(),
+            preferred: true,
+        };
+        let student = Student {
+            id: 1,
+            name: "Siti Rahma".to_string(),
+            email: Some(" siti@example.com ".to_string()),
+            contacts: Some(vec![phone]),
+            ..Default::default()
+        };
+        let mut students = HashMap::from([(1, student)]);
+        fill_contacts(&mut students);
+
+        let student = &students[&1];
+        assert!(student.email.is_none());
+        let email = student.preferred_contact(ContactKind::Email).unwrap();
+        assert_eq!(email.value, "siti@example.com");
+        assert!(email.preferred);
+        assert_eq!(student.contacts().len(), 2);
+    }
+}
@@ -0,0 +1,180 @@
+use crate::auth::require_admin;
+use crate::book::Book;
+use crate::consent::{has_consent, ConsentKind};
+use crate::crud;
+use crate::date::format_date;
+use crate::env::{CanisterEnv, Clock};
+use crate::id_counter::{next_id, Entity};
+use crate::loan::Loan;
+use crate::log;
+use crate::pagination::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::student::{caller_student, ContactKind, Student};
+use crate::{Error, NOTIFICATION_STORAGE};
+
+// Define the Notification struct to represent a message for a student. The canister can't send
+// email or text messages itself, so a relay picks pending notifications up, delivers them, and
+// marks them sent.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct Notification {
+    pub id: u64,
+    pub student_id: u64,
+    pub channel: ContactKind,
+    // The address or phone number to deliver to.
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    pub created_at: u64,
+    pub sent_at: Option<u64>,
+}
+
+// Store Notification as Candid, bounded to 2048 bytes.
+candid_storable!(Notification, max_size = 2048);
+
+// Which optional messages a student wants. Messages a student must get, such as wishlist
+// alerts they asked for, aren't listed here.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, Default)]
+pub struct NotificationPreferences {
+    // Email a receipt for every book the student checks out.
+    pub checkout_receipts: bool,
+}
+
+// Set a student's notification preferences.
+#[ic_cdk::update]
+fn set_notification_preferences(
+    student_id: u64,
+    preferences: NotificationPreferences,
+) -> Result<Student, Error> {
+    let _perf = perf::measure("set_notification_preferences");
+    require_admin()?;
+    _set_notification_preferences(&CanisterEnv, student_id, preferences)
+}
+
+// Set the notification preferences of the student linked to the caller.
+#[ic_cdk::update]
+fn set_my_notification_preferences(preferences: NotificationPreferences) -> Result<Student, Error> {
+    let _perf = perf::measure("set_my_notification_preferences");
+    let student = caller_student(&CanisterEnv)?;
+    _set_notification_preferences(&CanisterEnv, student.id, preferences)
+}
+
+// Internal function to store a student's preferences, taking the timestamp from `clock`.
+pub(crate) fn _set_notification_preferences(
+    clock: &impl Clock,
+    student_id: u64,
+    preferences: NotificationPreferences,
+) -> Result<Student, Error> {
+    crud::update(student_id, |student: &mut Student| {
+        student.notification_preferences = Some(preferences);
+        student.updated_at = Some(clock.now());
+    })
+}
+
+// Retrieve notifications waiting to be delivered, oldest first.
+#[ic_cdk::query]
+fn get_pending_notifications(limit: Option<u32>) -> Result<Vec<Notification>, Error> {
+    require_admin()?;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+    Ok(NOTIFICATION_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, notification)| notification)
+            .filter(|notification| notification.sent_at.is_none())
+            .take(limit)
+            .collect()
+    }))
+}
+
+// Record that the relay delivered notifications. Returns how many were newly marked.
+#[ic_cdk::update]
+fn mark_notifications_sent(ids: Vec<u64>) -> Result<u64, Error> {
+    let _perf = perf::measure("mark_notifications_sent");
+    require_admin()?;
+    let now = CanisterEnv.now();
+    Ok(NOTIFICATION_STORAGE.with(|s| {
+        let mut notifications = s.borrow_mut();
+        let mut marked = 0;
+        for id in ids {
+            let Some(mut notification) = notifications.get(&id) else {
+                continue;
+            };
+            if notification.sent_at.is_none() {
+                notification.sent_at = Some(now);
+                notifications.insert(id, notification);
+                marked += 1;
+            }
+        }
+        marked
+    }))
+}
+
+// Queue a message for a student on `channel`, addressed to their preferred contact for it.
+// Nothing is queued for students without Communication consent or without a contact on the
+// channel.
+pub(crate) fn dispatch(
+    now: u64,
+    student: &Student,
+    channel: ContactKind,
+    subject: String,
+    body: String,
+) -> Option<Notification> {
+    if !has_consent(student.id, ConsentKind::Communication) {
+        return None;
+    }
+    let contact = student.preferred_contact(channel)?;
+    let notification = Notification {
+        id: next_id(Entity::Notification).ok()?,
+        student_id: student.id,
+        channel,
+        to: contact.value.clone(),
+        subject,
+        body,
+        created_at: now,
+        sent_at: None,
+    };
+    NOTIFICATION_STORAGE.with(|s| s.borrow_mut().insert(notification.id, notification.clone()));
+    Some(notification)
+}
+
+// Hook run when a book is checked out: email the student a receipt if they asked for them.
+pub(crate) fn checkout_receipt(now: u64, loan: &Loan, book: &Book) -> Option<Notification> {
+    let student = crud::get::<Student>(&loan.student_id)?;
+    if !student
+        .notification_preferences
+        .is_some_and(|preferences| preferences.checkout_receipts)
+    {
+        return None;
+    }
+    let due = loan
+        .due_date
+        .map_or_else(|| "no due date".to_string(), format_date);
+    let body = format!(
+        "You checked out \"{}\" by {} on {} (loan {}). Please return it by {}.\n\n\
+         Need longer? Ask for an extension from your library account before the due date.",
+        book.title,
+        book.author,
+        format_date(loan.loan_date),
+        loan.id,
+        due
+    );
+    let notification = dispatch(
+        now,
+        &student,
+        ContactKind::Email,
+        format!("Receipt: {}", book.title),
+        body,
+    );
+    match &notification {
+        Some(notification) => log::info(format!(
+            "Queued checkout receipt notification id={} for loan id={}.",
+            notification.id, loan.id
+        )),
+        None => log::warn(format!(
+            "Could not queue a checkout receipt for loan id={}: student id={} has no email or \
+             hasn't consented to communication.",
+            loan.id, student.id
+        )),
+    }
+    notification
+}
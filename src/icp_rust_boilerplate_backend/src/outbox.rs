@@ -0,0 +1,229 @@
+use crate::auth::require_admin;
+use crate::cover;
+use crate::dead_letter;
+use crate::env::{CanisterEnv, Clock};
+use crate::id_counter::{next_id, Entity};
+use crate::log;
+use crate::pagination::{self, Page};
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::{Error, OUTBOX_SCHEDULE, OUTBOX_STORAGE};
+
+// Wait before retrying a failed item: one minute in nanoseconds, doubling with every further
+// failure.
+const BASE_RETRY_DELAY: u64 = 60 * 1_000_000_000;
+
+// Longest wait between two attempts: six hours in nanoseconds.
+const MAX_RETRY_DELAY: u64 = 6 * 60 * 60 * 1_000_000_000;
+
+// Attempts after which the worker gives up on an item.
+pub const MAX_ATTEMPTS: u32 = 8;
+
+// Most items a single worker pass starts.
+const MAX_OUTBOX_BATCH: usize = 5;
+
+// Outbound work done through HTTPS outcalls.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub enum OutboxTask {
+    // Fetch a book's cover from its cover_url and cache it in the canister.
+    FetchCover { book_id: u64 },
+}
+
+// Define the OutboxItem struct to represent outbound work queued by a write. It stays in the
+// outbox until it succeeds, or until the worker gives up on it and moves it to the dead letters.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct OutboxItem {
+    pub id: u64,
+    pub task: OutboxTask,
+    pub attempts: u32,
+    // When the worker next tries the item; None once it is a dead letter.
+    pub next_attempt_at: Option<u64>,
+    pub last_attempt_at: Option<u64>,
+    pub last_error: Option<Error>,
+    pub created_at: u64,
+}
+
+// Store OutboxItem as Candid, bounded to 2048 bytes.
+candid_storable!(OutboxItem, max_size = 2048);
+
+// Number of items a worker pass tried, and how those attempts ended. Items neither delivered
+// nor failed are waiting for a retry.
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+pub struct OutboxRun {
+    pub attempted: u32,
+    pub delivered: u32,
+    // Items the worker gave up on in this pass.
+    pub failed: u32,
+}
+
+// What became of one attempt.
+enum Outcome {
+    Delivered,
+    Retrying,
+    Failed,
+}
+
+// Retrieve the outbox one page at a time, oldest item first.
+#[ic_cdk::query]
+fn get_outbox(cursor: Option<u64>, limit: Option<u32>) -> Result<Page<OutboxItem>, Error> {
+    require_admin()?;
+    Ok(pagination::paginate(&OUTBOX_STORAGE, cursor, limit))
+}
+
+// Try the items that are due now instead of waiting for the periodic jobs.
+#[ic_cdk::update]
+async fn run_outbox() -> Result<OutboxRun, Error> {
+    let _perf = perf::measure("run_outbox");
+    require_admin()?;
+    Ok(_attempt_all(claim_due(CanisterEnv.now())).await)
+}
+
+// Internal function to queue outbound work, due straight away. The write that queues it
+// doesn't wait for it, and failures are retried with exponential backoff.
+pub(crate) fn enqueue(now: u64, task: OutboxTask) -> Result<OutboxItem, Error> {
+    let item = OutboxItem {
+        id: next_id(Entity::OutboxItem)?,
+        task,
+        attempts: 0,
+        next_attempt_at: Some(now),
+        last_attempt_at: None,
+        last_error: None,
+        created_at: now,
+    };
+    schedule(&item);
+    Ok(item)
+}
+
+// Put a dead letter back in the outbox with a fresh set of attempts, due straight away.
+pub(crate) fn requeue(now: u64, mut item: OutboxItem) -> OutboxItem {
+    item.attempts = 0;
+    item.next_attempt_at = Some(now);
+    schedule(&item);
+    item
+}
+
+// Store an item and its place in the schedule.
+fn schedule(item: &OutboxItem) {
+    OUTBOX_STORAGE.with(|s| s.borrow_mut().insert(item.id, item.clone()));
+    if let Some(at) = item.next_attempt_at {
+        OUTBOX_SCHEDULE.with(|s| s.borrow_mut().insert((at, item.id), ()));
+    }
+}
+
+// Hook run from the periodic jobs: start the items that are due without waiting for them.
+pub(crate) fn run_due(clock: &impl Clock) {
+    let ids = claim_due(clock.now());
+    if !ids.is_empty() {
+        ic_cdk::spawn(async move {
+            _attempt_all(ids).await;
+        });
+    }
+}
+
+// Take up to a batch of due items, counting the attempt and scheduling the next one as if it
+// will fail. An item is then never started twice, and one whose call traps is still retried.
+// Items out of attempts are moved to the dead letters instead.
+fn claim_due(now: u64) -> Vec<u64> {
+    let due: Vec<(u64, u64)> = OUTBOX_SCHEDULE.with(|s| {
+        s.borrow()
+            .range(..=(now, u64::MAX))
+            .take(MAX_OUTBOX_BATCH)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    let mut claimed = Vec::new();
+    for key in due {
+        OUTBOX_SCHEDULE.with(|s| s.borrow_mut().remove(&key));
+        let Some(mut item) = OUTBOX_STORAGE.with(|s| s.borrow().get(&key.1)) else {
+            continue;
+        };
+        item.next_attempt_at = None;
+        if item.attempts >= MAX_ATTEMPTS {
+            give_up(now, item);
+            continue;
+        }
+        item.attempts += 1;
+        item.last_attempt_at = Some(now);
+        item.next_attempt_at = Some(now.saturating_add(retry_delay(item.attempts)));
+        schedule(&item);
+        claimed.push(item.id);
+    }
+    claimed
+}
+
+// Internal function to try claimed items one after another and record how each went.
+pub(crate) async fn _attempt_all(ids: Vec<u64>) -> OutboxRun {
+    let mut run = OutboxRun::default();
+    for id in ids {
+        let Some(item) = OUTBOX_STORAGE.with(|s| s.borrow().get(&id)) else {
+            continue;
+        };
+        let result = match item.task {
+            OutboxTask::FetchCover { book_id } => cover::_fetch_cover(book_id).await.map(|_| ()),
+        };
+        run.attempted += 1;
+        match finish(CanisterEnv.now(), id, result) {
+            Outcome::Delivered => run.delivered += 1,
+            Outcome::Retrying => {}
+            Outcome::Failed => run.failed += 1,
+        }
+    }
+    run
+}
+
+// Record the result of an attempt. Done items leave the outbox, as do items whose record was
+// deleted meanwhile. Unavailable is worth retrying until the attempts run out; any other error
+// won't go away by itself, so the worker gives up straight away.
+fn finish(now: u64, id: u64, result: Result<(), Error>) -> Outcome {
+    let Some(mut item) = OUTBOX_STORAGE.with(|s| s.borrow().get(&id)) else {
+        return Outcome::Delivered;
+    };
+    let error = match result {
+        Ok(()) | Err(Error::NotFound { .. }) => {
+            remove(&item);
+            return Outcome::Delivered;
+        }
+        Err(error) => error,
+    };
+    let retry = matches!(error, Error::Unavailable { .. }) && item.attempts < MAX_ATTEMPTS;
+    item.last_error = Some(error);
+    if retry {
+        log::warn(format!(
+            "Outbox item id={} failed on attempt {} of {}; retrying later.",
+            item.id, item.attempts, MAX_ATTEMPTS
+        ));
+        OUTBOX_STORAGE.with(|s| s.borrow_mut().insert(item.id, item));
+        return Outcome::Retrying;
+    }
+    if let Some(at) = item.next_attempt_at.take() {
+        OUTBOX_SCHEDULE.with(|s| s.borrow_mut().remove(&(at, item.id)));
+    }
+    give_up(now, item);
+    Outcome::Failed
+}
+
+// Delete an item and its place in the schedule.
+fn remove(item: &OutboxItem) {
+    if let Some(at) = item.next_attempt_at {
+        OUTBOX_SCHEDULE.with(|s| s.borrow_mut().remove(&(at, item.id)));
+    }
+    OUTBOX_STORAGE.with(|s| s.borrow_mut().remove(&item.id));
+}
+
+// Stop trying an item, already out of the schedule, and move it to the dead letters for an
+// admin to look at.
+fn give_up(now: u64, item: OutboxItem) {
+    log::error(format!(
+        "Gave up on outbox item id={} after {} attempts; moved it to the dead letters.",
+        item.id, item.attempts
+    ));
+    OUTBOX_STORAGE.with(|s| s.borrow_mut().remove(&item.id));
+    dead_letter::bury(now, item);
+}
+
+// How long to wait after the `attempts`-th attempt before the next one.
+fn retry_delay(attempts: u32) -> u64 {
+    BASE_RETRY_DELAY
+        .saturating_mul(1 << attempts.saturating_sub(1).min(32))
+        .min(MAX_RETRY_DELAY)
+}
@@ -0,0 +1,54 @@
+use ic_stable_structures::{BoundedStorable, StableBTreeMap};
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::Memory;
+
+// Instructions a paginated query may use before it stops early; the hard query limit is 5 billion.
+const QUERY_INSTRUCTION_BUDGET: u64 = 4_000_000_000;
+
+// Page size used when the caller doesn't ask for one.
+pub const DEFAULT_PAGE_SIZE: u32 = 50;
+
+// Largest page a caller may request.
+pub const MAX_PAGE_SIZE: u32 = 500;
+
+// One page of results; pass `next_cursor` back to continue, it is None on the last page.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<u64>,
+}
+
+// Whether the current call has used up its instruction budget. Native unit tests have no
+// instruction counter, so their budget never runs out.
+pub(crate) fn budget_exhausted() -> bool {
+    cfg!(not(test)) && ic_cdk::api::performance_counter(0) > QUERY_INSTRUCTION_BUDGET
+}
+
+// Read up to `limit` records starting at key `cursor`, stopping early (with a cursor to resume
+// from) if the instruction budget runs out, so large collections never trap the query.
+pub(crate) fn paginate<V>(
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Page<V>
+where
+    V: BoundedStorable,
+{
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+
+    storage.with(|map| {
+        let mut items = Vec::new();
+        let mut next_cursor = None;
+        for (key, value) in map.borrow().range(cursor.unwrap_or(0)..) {
+            // Always return at least one record so that every call makes progress.
+            if items.len() >= limit || (!items.is_empty() && budget_exhausted()) {
+                next_cursor = Some(key);
+                break;
+            }
+            items.push(value);
+        }
+        Page { items, next_cursor }
+    })
+}
@@ -0,0 +1,136 @@
+use std::cmp::Reverse;
+
+use crate::auth::require_admin;
+use crate::storable::{candid_storable, IndexString};
+use crate::{Error, PERF_STATS};
+
+// Buckets of the histograms: bucket 0 counts zeros and bucket i values from 2^(i-1) up to
+// 2^i - 1, with the last bucket also taking everything larger.
+const BUCKETS: usize = 64;
+
+// Define the EndpointStats struct to accumulate how expensive one method's calls were. Values
+// are kept as histograms with power-of-two buckets, so percentiles are exact to within a factor
+// of two without storing every call.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct EndpointStats {
+    pub calls: u64,
+    pub instructions: Vec<u64>,
+    pub max_instructions: u64,
+    // Wall-clock time in nanoseconds from the call starting to its reply, which only differs
+    // from zero for methods that await other canisters.
+    pub durations: Vec<u64>,
+    pub max_duration: u64,
+}
+
+// Store EndpointStats as Candid, bounded to 2048 bytes.
+candid_storable!(EndpointStats, max_size = 2048);
+
+// One method's statistics, with percentiles read off the histograms as bucket upper bounds.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub struct EndpointPerformance {
+    pub method: String,
+    pub calls: u64,
+    pub p50_instructions: u64,
+    pub p95_instructions: u64,
+    pub max_instructions: u64,
+    pub p50_duration: u64,
+    pub p95_duration: u64,
+    pub max_duration: u64,
+}
+
+// Records a call's cost when dropped; hold one for the whole body of an update method. Queries
+// aren't measured, since whatever a query writes is discarded when it returns.
+pub(crate) struct Measurement {
+    method: &'static str,
+    started_at: u64,
+}
+
+impl Drop for Measurement {
+    fn drop(&mut self) {
+        // Counter 1 counts the whole call context, including the parts before any awaits.
+        let instructions = ic_cdk::api::performance_counter(1);
+        let duration = ic_cdk::api::time().saturating_sub(self.started_at);
+        record(self.method, instructions, duration);
+    }
+}
+
+// Start measuring a call to `method`.
+pub(crate) fn measure(method: &'static str) -> Measurement {
+    Measurement {
+        method,
+        started_at: ic_cdk::api::time(),
+    }
+}
+
+// Retrieve the statistics of every update method called since they were last reset, most
+// expensive first.
+#[ic_cdk::query]
+fn get_performance_stats() -> Result<Vec<EndpointPerformance>, Error> {
+    require_admin()?;
+    let mut stats: Vec<EndpointPerformance> = PERF_STATS.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(method, stats)| EndpointPerformance {
+                method: method.0,
+                calls: stats.calls,
+                p50_instructions: percentile(&stats.instructions, stats.calls, 50),
+                p95_instructions: percentile(&stats.instructions, stats.calls, 95),
+                max_instructions: stats.max_instructions,
+                p50_duration: percentile(&stats.durations, stats.calls, 50),
+                p95_duration: percentile(&stats.durations, stats.calls, 95),
+                max_duration: stats.max_duration,
+            })
+            .collect()
+    });
+    stats.sort_by_key(|stats| Reverse(stats.p95_instructions));
+    Ok(stats)
+}
+
+// Discard the statistics gathered so far.
+#[ic_cdk::update]
+fn reset_performance_stats() -> Result<(), Error> {
+    require_admin()?;
+    PERF_STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        let methods: Vec<IndexString> = stats.iter().map(|(method, _)| method).collect();
+        for method in methods {
+            stats.remove(&method);
+        }
+    });
+    Ok(())
+}
+
+// Helper function to add one call to a method's statistics.
+fn record(method: &'static str, instructions: u64, duration: u64) {
+    let key = IndexString(method.to_string());
+    PERF_STATS.with(|s| {
+        let mut all = s.borrow_mut();
+        let mut stats = all.get(&key).unwrap_or_default();
+        stats.instructions.resize(BUCKETS, 0);
+        stats.durations.resize(BUCKETS, 0);
+        stats.calls += 1;
+        stats.instructions[bucket(instructions)] += 1;
+        stats.max_instructions = stats.max_instructions.max(instructions);
+        stats.durations[bucket(duration)] += 1;
+        stats.max_duration = stats.max_duration.max(duration);
+        all.insert(key, stats);
+    });
+}
+
+// The bucket counting `value`: the number of bits needed to write it, capped to the last one.
+fn bucket(value: u64) -> usize {
+    ((u64::BITS - value.leading_zeros()) as usize).min(BUCKETS - 1)
+}
+
+// The upper bound of the bucket holding the `pct`-th percentile of `calls` values.
+fn percentile(histogram: &[u64], calls: u64, pct: u64) -> u64 {
+    let rank = (calls * pct).div_ceil(100).max(1);
+    let mut seen = 0;
+    for (i, count) in histogram.iter().enumerate() {
+        seen += count;
+        if seen >= rank {
+            return if i == 0 { 0 } else { (1u64 << i) - 1 };
+        }
+    }
+    0
+}
@@ -0,0 +1,257 @@
+use candid::Principal;
+use sha2::{Digest, Sha256};
+
+use crate::auth::require_admin;
+use crate::crud;
+use crate::digital_asset::{EbookChunk, CHUNK_SIZE};
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::student::{require_student_access, Student};
+use crate::{Error, STUDENT_PHOTO_CHUNK_STORAGE, STUDENT_PHOTO_STORAGE};
+
+// Largest profile photo accepted.
+pub const MAX_PHOTO_SIZE: u64 = 512 * 1024;
+
+// Image formats accepted as photos, with the bytes their files start with. WebP files start
+// with "RIFF", a 4-byte length, then "WEBP"; only the first marker is checked here and the
+// second in `has_signature`.
+const PHOTO_FORMATS: &[(&str, &[u8])] = &[
+    ("image/jpeg", &[0xFF, 0xD8, 0xFF]),
+    (
+        "image/png",
+        &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A],
+    ),
+    ("image/webp", b"RIFF"),
+];
+
+// Define the StudentPhoto struct describing a student's profile photo. The image itself is
+// kept in CHUNK_SIZE pieces alongside it.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct StudentPhoto {
+    pub student_id: u64,
+    pub content_type: String,
+    pub size: u64,
+    pub chunk_count: u32,
+    // Hex-encoded SHA-256 of the image, set once the upload has been finished.
+    pub sha256: Option<String>,
+    pub uploaded_by: Principal,
+    pub updated_at: u64,
+}
+
+// Store StudentPhoto as Candid, bounded to 512 bytes.
+candid_storable!(StudentPhoto, max_size = 512);
+
+// Retrieve the description of a student's photo, for the student, their guardians, their
+// teachers and admins.
+#[ic_cdk::query]
+fn get_student_photo(student_id: u64) -> Result<StudentPhoto, Error> {
+    require_student_access(&CanisterEnv, student_id)?;
+    _get_photo(student_id).ok_or_else(|| Error::NotFound {
+        msg: format!("No photo for student id={}.", student_id),
+    })
+}
+
+// Retrieve one chunk of a student's finished photo, for whoever may see its description.
+#[ic_cdk::query]
+fn get_student_photo_chunk(student_id: u64, index: u32) -> Result<Vec<u8>, Error> {
+    require_student_access(&CanisterEnv, student_id)?;
+    let photo = _get_photo(student_id)
+        .filter(|photo| photo.sha256.is_some())
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("No photo for student id={}.", student_id),
+        })?;
+    if index >= photo.chunk_count {
+        return Err(Error::InvalidInput {
+            msg: format!("The photo has {} chunks.", photo.chunk_count),
+        });
+    }
+    STUDENT_PHOTO_CHUNK_STORAGE
+        .with(|s| s.borrow().get(&(student_id, index)))
+        .map(|chunk| chunk.0)
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Chunk {} of the photo is missing.", index),
+        })
+}
+
+// Start (or restart) the upload of a student's photo, discarding any previous one.
+#[ic_cdk::update]
+fn start_student_photo_upload(
+    student_id: u64,
+    content_type: String,
+    size: u64,
+) -> Result<StudentPhoto, Error> {
+    let _perf = perf::measure("start_student_photo_upload");
+    require_admin()?;
+    _start_photo_upload(&CanisterEnv, student_id, content_type, size)
+}
+
+// Internal function to start a photo upload on behalf of `env`'s caller.
+pub(crate) fn _start_photo_upload(
+    env: &(impl Clock + CallerProvider),
+    student_id: u64,
+    content_type: String,
+    size: u64,
+) -> Result<StudentPhoto, Error> {
+    crud::find::<Student>(student_id)?;
+    let content_type = content_type.trim().to_ascii_lowercase();
+    if !PHOTO_FORMATS.iter().any(|(name, _)| *name == content_type) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A photo must be one of {}.",
+                PHOTO_FORMATS
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        });
+    }
+    if size == 0 || size > MAX_PHOTO_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!("A photo must be between 1 and {} bytes.", MAX_PHOTO_SIZE),
+        });
+    }
+
+    remove_photo(student_id);
+    let photo = StudentPhoto {
+        student_id,
+        content_type,
+        size,
+        chunk_count: size.div_ceil(CHUNK_SIZE) as u32,
+        sha256: None,
+        uploaded_by: env.caller(),
+        updated_at: env.now(),
+    };
+    do_insert(&photo);
+    Ok(photo)
+}
+
+// Store one chunk of an in-progress photo upload.
+#[ic_cdk::update]
+fn upload_student_photo_chunk(student_id: u64, index: u32, data: Vec<u8>) -> Result<(), Error> {
+    let _perf = perf::measure("upload_student_photo_chunk");
+    require_admin()?;
+    let photo = match _get_photo(student_id) {
+        Some(photo) if photo.sha256.is_none() => photo,
+        Some(_) => {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "The photo upload for student id={} is already finished.",
+                    student_id
+                ),
+            })
+        }
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("No photo upload in progress for student id={}.", student_id),
+            })
+        }
+    };
+    if index >= photo.chunk_count {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Chunk index {} is out of range; the photo has {} chunks.",
+                index, photo.chunk_count
+            ),
+        });
+    }
+    let expected_len = (photo.size - index as u64 * CHUNK_SIZE).min(CHUNK_SIZE);
+    if data.len() as u64 != expected_len {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Chunk {} must be {} bytes long, got {}.",
+                index,
+                expected_len,
+                data.len()
+            ),
+        });
+    }
+    STUDENT_PHOTO_CHUNK_STORAGE
+        .with(|s| s.borrow_mut().insert((student_id, index), EbookChunk(data)));
+    Ok(())
+}
+
+// Finish a photo upload once every chunk is present and the image matches its content type.
+#[ic_cdk::update]
+fn finish_student_photo_upload(student_id: u64) -> Result<StudentPhoto, Error> {
+    let _perf = perf::measure("finish_student_photo_upload");
+    require_admin()?;
+    _finish_photo_upload(&CanisterEnv, student_id)
+}
+
+// Internal function to finish a photo upload, taking the timestamp from `clock`.
+pub(crate) fn _finish_photo_upload(
+    clock: &impl Clock,
+    student_id: u64,
+) -> Result<StudentPhoto, Error> {
+    let mut photo = _get_photo(student_id).ok_or_else(|| Error::NotFound {
+        msg: format!("No photo upload in progress for student id={}.", student_id),
+    })?;
+    let image = read_image(&photo).ok_or_else(|| Error::InvalidInput {
+        msg: format!(
+            "The photo for student id={} has not been fully uploaded.",
+            student_id
+        ),
+    })?;
+    if !has_signature(&photo.content_type, &image) {
+        return Err(Error::InvalidInput {
+            msg: format!("The uploaded file is not a {} image.", photo.content_type),
+        });
+    }
+    photo.sha256 = Some(hex::encode(Sha256::digest(&image)));
+    photo.updated_at = clock.now();
+    do_insert(&photo);
+    Ok(photo)
+}
+
+// Delete a student's photo.
+#[ic_cdk::update]
+fn delete_student_photo(student_id: u64) -> Result<StudentPhoto, Error> {
+    let _perf = perf::measure("delete_student_photo");
+    require_admin()?;
+    remove_photo(student_id).ok_or_else(|| Error::NotFound {
+        msg: format!("No photo for student id={}.", student_id),
+    })
+}
+
+// Internal function to look up a student's photo.
+fn _get_photo(student_id: u64) -> Option<StudentPhoto> {
+    STUDENT_PHOTO_STORAGE.with(|s| s.borrow().get(&student_id))
+}
+
+// Internal function to read a photo's image, or None while chunks are missing.
+fn read_image(photo: &StudentPhoto) -> Option<Vec<u8>> {
+    let mut image = Vec::with_capacity(photo.size as usize);
+    for index in 0..photo.chunk_count {
+        let chunk =
+            STUDENT_PHOTO_CHUNK_STORAGE.with(|s| s.borrow().get(&(photo.student_id, index)));
+        image.extend_from_slice(&chunk?.0);
+    }
+    Some(image)
+}
+
+// Helper function to drop a student's photo and its chunks, returning the photo removed.
+pub(crate) fn remove_photo(student_id: u64) -> Option<StudentPhoto> {
+    let photo = STUDENT_PHOTO_STORAGE.with(|s| s.borrow_mut().remove(&student_id))?;
+    STUDENT_PHOTO_CHUNK_STORAGE.with(|s| {
+        let mut chunks = s.borrow_mut();
+        for index in 0..photo.chunk_count {
+            chunks.remove(&(student_id, index));
+        }
+    });
+    Some(photo)
+}
+
+// Whether `image` starts the way files of `content_type` do.
+fn has_signature(content_type: &str, image: &[u8]) -> bool {
+    let Some((_, magic)) = PHOTO_FORMATS.iter().find(|(name, _)| *name == content_type) else {
+        return false;
+    };
+    image.starts_with(magic) && (content_type != "image/webp" || image.get(8..12) == Some(b"WEBP"))
+}
+
+// Helper function to store a photo's description.
+fn do_insert(photo: &StudentPhoto) {
+    STUDENT_PHOTO_STORAGE.with(|s| s.borrow_mut().insert(photo.student_id, photo.clone()));
+}
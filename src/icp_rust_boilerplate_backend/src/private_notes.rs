@@ -0,0 +1,119 @@
+use candid::Principal;
+
+use crate::auth::require_admin;
+use crate::consent::{require_consent, ConsentKind};
+use crate::crud;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::log;
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::student::Student;
+use crate::vetkd;
+use crate::{Error, PRIVATE_NOTES_STORAGE};
+
+// vetKD context the notes keys are derived in, so they can't be confused with other keys the
+// canister derives.
+const NOTES_CONTEXT: &[u8] = b"student-private-notes";
+
+// Longest ciphertext accepted, in bytes.
+const MAX_NOTES_BYTES: usize = 4096;
+
+// Notes about a student, encrypted by the librarian's client before they reach the canister.
+// The canister never sees the plaintext; it only hands authorized librarians the key to
+// decrypt it, derived per student with vetKD.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct PrivateNotes {
+    pub student_id: u64,
+    pub ciphertext: Vec<u8>,
+    pub updated_by: Principal,
+    pub updated_at: u64,
+}
+
+// Store PrivateNotes as Candid, bounded to fit the largest ciphertext.
+candid_storable!(PrivateNotes, max_size = 4352);
+
+// Retrieve a student's encrypted notes, if any.
+#[ic_cdk::query]
+fn get_private_notes(student_id: u64) -> Result<Option<PrivateNotes>, Error> {
+    require_admin()?;
+    Ok(PRIVATE_NOTES_STORAGE.with(|s| s.borrow().get(&student_id)))
+}
+
+// Replace a student's encrypted notes, or remove them with an empty ciphertext. Notes are only
+// kept for students who gave SensitiveNotes consent.
+#[ic_cdk::update]
+fn set_private_notes(student_id: u64, ciphertext: Vec<u8>) -> Result<Option<PrivateNotes>, Error> {
+    let _perf = perf::measure("set_private_notes");
+    require_admin()?;
+    _set_private_notes(&CanisterEnv, student_id, ciphertext)
+}
+
+// Internal function to store notes written by `env`'s caller.
+pub(crate) fn _set_private_notes(
+    env: &(impl Clock + CallerProvider),
+    student_id: u64,
+    ciphertext: Vec<u8>,
+) -> Result<Option<PrivateNotes>, Error> {
+    crud::find::<Student>(student_id)?;
+    if !ciphertext.is_empty() {
+        require_consent(student_id, ConsentKind::SensitiveNotes)?;
+    }
+    if ciphertext.len() > MAX_NOTES_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!("Notes can be at most {} bytes.", MAX_NOTES_BYTES),
+        });
+    }
+    if ciphertext.is_empty() {
+        remove_private_notes(student_id);
+        return Ok(None);
+    }
+
+    let notes = PrivateNotes {
+        student_id,
+        ciphertext,
+        updated_by: env.caller(),
+        updated_at: env.now(),
+    };
+    PRIVATE_NOTES_STORAGE.with(|s| s.borrow_mut().insert(student_id, notes.clone()));
+    log::info(format!(
+        "{} updated the private notes of student id={}.",
+        notes.updated_by, student_id
+    ));
+    Ok(Some(notes))
+}
+
+// Retrieve the key for a student's notes, encrypted to the caller's transport public key.
+// Every request is logged, since it grants access to the notes.
+#[ic_cdk::update]
+async fn get_private_notes_key(
+    student_id: u64,
+    transport_public_key: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    let _perf = perf::measure("get_private_notes_key");
+    require_admin()?;
+    crud::find::<Student>(student_id)?;
+    let key = vetkd::derive_encrypted_key(
+        NOTES_CONTEXT,
+        student_id.to_be_bytes().to_vec(),
+        transport_public_key,
+    )
+    .await?;
+    log::info(format!(
+        "{} obtained the private notes key of student id={}.",
+        ic_cdk::caller(),
+        student_id
+    ));
+    Ok(key)
+}
+
+// Retrieve the vetKD public key that clients verify derived notes keys against.
+#[ic_cdk::update]
+async fn get_private_notes_public_key() -> Result<Vec<u8>, Error> {
+    let _perf = perf::measure("get_private_notes_public_key");
+    vetkd::public_key(NOTES_CONTEXT).await
+}
+
+// Helper function to drop a student's notes.
+pub(crate) fn remove_private_notes(student_id: u64) {
+    PRIVATE_NOTES_STORAGE.with(|s| s.borrow_mut().remove(&student_id));
+}
@@ -0,0 +1,361 @@
+use candid::Principal;
+
+use crate::auth::{self, require_admin};
+use crate::book::Book;
+use crate::catalog::{self, CatalogConfig};
+use crate::crud::{self, RecordKind};
+use crate::date::DAY;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::factory;
+use crate::feature::{_set_feature_flag, Feature};
+use crate::id_counter::{next_id, Entity};
+use crate::loan::Loan;
+use crate::log;
+use crate::perf;
+use crate::retention::{self, RetentionPolicy};
+use crate::snapshot;
+use crate::storable::candid_storable;
+use crate::storage_report;
+use crate::student::Student;
+use crate::{Error, PROPOSAL_STORAGE};
+
+// How long a proposal waits for its second approval: 7 days in nanoseconds.
+pub const PROPOSAL_LIFETIME: u64 = 7 * DAY;
+
+// Most records a single bulk delete may remove.
+const MAX_BULK_DELETE: usize = 100;
+
+// A destructive administrative action or config change that needs a second administrator's
+// approval.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub enum AdminAction {
+    SetStorageCapacity {
+        pages: u64,
+    },
+    AddAdmin {
+        principal: Principal,
+    },
+    RemoveAdmin {
+        principal: Principal,
+    },
+    // With `cascade`, references to the records are closed first instead of blocking them.
+    DeleteRecords {
+        kind: RecordKind,
+        ids: Vec<u64>,
+        cascade: bool,
+    },
+    RollbackToSnapshot {
+        snapshot_id: u64,
+    },
+    DeleteSnapshot {
+        snapshot_id: u64,
+    },
+    SetRetentionPolicy {
+        policy: RetentionPolicy,
+    },
+    SetLogCapacity {
+        capacity: u64,
+    },
+    SetFeatureFlag {
+        feature: Feature,
+        enabled: bool,
+    },
+    SetCatalogConfig {
+        config: CatalogConfig,
+    },
+    ClearSchoolWasm,
+}
+
+// Where a proposal is in its lifecycle.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub enum ProposalStatus {
+    Pending,
+    Executed,
+    Rejected,
+    Expired,
+}
+
+// Define the Proposal struct to track an action awaiting approval.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct Proposal {
+    pub id: u64,
+    pub action: AdminAction,
+    pub proposed_by: Principal,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub status: ProposalStatus,
+    // Administrator who approved or rejected the proposal.
+    pub decided_by: Option<Principal>,
+    pub decided_at: Option<u64>,
+}
+
+// Store Proposal as Candid, bounded to 2048 bytes to fit a full bulk delete.
+candid_storable!(Proposal, max_size = 2048);
+
+// Retrieve every proposal, oldest first.
+#[ic_cdk::query]
+fn list_proposals() -> Result<Vec<Proposal>, Error> {
+    require_admin()?;
+    let now = CanisterEnv.now();
+    Ok(PROPOSAL_STORAGE.with(|proposals| {
+        proposals
+            .borrow()
+            .iter()
+            .map(|(_, proposal)| with_expiry(proposal, now))
+            .collect()
+    }))
+}
+
+// Propose a destructive action; another administrator must approve it before it runs.
+#[ic_cdk::update]
+fn propose_admin_action(action: AdminAction) -> Result<Proposal, Error> {
+    let _perf = perf::measure("propose_admin_action");
+    require_admin()?;
+    _propose_admin_action(&CanisterEnv, action)
+}
+
+// Internal function to record a proposal from `env`'s caller.
+pub(crate) fn _propose_admin_action(
+    env: &(impl Clock + CallerProvider),
+    action: AdminAction,
+) -> Result<Proposal, Error> {
+    validate(&action)?;
+    let now = env.now();
+    let proposal = Proposal {
+        id: next_id(Entity::Proposal)?,
+        action,
+        proposed_by: env.caller(),
+        created_at: now,
+        expires_at: now.saturating_add(PROPOSAL_LIFETIME),
+        status: ProposalStatus::Pending,
+        decided_by: None,
+        decided_at: None,
+    };
+    PROPOSAL_STORAGE.with(|service| service.borrow_mut().insert(proposal.id, proposal.clone()));
+    Ok(proposal)
+}
+
+// Approve and run a pending proposal. The approver must not be the proposer.
+#[ic_cdk::update]
+fn approve_proposal(id: u64) -> Result<Proposal, Error> {
+    let _perf = perf::measure("approve_proposal");
+    require_admin()?;
+    _approve_proposal(&CanisterEnv, id)
+}
+
+// Internal function to approve a proposal on behalf of `env`'s caller.
+pub(crate) fn _approve_proposal(
+    env: &(impl Clock + CallerProvider),
+    id: u64,
+) -> Result<Proposal, Error> {
+    let mut proposal = pending(env, id)?;
+    if proposal.proposed_by == env.caller() {
+        return Err(Error::Unauthorized {
+            msg: "A proposal must be approved by a different administrator.".to_string(),
+        });
+    }
+
+    // Re-check, since the state may have changed since the proposal was made.
+    validate(&proposal.action)?;
+    execute(env, &proposal)?;
+
+    log::warn(format!(
+        "{} approved proposal id={} from {}; it has been executed.",
+        env.caller(),
+        id,
+        proposal.proposed_by
+    ));
+    proposal.status = ProposalStatus::Executed;
+    proposal.decided_by = Some(env.caller());
+    proposal.decided_at = Some(env.now());
+    PROPOSAL_STORAGE.with(|service| service.borrow_mut().insert(id, proposal.clone()));
+    Ok(proposal)
+}
+
+// Reject a pending proposal. The proposer may use this to withdraw it.
+#[ic_cdk::update]
+fn reject_proposal(id: u64) -> Result<Proposal, Error> {
+    let _perf = perf::measure("reject_proposal");
+    require_admin()?;
+    _reject_proposal(&CanisterEnv, id)
+}
+
+// Internal function to reject a proposal on behalf of `env`'s caller.
+pub(crate) fn _reject_proposal(
+    env: &(impl Clock + CallerProvider),
+    id: u64,
+) -> Result<Proposal, Error> {
+    let mut proposal = pending(env, id)?;
+    log::info(format!("{} rejected proposal id={}.", env.caller(), id));
+    proposal.status = ProposalStatus::Rejected;
+    proposal.decided_by = Some(env.caller());
+    proposal.decided_at = Some(env.now());
+    PROPOSAL_STORAGE.with(|service| service.borrow_mut().insert(id, proposal.clone()));
+    Ok(proposal)
+}
+
+// Fetch a proposal that can still be decided.
+fn pending(clock: &impl Clock, id: u64) -> Result<Proposal, Error> {
+    let proposal = PROPOSAL_STORAGE
+        .with(|service| service.borrow().get(&id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("A proposal with id={} not found.", id),
+        })?;
+    match with_expiry(proposal, clock.now()) {
+        proposal if proposal.status == ProposalStatus::Pending => Ok(proposal),
+        _ => Err(Error::InvalidInput {
+            msg: format!("The proposal id={} is no longer pending.", id),
+        }),
+    }
+}
+
+// Report an undecided proposal past its expiry as Expired.
+fn with_expiry(mut proposal: Proposal, now: u64) -> Proposal {
+    if proposal.status == ProposalStatus::Pending && now > proposal.expires_at {
+        proposal.status = ProposalStatus::Expired;
+    }
+    proposal
+}
+
+// Check that an action could run right now.
+fn validate(action: &AdminAction) -> Result<(), Error> {
+    match action {
+        AdminAction::SetStorageCapacity { pages } => storage_report::validate_capacity(*pages),
+        AdminAction::AddAdmin { principal } => auth::validate_addition(principal),
+        AdminAction::RemoveAdmin { principal } => auth::validate_removal(principal).map(|_| ()),
+        AdminAction::DeleteRecords { ids, .. } => {
+            if ids.is_empty() || ids.len() > MAX_BULK_DELETE {
+                return Err(Error::InvalidInput {
+                    msg: format!("A bulk delete must name 1 to {} records.", MAX_BULK_DELETE),
+                });
+            }
+            Ok(())
+        }
+        AdminAction::RollbackToSnapshot { snapshot_id } => {
+            snapshot::validate_rollback(*snapshot_id)
+        }
+        AdminAction::DeleteSnapshot { snapshot_id } => snapshot::validate_deletion(*snapshot_id),
+        AdminAction::SetRetentionPolicy { policy } => retention::validate_policy(policy),
+        AdminAction::SetLogCapacity { capacity } => log::validate_capacity(*capacity),
+        AdminAction::SetFeatureFlag { .. }
+        | AdminAction::SetCatalogConfig { .. }
+        | AdminAction::ClearSchoolWasm => Ok(()),
+    }
+}
+
+// Run an approved proposal's action on behalf of `env`'s caller, the approver.
+fn execute(env: &(impl Clock + CallerProvider), proposal: &Proposal) -> Result<(), Error> {
+    match &proposal.action {
+        AdminAction::SetStorageCapacity { pages } => {
+            storage_report::set_storage_capacity(*pages).map(|_| ())
+        }
+        AdminAction::AddAdmin { principal } => {
+            auth::_add_admin(env, proposal.proposed_by, *principal).map(|_| ())
+        }
+        AdminAction::RemoveAdmin { principal } => auth::_remove_admin(principal).map(|_| ()),
+        AdminAction::DeleteRecords { kind, ids, cascade } => {
+            // Records that are already gone or still referenced are skipped, so a partly
+            // applied delete can't block the rest.
+            for id in ids {
+                let _ = match kind {
+                    RecordKind::Student => crud::delete::<Student>(*id, *cascade).map(|_| ()),
+                    RecordKind::Book => crud::delete::<Book>(*id, *cascade).map(|_| ()),
+                    RecordKind::Loan => crud::delete::<Loan>(*id, *cascade).map(|_| ()),
+                };
+            }
+            Ok(())
+        }
+        AdminAction::RollbackToSnapshot { snapshot_id } => snapshot::rollback(env, *snapshot_id),
+        AdminAction::DeleteSnapshot { snapshot_id } => snapshot::delete(*snapshot_id).map(|_| ()),
+        AdminAction::SetRetentionPolicy { policy } => {
+            retention::set_policy(policy.clone()).map(|_| ())
+        }
+        AdminAction::SetLogCapacity { capacity } => log::set_capacity(*capacity).map(|_| ()),
+        AdminAction::SetFeatureFlag { feature, enabled } => {
+            _set_feature_flag(env, *feature, *enabled);
+            Ok(())
+        }
+        AdminAction::SetCatalogConfig { config } => {
+            catalog::set_config(env.caller(), *config);
+            Ok(())
+        }
+        AdminAction::ClearSchoolWasm => {
+            factory::clear_wasm();
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{bootstrap, InitArgs};
+    use crate::env::TestEnv;
+    use crate::storable::StorablePrincipal;
+    use crate::ADMIN_STORAGE;
+
+    // 2023-11-14, an arbitrary day to start the clock on.
+    const START: u64 = 1_700_000_000_000_000_000;
+
+    // Whether `principal` is a recorded administrator; controllers can't be checked natively.
+    fn is_admin(principal: Principal) -> bool {
+        ADMIN_STORAGE.with(|s| s.borrow().contains_key(&StorablePrincipal(principal)))
+    }
+
+    // Install with administrators 1, 2 and 3, and have administrator 1 propose removing 3.
+    fn propose_removal() -> (TestEnv, Proposal) {
+        let env = TestEnv::new(START, 1);
+        let admins = vec![env.as_caller(2).caller, env.as_caller(3).caller];
+        bootstrap(&env, Some(InitArgs { admins }));
+        let action = AdminAction::RemoveAdmin {
+            principal: env.as_caller(3).caller,
+        };
+        let proposal = _propose_admin_action(&env, action).unwrap();
+        (env, proposal)
+    }
+
+    #[test]
+    fn proposal_runs_once_another_admin_approves() {
+        let (env, proposal) = propose_removal();
+        assert!(_approve_proposal(&env, proposal.id).is_err());
+
+        let approved = _approve_proposal(&env.as_caller(2), proposal.id).unwrap();
+        assert!(approved.status == ProposalStatus::Executed);
+        assert!(!is_admin(env.as_caller(3).caller));
+    }
+
+    #[test]
+    fn proposal_expires_after_its_lifetime() {
+        let (env, proposal) = propose_removal();
+        assert_eq!(proposal.expires_at, START + PROPOSAL_LIFETIME);
+
+        env.advance(PROPOSAL_LIFETIME);
+        let stored = PROPOSAL_STORAGE
+            .with(|s| s.borrow().get(&proposal.id))
+            .unwrap();
+        assert!(with_expiry(stored.clone(), env.now()).status == ProposalStatus::Pending);
+
+        env.advance(1);
+        assert!(with_expiry(stored, env.now()).status == ProposalStatus::Expired);
+        let approver = env.as_caller(2);
+        assert!(matches!(
+            _approve_proposal(&approver, proposal.id),
+            Err(Error::InvalidInput { .. })
+        ));
+        assert!(is_admin(env.as_caller(3).caller));
+    }
+
+    #[test]
+    fn config_change_waits_for_approval() {
+        let (env, _) = propose_removal();
+        let action = AdminAction::SetFeatureFlag {
+            feature: Feature::Fines,
+            enabled: false,
+        };
+        let proposal = _propose_admin_action(&env, action).unwrap();
+        assert!(crate::feature::is_enabled(Feature::Fines));
+
+        _approve_proposal(&env.as_caller(2), proposal.id).unwrap();
+        assert!(!crate::feature::is_enabled(Feature::Fines));
+    }
+}
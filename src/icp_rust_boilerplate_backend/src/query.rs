@@ -0,0 +1,286 @@
+use serde_json::Value;
+
+use crate::auth::require_admin;
+use crate::book::Book;
+use crate::catalog::require_catalog_access;
+use crate::crud::{Record, RecordKind};
+use crate::loan::Loan;
+use crate::pagination::{budget_exhausted, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::student::Student;
+use crate::Error;
+
+// A single field of a record. Nested values, such as a loan's status, are given as their JSON
+// text.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, PartialEq, PartialOrd)]
+pub enum FieldValue {
+    Null,
+    Bool(bool),
+    Nat(u64),
+    Text(String),
+}
+
+// How a filter compares a field with its value. Ordering comparisons only match values of the
+// same type; Contains matches text case-insensitively.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+pub enum FilterOp {
+    Eq,
+    Ne,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+    Contains,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct Filter {
+    pub field: String,
+    pub op: FilterOp,
+    pub value: FieldValue,
+}
+
+// Records are walked in storage order, so only the stored key, "id", can be sorted on; IDs are
+// handed out in creation order.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct Sort {
+    pub field: String,
+    pub descending: bool,
+}
+
+// Define the request structure for querying a collection. Records must match every filter;
+// `fields` lists the fields to return, or every field when empty. `cursor` is the ID to resume
+// from.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct QueryRequest {
+    pub kind: RecordKind,
+    pub filters: Vec<Filter>,
+    pub sort: Option<Sort>,
+    pub fields: Vec<String>,
+    pub cursor: Option<u64>,
+    pub limit: Option<u32>,
+}
+
+// One page of matching records, each reduced to the requested fields in the requested order.
+// Pass `next_cursor` back as the cursor to continue; it is None once the collection has been
+// walked. A page can come back short, or even empty, when the instruction budget runs out
+// before `limit` records matched.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub struct QueryResult {
+    pub rows: Vec<Vec<(String, FieldValue)>>,
+    pub next_cursor: Option<u64>,
+}
+
+type Row = Vec<(String, FieldValue)>;
+
+// Filter and page through a collection in ID order, returning only the requested fields.
+#[ic_cdk::query]
+fn query_records(request: QueryRequest) -> Result<QueryResult, Error> {
+    match request.kind {
+        RecordKind::Student => require_admin().and_then(|_| run::<Student>(&request)),
+        RecordKind::Book => require_catalog_access().and_then(|_| run::<Book>(&request)),
+        RecordKind::Loan => require_admin().and_then(|_| run::<Loan>(&request)),
+    }
+}
+
+// Helper function to answer a query against the collection of `R`.
+fn run<R: Record + Default + serde::Serialize>(
+    request: &QueryRequest,
+) -> Result<QueryResult, Error> {
+    let known: Vec<String> = to_row(&R::default())
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    let named = request
+        .fields
+        .iter()
+        .chain(request.filters.iter().map(|filter| &filter.field))
+        .chain(request.sort.iter().map(|sort| &sort.field));
+    for field in named {
+        if !known.contains(field) {
+            return Err(Error::InvalidInput {
+                msg: format!("A {} has no field {}.", R::KIND, field),
+            });
+        }
+    }
+
+    let descending = match &request.sort {
+        None => false,
+        Some(sort) if sort.field == "id" => sort.descending,
+        Some(sort) => {
+            return Err(Error::InvalidInput {
+                msg: format!("Records can only be sorted by id, not {}.", sort.field),
+            })
+        }
+    };
+
+    let limit = request
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE) as usize;
+    R::storage().with(|map| {
+        let map = map.borrow();
+        let records: Box<dyn Iterator<Item = (u64, R)>> = if descending {
+            // The map only iterates forwards, so step down one key at a time.
+            let first = match request.cursor {
+                Some(cursor) => map.iter_upper_bound(&cursor.saturating_add(1)).next(),
+                None => map.last_key_value(),
+            };
+            Box::new(std::iter::successors(first, |(id, _)| {
+                map.iter_upper_bound(id).next()
+            }))
+        } else {
+            Box::new(map.range(request.cursor.unwrap_or(0)..))
+        };
+
+        let mut rows = Vec::new();
+        let mut next_cursor = None;
+        for (scanned, (id, record)) in records.enumerate() {
+            // Always read at least one record so that every call makes progress.
+            if rows.len() >= limit || (scanned > 0 && budget_exhausted()) {
+                next_cursor = Some(id);
+                break;
+            }
+            if record.is_corrupted() {
+                continue;
+            }
+            let row = to_row(&record);
+            if request.filters.iter().all(|filter| matches(&row, filter)) {
+                rows.push(project(row, &request.fields));
+            }
+        }
+        Ok(QueryResult { rows, next_cursor })
+    })
+}
+
+// Helper function to break a record into its named fields.
+fn to_row(record: &impl serde::Serialize) -> Row {
+    match serde_json::to_value(record).expect("Library records always serialize") {
+        Value::Object(fields) => fields
+            .into_iter()
+            .map(|(name, value)| (name, to_field_value(value)))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Helper function to convert a JSON value into a FieldValue.
+fn to_field_value(value: Value) -> FieldValue {
+    match value {
+        Value::Null => FieldValue::Null,
+        Value::Bool(value) => FieldValue::Bool(value),
+        Value::Number(number) => number
+            .as_u64()
+            .map_or_else(|| FieldValue::Text(number.to_string()), FieldValue::Nat),
+        Value::String(text) => FieldValue::Text(text),
+        other => FieldValue::Text(other.to_string()),
+    }
+}
+
+// Helper function to look up a field of a row, reading a missing one as Null.
+fn field<'a>(row: &'a Row, name: &str) -> &'a FieldValue {
+    row.iter()
+        .find(|(field, _)| field == name)
+        .map_or(&FieldValue::Null, |(_, value)| value)
+}
+
+// Whether a row satisfies a filter.
+fn matches(row: &Row, filter: &Filter) -> bool {
+    let value = field(row, &filter.field);
+    let same_type = std::mem::discriminant(value) == std::mem::discriminant(&filter.value);
+    match filter.op {
+        FilterOp::Eq => *value == filter.value,
+        FilterOp::Ne => *value != filter.value,
+        FilterOp::Lt => same_type && *value < filter.value,
+        FilterOp::Lte => same_type && *value <= filter.value,
+        FilterOp::Gt => same_type && *value > filter.value,
+        FilterOp::Gte => same_type && *value >= filter.value,
+        FilterOp::Contains => match (value, &filter.value) {
+            (FieldValue::Text(text), FieldValue::Text(needle)) => {
+                text.to_lowercase().contains(&needle.to_lowercase())
+            }
+            _ => false,
+        },
+    }
+}
+
+// Helper function to keep only the requested fields of a row, in the requested order.
+fn project(row: Row, fields: &[String]) -> Row {
+    if fields.is_empty() {
+        return row;
+    }
+    fields
+        .iter()
+        .map(|name| (name.clone(), field(&row, name).clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book_row() -> Row {
+        to_row(&Book {
+            id: 3,
+            title: "Bumi Manusia".to_string(),
+            author: "Pramoedya Ananta Toer".to_string(),
+            min_age: Some(15),
+            ..Default::default()
+        })
+    }
+
+    fn filter(field: &str, op: FilterOp, value: FieldValue) -> Filter {
+        Filter {
+            field: field.to_string(),
+            op,
+            value,
+        }
+    }
+
+    #[test]
+    fn rows_hold_every_field_by_name() {
+        let row = book_row();
+        assert!(field(&row, "id") == &FieldValue::Nat(3));
+        assert!(field(&row, "min_age") == &FieldValue::Nat(15));
+        assert!(field(&row, "barcode") == &FieldValue::Null);
+        assert!(field(&row, "no_such_field") == &FieldValue::Null);
+    }
+
+    #[test]
+    fn filters_compare_values_of_the_same_type() {
+        let row = book_row();
+        assert!(matches(
+            &row,
+            &filter("id", FilterOp::Eq, FieldValue::Nat(3))
+        ));
+        assert!(matches(
+            &row,
+            &filter("id", FilterOp::Gte, FieldValue::Nat(3))
+        ));
+        assert!(!matches(
+            &row,
+            &filter("id", FilterOp::Lt, FieldValue::Nat(3))
+        ));
+        // Ordering a number against text never matches, either way round.
+        let text = FieldValue::Text("3".to_string());
+        assert!(!matches(&row, &filter("id", FilterOp::Lt, text.clone())));
+        assert!(!matches(&row, &filter("id", FilterOp::Gte, text)));
+    }
+
+    #[test]
+    fn contains_ignores_case() {
+        let row = book_row();
+        let needle = FieldValue::Text("ANANTA".to_string());
+        assert!(matches(&row, &filter("author", FilterOp::Contains, needle)));
+        let number = FieldValue::Nat(3);
+        assert!(!matches(&row, &filter("id", FilterOp::Contains, number)));
+    }
+
+    #[test]
+    fn projection_keeps_the_requested_order() {
+        let fields = vec!["title".to_string(), "id".to_string()];
+        let row = project(book_row(), &fields);
+        let names: Vec<&str> = row.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["title", "id"]);
+        assert_eq!(project(book_row(), &[]).len(), book_row().len());
+    }
+}
@@ -0,0 +1,139 @@
+use crate::book::Book;
+use crate::catalog::require_catalog_access;
+use crate::class_set;
+use crate::crud;
+use crate::env::{CanisterEnv, Clock};
+use crate::loan::{Loan, LoanStatus};
+use crate::storable::candid_storable;
+use crate::student::{require_student_access, Student};
+use crate::{Error, OPEN_LOANS_BY_BOOK, OPEN_LOANS_BY_STUDENT};
+
+// Define the LoanSummary struct to describe one open loan the way student screens show it,
+// kept under (student ID, loan ID) so a student's loans are read without scanning every loan.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct LoanSummary {
+    pub loan_id: u64,
+    pub book_id: u64,
+    pub title: String,
+    pub status: LoanStatus,
+    pub loan_date: u64,
+    pub due_date: Option<u64>,
+}
+
+// Store LoanSummary as Candid, bounded to 1024 bytes.
+candid_storable!(LoanSummary, max_size = 1024);
+
+// Whether a book can be borrowed, and what holds it if not.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub struct BookAvailability {
+    pub book_id: u64,
+    pub available: bool,
+    pub withdrawn: bool,
+    pub open_loan_id: Option<u64>,
+    pub due_date: Option<u64>,
+    // The class whose set reservation holds the book.
+    pub reserved_for_class: Option<u64>,
+}
+
+// Retrieve a student's open loans, oldest first.
+#[ic_cdk::query]
+fn get_student_open_loans(student_id: u64) -> Result<Vec<LoanSummary>, Error> {
+    require_student_access(&CanisterEnv, student_id)?;
+    crud::find::<Student>(student_id)?;
+    Ok(student_open_loans(student_id))
+}
+
+// Retrieve whether a book can be borrowed right now.
+#[ic_cdk::query]
+fn get_book_availability(book_id: u64) -> Result<BookAvailability, Error> {
+    require_catalog_access()?;
+    let book = crud::find::<Book>(book_id)?;
+    let open_loan = open_loans_of_book(book_id)
+        .first()
+        .and_then(crud::get::<Loan>);
+    let reserved_for_class = class_set::reservation_of(book_id, CanisterEnv.now())
+        .map(|reservation| reservation.class_id);
+    let withdrawn = book.withdrawn_at.is_some();
+    Ok(BookAvailability {
+        book_id,
+        available: !withdrawn && open_loan.is_none() && reserved_for_class.is_none(),
+        withdrawn,
+        open_loan_id: open_loan.as_ref().map(|loan| loan.id),
+        due_date: open_loan.and_then(|loan| loan.due_date),
+        reserved_for_class,
+    })
+}
+
+// Internal function to read a student's open loans.
+pub(crate) fn student_open_loans(student_id: u64) -> Vec<LoanSummary> {
+    OPEN_LOANS_BY_STUDENT.with(|m| {
+        m.borrow()
+            .range((student_id, 0)..=(student_id, u64::MAX))
+            .map(|(_, summary)| summary)
+            .collect()
+    })
+}
+
+// Internal function to find the open loans of a book; there is at most one unless older data
+// holds double loans.
+pub(crate) fn open_loans_of_book(book_id: u64) -> Vec<u64> {
+    OPEN_LOANS_BY_BOOK.with(|m| {
+        m.borrow()
+            .range((book_id, 0)..=(book_id, u64::MAX))
+            .map(|((_, loan_id), _)| loan_id)
+            .collect()
+    })
+}
+
+// Hook run on every loan write: drop what the `previous` version put in the read models and
+// add the `current` one if it is open. Either is None when the loan is created or deleted.
+pub(crate) fn loan_written(previous: Option<&Loan>, current: Option<&Loan>) {
+    if let Some(previous) = previous {
+        OPEN_LOANS_BY_STUDENT.with(|m| m.borrow_mut().remove(&(previous.student_id, previous.id)));
+        OPEN_LOANS_BY_BOOK.with(|m| m.borrow_mut().remove(&(previous.book_id, previous.id)));
+    }
+    let Some(loan) = current.filter(|loan| loan.status().is_open()) else {
+        return;
+    };
+    let title = crud::get::<Book>(&loan.book_id).map_or_else(String::new, |book| book.title);
+    let summary = LoanSummary {
+        loan_id: loan.id,
+        book_id: loan.book_id,
+        title,
+        status: loan.status(),
+        loan_date: loan.loan_date,
+        due_date: loan.due_date,
+    };
+    OPEN_LOANS_BY_STUDENT.with(|m| m.borrow_mut().insert((loan.student_id, loan.id), summary));
+    OPEN_LOANS_BY_BOOK.with(|m| m.borrow_mut().insert((loan.book_id, loan.id), ()));
+}
+
+// Hook run on every book write: carry a new title into the summaries of its open loans.
+pub(crate) fn book_written(previous: Option<&Book>, current: Option<&Book>) {
+    let (Some(previous), Some(book)) = (previous, current) else {
+        return;
+    };
+    if previous.title == book.title {
+        return;
+    }
+    for loan_id in open_loans_of_book(book.id) {
+        let Some(loan) = crud::get::<Loan>(&loan_id) else {
+            continue;
+        };
+        OPEN_LOANS_BY_STUDENT.with(|m| {
+            let mut summaries = m.borrow_mut();
+            if let Some(mut summary) = summaries.get(&(loan.student_id, loan_id)) {
+                summary.title = book.title.clone();
+                summaries.insert((loan.student_id, loan_id), summary);
+            }
+        });
+    }
+}
+
+// Helper function to fill both read models from the stored loans, for data written before
+// they existed.
+pub(crate) fn build() {
+    for loan in crud::all::<Loan>() {
+        loan_written(None, Some(&loan));
+    }
+}
@@ -0,0 +1,242 @@
+use candid::Principal;
+
+use crate::auth::is_admin;
+use crate::book::Book;
+use crate::class::{_get_class, is_teacher, Class};
+use crate::crud;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::id_counter::{next_id, Entity};
+use crate::loan;
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::student::_get_student_by_principal;
+use crate::{Error, READING_LIST_STORAGE};
+
+// Most books a reading list may hold.
+const MAX_LIST_BOOKS: usize = 100;
+
+// Longest title accepted.
+const MAX_TITLE_LENGTH: usize = 100;
+
+// Longest description accepted.
+const MAX_DESCRIPTION_LENGTH: usize = 1_000;
+
+// Who can see a reading list.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub enum ListVisibility {
+    Public,
+    // Only the members of a class, until it is archived.
+    Class { class_id: u64 },
+    // Only the owner and administrators.
+    Private,
+}
+
+// Define the ReadingList struct to represent a curated, ordered list of books.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct ReadingList {
+    pub id: u64,
+    pub title: String,
+    pub description: String,
+    pub book_ids: Vec<u64>,
+    pub owner: Principal,
+    pub visibility: ListVisibility,
+    pub created_at: u64,
+    pub updated_at: Option<u64>,
+}
+
+// Store ReadingList as Candid, bounded to 4096 bytes to fit a full list.
+candid_storable!(ReadingList, max_size = 4096);
+
+// Define the payload structure for creating or updating a reading list.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct ReadingListPayload {
+    title: String,
+    description: String,
+    book_ids: Vec<u64>,
+    visibility: ListVisibility,
+}
+
+// A book on a reading list and whether it can be borrowed right now.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct ReadingListEntry {
+    pub book: Book,
+    pub available: bool,
+}
+
+// Retrieve the reading lists the caller can see, in ID order.
+#[ic_cdk::query]
+fn get_reading_lists() -> Result<Vec<ReadingList>, Error> {
+    let caller = CanisterEnv.caller();
+    Ok(READING_LIST_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, list)| list)
+            .filter(|list| can_see(&caller, list))
+            .collect()
+    }))
+}
+
+// Retrieve a reading list the caller can see.
+#[ic_cdk::query]
+fn get_reading_list(id: u64) -> Result<ReadingList, Error> {
+    visible(&CanisterEnv, id)
+}
+
+// Retrieve the books on a reading list in their listed order, with whether each can be
+// borrowed. Books deleted since they were listed are left out.
+#[ic_cdk::query]
+fn get_reading_list_books(id: u64) -> Result<Vec<ReadingListEntry>, Error> {
+    let list = visible(&CanisterEnv, id)?;
+    Ok(list
+        .book_ids
+        .iter()
+        .filter_map(crud::get::<Book>)
+        .map(|book| ReadingListEntry {
+            available: loan::is_available(&book, CanisterEnv.now()),
+            book,
+        })
+        .collect())
+}
+
+// Publish a reading list. Administrators and teachers can create lists.
+#[ic_cdk::update]
+fn create_reading_list(payload: ReadingListPayload) -> Result<ReadingList, Error> {
+    let _perf = perf::measure("create_reading_list");
+    _create_reading_list(&CanisterEnv, payload)
+}
+
+// Internal function to create a list owned by `env`'s caller.
+pub(crate) fn _create_reading_list(
+    env: &(impl Clock + CallerProvider),
+    payload: ReadingListPayload,
+) -> Result<ReadingList, Error> {
+    let caller = env.caller();
+    if !is_admin(&caller) && !is_teacher(&caller) {
+        return Err(Error::Unauthorized {
+            msg: "Only an administrator or a teacher can create reading lists.".to_string(),
+        });
+    }
+    validate(&payload)?;
+    let list = ReadingList {
+        id: next_id(Entity::ReadingList)?,
+        title: payload.title,
+        description: payload.description,
+        book_ids: payload.book_ids,
+        owner: caller,
+        visibility: payload.visibility,
+        created_at: env.now(),
+        updated_at: None,
+    };
+    do_insert(&list);
+    Ok(list)
+}
+
+// Replace a reading list's details. Only its owner or an administrator may.
+#[ic_cdk::update]
+fn update_reading_list(id: u64, payload: ReadingListPayload) -> Result<ReadingList, Error> {
+    let _perf = perf::measure("update_reading_list");
+    let mut list = owned(&CanisterEnv, id)?;
+    validate(&payload)?;
+    list.title = payload.title;
+    list.description = payload.description;
+    list.book_ids = payload.book_ids;
+    list.visibility = payload.visibility;
+    list.updated_at = Some(CanisterEnv.now());
+    do_insert(&list);
+    Ok(list)
+}
+
+// Delete a reading list. Only its owner or an administrator may.
+#[ic_cdk::update]
+fn delete_reading_list(id: u64) -> Result<ReadingList, Error> {
+    let _perf = perf::measure("delete_reading_list");
+    owned(&CanisterEnv, id)?;
+    Ok(READING_LIST_STORAGE
+        .with(|s| s.borrow_mut().remove(&id))
+        .expect("The list was just read"))
+}
+
+// Whether `caller` may see `list`.
+fn can_see(caller: &Principal, list: &ReadingList) -> bool {
+    if list.owner == *caller || is_admin(caller) {
+        return true;
+    }
+    match &list.visibility {
+        ListVisibility::Public => true,
+        ListVisibility::Class { class_id } => {
+            let Some(class) = _get_class(*class_id).ok().filter(Class::is_current) else {
+                return false;
+            };
+            class.teacher == Some(*caller)
+                || _get_student_by_principal(caller)
+                    .is_some_and(|student| class.members.contains(&student.id))
+        }
+        ListVisibility::Private => false,
+    }
+}
+
+// Fetch a list `env`'s caller can see.
+fn visible(env: &impl CallerProvider, id: u64) -> Result<ReadingList, Error> {
+    get(id)
+        .filter(|list| can_see(&env.caller(), list))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("A reading list with id={} not found.", id),
+        })
+}
+
+// Fetch a list `env`'s caller may change.
+fn owned(env: &impl CallerProvider, id: u64) -> Result<ReadingList, Error> {
+    let caller = env.caller();
+    let list = visible(env, id)?;
+    if list.owner != caller && !is_admin(&caller) {
+        return Err(Error::Unauthorized {
+            msg: "Only the list's owner or an administrator can change it.".to_string(),
+        });
+    }
+    Ok(list)
+}
+
+// Internal function to fetch a list by ID.
+fn get(id: u64) -> Option<ReadingList> {
+    READING_LIST_STORAGE.with(|s| s.borrow().get(&id))
+}
+
+// Helper function to store a reading list.
+fn do_insert(list: &ReadingList) {
+    READING_LIST_STORAGE.with(|s| s.borrow_mut().insert(list.id, list.clone()));
+}
+
+// Validate a reading list payload.
+fn validate(payload: &ReadingListPayload) -> Result<(), Error> {
+    if payload.title.trim().is_empty() || payload.title.len() > MAX_TITLE_LENGTH {
+        return Err(Error::InvalidInput {
+            msg: format!("A title must be 1 to {} characters.", MAX_TITLE_LENGTH),
+        });
+    }
+    if payload.description.len() > MAX_DESCRIPTION_LENGTH {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A description can be at most {} characters.",
+                MAX_DESCRIPTION_LENGTH
+            ),
+        });
+    }
+    if payload.book_ids.len() > MAX_LIST_BOOKS {
+        return Err(Error::InvalidInput {
+            msg: format!("A list can hold at most {} books.", MAX_LIST_BOOKS),
+        });
+    }
+    if let Some(&missing) = payload
+        .book_ids
+        .iter()
+        .find(|&&id| !crud::exists::<Book>(id))
+    {
+        return Err(Error::BookNotFound {
+            msg: format!("A book with id={} not found.", missing),
+        });
+    }
+    if let ListVisibility::Class { class_id } = payload.visibility {
+        _get_class(class_id)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use candid::{Encode, Principal};
+use sha2::{Digest, Sha256};
+
+use crate::auth::require_admin;
+use crate::crud;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::loan::{Loan, LoanStatus};
+use crate::perf;
+use crate::signing::{self, SigningPurpose};
+use crate::student::_get_student_by_principal;
+use crate::Error;
+
+// What a receipt attests.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiptEvent {
+    Checkout,
+    Return,
+}
+
+// The facts of a checkout or return, as recorded by the canister.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct LoanReceipt {
+    pub event: ReceiptEvent,
+    pub loan_id: u64,
+    pub student_id: u64,
+    pub book_id: u64,
+    pub loan_date: u64,
+    pub due_date: Option<u64>,
+    pub returned_at: Option<u64>,
+    pub issued_at: u64,
+    pub canister: Principal,
+}
+
+// A receipt with the canister's signature. `message` is the receipt's Candid encoding; the
+// signature is over its SHA-256 hash and verifies with the LoanReceipts public key.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct SignedReceipt {
+    pub receipt: LoanReceipt,
+    pub message: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub key_name: String,
+}
+
+// Issue a signed receipt for a loan's checkout, or for its return once the book is back. Meant
+// to be called right after the checkout or return; administrators and the loan's student may
+// ask for one.
+#[ic_cdk::update]
+async fn issue_loan_receipt(loan_id: u64) -> Result<SignedReceipt, Error> {
+    let _perf = perf::measure("issue_loan_receipt");
+    let receipt = _loan_receipt(&CanisterEnv, loan_id)?;
+    let message = Encode!(&receipt).expect("Candid encoding of a receipt cannot fail");
+    let signature = signing::sign(
+        SigningPurpose::LoanReceipts,
+        Sha256::digest(&message).into(),
+    )
+    .await?;
+    Ok(SignedReceipt {
+        receipt,
+        message,
+        signature,
+        key_name: signing::key_name(),
+    })
+}
+
+// Internal function to build the receipt of a loan for `env`'s caller.
+pub(crate) fn _loan_receipt(
+    env: &(impl Clock + CallerProvider),
+    loan_id: u64,
+) -> Result<LoanReceipt, Error> {
+    let loan = crud::find::<Loan>(loan_id)?;
+    if require_admin().is_err() {
+        let caller = env.caller();
+        let own = _get_student_by_principal(&caller).is_some_and(|s| s.id == loan.student_id);
+        if !own {
+            return Err(Error::Unauthorized {
+                msg: "Only an administrator or the loan's student can get its receipt.".to_string(),
+            });
+        }
+    }
+
+    let (event, returned_at) = match loan.status() {
+        LoanStatus::Active | LoanStatus::Overdue => (ReceiptEvent::Checkout, None),
+        LoanStatus::Returned => (ReceiptEvent::Return, loan.status_changed_at),
+        status => {
+            return Err(Error::InvalidInput {
+                msg: format!("A {:?} loan has no checkout or return to attest.", status),
+            })
+        }
+    };
+    Ok(LoanReceipt {
+        event,
+        loan_id,
+        student_id: loan.student_id,
+        book_id: loan.book_id,
+        loan_date: loan.loan_date,
+        due_date: loan.due_date,
+        returned_at,
+        issued_at: env.now(),
+        canister: ic_cdk::id(),
+    })
+}
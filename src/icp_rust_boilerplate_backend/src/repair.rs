@@ -0,0 +1,260 @@
+use crate::auth::require_admin;
+use crate::book::Book;
+use crate::cover;
+use crate::created_index;
+use crate::crud::{self, Record, RecordKind};
+use crate::digital_asset;
+use crate::ebook_loan;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::integrity::{self, Violation};
+use crate::loan::{check_references, Loan, LoanPayload, LoanStatus};
+use crate::log;
+use crate::perf;
+use crate::storable::{IndexString, StorablePrincipal};
+use crate::student::Student;
+use crate::{
+    Error, BARCODE_INDEX, CHARGE_STORAGE, EXTENSION_REQUEST_STORAGE, STUDENT_PRINCIPAL_INDEX,
+    TITLE_INDEX,
+};
+
+// Most repairs a single call may carry.
+const MAX_REPAIRS: usize = 100;
+
+// A fix for a violation reported by run_integrity_check.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub enum RepairAction {
+    // Cancel an open loan whose student or book is gone.
+    CloseLoan {
+        loan_id: u64,
+    },
+    // Point an orphan loan at existing records in place of the missing ones.
+    RelinkLoan {
+        loan_id: u64,
+        student_id: Option<u64>,
+        book_id: Option<u64>,
+    },
+    // Bill an orphan charge to an existing student.
+    RelinkCharge {
+        charge_id: u64,
+        student_id: u64,
+    },
+    // Remove the orphan record or stale index entry a violation names. Orphan loans and
+    // charges are closed or re-linked instead.
+    Discard {
+        violation: Violation,
+    },
+}
+
+// Apply a list of repairs, each checked against the current state first so that nothing fixed
+// in the meantime is touched. Each repair succeeds or fails on its own; the results describe
+// what was done, in the order the repairs were given, and every repair is logged.
+#[ic_cdk::update]
+fn repair_orphans(actions: Vec<RepairAction>) -> Result<Vec<Result<String, Error>>, Error> {
+    let _perf = perf::measure("repair_orphans");
+    require_admin()?;
+    _repair_orphans(&CanisterEnv, actions)
+}
+
+// Internal function to apply repairs on behalf of `env`'s caller.
+pub(crate) fn _repair_orphans(
+    env: &(impl Clock + CallerProvider),
+    actions: Vec<RepairAction>,
+) -> Result<Vec<Result<String, Error>>, Error> {
+    if actions.len() > MAX_REPAIRS {
+        return Err(Error::InvalidInput {
+            msg: format!("At most {} repairs can be applied at once.", MAX_REPAIRS),
+        });
+    }
+    Ok(actions
+        .into_iter()
+        .map(|action| {
+            let done = repair(env, action)?;
+            log::warn(format!("{} repaired: {}", env.caller(), done));
+            Ok(done)
+        })
+        .collect())
+}
+
+// Apply one repair, describing what it did.
+fn repair(clock: &impl Clock, action: RepairAction) -> Result<String, Error> {
+    match action {
+        RepairAction::CloseLoan { loan_id } => {
+            orphan_loan(loan_id)?;
+            crud::update(loan_id, |loan: &mut Loan| {
+                loan.status = Some(LoanStatus::Cancelled);
+                loan.status_changed_at = Some(clock.now());
+            })?;
+            Ok(format!("cancelled orphan loan id={}.", loan_id))
+        }
+        RepairAction::RelinkLoan {
+            loan_id,
+            student_id,
+            book_id,
+        } => {
+            let loan = orphan_loan(loan_id)?;
+            let student_id = relink(student_id, loan.student_id, crud::exists::<Student>)?;
+            let book_id = relink(book_id, loan.book_id, crud::exists::<Book>)?;
+            let payload = LoanPayload {
+                student_id,
+                book_id,
+                loan_date: loan.loan_date,
+            };
+            check_references(clock, &payload, Some(loan_id), false)?;
+            crud::update(loan_id, |loan: &mut Loan| {
+                loan.student_id = student_id;
+                loan.book_id = book_id;
+                loan.updated_at = Some(clock.now());
+            })?;
+            Ok(format!(
+                "re-linked loan id={} to student id={} and book id={}.",
+                loan_id, student_id, book_id
+            ))
+        }
+        RepairAction::RelinkCharge {
+            charge_id,
+            student_id,
+        } => {
+            let mut charge = CHARGE_STORAGE
+                .with(|s| s.borrow().get(&charge_id))
+                .filter(|charge| {
+                    integrity::still_holds(&Violation::OrphanCharge {
+                        charge_id,
+                        student_id: charge.student_id,
+                    })
+                })
+                .ok_or_else(|| not_violated(format!("charge id={}", charge_id)))?;
+            if !crud::exists::<Student>(student_id) {
+                return Err(Error::StudentNotFound {
+                    msg: format!("A student with id={} not found.", student_id),
+                });
+            }
+            charge.student_id = student_id;
+            CHARGE_STORAGE.with(|s| s.borrow_mut().insert(charge_id, charge));
+            Ok(format!(
+                "re-linked charge id={} to student id={}.",
+                charge_id, student_id
+            ))
+        }
+        RepairAction::Discard { violation } => {
+            if !integrity::still_holds(&violation) {
+                return Err(not_violated("the reported record".to_string()));
+            }
+            discard(violation)
+        }
+    }
+}
+
+// Remove what a violation names.
+fn discard(violation: Violation) -> Result<String, Error> {
+    match violation {
+        Violation::OrphanLoan { .. } | Violation::OrphanCharge { .. } => Err(Error::InvalidInput {
+            msg: "Orphan loans and charges are closed or re-linked, not discarded.".to_string(),
+        }),
+        Violation::OrphanEbookHold { hold_id, .. } => {
+            ebook_loan::remove_hold(hold_id);
+            Ok(format!("removed orphan e-book hold id={}.", hold_id))
+        }
+        Violation::OrphanExtensionRequest { request_id, .. } => {
+            EXTENSION_REQUEST_STORAGE.with(|s| s.borrow_mut().remove(&request_id));
+            Ok(format!(
+                "removed orphan extension request id={}.",
+                request_id
+            ))
+        }
+        Violation::OrphanDigitalAsset { book_id } => {
+            digital_asset::remove_digital_asset(book_id);
+            Ok(format!(
+                "removed orphan digital asset of book id={}.",
+                book_id
+            ))
+        }
+        Violation::OrphanCover { book_id } => {
+            cover::remove_cover(book_id);
+            Ok(format!("removed orphan cover of book id={}.", book_id))
+        }
+        Violation::StaleCreatedIndexEntry {
+            kind,
+            created_at,
+            id,
+        } => {
+            let (index, name) = match kind {
+                RecordKind::Student => (Student::created_index(), Student::KIND),
+                RecordKind::Book => (Book::created_index(), Book::KIND),
+                RecordKind::Loan => (Loan::created_index(), Loan::KIND),
+            };
+            created_index::remove(index, created_at, id);
+            Ok(format!(
+                "dropped stale {} creation index entry ({}, {}).",
+                name, created_at, id
+            ))
+        }
+        Violation::StaleBarcodeEntry { barcode, book_id } => {
+            BARCODE_INDEX.with(|index| index.borrow_mut().remove(&IndexString(barcode.clone())));
+            Ok(format!(
+                "dropped stale barcode entry {} for book id={}.",
+                barcode, book_id
+            ))
+        }
+        Violation::StaleTitleEntry { title_key, book_id } => {
+            TITLE_INDEX.with(|index| {
+                index
+                    .borrow_mut()
+                    .remove(&(IndexString(title_key.clone()), book_id))
+            });
+            Ok(format!(
+                "dropped stale title entry \"{}\" for book id={}.",
+                title_key, book_id
+            ))
+        }
+        Violation::StalePrincipalEntry {
+            principal,
+            student_id,
+        } => {
+            STUDENT_PRINCIPAL_INDEX
+                .with(|index| index.borrow_mut().remove(&StorablePrincipal(principal)));
+            Ok(format!(
+                "dropped stale principal entry {} for student id={}.",
+                principal, student_id
+            ))
+        }
+    }
+}
+
+// Fetch a loan that is still an orphan.
+fn orphan_loan(loan_id: u64) -> Result<Loan, Error> {
+    let violation = Violation::OrphanLoan {
+        loan_id,
+        student_missing: false,
+        book_missing: false,
+    };
+    if !integrity::still_holds(&violation) {
+        return Err(not_violated(format!("loan id={}", loan_id)));
+    }
+    crud::find::<Loan>(loan_id)
+}
+
+// The reference to keep: `replacement` when given, which may only replace a `current`
+// reference to a missing record.
+fn relink(
+    replacement: Option<u64>,
+    current: u64,
+    exists: impl Fn(u64) -> bool,
+) -> Result<u64, Error> {
+    match replacement {
+        Some(id) if exists(current) && id != current => Err(Error::InvalidInput {
+            msg: format!(
+                "Only a reference to a missing record can be re-linked, not id={}.",
+                current
+            ),
+        }),
+        Some(id) => Ok(id),
+        None => Ok(current),
+    }
+}
+
+// The error for a repair whose violation no longer holds.
+fn not_violated(what: String) -> Error {
+    Error::InvalidInput {
+        msg: format!("The integrity check no longer flags {}.", what),
+    }
+}
@@ -0,0 +1,339 @@
+use candid::{Encode, Principal};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::auth::require_admin;
+use crate::book::{title_key, Book, BookFormat};
+use crate::cache;
+use crate::charge::CHARGE_CURRENCY;
+use crate::class::_get_class;
+use crate::crud;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::loan::{Loan, LoanStatus};
+use crate::perf;
+use crate::signing::{self, SigningPurpose};
+use crate::student::Student;
+use crate::{Error, CHARGE_STORAGE};
+
+// Number of books listed as most borrowed.
+const TOP_BOOKS: usize = 10;
+
+// How often a book was lent within a report's period.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct BookCirculation {
+    pub book_id: u64,
+    pub title: Option<String>,
+    pub loans: u64,
+}
+
+// Circulation figures for a period, e.g. a school year. Times are inclusive bounds.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct CirculationReport {
+    pub from: u64,
+    pub to: u64,
+    pub checkouts: u64,
+    pub returns: u64,
+    pub lost: u64,
+    pub borrowers: u64,
+    pub most_borrowed: Vec<BookCirculation>,
+    // Charge totals in minor units of `currency`.
+    pub currency: String,
+    pub charges_billed: u64,
+    pub charges_paid: u64,
+    pub generated_at: u64,
+    pub canister: Principal,
+}
+
+// A span of time, inclusive at both ends.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+pub struct Period {
+    pub from: u64,
+    pub to: u64,
+}
+
+// One student's reading within a report's period: loans started, loans returned, and loans
+// that ran past their due date.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct StudentReading {
+    pub student_id: u64,
+    pub name: String,
+    pub loans: u64,
+    pub completed: u64,
+    pub overdue: u64,
+}
+
+// Reading figures for the members of a class.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct ClassReadingReport {
+    pub class_id: u64,
+    pub class_name: String,
+    pub period: Period,
+    pub students: Vec<StudentReading>,
+    pub generated_at: u64,
+}
+
+// A report with the canister's signature. `message` is the report's Candid encoding; the
+// signature is over its SHA-256 hash and verifies with the Reports public key.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct SignedReport {
+    pub report: CirculationReport,
+    pub message: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub key_name: String,
+}
+
+// How many titles in one language are held in each accessible format. A title counts for a
+// format when at least one of its copies is in it; `language` is None for books without one.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct FormatCoverage {
+    pub language: Option<String>,
+    pub titles: u64,
+    pub audiobook: u64,
+    pub large_print: u64,
+    pub braille: u64,
+}
+
+// Compile the circulation report for a period.
+#[ic_cdk::query]
+fn get_circulation_report(from: u64, to: u64) -> Result<CirculationReport, Error> {
+    require_admin()?;
+    match cache::get(&report_key(from, to), CanisterEnv.now()) {
+        Some(report) => Ok(report),
+        None => _circulation_report(&CanisterEnv, from, to),
+    }
+}
+
+// Compile the circulation report for a period and cache it, so get_circulation_report serves
+// it without recomputing until a record changes or the cache entry expires.
+#[ic_cdk::update]
+fn refresh_circulation_report(from: u64, to: u64) -> Result<CirculationReport, Error> {
+    let _perf = perf::measure("refresh_circulation_report");
+    require_admin()?;
+    let report = _circulation_report(&CanisterEnv, from, to)?;
+    cache::put(&report_key(from, to), &report, report.generated_at);
+    Ok(report)
+}
+
+// Compile the circulation report for a period and sign it, so a printed or archived copy can
+// later be shown to be authentic and unaltered.
+#[ic_cdk::update]
+async fn export_circulation_report(from: u64, to: u64) -> Result<SignedReport, Error> {
+    let _perf = perf::measure("export_circulation_report");
+    require_admin()?;
+    let report = _circulation_report(&CanisterEnv, from, to)?;
+    cache::put(&report_key(from, to), &report, report.generated_at);
+    let message = Encode!(&report).expect("Candid encoding of a report cannot fail");
+    let signature = signing::sign(SigningPurpose::Reports, Sha256::digest(&message).into()).await?;
+    Ok(SignedReport {
+        report,
+        message,
+        signature,
+        key_name: signing::key_name(),
+    })
+}
+
+// Report how many titles of each language the library holds in accessible formats, so gaps for
+// students who need them can be filled. Withdrawn copies don't count.
+#[ic_cdk::query]
+fn get_format_coverage_report() -> Result<Vec<FormatCoverage>, Error> {
+    require_admin()?;
+    let mut titles: BTreeMap<(Option<String>, String, String), BTreeSet<BookFormat>> =
+        BTreeMap::new();
+    for book in crud::all::<Book>() {
+        if book.withdrawn_at.is_some() {
+            continue;
+        }
+        let title = (
+            book.language.clone(),
+            title_key(&book.title).0,
+            book.author.trim().to_lowercase(),
+        );
+        titles
+            .entry(title)
+            .or_default()
+            .extend(book.formats.into_iter().flatten());
+    }
+    let mut coverage: BTreeMap<Option<String>, FormatCoverage> = BTreeMap::new();
+    for ((language, _, _), formats) in titles {
+        let entry = coverage
+            .entry(language.clone())
+            .or_insert_with(|| FormatCoverage {
+                language,
+                titles: 0,
+                audiobook: 0,
+                large_print: 0,
+                braille: 0,
+            });
+        entry.titles += 1;
+        for format in formats {
+            match format {
+                BookFormat::Audiobook => entry.audiobook += 1,
+                BookFormat::LargePrint => entry.large_print += 1,
+                BookFormat::Braille => entry.braille += 1,
+            }
+        }
+    }
+    Ok(coverage.into_values().collect())
+}
+
+// Summarize the reading of each student in a class. Available to administrators and to the
+// class's teacher.
+#[ic_cdk::query]
+fn get_class_reading_report(class_id: u64, period: Period) -> Result<ClassReadingReport, Error> {
+    _class_reading_report(&CanisterEnv, class_id, period)
+}
+
+// Internal function to compile a class's reading report for `env`'s caller. Loans count by
+// their loan date; a loan is overdue if it still is, or came back after its due date.
+pub(crate) fn _class_reading_report(
+    env: &(impl Clock + CallerProvider),
+    class_id: u64,
+    period: Period,
+) -> Result<ClassReadingReport, Error> {
+    let class = _get_class(class_id)?;
+    if require_admin().is_err() && class.teacher != Some(env.caller()) {
+        return Err(Error::Unauthorized {
+            msg: "Only an administrator or the class's teacher can see its report.".to_string(),
+        });
+    }
+    if period.from > period.to {
+        return Err(Error::InvalidInput {
+            msg: "The period must not end before it starts.".to_string(),
+        });
+    }
+
+    let mut readings: BTreeMap<u64, StudentReading> = class
+        .members
+        .iter()
+        .filter_map(crud::get::<Student>)
+        .map(|student| {
+            let reading = StudentReading {
+                student_id: student.id,
+                name: student.name,
+                loans: 0,
+                completed: 0,
+                overdue: 0,
+            };
+            (student.id, reading)
+        })
+        .collect();
+    for loan in crud::all::<Loan>() {
+        let Some(reading) = readings.get_mut(&loan.student_id) else {
+            continue;
+        };
+        let status = loan.status();
+        if matches!(status, LoanStatus::Requested | LoanStatus::Cancelled)
+            || !(period.from..=period.to).contains(&loan.loan_date)
+        {
+            continue;
+        }
+        reading.loans += 1;
+        let returned_late = loan
+            .due_date
+            .zip(loan.status_changed_at)
+            .is_some_and(|(due, returned)| returned > due);
+        match status {
+            LoanStatus::Returned => {
+                reading.completed += 1;
+                if returned_late {
+                    reading.overdue += 1;
+                }
+            }
+            LoanStatus::Overdue => reading.overdue += 1,
+            _ => {}
+        }
+    }
+
+    Ok(ClassReadingReport {
+        class_id,
+        class_name: class.name,
+        period,
+        students: class
+            .members
+            .iter()
+            .filter_map(|id| readings.remove(id))
+            .collect(),
+        generated_at: env.now(),
+    })
+}
+
+// Internal function to compile the report, timestamped by `clock`. Loans count as checkouts by
+// their loan date, and as returns or losses by when they changed to that status; requested
+// and cancelled loans were never handed out.
+pub(crate) fn _circulation_report(
+    clock: &impl Clock,
+    from: u64,
+    to: u64,
+) -> Result<CirculationReport, Error> {
+    if from > to {
+        return Err(Error::InvalidInput {
+            msg: "The period must not end before it starts.".to_string(),
+        });
+    }
+    let within = |time: u64| (from..=to).contains(&time);
+
+    let mut checkouts = 0;
+    let mut returns = 0;
+    let mut lost = 0;
+    let mut borrowers = BTreeSet::new();
+    let mut loans_per_book: BTreeMap<u64, u64> = BTreeMap::new();
+    for loan in crud::all::<Loan>() {
+        let lent = !matches!(loan.status(), LoanStatus::Requested | LoanStatus::Cancelled);
+        if lent && within(loan.loan_date) {
+            checkouts += 1;
+            borrowers.insert(loan.student_id);
+            *loans_per_book.entry(loan.book_id).or_default() += 1;
+        }
+        let changed_within = loan.status_changed_at.is_some_and(within);
+        match loan.status() {
+            LoanStatus::Returned if changed_within => returns += 1,
+            LoanStatus::Lost if changed_within => lost += 1,
+            _ => {}
+        }
+    }
+
+    let mut ranked: Vec<(u64, u64)> = loans_per_book.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let most_borrowed = ranked
+        .into_iter()
+        .take(TOP_BOOKS)
+        .map(|(book_id, loans)| BookCirculation {
+            book_id,
+            title: crud::get::<Book>(&book_id).map(|book| book.title),
+            loans,
+        })
+        .collect();
+
+    let mut charges_billed = 0u64;
+    let mut charges_paid = 0u64;
+    CHARGE_STORAGE.with(|service| {
+        for (_, charge) in service.borrow().iter() {
+            if within(charge.created_at) {
+                charges_billed = charges_billed.saturating_add(charge.amount);
+            }
+            if charge.paid_at.is_some_and(within) {
+                charges_paid = charges_paid.saturating_add(charge.amount);
+            }
+        }
+    });
+
+    Ok(CirculationReport {
+        from,
+        to,
+        checkouts,
+        returns,
+        lost,
+        borrowers: borrowers.len() as u64,
+        most_borrowed,
+        currency: CHARGE_CURRENCY.to_string(),
+        charges_billed,
+        charges_paid,
+        generated_at: clock.now(),
+        canister: ic_cdk::id(),
+    })
+}
+
+// Key a period's circulation report is cached under.
+fn report_key(from: u64, to: u64) -> String {
+    format!("circulation_report:{}:{}", from, to)
+}
@@ -0,0 +1,60 @@
+use ic_stable_structures::{BoundedStorable, Memory, StableBTreeMap};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+// Key-value storage for one collection. Domain logic written against this trait runs on the
+// stable-memory maps in the canister and on a plain HashMap in native unit tests.
+pub trait Repository<K, V> {
+    fn get(&self, key: &K) -> Option<V>;
+    fn insert(&mut self, key: K, value: V) -> Option<V>;
+    fn remove(&mut self, key: &K) -> Option<V>;
+    // Every entry, in ascending key order.
+    fn entries(&self) -> Vec<(K, V)>;
+}
+
+impl<K, V, M> Repository<K, V> for StableBTreeMap<K, V, M>
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+    M: Memory,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        StableBTreeMap::get(self, key)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        StableBTreeMap::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        StableBTreeMap::remove(self, key)
+    }
+
+    fn entries(&self) -> Vec<(K, V)> {
+        self.iter().collect()
+    }
+}
+
+impl<K, V> Repository<K, V> for HashMap<K, V>
+where
+    K: Hash + Ord + Clone,
+    V: Clone,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        HashMap::get(self, key).cloned()
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        HashMap::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        HashMap::remove(self, key)
+    }
+
+    fn entries(&self) -> Vec<(K, V)> {
+        let mut entries: Vec<_> = self.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries
+    }
+}
@@ -0,0 +1,196 @@
+use ic_stable_structures::{BoundedStorable, StableBTreeMap};
+use std::cell::{Cell as StdCell, RefCell};
+use std::thread::LocalKey;
+
+use crate::auth::require_admin;
+use crate::date::DAY;
+use crate::env::{CanisterEnv, Clock};
+use crate::extension::ExtensionStatus;
+use crate::log;
+use crate::perf;
+use crate::proposal::{_propose_admin_action, AdminAction, Proposal, ProposalStatus};
+use crate::storable::candid_storable;
+use crate::{
+    Error, Memory, EBOOK_LOAN_STORAGE, EXTENSION_REQUEST_STORAGE, LOG_STORAGE, PROPOSAL_STORAGE,
+    RETENTION_POLICY,
+};
+
+// How often the periodic jobs start a cleanup pass: one hour in nanoseconds.
+const CLEANUP_INTERVAL: u64 = 60 * 60 * 1_000_000_000;
+
+// Most records a single batch removes from one collection.
+const MAX_CLEANUP_BATCH: usize = 500;
+
+// Instructions a cleanup batch may use before it stops and leaves the rest for the next round.
+const CLEANUP_INSTRUCTION_BUDGET: u64 = 1_000_000_000;
+
+thread_local! {
+    // When the last complete cleanup pass finished; reset on upgrade, which just means the
+    // first run of the periodic jobs after an upgrade starts one.
+    static LAST_CLEANUP: StdCell<u64> = const { StdCell::new(0) };
+}
+
+// How many days finished records are kept before cleanup deletes them. None keeps them forever.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct RetentionPolicy {
+    pub log_days: Option<u32>,
+    // Counted from when a proposal was decided or expired.
+    pub proposal_days: Option<u32>,
+    // Counted from when an extension request was decided.
+    pub extension_request_days: Option<u32>,
+    // Counted from when a digital loan expired.
+    pub ebook_loan_days: Option<u32>,
+}
+
+// Store RetentionPolicy as Candid.
+candid_storable!(RetentionPolicy);
+
+// Number of records a cleanup batch deleted from each collection.
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+pub struct CleanupReport {
+    pub logs: u64,
+    pub proposals: u64,
+    pub extension_requests: u64,
+    pub ebook_loans: u64,
+    // Whether expired records were left for a later batch.
+    pub incomplete: bool,
+}
+
+// Retrieve the retention policy.
+#[ic_cdk::query]
+fn get_retention_policy() -> Result<RetentionPolicy, Error> {
+    require_admin()?;
+    Ok(RETENTION_POLICY.with(|p| p.borrow().get().clone()))
+}
+
+// Propose replacing the retention policy. It takes effect once another administrator approves
+// the proposal, and the next cleanup applies it.
+#[ic_cdk::update]
+fn set_retention_policy(policy: RetentionPolicy) -> Result<Proposal, Error> {
+    let _perf = perf::measure("set_retention_policy");
+    require_admin()?;
+    _propose_admin_action(&CanisterEnv, AdminAction::SetRetentionPolicy { policy })
+}
+
+// Check that a retention policy can be applied.
+pub(crate) fn validate_policy(policy: &RetentionPolicy) -> Result<(), Error> {
+    let periods = [
+        policy.log_days,
+        policy.proposal_days,
+        policy.extension_request_days,
+        policy.ebook_loan_days,
+    ];
+    if periods.contains(&Some(0)) {
+        return Err(Error::InvalidInput {
+            msg: "Retention periods must be at least one day.".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Replace the retention policy. Shortening it deletes records, so it runs only through an
+// approved proposal.
+pub(crate) fn set_policy(policy: RetentionPolicy) -> Result<RetentionPolicy, Error> {
+    validate_policy(&policy)?;
+    RETENTION_POLICY
+        .with(|p| p.borrow_mut().set(policy.clone()))
+        .expect("Cannot store the retention policy");
+    Ok(policy)
+}
+
+// Run one cleanup batch now instead of waiting for the periodic jobs.
+#[ic_cdk::update]
+fn run_retention_cleanup() -> Result<CleanupReport, Error> {
+    let _perf = perf::measure("run_retention_cleanup");
+    require_admin()?;
+    Ok(_run_cleanup(&CanisterEnv))
+}
+
+// Hook run from the periodic jobs: start a cleanup batch once an interval has passed since the
+// last complete pass, and keep running a batch on every run until nothing expired is left.
+pub(crate) fn run_pending(clock: &impl Clock) {
+    let now = clock.now();
+    if now.saturating_sub(LAST_CLEANUP.with(|last| last.get())) < CLEANUP_INTERVAL {
+        return;
+    }
+    if !_run_cleanup(clock).incomplete {
+        LAST_CLEANUP.with(|last| last.set(now));
+    }
+}
+
+// Internal function to delete records older than the retention policy allows, as of `clock`.
+pub(crate) fn _run_cleanup(clock: &impl Clock) -> CleanupReport {
+    let policy = RETENTION_POLICY.with(|p| p.borrow().get().clone());
+    let now = clock.now();
+    let cutoff = |days: Option<u32>| days.map(|days| now.saturating_sub(days as u64 * DAY));
+    let mut report = CleanupReport::default();
+
+    if let Some(cutoff) = cutoff(policy.log_days) {
+        let (removed, more) = remove_where(&LOG_STORAGE, |entry| entry.timestamp < cutoff);
+        report.logs = removed;
+        report.incomplete |= more;
+    }
+    if let Some(cutoff) = cutoff(policy.proposal_days) {
+        let (removed, more) = remove_where(&PROPOSAL_STORAGE, |proposal| {
+            let closed_at = match proposal.status {
+                ProposalStatus::Pending => Some(proposal.expires_at).filter(|&at| at <= now),
+                _ => proposal.decided_at.or(Some(proposal.expires_at)),
+            };
+            closed_at.is_some_and(|at| at < cutoff)
+        });
+        report.proposals = removed;
+        report.incomplete |= more;
+    }
+    if let Some(cutoff) = cutoff(policy.extension_request_days) {
+        let (removed, more) = remove_where(&EXTENSION_REQUEST_STORAGE, |request| {
+            request.status != ExtensionStatus::Pending
+                && request.decided_at.is_some_and(|at| at < cutoff)
+        });
+        report.extension_requests = removed;
+        report.incomplete |= more;
+    }
+    if let Some(cutoff) = cutoff(policy.ebook_loan_days) {
+        let (removed, more) = remove_where(&EBOOK_LOAN_STORAGE, |loan| loan.expires_at < cutoff);
+        report.ebook_loans = removed;
+        report.incomplete |= more;
+    }
+
+    let total = report.logs + report.proposals + report.extension_requests + report.ebook_loans;
+    if total > 0 {
+        log::info(format!(
+            "Retention cleanup deleted {} logs, {} proposals, {} extension requests and {} \
+             e-book loans.",
+            report.logs, report.proposals, report.extension_requests, report.ebook_loans
+        ));
+    }
+    report
+}
+
+// Delete up to a batch of records matching `expired`, stopping early when the instruction
+// budget runs out. Returns how many were deleted and whether matching records may remain.
+fn remove_where<V: BoundedStorable>(
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+    expired: impl Fn(&V) -> bool,
+) -> (u64, bool) {
+    storage.with(|s| {
+        let mut more = false;
+        let mut keys = Vec::new();
+        for (key, value) in s.borrow().iter() {
+            if keys.len() >= MAX_CLEANUP_BATCH
+                || ic_cdk::api::performance_counter(0) > CLEANUP_INSTRUCTION_BUDGET
+            {
+                more = true;
+                break;
+            }
+            if expired(&value) {
+                keys.push(key);
+            }
+        }
+
+        let mut map = s.borrow_mut();
+        for key in &keys {
+            map.remove(key);
+        }
+        (keys.len() as u64, more)
+    })
+}
@@ -0,0 +1,37 @@
+use ic_stable_structures::{BoundedStorable, StableBTreeMap};
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::Memory;
+
+// A bounded, append-only sequence kept in a map keyed by increasing sequence numbers. Once it
+// holds more than its capacity, the oldest entries are evicted, so it can't grow without bound.
+pub type RingStorage<V> = &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>;
+
+// Append `value` under `seq`, which must be larger than any sequence number already held, then
+// evict down to `capacity`. Returns the evicted entries, oldest first.
+pub(crate) fn push<V: BoundedStorable>(
+    storage: RingStorage<V>,
+    seq: u64,
+    value: V,
+    capacity: u64,
+) -> Vec<V> {
+    storage.with(|s| s.borrow_mut().insert(seq, value));
+    evict_to(storage, capacity)
+}
+
+// Evict the oldest entries until at most `capacity` remain, e.g. after the capacity has been
+// lowered. Returns the evicted entries, oldest first.
+pub(crate) fn evict_to<V: BoundedStorable>(storage: RingStorage<V>, capacity: u64) -> Vec<V> {
+    storage.with(|s| {
+        let mut map = s.borrow_mut();
+        let mut evicted = Vec::new();
+        while map.len() > capacity {
+            let Some((oldest, _)) = map.first_key_value() else {
+                break;
+            };
+            evicted.extend(map.remove(&oldest));
+        }
+        evicted
+    })
+}
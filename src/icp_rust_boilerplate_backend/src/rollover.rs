@@ -0,0 +1,231 @@
+use candid::Principal;
+
+use crate::auth::require_admin;
+use crate::class::{self, Class};
+use crate::crud;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::log;
+use crate::pagination::budget_exhausted;
+use crate::perf;
+use crate::scheduler;
+use crate::snapshot;
+use crate::storable::candid_storable;
+use crate::student::Student;
+use crate::{Error, CLASS_STORAGE, ROLLOVER_STATE};
+
+// Most records a single batch changes.
+const MAX_ROLLOVER_BATCH: u32 = 200;
+
+// What a rollover is doing; it moves through the steps in order.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RolloverStep {
+    // Archiving the finished year's classes, which expires their memberships.
+    ArchiveClasses,
+    // Moving students up a grade, if asked for.
+    PromoteStudents,
+    Done,
+}
+
+// Define the payload structure for starting a rollover.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct RolloverPayload {
+    // The academic year that finished, e.g. "2025/2026"; its classes are archived.
+    pub academic_year: String,
+    pub promote_grades: bool,
+    // Students already in this grade are left as they are instead of being promoted.
+    pub final_grade: Option<u8>,
+}
+
+// Define the Rollover struct to record the progress of a semester rollover.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct Rollover {
+    pub academic_year: String,
+    pub promote_grades: bool,
+    pub final_grade: Option<u8>,
+    pub step: RolloverStep,
+    // ID to continue the current step from.
+    pub cursor: Option<u64>,
+    pub classes_archived: u64,
+    pub students_promoted: u64,
+    pub started_by: Principal,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+}
+
+// The latest rollover, finished or not.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct RolloverState {
+    pub rollover: Option<Rollover>,
+}
+
+// Store RolloverState as Candid.
+candid_storable!(RolloverState);
+
+// Retrieve the latest rollover and how far it got.
+#[ic_cdk::query]
+fn get_rollover() -> Result<Option<Rollover>, Error> {
+    require_admin()?;
+    Ok(current())
+}
+
+// Start a rollover. It runs in batches on a timer, or sooner through run_rollover_batch.
+#[ic_cdk::update]
+fn start_rollover(payload: RolloverPayload) -> Result<Rollover, Error> {
+    let _perf = perf::measure("start_rollover");
+    require_admin()?;
+    _start_rollover(&CanisterEnv, payload)
+}
+
+// Internal function to start a rollover on behalf of `env`'s caller.
+pub(crate) fn _start_rollover(
+    env: &(impl Clock + CallerProvider),
+    payload: RolloverPayload,
+) -> Result<Rollover, Error> {
+    if current().is_some_and(|rollover| rollover.step != RolloverStep::Done) {
+        return Err(Error::InvalidInput {
+            msg: "A rollover is already in progress.".to_string(),
+        });
+    }
+    if payload.academic_year.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "The academic year cannot be empty.".to_string(),
+        });
+    }
+    let rollover = Rollover {
+        academic_year: payload.academic_year,
+        promote_grades: payload.promote_grades,
+        final_grade: payload.final_grade,
+        step: RolloverStep::ArchiveClasses,
+        cursor: None,
+        classes_archived: 0,
+        students_promoted: 0,
+        started_by: env.caller(),
+        started_at: env.now(),
+        finished_at: None,
+    };
+    store(&rollover);
+    scheduler::wake_batches();
+    log::warn(format!(
+        "{} started the rollover of academic year {}.",
+        rollover.started_by, rollover.academic_year
+    ));
+    Ok(rollover)
+}
+
+// Run the next batch of the rollover in progress now instead of waiting for the timer.
+#[ic_cdk::update]
+fn run_rollover_batch() -> Result<Rollover, Error> {
+    let _perf = perf::measure("run_rollover_batch");
+    require_admin()?;
+    _run_batch(&CanisterEnv).ok_or_else(|| Error::NotFound {
+        msg: "No rollover is in progress.".to_string(),
+    })
+}
+
+// Whether a rollover has started and not finished yet.
+pub(crate) fn in_progress() -> bool {
+    current().is_some_and(|rollover| rollover.step != RolloverStep::Done)
+}
+
+// Hook run from the batch timer: continue the rollover in progress, if any.
+pub(crate) fn run_pending(clock: &impl Clock) {
+    _run_batch(clock);
+}
+
+// Internal function to run one batch of the rollover in progress, returning its progress, or
+// None when there is nothing to do. A batch stops at MAX_ROLLOVER_BATCH records or when the
+// instruction budget runs out, recording where to continue.
+pub(crate) fn _run_batch(clock: &impl Clock) -> Option<Rollover> {
+    let mut rollover = current().filter(|rollover| rollover.step != RolloverStep::Done)?;
+    // Students can't be promoted while a snapshot is taken or rolled back; wait for it.
+    if snapshot::in_progress() {
+        return Some(rollover);
+    }
+    let now = clock.now();
+    let done = match rollover.step {
+        RolloverStep::ArchiveClasses => archive_classes(&mut rollover, now),
+        RolloverStep::PromoteStudents => promote_students(&mut rollover, now),
+        RolloverStep::Done => true,
+    };
+    if done {
+        rollover.cursor = None;
+        rollover.step = match rollover.step {
+            RolloverStep::ArchiveClasses if rollover.promote_grades => {
+                RolloverStep::PromoteStudents
+            }
+            _ => RolloverStep::Done,
+        };
+        if rollover.step == RolloverStep::Done {
+            rollover.finished_at = Some(now);
+            log::info(format!(
+                "Finished the rollover of academic year {}: {} classes archived, {} students \
+                 promoted.",
+                rollover.academic_year, rollover.classes_archived, rollover.students_promoted
+            ));
+        }
+    }
+    store(&rollover);
+    Some(rollover)
+}
+
+// Archive the current classes of the finished year from the cursor on. Returns whether the
+// step is complete.
+fn archive_classes(rollover: &mut Rollover, now: u64) -> bool {
+    let start = rollover.cursor.unwrap_or(0);
+    let batch: Vec<Class> = CLASS_STORAGE.with(|s| {
+        s.borrow()
+            .range(start..)
+            .map(|(_, class)| class)
+            .take(MAX_ROLLOVER_BATCH as usize + 1)
+            .collect()
+    });
+    for (handled, class) in batch.into_iter().enumerate() {
+        if handled == MAX_ROLLOVER_BATCH as usize || (handled > 0 && budget_exhausted()) {
+            rollover.cursor = Some(class.id);
+            return false;
+        }
+        if class.is_current() && class.academic_year == rollover.academic_year {
+            class::archive(class, now);
+            rollover.classes_archived += 1;
+        }
+    }
+    true
+}
+
+// Move students up a grade from the cursor on. Returns whether the step is complete.
+fn promote_students(rollover: &mut Rollover, now: u64) -> bool {
+    let page = crud::list::<Student>(rollover.cursor, Some(MAX_ROLLOVER_BATCH));
+    for student in page.items {
+        let Some(grade) = student.grade else {
+            continue;
+        };
+        if rollover.final_grade.is_some_and(|last| grade >= last) {
+            continue;
+        }
+        let promoted = crud::update(student.id, |student: &mut Student| {
+            student.grade = Some(grade.saturating_add(1));
+            student.updated_at = Some(now);
+        });
+        if promoted.is_ok() {
+            rollover.students_promoted += 1;
+        }
+    }
+    rollover.cursor = page.next_cursor;
+    page.next_cursor.is_none()
+}
+
+// The latest rollover, if any.
+fn current() -> Option<Rollover> {
+    ROLLOVER_STATE.with(|s| s.borrow().get().rollover.clone())
+}
+
+// Helper function to store a rollover's progress.
+fn store(rollover: &Rollover) {
+    ROLLOVER_STATE
+        .with(|s| {
+            s.borrow_mut().set(RolloverState {
+                rollover: Some(rollover.clone()),
+            })
+        })
+        .expect("Cannot store the rollover state");
+}
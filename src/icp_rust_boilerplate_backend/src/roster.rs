@@ -0,0 +1,188 @@
+use crate::auth::require_admin;
+use crate::class::{self, _add_class_members};
+use crate::env::{CanisterEnv, Clock};
+use crate::perf;
+use crate::student::{upsert_by_external_id, Student};
+use crate::Error;
+
+// Most rows a single import may carry.
+const MAX_ROSTER_ROWS: usize = 500;
+
+// Columns a CSV roster must have, in any order. `class` is optional.
+const REQUIRED_COLUMNS: [&str; 3] = ["name", "email", "external_id"];
+
+// How a roster is encoded.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+pub enum RosterFormat {
+    // Comma-separated, with a header row naming the columns.
+    Csv,
+    // An array of objects with the same fields as the CSV columns.
+    Json,
+}
+
+// One student as the school information system lists them.
+#[derive(Deserialize)]
+struct RosterRow {
+    name: String,
+    email: String,
+    external_id: String,
+    // Name of a current class to add the student to.
+    class: Option<String>,
+}
+
+// What importing one row did.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub struct RosterImport {
+    pub student: Student,
+    // Whether the student was new rather than updated.
+    pub created: bool,
+    pub class_id: Option<u64>,
+}
+
+// Create or update students from a roster exported by the school information system, matching
+// them on their external ID. Each row succeeds or fails on its own; the results are returned in
+// the order of the rows.
+#[ic_cdk::update]
+fn import_roster(
+    format: RosterFormat,
+    data: String,
+) -> Result<Vec<Result<RosterImport, Error>>, Error> {
+    let _perf = perf::measure("import_roster");
+    require_admin()?;
+    _import_roster(&CanisterEnv, format, &data)
+}
+
+// Run a roster import without keeping anything: this is a query, so every write the import
+// makes is discarded when it returns. The results are what import_roster would return right
+// now, including rows that update a student created by an earlier row of the same roster.
+#[ic_cdk::query]
+fn preview_roster_import(
+    format: RosterFormat,
+    data: String,
+) -> Result<Vec<Result<RosterImport, Error>>, Error> {
+    require_admin()?;
+    _import_roster(&CanisterEnv, format, &data)
+}
+
+// Internal function to import a roster, taking timestamps from `clock`.
+pub(crate) fn _import_roster(
+    clock: &impl Clock,
+    format: RosterFormat,
+    data: &str,
+) -> Result<Vec<Result<RosterImport, Error>>, Error> {
+    let rows = match format {
+        RosterFormat::Csv => parse_csv(data)?,
+        RosterFormat::Json => serde_json::from_str(data).map_err(|e| Error::InvalidInput {
+            msg: format!("The roster is not valid JSON: {}.", e),
+        })?,
+    };
+    if rows.len() > MAX_ROSTER_ROWS {
+        return Err(Error::InvalidInput {
+            msg: format!("A roster can have at most {} rows.", MAX_ROSTER_ROWS),
+        });
+    }
+    Ok(rows.into_iter().map(|row| import_row(clock, row)).collect())
+}
+
+// Import one row. The class is resolved first, so a row naming an unknown class changes nothing.
+fn import_row(clock: &impl Clock, row: RosterRow) -> Result<RosterImport, Error> {
+    let class_id = match row.class.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(name) => Some(find_class(name)?),
+    };
+    let (student, created) = upsert_by_external_id(clock, &row.external_id, row.name, row.email)?;
+    if let Some(class_id) = class_id {
+        _add_class_members(clock, class_id, vec![student.id])?;
+    }
+    Ok(RosterImport {
+        student,
+        created,
+        class_id,
+    })
+}
+
+// The ID of the one current class called `name`, ignoring case.
+fn find_class(name: &str) -> Result<u64, Error> {
+    let matches =
+        class::classes_where(|c| c.is_current() && c.name.trim().eq_ignore_ascii_case(name));
+    match matches.as_slice() {
+        [class] => Ok(class.id),
+        [] => Err(Error::NotFound {
+            msg: format!("No current class is called \"{}\".", name),
+        }),
+        _ => Err(Error::InvalidInput {
+            msg: format!("Several current classes are called \"{}\".", name),
+        }),
+    }
+}
+
+// Parse a CSV roster. Blank lines are skipped.
+fn parse_csv(data: &str) -> Result<Vec<RosterRow>, Error> {
+    let mut records = csv_records(data)?
+        .into_iter()
+        .filter(|record| record.iter().any(|field| !field.trim().is_empty()));
+    let Some(header) = records.next() else {
+        return Ok(Vec::new());
+    };
+    let header: Vec<String> = header.iter().map(|h| h.trim().to_lowercase()).collect();
+    let column = |name: &str| header.iter().position(|h| h == name);
+    if let Some(missing) = REQUIRED_COLUMNS.iter().find(|name| column(name).is_none()) {
+        return Err(Error::InvalidInput {
+            msg: format!("The roster has no \"{}\" column.", missing),
+        });
+    }
+    let (name, email, external_id) = (
+        column("name").unwrap_or_default(),
+        column("email").unwrap_or_default(),
+        column("external_id").unwrap_or_default(),
+    );
+    let class = column("class");
+
+    let field = |record: &[String], i: usize| record.get(i).map_or("", |f| f.trim()).to_string();
+    Ok(records
+        .map(|record| RosterRow {
+            name: field(&record, name),
+            email: field(&record, email),
+            external_id: field(&record, external_id),
+            class: class.map(|i| field(&record, i)),
+        })
+        .collect())
+}
+
+// Split CSV text into records of fields, following RFC 4180: fields may be quoted, and quotes
+// inside quoted fields are doubled.
+fn csv_records(data: &str) -> Result<Vec<Vec<String>>, Error> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = data.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => record.push(std::mem::take(&mut field)),
+            (false, '\r') if chars.peek() == Some(&'\n') => {}
+            (false, '\n') => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            (false, c) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(Error::InvalidInput {
+            msg: "The roster ends inside a quoted field.".to_string(),
+        });
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
@@ -0,0 +1,56 @@
+use std::cell::Cell;
+use std::time::Duration;
+
+use crate::class_set;
+use crate::ebook_loan;
+use crate::env::{CanisterEnv, Clock};
+use crate::outbox;
+use crate::retention;
+use crate::rollover;
+use crate::snapshot;
+
+// How often the periodic jobs run. Nothing they do needs to happen sooner than this.
+const JOB_INTERVAL: Duration = Duration::from_secs(60);
+
+thread_local! {
+    // Whether a timer to run the next snapshot and rollover batch is already set.
+    static BATCH_SCHEDULED: Cell<bool> = const { Cell::new(false) };
+}
+
+// Register the timer for the periodic jobs. Timers don't survive an upgrade, so init and
+// post_upgrade both call this.
+pub(crate) fn start() {
+    ic_cdk_timers::set_timer_interval(JOB_INTERVAL, run_jobs);
+}
+
+// Have the next batch of the snapshot or rollover in progress run right after this call, instead
+// of waiting for the periodic jobs.
+pub(crate) fn wake_batches() {
+    if cfg!(not(test)) && !BATCH_SCHEDULED.with(|scheduled| scheduled.replace(true)) {
+        ic_cdk_timers::set_timer(Duration::ZERO, run_batches);
+    }
+}
+
+// Release class set reservations whose window ended, start the outbox items that are due, move
+// e-book hold queues on and continue the retention cleanup. Also picks a snapshot or rollover
+// back up if nothing is running its batches, as after an upgrade.
+fn run_jobs() {
+    class_set::release_expired(CanisterEnv.now());
+    outbox::run_due(&CanisterEnv);
+    ebook_loan::run_pending(&CanisterEnv);
+    retention::run_pending(&CanisterEnv);
+    if snapshot::in_progress() || rollover::in_progress() {
+        wake_batches();
+    }
+}
+
+// Run one batch of the snapshot and the rollover in progress, and set another timer until both
+// are done. Each batch is a message of its own, so a large job never hits the instruction limit.
+fn run_batches() {
+    BATCH_SCHEDULED.with(|scheduled| scheduled.set(false));
+    snapshot::run_pending(&CanisterEnv);
+    rollover::run_pending(&CanisterEnv);
+    if snapshot::in_progress() || rollover::in_progress() {
+        wake_batches();
+    }
+}
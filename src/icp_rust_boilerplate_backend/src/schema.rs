@@ -0,0 +1,54 @@
+use candid::types::{CandidType, Label, TypeInner};
+
+use crate::http::ROUTES;
+use crate::Error;
+
+// Machine-readable description of everything the canister exposes.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct ApiSchema {
+    // The canister's Candid interface: every method with its argument and result types.
+    pub candid: String,
+    pub http_routes: Vec<HttpRoute>,
+    // Variants of the Error type methods fail with.
+    pub error_codes: Vec<String>,
+}
+
+// A path served through `http_request`.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct HttpRoute {
+    pub method: String,
+    pub path: String,
+    pub description: String,
+}
+
+// Describe the canister's methods, HTTP routes and error codes, derived from the code itself so
+// it always matches the running build.
+#[ic_cdk::query]
+fn get_api_schema() -> ApiSchema {
+    ApiSchema {
+        candid: crate::__export_service(),
+        http_routes: ROUTES
+            .iter()
+            .map(|(method, path, description)| HttpRoute {
+                method: method.to_string(),
+                path: path.to_string(),
+                description: description.to_string(),
+            })
+            .collect(),
+        error_codes: error_codes(),
+    }
+}
+
+// Names of the Error variants, read from the type's Candid description.
+fn error_codes() -> Vec<String> {
+    match Error::ty().as_ref() {
+        TypeInner::Variant(fields) => fields
+            .iter()
+            .map(|field| match field.id.as_ref() {
+                Label::Named(name) => name.clone(),
+                label => label.to_string(),
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,121 @@
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument,
+    SignWithEcdsaArgument,
+};
+
+use crate::auth::require_admin;
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::{Error, SIGNING_CONFIG};
+
+// Threshold key used unless configured otherwise, for both ECDSA signing and vetKD; local
+// replicas provide "dfx_test_key" and test subnets "test_key_1".
+pub const DEFAULT_KEY_NAME: &str = "key_1";
+
+// Longest key name accepted.
+const MAX_KEY_NAME_LENGTH: usize = 64;
+
+// What a signature is for. Each purpose signs with its own key derived from the canister's, so
+// a signature made for one can't be passed off as another.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+pub enum SigningPurpose {
+    LoanReceipts,
+    Reports,
+}
+
+impl SigningPurpose {
+    fn derivation_path(self) -> Vec<Vec<u8>> {
+        match self {
+            SigningPurpose::LoanReceipts => vec![b"loan-receipts".to_vec()],
+            SigningPurpose::Reports => vec![b"reports".to_vec()],
+        }
+    }
+}
+
+// Which threshold key the canister signs and derives vetKD keys with.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct SigningConfig {
+    // Optional so that None means DEFAULT_KEY_NAME.
+    pub key_name: Option<String>,
+}
+
+// Store SigningConfig as Candid.
+candid_storable!(SigningConfig);
+
+// Retrieve the name of the threshold key the canister signs and derives vetKD keys with.
+#[ic_cdk::query]
+fn get_signing_key_name() -> String {
+    key_name()
+}
+
+// Choose the threshold ECDSA key to sign with, or the default with None.
+#[ic_cdk::update]
+fn set_signing_key_name(name: Option<String>) -> Result<String, Error> {
+    let _perf = perf::measure("set_signing_key_name");
+    require_admin()?;
+    if let Some(name) = &name {
+        if name.trim().is_empty() || name.len() > MAX_KEY_NAME_LENGTH {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "A key name must be 1 to {} characters.",
+                    MAX_KEY_NAME_LENGTH
+                ),
+            });
+        }
+    }
+    SIGNING_CONFIG
+        .with(|c| c.borrow_mut().set(SigningConfig { key_name: name }))
+        .expect("Cannot store the signing config");
+    Ok(key_name())
+}
+
+// Retrieve the SEC1-encoded public key that verifies signatures made for `purpose`. This asks
+// the management canister, so it is an update call.
+#[ic_cdk::update]
+async fn get_signing_public_key(purpose: SigningPurpose) -> Result<Vec<u8>, Error> {
+    let _perf = perf::measure("get_signing_public_key");
+    let argument = EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: purpose.derivation_path(),
+        key_id: key_id(),
+    };
+    match ecdsa_public_key(argument).await {
+        Ok((response,)) => Ok(response.public_key),
+        Err((code, msg)) => Err(Error::Unavailable {
+            msg: format!("Cannot obtain the public key ({:?}): {}", code, msg),
+        }),
+    }
+}
+
+// Sign a SHA-256 message hash for `purpose`, returning the 64-byte r || s signature.
+pub(crate) async fn sign(
+    purpose: SigningPurpose,
+    message_hash: [u8; 32],
+) -> Result<Vec<u8>, Error> {
+    let argument = SignWithEcdsaArgument {
+        message_hash: message_hash.to_vec(),
+        derivation_path: purpose.derivation_path(),
+        key_id: key_id(),
+    };
+    match sign_with_ecdsa(argument).await {
+        Ok((response,)) => Ok(response.signature),
+        Err((code, msg)) => Err(Error::Unavailable {
+            msg: format!("Cannot sign ({:?}): {}", code, msg),
+        }),
+    }
+}
+
+// Name of the configured key.
+pub(crate) fn key_name() -> String {
+    SIGNING_CONFIG
+        .with(|c| c.borrow().get().key_name.clone())
+        .unwrap_or_else(|| DEFAULT_KEY_NAME.to_string())
+}
+
+// The configured key on secp256k1, the only curve threshold ECDSA offers.
+fn key_id() -> EcdsaKeyId {
+    EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name: key_name(),
+    }
+}
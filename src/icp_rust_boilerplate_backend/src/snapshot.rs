@@ -0,0 +1,575 @@
+use candid::Principal;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::{Cell as StdCell, RefCell};
+use std::thread::LocalKey;
+
+use crate::auth::require_admin;
+use crate::book::{self, Book};
+use crate::crud::{self, Record};
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::id_counter::{next_id, Entity};
+use crate::loan::Loan;
+use crate::log;
+use crate::pagination::budget_exhausted;
+use crate::perf;
+use crate::proposal::{_propose_admin_action, AdminAction, Proposal};
+use crate::scheduler;
+use crate::storable::candid_storable;
+use crate::student::{self, Student};
+use crate::{
+    Error, Memory, SNAPSHOT_BOOKS, SNAPSHOT_JOB, SNAPSHOT_LOANS, SNAPSHOT_STORAGE,
+    SNAPSHOT_STUDENTS,
+};
+
+// Longest snapshot label accepted.
+const MAX_LABEL_LENGTH: usize = 100;
+
+// Most records a single batch copies, deletes or restores.
+const MAX_SNAPSHOT_BATCH: usize = 200;
+
+// Copies of one collection's records, keyed by (snapshot ID, record ID).
+type SnapshotCopies<R> = &'static LocalKey<RefCell<StableBTreeMap<(u64, u64), R, Memory>>>;
+
+thread_local! {
+    // Whether the running call is a snapshot batch, which may write while the collections are
+    // frozen.
+    static IN_BATCH: StdCell<bool> = const { StdCell::new(false) };
+}
+
+// Define the Snapshot struct to describe a copy of the students, books and loans taken at one
+// point, e.g. before a bulk import, so they can be rolled back to it.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct Snapshot {
+    pub id: u64,
+    pub label: String,
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub students: u64,
+    pub books: u64,
+    pub loans: u64,
+}
+
+// Store Snapshot as Candid, bounded to 512 bytes.
+candid_storable!(Snapshot, max_size = 512);
+
+// What a snapshot job is doing. Taking a snapshot copies each collection in turn; rolling back
+// deletes the records created since, loans first, then writes the copies back, students and
+// books before the loans that reference them.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SnapshotStep {
+    CopyStudents,
+    CopyBooks,
+    CopyLoans,
+    RemoveLoans,
+    RemoveBooks,
+    RemoveStudents,
+    RestoreStudents,
+    RestoreBooks,
+    RestoreLoans,
+    Done,
+}
+
+impl SnapshotStep {
+    // The step after this one.
+    fn next(self) -> SnapshotStep {
+        match self {
+            SnapshotStep::CopyStudents => SnapshotStep::CopyBooks,
+            SnapshotStep::CopyBooks => SnapshotStep::CopyLoans,
+            SnapshotStep::RemoveLoans => SnapshotStep::RemoveBooks,
+            SnapshotStep::RemoveBooks => SnapshotStep::RemoveStudents,
+            SnapshotStep::RemoveStudents => SnapshotStep::RestoreStudents,
+            SnapshotStep::RestoreStudents => SnapshotStep::RestoreBooks,
+            SnapshotStep::RestoreBooks => SnapshotStep::RestoreLoans,
+            SnapshotStep::CopyLoans | SnapshotStep::RestoreLoans | SnapshotStep::Done => {
+                SnapshotStep::Done
+            }
+        }
+    }
+}
+
+// Define the SnapshotJob struct to record the progress of taking a snapshot or rolling back to
+// one. The snapshot is listed once its copy is complete.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct SnapshotJob {
+    pub snapshot: Snapshot,
+    pub rollback: bool,
+    pub step: SnapshotStep,
+    // Record ID to continue the current step from.
+    pub cursor: Option<u64>,
+    // Records a rollback deleted and wrote back so far.
+    pub removed: u64,
+    pub restored: u64,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+}
+
+// The latest snapshot job, finished or not.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct SnapshotJobState {
+    pub job: Option<SnapshotJob>,
+}
+
+// Store SnapshotJobState as Candid.
+candid_storable!(SnapshotJobState);
+
+// Start copying the students, books and loans. The copy runs in batches on a timer, or sooner
+// through run_snapshot_batch; until it finishes those collections can't be changed, so
+// the copy is consistent.
+#[ic_cdk::update]
+fn create_snapshot(label: String) -> Result<SnapshotJob, Error> {
+    let _perf = perf::measure("create_snapshot");
+    require_admin()?;
+    _create_snapshot(&CanisterEnv, label)
+}
+
+// Retrieve every complete snapshot, oldest first.
+#[ic_cdk::query]
+fn list_snapshots() -> Result<Vec<Snapshot>, Error> {
+    require_admin()?;
+    Ok(SNAPSHOT_STORAGE.with(|s| s.borrow().iter().map(|(_, snapshot)| snapshot).collect()))
+}
+
+// Retrieve the latest snapshot or rollback job and how far it got.
+#[ic_cdk::query]
+fn get_snapshot_job() -> Result<Option<SnapshotJob>, Error> {
+    require_admin()?;
+    Ok(current())
+}
+
+// Run the next batch of the snapshot or rollback in progress now instead of waiting for the
+// timer.
+#[ic_cdk::update]
+fn run_snapshot_batch() -> Result<SnapshotJob, Error> {
+    let _perf = perf::measure("run_snapshot_batch");
+    require_admin()?;
+    _run_batch(&CanisterEnv).ok_or_else(|| Error::NotFound {
+        msg: "No snapshot or rollback is in progress.".to_string(),
+    })
+}
+
+// Propose deleting a snapshot and its copies to free their memory. It is deleted once another
+// administrator approves.
+#[ic_cdk::update]
+fn delete_snapshot(id: u64) -> Result<Proposal, Error> {
+    let _perf = perf::measure("delete_snapshot");
+    require_admin()?;
+    _propose_admin_action(
+        &CanisterEnv,
+        AdminAction::DeleteSnapshot { snapshot_id: id },
+    )
+}
+
+// Check that a snapshot could be deleted right now.
+pub(crate) fn validate_deletion(id: u64) -> Result<(), Error> {
+    find(id)?;
+    check_idle()
+}
+
+// Delete a snapshot and its copies. A deleted snapshot can't be rolled back to, so this runs
+// only through an approved proposal.
+pub(crate) fn delete(id: u64) -> Result<Snapshot, Error> {
+    check_idle()?;
+    let snapshot = find(id)?;
+    remove_copies(id, &SNAPSHOT_STUDENTS);
+    remove_copies(id, &SNAPSHOT_BOOKS);
+    remove_copies(id, &SNAPSHOT_LOANS);
+    SNAPSHOT_STORAGE.with(|s| s.borrow_mut().remove(&id));
+    log::info(format!("Deleted snapshot id={} ({}).", id, snapshot.label));
+    Ok(snapshot)
+}
+
+// Propose rolling the students, books and loans back to a snapshot. It starts once another
+// administrator approves the proposal and runs in batches like taking a snapshot.
+#[ic_cdk::update]
+fn rollback_to_snapshot(id: u64) -> Result<Proposal, Error> {
+    let _perf = perf::measure("rollback_to_snapshot");
+    require_admin()?;
+    _propose_admin_action(
+        &CanisterEnv,
+        AdminAction::RollbackToSnapshot { snapshot_id: id },
+    )
+}
+
+// Internal function to start a snapshot on behalf of `env`'s caller.
+pub(crate) fn _create_snapshot(
+    env: &(impl Clock + CallerProvider),
+    label: String,
+) -> Result<SnapshotJob, Error> {
+    let label = label.trim().to_string();
+    if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A snapshot label must be 1 to {} characters.",
+                MAX_LABEL_LENGTH
+            ),
+        });
+    }
+    check_idle()?;
+    let snapshot = Snapshot {
+        id: next_id(Entity::Snapshot)?,
+        label,
+        created_by: env.caller(),
+        created_at: env.now(),
+        students: 0,
+        books: 0,
+        loans: 0,
+    };
+    let job = start(snapshot, false, env.now());
+    log::info(format!(
+        "{} started snapshot id={} ({}).",
+        job.snapshot.created_by, job.snapshot.id, job.snapshot.label
+    ));
+    Ok(job)
+}
+
+// Check that a snapshot exists and nothing else is running, for a rollback proposal.
+pub(crate) fn validate_rollback(id: u64) -> Result<(), Error> {
+    find(id)?;
+    check_idle()
+}
+
+// Start putting the students, books and loans back as they were in a snapshot. Records created
+// since are deleted with their delete policies applied, and every copied record is written back
+// with its lookup indexes.
+pub(crate) fn rollback(clock: &impl Clock, id: u64) -> Result<(), Error> {
+    validate_rollback(id)?;
+    let job = start(find(id)?, true, clock.now());
+    log::warn(format!(
+        "Started rolling back to snapshot id={} ({}).",
+        id, job.snapshot.label
+    ));
+    Ok(())
+}
+
+// Whether students, books and loans may be written now: not while a snapshot is being taken or
+// rolled back, except by the job's own batches.
+pub(crate) fn check_writable() -> Result<(), Error> {
+    if IN_BATCH.with(|in_batch| in_batch.get()) || !in_progress() {
+        return Ok(());
+    }
+    Err(Error::Unavailable {
+        msg: "Students, books and loans can't change while a snapshot is taken or rolled back."
+            .to_string(),
+    })
+}
+
+// Whether a snapshot is being taken or rolled back.
+pub(crate) fn in_progress() -> bool {
+    current().is_some_and(|job| job.step != SnapshotStep::Done)
+}
+
+// Hook run from the batch timer: continue the snapshot or rollback in progress, if any.
+pub(crate) fn run_pending(clock: &impl Clock) {
+    _run_batch(clock);
+}
+
+// Internal function to run one batch of the job in progress, returning its progress, or None
+// when there is nothing to do. A batch stops at MAX_SNAPSHOT_BATCH records or when the
+// instruction budget runs out, recording where to continue.
+pub(crate) fn _run_batch(clock: &impl Clock) -> Option<SnapshotJob> {
+    let mut job = current().filter(|job| job.step != SnapshotStep::Done)?;
+    IN_BATCH.with(|in_batch| in_batch.set(true));
+    let id = job.snapshot.id;
+    let start = job.cursor.unwrap_or(0);
+    let (handled, cursor) = match job.step {
+        SnapshotStep::CopyStudents => copy::<Student>(id, &SNAPSHOT_STUDENTS, start),
+        SnapshotStep::CopyBooks => copy::<Book>(id, &SNAPSHOT_BOOKS, start),
+        SnapshotStep::CopyLoans => copy::<Loan>(id, &SNAPSHOT_LOANS, start),
+        SnapshotStep::RemoveLoans => remove_extra::<Loan>(id, &SNAPSHOT_LOANS, start),
+        SnapshotStep::RemoveBooks => remove_extra::<Book>(id, &SNAPSHOT_BOOKS, start),
+        SnapshotStep::RemoveStudents => remove_extra::<Student>(id, &SNAPSHOT_STUDENTS, start),
+        SnapshotStep::RestoreStudents => restore(id, &SNAPSHOT_STUDENTS, start, student::restore),
+        SnapshotStep::RestoreBooks => restore(id, &SNAPSHOT_BOOKS, start, book::restore),
+        SnapshotStep::RestoreLoans => {
+            restore(id, &SNAPSHOT_LOANS, start, |loan: Loan| crud::insert(&loan))
+        }
+        SnapshotStep::Done => (0, None),
+    };
+    IN_BATCH.with(|in_batch| in_batch.set(false));
+
+    match job.step {
+        SnapshotStep::CopyStudents => job.snapshot.students += handled,
+        SnapshotStep::CopyBooks => job.snapshot.books += handled,
+        SnapshotStep::CopyLoans => job.snapshot.loans += handled,
+        SnapshotStep::RemoveLoans | SnapshotStep::RemoveBooks | SnapshotStep::RemoveStudents => {
+            job.removed += handled
+        }
+        _ => job.restored += handled,
+    }
+    job.cursor = cursor;
+    if cursor.is_none() {
+        job.step = job.step.next();
+    }
+    if job.step == SnapshotStep::Done {
+        finish(&mut job, clock.now());
+    }
+    store(&job);
+    Some(job)
+}
+
+// Helper function to record a finished job: a finished copy becomes a listed snapshot.
+fn finish(job: &mut SnapshotJob, now: u64) {
+    job.finished_at = Some(now);
+    let snapshot = &job.snapshot;
+    if job.rollback {
+        log::warn(format!(
+            "Rolled back to snapshot id={} ({}): restored {} records and deleted {} created \
+             since.",
+            snapshot.id, snapshot.label, job.restored, job.removed
+        ));
+    } else {
+        SNAPSHOT_STORAGE.with(|s| s.borrow_mut().insert(snapshot.id, snapshot.clone()));
+        log::info(format!(
+            "Snapshot id={} ({}) copied {} students, {} books and {} loans.",
+            snapshot.id, snapshot.label, snapshot.students, snapshot.books, snapshot.loans
+        ));
+    }
+}
+
+// Fetch a complete snapshot by ID.
+fn find(id: u64) -> Result<Snapshot, Error> {
+    SNAPSHOT_STORAGE
+        .with(|s| s.borrow().get(&id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("A snapshot with id={} not found.", id),
+        })
+}
+
+// Refuse to start or delete anything while a snapshot is being taken or rolled back.
+fn check_idle() -> Result<(), Error> {
+    if in_progress() {
+        return Err(Error::InvalidInput {
+            msg: "A snapshot or rollback is already in progress.".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Copy a batch of readable records of a collection into `copies`, from record ID `start` on.
+// Returns how many were copied and the ID to continue from, None once the collection is done.
+fn copy<R: Record>(id: u64, copies: SnapshotCopies<R>, start: u64) -> (u64, Option<u64>) {
+    let (batch, cursor) = batch(R::storage(), start);
+    let mut copied = 0;
+    copies.with(|c| {
+        let mut copies = c.borrow_mut();
+        for (record_id, record) in batch {
+            if !record.is_corrupted() {
+                copies.insert((id, record_id), record);
+                copied += 1;
+            }
+        }
+    });
+    (copied, cursor)
+}
+
+// Delete a batch of the records of a collection that the snapshot has no copy of, from record
+// ID `start` on. Records that can't be deleted are logged and left in place.
+fn remove_extra<R: Record>(id: u64, copies: SnapshotCopies<R>, start: u64) -> (u64, Option<u64>) {
+    let (batch, cursor) = batch(R::storage(), start);
+    let mut removed = 0;
+    for (record_id, _) in batch {
+        if copies.with(|c| c.borrow().contains_key(&(id, record_id))) {
+            continue;
+        }
+        match crud::delete::<R>(record_id, true) {
+            Ok(_) => removed += 1,
+            Err(_) => log::warn(format!(
+                "Rollback to snapshot id={} could not delete {} id={}.",
+                id,
+                R::KIND,
+                record_id
+            )),
+        }
+    }
+    (removed, cursor)
+}
+
+// Write a batch of copies back with `restore`, from record ID `start` on. Copies that can't be
+// written are logged and skipped.
+fn restore<R: Record>(
+    id: u64,
+    copies: SnapshotCopies<R>,
+    start: u64,
+    restore: impl Fn(R) -> Result<(), Error>,
+) -> (u64, Option<u64>) {
+    let mut batch: Vec<(u64, R)> = copies.with(|c| {
+        c.borrow()
+            .range((id, start)..=(id, u64::MAX))
+            .map(|((_, record_id), record)| (record_id, record))
+            .take(MAX_SNAPSHOT_BATCH + 1)
+            .collect()
+    });
+    let cursor = take_batch(&mut batch);
+    let mut restored = 0;
+    for (record_id, record) in batch {
+        match restore(record) {
+            Ok(()) => restored += 1,
+            Err(_) => log::warn(format!(
+                "Rollback to snapshot id={} could not restore {} id={}.",
+                id,
+                R::KIND,
+                record_id
+            )),
+        }
+    }
+    (restored, cursor)
+}
+
+// Read a batch of a collection from key `start` on, with the key to continue from.
+fn batch<R: Record>(storage: crud::StorageKey<R>, start: u64) -> (Vec<(u64, R)>, Option<u64>) {
+    let mut batch: Vec<(u64, R)> = storage.with(|s| {
+        s.borrow()
+            .range(start..)
+            .take(MAX_SNAPSHOT_BATCH + 1)
+            .collect()
+    });
+    let cursor = take_batch(&mut batch);
+    (batch, cursor)
+}
+
+// Cut `read` down to what this batch handles: at most MAX_SNAPSHOT_BATCH records, fewer once the
+// instruction budget runs out, but always at least one. Returns the ID of the first record left
+// for the next batch.
+fn take_batch<R>(read: &mut Vec<(u64, R)>) -> Option<u64> {
+    let mut end = read.len().min(MAX_SNAPSHOT_BATCH);
+    if end > 1 && budget_exhausted() {
+        end = 1;
+    }
+    let cursor = read.get(end).map(|(id, _)| *id);
+    read.truncate(end);
+    cursor
+}
+
+// Remove a snapshot's copies of one collection.
+fn remove_copies<R: Record>(id: u64, copies: SnapshotCopies<R>) {
+    copies.with(|c| {
+        let mut copies = c.borrow_mut();
+        let keys: Vec<(u64, u64)> = copies
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            copies.remove(&key);
+        }
+    });
+}
+
+// Helper function to store a new job and return it.
+fn start(snapshot: Snapshot, rollback: bool, now: u64) -> SnapshotJob {
+    let job = SnapshotJob {
+        snapshot,
+        rollback,
+        step: if rollback {
+            SnapshotStep::RemoveLoans
+        } else {
+            SnapshotStep::CopyStudents
+        },
+        cursor: None,
+        removed: 0,
+        restored: 0,
+        started_at: now,
+        finished_at: None,
+    };
+    store(&job);
+    scheduler::wake_batches();
+    job
+}
+
+// The latest snapshot job, if any.
+fn current() -> Option<SnapshotJob> {
+    SNAPSHOT_JOB.with(|s| s.borrow().get().job.clone())
+}
+
+// Helper function to store a job's progress.
+fn store(job: &SnapshotJob) {
+    SNAPSHOT_JOB
+        .with(|s| {
+            s.borrow_mut().set(SnapshotJobState {
+                job: Some(job.clone()),
+            })
+        })
+        .expect("Cannot store the snapshot job");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::{_add_book, BookPayload};
+    use crate::env::TestEnv;
+    use crate::student::{_add_student, StudentPayload};
+
+    // 2023-11-14, an arbitrary day to start the clock on.
+    const START: u64 = 1_700_000_000_000_000_000;
+
+    fn add_student(env: &TestEnv, name: &str) -> Student {
+        let payload: StudentPayload = serde_json::from_str(&format!(
+            r#"{{"name": "{}", "contacts": [
+                {{"kind": "Email", "value": "rahma@example.com", "preferred": true}}]}}"#,
+            name
+        ))
+        .unwrap();
+        _add_student(env, payload).unwrap()
+    }
+
+    // Run batches until the job in progress finishes.
+    fn finish_job(env: &TestEnv) -> SnapshotJob {
+        loop {
+            let job = _run_batch(env).unwrap();
+            if job.step == SnapshotStep::Done {
+                return job;
+            }
+        }
+    }
+
+    #[test]
+    fn snapshot_freezes_writes_until_copied() {
+        let env = TestEnv::new(START, 1);
+        add_student(&env, "Siti Rahma");
+        let job = _create_snapshot(&env, " before import ".to_string()).unwrap();
+        assert!(find(job.snapshot.id).is_err());
+        assert!(matches!(
+            try_add_student(&env),
+            Err(Error::Unavailable { .. })
+        ));
+
+        let job = finish_job(&env);
+        assert_eq!(job.snapshot.label, "before import");
+        assert_eq!((job.snapshot.students, job.snapshot.books), (1, 0));
+        assert!(find(job.snapshot.id).is_ok());
+        assert!(try_add_student(&env).is_ok());
+    }
+
+    fn try_add_student(env: &TestEnv) -> Result<Student, Error> {
+        let payload: StudentPayload = serde_json::from_str(
+            r#"{"name": "Budi", "contacts": [
+                {"kind": "Email", "value": "budi@example.com", "preferred": true}]}"#,
+        )
+        .unwrap();
+        _add_student(env, payload)
+    }
+
+    #[test]
+    fn rollback_deletes_new_records_and_restores_changed_ones() {
+        let env = TestEnv::new(START, 1);
+        let kept = add_student(&env, "Siti Rahma");
+        let book: BookPayload =
+            serde_json::from_str(r#"{"title": "Laskar Pelangi", "author": "Andrea Hirata"}"#)
+                .unwrap();
+        let book = _add_book(&env, book).unwrap();
+        _create_snapshot(&env, "before import".to_string()).unwrap();
+        let snapshot = finish_job(&env).snapshot;
+
+        let added = add_student(&env, "Budi");
+        crud::update(book.id, |book: &mut Book| {
+            book.title = "Sang Pemimpi".to_string()
+        })
+        .unwrap();
+        rollback(&env, snapshot.id).unwrap();
+        let job = finish_job(&env);
+        assert!(job.rollback);
+        assert_eq!((job.removed, job.restored), (1, 2));
+        assert!(!crud::exists::<Student>(added.id));
+        assert!(crud::exists::<Student>(kept.id));
+        assert_eq!(crud::find::<Book>(book.id).unwrap().title, "Laskar Pelangi");
+    }
+}
@@ -0,0 +1,99 @@
+// Implement Storable for a Candid type, and BoundedStorable when a `max_size` is given, so the
+// size bound is declared next to the type it limits. With a `fallback`, a record that fails to
+// decode is read as that value instead of trapping, so one corrupted record can't make every
+// query touching its collection fail.
+macro_rules! candid_storable {
+    ($type:ty) => {
+        crate::storable::candid_storable!(@impl $type, |error| ic_cdk::trap(&format!(
+            "Cannot decode a stored {}: {}",
+            stringify!($type),
+            error
+        )));
+    };
+    ($type:ty, max_size = $max_size:expr) => {
+        crate::storable::candid_storable!($type);
+        crate::storable::candid_storable!(@bound $type, $max_size);
+    };
+    ($type:ty, max_size = $max_size:expr, fallback = $fallback:expr) => {
+        crate::storable::candid_storable!(@impl $type, |_| $fallback);
+        crate::storable::candid_storable!(@bound $type, $max_size);
+    };
+    (@impl $type:ty, $on_error:expr) => {
+        // Implement serialization and deserialization through Candid.
+        impl ic_stable_structures::Storable for $type {
+            // The Candid macros call each other unqualified, so they must be in scope.
+            fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+                use candid::Encode;
+                std::borrow::Cow::Owned(
+                    Encode!(self).expect("Candid encoding of a stored type cannot fail"),
+                )
+            }
+
+            fn from_bytes(bytes: std::borrow::Cow<'_, [u8]>) -> Self {
+                use candid::Decode;
+                Decode!(bytes.as_ref(), Self).unwrap_or_else($on_error)
+            }
+        }
+    };
+    (@bound $type:ty, $max_size:expr) => {
+        // Set limits for storage size and flexibility.
+        impl ic_stable_structures::BoundedStorable for $type {
+            const MAX_SIZE: u32 = $max_size;
+            const IS_FIXED_SIZE: bool = false;
+        }
+    };
+}
+pub(crate) use candid_storable;
+
+// A Principal usable as a stable-structures key or value.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StorablePrincipal(pub candid::Principal);
+
+// Store a Principal as its raw bytes.
+impl ic_stable_structures::Storable for StorablePrincipal {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<'_, [u8]>) -> Self {
+        StorablePrincipal(candid::Principal::from_slice(&bytes))
+    }
+}
+
+// The empty principal, which sorts first; needed to use StorablePrincipal in tuple keys.
+impl Default for StorablePrincipal {
+    fn default() -> Self {
+        StorablePrincipal(candid::Principal::from_slice(&[]))
+    }
+}
+
+// Principals are at most 29 bytes long.
+impl ic_stable_structures::BoundedStorable for StorablePrincipal {
+    const MAX_SIZE: u32 = 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Longest string an IndexString can hold, in bytes.
+pub const MAX_INDEX_STRING_BYTES: usize = 128;
+
+// A short string usable as a stable-structures key, e.g. for lookup indexes. Callers must keep
+// it within MAX_INDEX_STRING_BYTES.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IndexString(pub String);
+
+// Store an IndexString as its UTF-8 bytes.
+impl ic_stable_structures::Storable for IndexString {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<'_, [u8]>) -> Self {
+        IndexString(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+// Set limits for IndexString storage size and flexibility.
+impl ic_stable_structures::BoundedStorable for IndexString {
+    const MAX_SIZE: u32 = MAX_INDEX_STRING_BYTES as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
@@ -0,0 +1,220 @@
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Memory as _, StableBTreeMap};
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::{
+    Error, Memory, ADMIN_STORAGE, AGGREGATE_CACHE, ALT_TITLE_INDEX, API_KEY_STORAGE, BARCODE_INDEX,
+    BLOCK_STORAGE, BOOK_ALT_TITLES, BOOK_CREATED_INDEX, BOOK_STORAGE, BOOK_TRANSLATIONS,
+    CALENDAR_TOKEN_STORAGE, CHARGE_STORAGE, CIRCULATION_JOURNAL, CLASS_SET_SCHEDULE,
+    CLASS_SET_STORAGE, CLASS_STORAGE, CONSENT_STORAGE, COVER_CHUNK_STORAGE, COVER_STORAGE,
+    DEAD_LETTER_STORAGE, DIGITAL_ASSET_STORAGE, EBOOK_CHUNK_STORAGE, EBOOK_HOLD_STORAGE,
+    EBOOK_LOAN_STORAGE, EXTENSION_REQUEST_STORAGE, GUARDIAN_STORAGE, ID_COUNTERS,
+    JOURNAL_LOAN_INDEX, LIVE_UPDATE_STORAGE, LOAN_CREATED_INDEX, LOAN_STORAGE, LOG_STORAGE,
+    MEMORY_MANAGER, MIGRATION_LOG, NOTIFICATION_STORAGE, OPEN_LOANS_BY_BOOK, OPEN_LOANS_BY_STUDENT,
+    OUTBOX_SCHEDULE, OUTBOX_STORAGE, PERF_STATS, PRIVATE_NOTES_STORAGE, PROPOSAL_STORAGE,
+    READING_LIST_STORAGE, SCHOOL_STORAGE, SCHOOL_WASM_CHUNKS, SNAPSHOT_BOOKS, SNAPSHOT_LOANS,
+    SNAPSHOT_STORAGE, SNAPSHOT_STUDENTS, STORAGE_CAPACITY, STUDENT_CREATED_INDEX,
+    STUDENT_EXTERNAL_ID_INDEX, STUDENT_PHOTO_CHUNK_STORAGE, STUDENT_PHOTO_STORAGE,
+    STUDENT_PRINCIPAL_INDEX, STUDENT_STORAGE, TITLE_INDEX, TRENDING_STORAGE, WISHLIST_STORAGE,
+};
+
+// Largest stable memory the memory manager can hand out: 32768 buckets of 128 pages.
+pub const MAX_CAPACITY_PAGES: u64 = 32_768 * 128;
+
+// Usage (in percent of the configured capacity) above which the report warns.
+const WARNING_THRESHOLD_PERCENT: u64 = 80;
+
+// Number of records sampled per collection to estimate its encoded size.
+const SAMPLE_SIZE: usize = 16;
+
+// Usage of one memory region and the collection stored in it.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct RegionUsage {
+    pub name: String,
+    pub memory_id: u8,
+    pub pages: u64,
+    pub records: u64,
+    pub estimated_bytes: u64,
+}
+
+// Overall stable memory usage of the canister.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct StorageReport {
+    pub total_pages: u64,
+    pub capacity_pages: u64,
+    pub regions: Vec<RegionUsage>,
+    pub warnings: Vec<String>,
+}
+
+// Report stable memory usage per region, with warnings when nearing the configured capacity.
+#[ic_cdk::query]
+fn get_storage_report() -> StorageReport {
+    let regions = vec![
+        map_usage("students", 1, &STUDENT_STORAGE),
+        map_usage("books", 2, &BOOK_STORAGE),
+        map_usage("loans", 3, &LOAN_STORAGE),
+        map_usage("digital_assets", 4, &DIGITAL_ASSET_STORAGE),
+        map_usage("ebook_chunks", 5, &EBOOK_CHUNK_STORAGE),
+        map_usage("ebook_loans", 6, &EBOOK_LOAN_STORAGE),
+        cell_usage("ebook_token_secret", 7, 32),
+        map_usage("ebook_holds", 8, &EBOOK_HOLD_STORAGE),
+        cell_usage("schema_version", 9, 4),
+        map_usage("migration_log", 10, &MIGRATION_LOG),
+        cell_usage("storage_capacity", 11, 8),
+        map_usage("id_counters", 12, &ID_COUNTERS),
+        cell_usage("ulid_generator", 13, 64),
+        map_usage("student_created_index", 14, &STUDENT_CREATED_INDEX),
+        map_usage("book_created_index", 15, &BOOK_CREATED_INDEX),
+        map_usage("loan_created_index", 16, &LOAN_CREATED_INDEX),
+        map_usage("api_keys", 17, &API_KEY_STORAGE),
+        map_usage("admins", 18, &ADMIN_STORAGE),
+        map_usage("proposals", 19, &PROPOSAL_STORAGE),
+        map_usage("logs", 20, &LOG_STORAGE),
+        cell_usage("log_config", 21, 24),
+        map_usage("barcode_index", 22, &BARCODE_INDEX),
+        map_usage("student_principal_index", 23, &STUDENT_PRINCIPAL_INDEX),
+        map_usage("extension_requests", 24, &EXTENSION_REQUEST_STORAGE),
+        map_usage("charges", 25, &CHARGE_STORAGE),
+        map_usage("title_index", 26, &TITLE_INDEX),
+        map_usage("covers", 27, &COVER_STORAGE),
+        map_usage("cover_chunks", 28, &COVER_CHUNK_STORAGE),
+        cell_usage("archive_config", 29, 40),
+        map_usage("school_wasm_chunks", 30, &SCHOOL_WASM_CHUNKS),
+        map_usage("schools", 31, &SCHOOL_STORAGE),
+        cell_usage("upgrade_state", 32, 4096),
+        cell_usage("retention_policy", 33, 64),
+        cell_usage("certified_digest", 34, 48),
+        cell_usage("signing_config", 35, 80),
+        map_usage("private_notes", 36, &PRIVATE_NOTES_STORAGE),
+        map_usage("consents", 37, &CONSENT_STORAGE),
+        map_usage("classes", 38, &CLASS_STORAGE),
+        map_usage("reading_lists", 39, &READING_LIST_STORAGE),
+        map_usage("wishlists", 40, &WISHLIST_STORAGE),
+        map_usage("class_sets", 41, &CLASS_SET_STORAGE),
+        cell_usage("rollover_state", 42, 256),
+        map_usage("student_external_id_index", 43, &STUDENT_EXTERNAL_ID_INDEX),
+        map_usage("guardians", 44, &GUARDIAN_STORAGE),
+        map_usage("notifications", 45, &NOTIFICATION_STORAGE),
+        map_usage("student_photos", 46, &STUDENT_PHOTO_STORAGE),
+        map_usage("student_photo_chunks", 47, &STUDENT_PHOTO_CHUNK_STORAGE),
+        map_usage("trending", 48, &TRENDING_STORAGE),
+        map_usage("calendar_tokens", 49, &CALENDAR_TOKEN_STORAGE),
+        cell_usage("catalog_config", 50, 16),
+        cell_usage("feature_flags", 51, 512),
+        map_usage("performance_stats", 52, &PERF_STATS),
+        map_usage("aggregate_cache", 53, &AGGREGATE_CACHE),
+        map_usage("open_loans_by_student", 54, &OPEN_LOANS_BY_STUDENT),
+        map_usage("open_loans_by_book", 55, &OPEN_LOANS_BY_BOOK),
+        map_usage("outbox", 56, &OUTBOX_STORAGE),
+        map_usage("outbox_schedule", 57, &OUTBOX_SCHEDULE),
+        map_usage("dead_letters", 58, &DEAD_LETTER_STORAGE),
+        map_usage("live_updates", 59, &LIVE_UPDATE_STORAGE),
+        map_usage("blocks", 60, &BLOCK_STORAGE),
+        map_usage("circulation_journal", 61, &CIRCULATION_JOURNAL),
+        map_usage("journal_loan_index", 62, &JOURNAL_LOAN_INDEX),
+        map_usage("snapshots", 63, &SNAPSHOT_STORAGE),
+        map_usage("snapshot_students", 64, &SNAPSHOT_STUDENTS),
+        map_usage("snapshot_books", 65, &SNAPSHOT_BOOKS),
+        map_usage("snapshot_loans", 66, &SNAPSHOT_LOANS),
+        map_usage("alt_title_index", 67, &ALT_TITLE_INDEX),
+        map_usage("book_translations", 68, &BOOK_TRANSLATIONS),
+        map_usage("book_alt_titles", 69, &BOOK_ALT_TITLES),
+        map_usage("class_set_schedule", 70, &CLASS_SET_SCHEDULE),
+        cell_usage("snapshot_job", 71, 1024),
+    ];
+
+    let total_pages = ic_cdk::api::stable::stable64_size();
+    let capacity_pages = STORAGE_CAPACITY.with(|c| *c.borrow().get());
+
+    let mut warnings = Vec::new();
+    let used_percent = total_pages.saturating_mul(100) / capacity_pages.max(1);
+    if used_percent >= WARNING_THRESHOLD_PERCENT {
+        warnings.push(format!(
+            "Stable memory is {}% full ({} of {} pages).",
+            used_percent, total_pages, capacity_pages
+        ));
+    }
+
+    StorageReport {
+        total_pages,
+        capacity_pages,
+        regions,
+        warnings,
+    }
+}
+
+// Check that a capacity (in 64 KiB pages) is one the memory manager can provide.
+pub(crate) fn validate_capacity(pages: u64) -> Result<(), Error> {
+    if pages == 0 || pages > MAX_CAPACITY_PAGES {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Capacity must be between 1 and {} pages.",
+                MAX_CAPACITY_PAGES
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Configure the stable memory capacity the report warns against. This is a config change, so
+// it runs only through an approved proposal.
+pub(crate) fn set_storage_capacity(pages: u64) -> Result<u64, Error> {
+    validate_capacity(pages)?;
+    STORAGE_CAPACITY
+        .with(|c| c.borrow_mut().set(pages))
+        .expect("Cannot store the storage capacity");
+    Ok(pages)
+}
+
+// Number of pages allocated to a memory region.
+fn region_pages(memory_id: u8) -> u64 {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id)).size())
+}
+
+// Usage of a region holding a single fixed-size value.
+fn cell_usage(name: &str, memory_id: u8, value_bytes: u64) -> RegionUsage {
+    RegionUsage {
+        name: name.to_string(),
+        memory_id,
+        pages: region_pages(memory_id),
+        records: 1,
+        estimated_bytes: value_bytes,
+    }
+}
+
+// Usage of a region holding a map, estimating its size from a sample of records.
+fn map_usage<K, V>(
+    name: &str,
+    memory_id: u8,
+    storage: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+) -> RegionUsage
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    let (records, estimated_bytes) = storage.with(|map| {
+        let map = map.borrow();
+        let records = map.len();
+        let (sampled, sample_bytes) =
+            map.iter()
+                .take(SAMPLE_SIZE)
+                .fold((0u64, 0u64), |(count, bytes), (key, value)| {
+                    let size = key.to_bytes().len() + value.to_bytes().len();
+                    (count + 1, bytes + size as u64)
+                });
+        let estimated_bytes = match sampled {
+            0 => 0,
+            _ => sample_bytes / sampled * records,
+        };
+        (records, estimated_bytes)
+    });
+
+    RegionUsage {
+        name: name.to_string(),
+        memory_id,
+        pages: region_pages(memory_id),
+        records,
+        estimated_bytes,
+    }
+}
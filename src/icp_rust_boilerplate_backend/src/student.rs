@@ -1,147 +1,560 @@
-use candid::{Decode, Encode};
-use ic_stable_structures::{BoundedStorable, Storable};
-use std::borrow::Cow;
+use candid::Principal;
 
-use crate::{time, Error, ID_COUNTER, STUDENT_STORAGE};
+use crate::auth::{is_admin, require_admin};
+use crate::calendar;
+use crate::class;
+use crate::consent;
+use crate::crud::{self, crud_endpoints, library_entity, RecordKind};
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::guardian;
+use crate::id_counter::Entity;
+use crate::loan::release_student;
+use crate::notification::NotificationPreferences;
+use crate::perf;
+use crate::photo;
+use crate::private_notes;
+use crate::storable::{IndexString, StorablePrincipal, MAX_INDEX_STRING_BYTES};
+use crate::wishlist;
+use crate::{Error, STUDENT_EXTERNAL_ID_INDEX, STUDENT_PRINCIPAL_INDEX};
 
 // Define the Student struct to represent a student in the system.
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 pub struct Student {
-    id: u64,
-    name: String,
-    email: String,
-    created_at: u64,
-    updated_at: Option<u64>,
+    pub id: u64,
+    pub name: String,
+    // The single email address students had before contact lists. Migration moves it into
+    // `contacts`, so it is None on every current record.
+    pub email: Option<String>,
+    // Ways to reach the student; optional so that students stored before contact lists still
+    // decode. Read it through `contacts()`.
+    pub contacts: Option<Vec<Contact>>,
+    // Postal address, for schools that send notices by mail.
+    pub address: Option<Address>,
+    // Principal the student signs in with, which lets them use self-service endpoints.
+    pub principal: Option<Principal>,
+    // Date of birth, in nanoseconds since the Unix epoch; decides access to age-rated books.
+    pub birth_date: Option<u64>,
+    // School grade, used to estimate the age of students without a date of birth.
+    pub grade: Option<u8>,
+    // ID of the student in the school information system, set by roster imports.
+    pub external_id: Option<String>,
+    // Optional messages the student wants; None asks for none of them.
+    pub notification_preferences: Option<NotificationPreferences>,
+    // Globally unique, time-sortable ID, set on records created while ULIDs are enabled.
+    pub ulid: Option<String>,
+    pub created_at: u64,
+    pub updated_at: Option<u64>,
+}
+
+// A channel a student can be reached on.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ContactKind {
+    Email,
+    // A phone number in E.164 form, e.g. "+14155550123".
+    Phone,
+}
+
+// One way to reach a student. At most one contact per kind is preferred.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Contact {
+    pub kind: ContactKind,
+    pub value: String,
+    pub preferred: bool,
 }
 
-// Implement serialization and deserialization for Student.
-impl Storable for Student {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+// A postal address.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Address {
+    pub street: String,
+    pub city: String,
+    // Empty in countries without postal codes.
+    pub postal_code: String,
+    // ISO 3166-1 alpha-2 code, e.g. "ID".
+    pub country: String,
+}
+
+// Longest street and city accepted.
+const MAX_ADDRESS_LINE_LENGTH: usize = 100;
+
+// Longest postal code accepted.
+const MAX_POSTAL_CODE_LENGTH: usize = 16;
+
+// Most contacts a student may have.
+const MAX_CONTACTS: usize = 4;
+
+// Longest contact value accepted.
+const MAX_CONTACT_LENGTH: usize = 100;
+
+// Average length of a year in nanoseconds.
+const YEAR: u64 = 31_556_952 * 1_000_000_000;
+
+impl Student {
+    // The student's age in whole years at `now`: from the date of birth, or failing that the
+    // youngest age in their grade, taken as the grade plus five.
+    pub fn age_at(&self, now: u64) -> Option<u64> {
+        match (self.birth_date, self.grade) {
+            (Some(birth_date), _) => Some(now.saturating_sub(birth_date) / YEAR),
+            (None, Some(grade)) => Some(grade as u64 + 5),
+            (None, None) => None,
+        }
     }
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+    // The student's contacts; students stored before contact lists have none.
+    pub fn contacts(&self) -> &[Contact] {
+        self.contacts.as_deref().unwrap_or_default()
     }
-}
 
-// Set limits for Student storage size and flexibility.
-impl BoundedStorable for Student {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+    // The contact to use for `kind`: the preferred one, or failing that the first.
+    pub fn preferred_contact(&self, kind: ContactKind) -> Option<&Contact> {
+        let mut of_kind = self.contacts().iter().filter(|c| c.kind == kind);
+        of_kind
+            .clone()
+            .find(|c| c.preferred)
+            .or_else(|| of_kind.next())
+    }
 }
 
+// Store students as Candid in their own collection, indexed by creation time.
+library_entity!(Student {
+    max_size: 1024,
+    kind: "student",
+    name: "Student",
+    entity: Entity::Student,
+    storage: STUDENT_STORAGE,
+    created_index: STUDENT_CREATED_INDEX,
+    on_delete: release,
+});
+
 // Define the payload structure for creating or updating a student.
 #[derive(candid::CandidType, Serialize, Deserialize, Default)]
 pub struct StudentPayload {
     name: String,
-    email: String,
+    contacts: Vec<Contact>,
+    address: Option<Address>,
+    birth_date: Option<u64>,
+    grade: Option<u8>,
+}
+
+// Define the payload structure for changing some of a student's details; fields left as None
+// keep their current value. The optional details are cleared with `opt null`.
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub struct StudentPatch {
+    name: Option<String>,
+    contacts: Option<Vec<Contact>>,
+    address: Option<Option<Address>>,
+    birth_date: Option<Option<u64>>,
+    grade: Option<Option<u8>>,
 }
 
-// Retrieve all students from the storage.
+// Expose the standard read and delete endpoints for students. The whole register is for
+// administrators; a single student can also be read by those `require_student_access` lets in.
+crud_endpoints!(Student {
+    all: get_all_students,
+    list: list_students,
+    get: get_student,
+    delete: delete_student,
+    kind: RecordKind::Student,
+    read_guard: require_admin,
+    get_guard: can_read,
+});
+
+// Retrieve the students registered within a time range (inclusive), oldest first.
 #[ic_cdk::query]
-fn get_all_students() -> Result<Vec<Student>, Error> {
-    let students = _get_all_students();
-    Ok(students)
+fn get_students_between(from: u64, to: u64) -> Result<Vec<Student>, Error> {
+    require_admin()?;
+    Ok(crud::created_between(from, to))
 }
 
-// Internal function to fetch all students as a vector.
-fn _get_all_students() -> Vec<Student> {
-    STUDENT_STORAGE.with(|students| {
-        students
-            .borrow()
-            .iter()
-            .map(|(_, value)| value.clone())
-            .collect()
+// Add a new student to the registry.
+#[ic_cdk::update]
+fn add_student(payload: StudentPayload) -> Result<Student, Error> {
+    let _perf = perf::measure("add_student");
+    require_admin()?;
+    _add_student(&CanisterEnv, payload)
+}
+
+// Internal function to add a student, taking its timestamps from `clock`.
+pub(crate) fn _add_student(clock: &impl Clock, payload: StudentPayload) -> Result<Student, Error> {
+    validate(&payload)?;
+    crud::create(clock, |id, ulid, now| Student {
+        id,
+        name: payload.name,
+        email: None,
+        contacts: Some(payload.contacts),
+        address: payload.address,
+        principal: None,
+        birth_date: payload.birth_date,
+        grade: payload.grade,
+        external_id: None,
+        notification_preferences: None,
+        ulid,
+        created_at: now,
+        updated_at: None,
+    })
+}
+
+// Update an existing student's details by ID.
+#[ic_cdk::update]
+fn update_student(id: u64, payload: StudentPayload) -> Result<Student, Error> {
+    let _perf = perf::measure("update_student");
+    require_admin()?;
+    _update_student(&CanisterEnv, id, payload)
+}
+
+// Internal function to update a student, taking its timestamps from `clock`.
+pub(crate) fn _update_student(
+    clock: &impl Clock,
+    id: u64,
+    payload: StudentPayload,
+) -> Result<Student, Error> {
+    validate(&payload)?;
+    crud::update(id, |student: &mut Student| {
+        student.name = payload.name;
+        student.contacts = Some(payload.contacts);
+        student.address = payload.address;
+        student.birth_date = payload.birth_date;
+        student.grade = payload.grade;
+        student.updated_at = Some(clock.now());
     })
 }
 
-// Retrieve a specific student by their ID.
+// Check a student payload without writing it, returning the error add_student would, or
+// update_student would for the student `id`.
 #[ic_cdk::query]
-fn get_student(id: u64) -> Result<Student, Error> {
-    match _get_student(&id) {
-        Some(student) => Ok(student),
-        None => Err(Error::NotFound {
-            msg: format!("A student with id={} not found.", id),
-        }),
+fn validate_student_payload(payload: StudentPayload, id: Option<u64>) -> Result<(), Error> {
+    require_admin()?;
+    validate(&payload)?;
+    if let Some(id) = id {
+        crud::find::<Student>(id)?;
     }
+    Ok(())
+}
+
+// Change only the given details of a student, leaving the rest as they are.
+#[ic_cdk::update]
+fn patch_student(id: u64, patch: StudentPatch) -> Result<Student, Error> {
+    let _perf = perf::measure("patch_student");
+    require_admin()?;
+    _patch_student(&CanisterEnv, id, patch)
 }
 
-// Internal function to fetch a student by ID.
-fn _get_student(id: &u64) -> Option<Student> {
-    STUDENT_STORAGE.with(|s| s.borrow().get(id))
+// Internal function to patch a student by applying `patch` over its stored details.
+pub(crate) fn _patch_student(
+    clock: &impl Clock,
+    id: u64,
+    patch: StudentPatch,
+) -> Result<Student, Error> {
+    let student = crud::find::<Student>(id)?;
+    let payload = StudentPayload {
+        contacts: patch
+            .contacts
+            .unwrap_or_else(|| student.contacts().to_vec()),
+        name: patch.name.unwrap_or(student.name),
+        address: patch.address.unwrap_or(student.address),
+        birth_date: patch.birth_date.unwrap_or(student.birth_date),
+        grade: patch.grade.unwrap_or(student.grade),
+    };
+    _update_student(clock, id, payload)
 }
 
-// Add a new student to the registry.
+// Link a student to the principal they sign in with, or unlink them with None.
 #[ic_cdk::update]
-fn add_student(payload: StudentPayload) -> Result<Student, Error> {
-    // Validate the input payload.
-    if payload.name.trim().is_empty() || payload.email.trim().is_empty() {
+fn link_student_principal(id: u64, principal: Option<Principal>) -> Result<Student, Error> {
+    let _perf = perf::measure("link_student_principal");
+    require_admin()?;
+    _link_student_principal(&CanisterEnv, id, principal)
+}
+
+// Internal function to link a student to a principal, taking the timestamp from `clock`.
+pub(crate) fn _link_student_principal(
+    clock: &impl Clock,
+    id: u64,
+    principal: Option<Principal>,
+) -> Result<Student, Error> {
+    if let Some(principal) = principal {
+        if principal == Principal::anonymous() {
+            return Err(Error::InvalidInput {
+                msg: "A student cannot be linked to the anonymous principal.".to_string(),
+            });
+        }
+        if let Some(other) = _get_student_by_principal(&principal).filter(|s| s.id != id) {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "{} is already linked to student id={}.",
+                    principal, other.id
+                ),
+            });
+        }
+    }
+
+    let previous = crud::find::<Student>(id)?.principal;
+    let student = crud::update(id, |student: &mut Student| {
+        student.principal = principal;
+        student.updated_at = Some(clock.now());
+    })?;
+    STUDENT_PRINCIPAL_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous) = previous {
+            index.remove(&StorablePrincipal(previous));
+        }
+        if let Some(principal) = principal {
+            index.insert(StorablePrincipal(principal), id);
+        }
+    });
+    Ok(student)
+}
+
+// Internal function to write back a student copied by a snapshot, moving their principal and
+// external ID index entries from whatever the student holds now.
+pub(crate) fn restore(student: Student) -> Result<(), Error> {
+    let previous = crud::get::<Student>(&student.id);
+    crud::insert(&student)?;
+    STUDENT_PRINCIPAL_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(principal) = previous.as_ref().and_then(|p| p.principal) {
+            index.remove(&StorablePrincipal(principal));
+        }
+        if let Some(principal) = student.principal {
+            index.insert(StorablePrincipal(principal), student.id);
+        }
+    });
+    STUDENT_EXTERNAL_ID_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(external_id) = previous.and_then(|p| p.external_id) {
+            index.remove(&IndexString(external_id));
+        }
+        if let Some(external_id) = &student.external_id {
+            index.insert(IndexString(external_id.clone()), student.id);
+        }
+    });
+    Ok(())
+}
+
+// Internal function to create or update the student with an external ID, keeping the details
+// the import doesn't carry. The email is added to the student's contacts if they don't have it
+// yet. Returns the student and whether it was created.
+pub(crate) fn upsert_by_external_id(
+    clock: &impl Clock,
+    external_id: &str,
+    name: String,
+    email: String,
+) -> Result<(Student, bool), Error> {
+    if external_id.trim().is_empty() || external_id.len() > MAX_INDEX_STRING_BYTES {
         return Err(Error::InvalidInput {
-            msg: "Name and email cannot be empty.".to_string(),
+            msg: format!(
+                "An external ID must be 1 to {} bytes.",
+                MAX_INDEX_STRING_BYTES
+            ),
         });
     }
+    let key = IndexString(external_id.to_string());
+    let existing = STUDENT_EXTERNAL_ID_INDEX
+        .with(|index| index.borrow().get(&key))
+        .and_then(|id| crud::get::<Student>(&id))
+        .filter(|student| student.external_id.as_deref() == Some(external_id));
+    match existing {
+        Some(student) => {
+            let payload = StudentPayload {
+                name,
+                contacts: with_email(student.contacts().to_vec(), email),
+                address: student.address,
+                birth_date: student.birth_date,
+                grade: student.grade,
+            };
+            Ok((_update_student(clock, student.id, payload)?, false))
+        }
+        None => {
+            let payload = StudentPayload {
+                name,
+                contacts: with_email(Vec::new(), email),
+                ..Default::default()
+            };
+            validate(&payload)?;
+            let student = crud::create(clock, |id, ulid, now| Student {
+                id,
+                name: payload.name,
+                contacts: Some(payload.contacts),
+                address: payload.address,
+                external_id: Some(external_id.to_string()),
+                ulid,
+                created_at: now,
+                ..Default::default()
+            })?;
+            STUDENT_EXTERNAL_ID_INDEX.with(|index| index.borrow_mut().insert(key, student.id));
+            Ok((student, true))
+        }
+    }
+}
 
-    // Generate a new unique ID for the student.
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
+// Reject callers who may not see a student's records. Only administrators, the student
+// themselves, their guardians and the teachers of their current classes may.
+pub(crate) fn require_student_access(
+    env: &impl CallerProvider,
+    student_id: u64,
+) -> Result<(), Error> {
+    let caller = env.caller();
+    if is_admin(&caller)
+        || _get_student_by_principal(&caller).is_some_and(|student| student.id == student_id)
+        || guardian::is_guardian(&caller, student_id)
+        || class::teaches(&caller, student_id)
+    {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!("You may not see the records of student id={}.", student_id),
         })
-        .expect("Cannot increment ID counter");
+    }
+}
 
-    // Create the new student with the provided payload.
-    let student = Student {
-        id,
-        name: payload.name,
-        email: payload.email,
-        created_at: time(),
-        updated_at: None,
-    };
+// Record guard of get_student.
+fn can_read(student: &Student) -> Result<(), Error> {
+    require_student_access(&CanisterEnv, student.id)
+}
 
-    // Insert the student into storage.
-    do_insert(&student);
-    Ok(student)
+// Internal function to find the student linked to `env`'s caller.
+pub(crate) fn caller_student(env: &impl CallerProvider) -> Result<Student, Error> {
+    let caller = env.caller();
+    _get_student_by_principal(&caller).ok_or_else(|| Error::Unauthorized {
+        msg: format!("{} is not linked to a student.", caller),
+    })
 }
 
-// Helper function to insert a student into storage.
-fn do_insert(student: &Student) {
-    STUDENT_STORAGE.with(|service| service.borrow_mut().insert(student.id, student.clone()));
+// Internal function to find the student linked to a principal. Index entries left behind by
+// deleted students are ignored, since the student they point to no longer exists.
+pub(crate) fn _get_student_by_principal(principal: &Principal) -> Option<Student> {
+    STUDENT_PRINCIPAL_INDEX
+        .with(|index| index.borrow().get(&StorablePrincipal(*principal)))
+        .and_then(|id| crud::get::<Student>(&id))
+        .filter(|student| student.principal == Some(*principal))
 }
 
-// Update an existing student's details by ID.
-#[ic_cdk::update]
-fn update_student(id: u64, payload: StudentPayload) -> Result<Student, Error> {
-    // Validate the input payload.
-    if payload.name.trim().is_empty() || payload.email.trim().is_empty() {
-        return Err(Error::InvalidInput {
-            msg: "Name and email cannot be empty.".to_string(),
+// `contacts` with `email` added, preferred unless another email already is. An address the
+// student already has is left as it is.
+pub(crate) fn with_email(mut contacts: Vec<Contact>, email: String) -> Vec<Contact> {
+    let email = email.trim().to_string();
+    let has = |c: &Contact| c.kind == ContactKind::Email && c.value.eq_ignore_ascii_case(&email);
+    if !contacts.iter().any(has) {
+        let preferred = !contacts
+            .iter()
+            .any(|c| c.kind == ContactKind::Email && c.preferred);
+        contacts.push(Contact {
+            kind: ContactKind::Email,
+            value: email,
+            preferred,
         });
     }
+    contacts
+}
 
-    // Fetch the student from storage and update their details.
-    match STUDENT_STORAGE.with(|service| service.borrow().get(&id)) {
-        Some(mut student) => {
-            student.name = payload.name;
-            student.email = payload.email;
-            student.updated_at = Some(time());
-            do_insert(&student); // Save the updated student back to storage.
-            Ok(student)
+// Validate a student payload.
+fn validate(payload: &StudentPayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Name cannot be empty.".to_string(),
+        });
+    }
+    if payload.contacts.is_empty() || payload.contacts.len() > MAX_CONTACTS {
+        return Err(Error::InvalidInput {
+            msg: format!("A student must have 1 to {} contacts.", MAX_CONTACTS),
+        });
+    }
+    for contact in &payload.contacts {
+        validate_contact(contact)?;
+    }
+    if let Some(address) = &payload.address {
+        validate_address(address)?;
+    }
+    for kind in [ContactKind::Email, ContactKind::Phone] {
+        let preferred = payload
+            .contacts
+            .iter()
+            .filter(|c| c.kind == kind && c.preferred)
+            .count();
+        if preferred > 1 {
+            return Err(Error::InvalidInput {
+                msg: format!("Only one {:?} contact can be preferred.", kind),
+            });
         }
-        None => Err(Error::NotFound {
-            msg: format!("Couldn't update a student with id={}. Student not found.", id),
-        }),
     }
+    Ok(())
 }
 
-// Delete a student by ID from the registry.
-#[ic_cdk::update]
-fn delete_student(id: u64) -> Result<Student, Error> {
-    // Remove the student from storage.
-    match STUDENT_STORAGE.with(|service| service.borrow_mut().remove(&id)) {
-        Some(student) => Ok(student),
-        None => Err(Error::NotFound {
-            msg: format!("Couldn't delete a student with id={}. Student not found.", id),
-        }),
+// Check that an address has a street, a city and a two-letter country code, and that the
+// postal code holds only letters, digits, spaces and hyphens.
+fn validate_address(address: &Address) -> Result<(), Error> {
+    let lines = [&address.street, &address.city];
+    if lines
+        .iter()
+        .any(|line| line.trim().is_empty() || line.len() > MAX_ADDRESS_LINE_LENGTH)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Street and city must be 1 to {} characters.",
+                MAX_ADDRESS_LINE_LENGTH
+            ),
+        });
+    }
+    let postal_code = &address.postal_code;
+    if postal_code.len() > MAX_POSTAL_CODE_LENGTH
+        || !postal_code
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-')
+    {
+        return Err(Error::InvalidInput {
+            msg: format!("\"{}\" is not a valid postal code.", postal_code),
+        });
+    }
+    let country = &address.country;
+    if country.len() != 2 || !country.chars().all(|c| c.is_ascii_uppercase()) {
+        return Err(Error::InvalidInput {
+            msg: format!("\"{}\" is not an ISO 3166-1 alpha-2 country code.", country),
+        });
+    }
+    Ok(())
+}
+
+// Check that a contact is a plausible email address or an E.164 phone number: a plus sign and
+// up to 15 digits, the first of them not zero.
+fn validate_contact(contact: &Contact) -> Result<(), Error> {
+    let value = contact.value.as_str();
+    let valid = value.len() <= MAX_CONTACT_LENGTH
+        && match contact.kind {
+            ContactKind::Email => value.split_once('@').is_some_and(|(local, domain)| {
+                !local.is_empty()
+                    && domain.contains('.')
+                    && !domain.starts_with('.')
+                    && !domain.ends_with('.')
+                    && !value.contains(char::is_whitespace)
+            }),
+            ContactKind::Phone => value.strip_prefix('+').is_some_and(|digits| {
+                (2..=15).contains(&digits.len())
+                    && !digits.starts_with('0')
+                    && digits.bytes().all(|b| b.is_ascii_digit())
+            }),
+        };
+    if valid {
+        return Ok(());
+    }
+    Err(Error::InvalidInput {
+        msg: format!("\"{}\" is not a valid {:?} contact.", value, contact.kind),
+    })
+}
+
+// Delete policy for students: apply the loan rules, then drop them from the external ID index,
+// take them out of their classes and discard their private notes, consent records, wishlist,
+// guardian links and photo.
+fn release(id: u64, cascade: bool) -> Result<(), Error> {
+    release_student(id, cascade)?;
+    if let Some(external_id) = crud::get::<Student>(&id).and_then(|s| s.external_id) {
+        STUDENT_EXTERNAL_ID_INDEX
+            .with(|index| index.borrow_mut().remove(&IndexString(external_id)));
     }
+    class::remove_student(id);
+    private_notes::remove_private_notes(id);
+    consent::remove_consents(id);
+    wishlist::remove_student(id);
+    guardian::remove_student(id);
+    photo::remove_photo(id);
+    calendar::remove_student(id);
+    Ok(())
 }
@@ -0,0 +1,117 @@
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::book::{title_key, Book, BookFormat};
+use crate::catalog::require_catalog_access;
+use crate::crud;
+use crate::env::{random_bytes, CanisterEnv, Clock};
+use crate::language;
+use crate::loan::is_available;
+use crate::perf;
+use crate::Error;
+
+// Filters narrowing which titles a random suggestion is drawn from; unset filters match
+// every book.
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub struct RandomBookFilter {
+    // Matched against the author's name, ignoring case.
+    pub author: Option<String>,
+    // Only suggest books a reader of this age may borrow.
+    pub reader_age: Option<u8>,
+    pub featured_only: Option<bool>,
+    // ISO 639-1 code of the language the book must be written in.
+    pub language: Option<String>,
+    // Only suggest copies in this accessible format.
+    pub format: Option<BookFormat>,
+    // Draw from the titles at this reading level first, e.g. a student's grade, and only fall
+    // back to other titles when none of them is available.
+    pub preferred_level: Option<u8>,
+}
+
+// Suggest a random available book. Every matching title is equally likely, however many copies
+// of it the library holds or whenever it was added.
+#[ic_cdk::update]
+async fn get_random_book(filter: RandomBookFilter) -> Result<Book, Error> {
+    let _perf = perf::measure("get_random_book");
+    require_catalog_access()?;
+    let seed = random_bytes().await?;
+    _get_random_book(&CanisterEnv, &seed, &filter)
+}
+
+// Internal function to draw a book matching `filter` that is available at `clock`'s current
+// time, using `seed` as the source of randomness. A title is drawn first, then one of its available copies; titles with no copy
+// available are dropped and the draw repeated.
+pub(crate) fn _get_random_book(
+    clock: &impl Clock,
+    seed: &[u8],
+    filter: &RandomBookFilter,
+) -> Result<Book, Error> {
+    let author = filter
+        .author
+        .as_deref()
+        .map(|author| author.trim().to_lowercase());
+    let language = filter
+        .language
+        .as_deref()
+        .map(language::check_language)
+        .transpose()?;
+    let mut titles: BTreeMap<(String, String), Vec<Book>> = BTreeMap::new();
+    for book in crud::all::<Book>() {
+        let book_author = book.author.trim().to_lowercase();
+        if author.as_ref().is_some_and(|author| *author != book_author)
+            || filter
+                .reader_age
+                .is_some_and(|age| book.min_age.unwrap_or(0) > age)
+            || (filter.featured_only == Some(true) && book.featured_at.is_none())
+            || language
+                .as_ref()
+                .is_some_and(|language| book.language.as_ref() != Some(language))
+            || filter.format.is_some_and(|format| !book.has_format(format))
+            || book.withdrawn_at.is_some()
+        {
+            continue;
+        }
+        titles
+            .entry((title_key(&book.title).0, book_author))
+            .or_default()
+            .push(book);
+    }
+
+    let (mut at_level, mut others): (Vec<Vec<Book>>, Vec<Vec<Book>>) =
+        titles.into_values().partition(|copies| {
+            filter.preferred_level.is_some()
+                && copies
+                    .iter()
+                    .any(|book| book.reading_level == filter.preferred_level)
+        });
+    let now = clock.now();
+    let mut draws = 0u64..;
+    let mut draw = |len: usize| pick(seed, draws.next().unwrap_or_default(), len);
+    for titles in [&mut at_level, &mut others] {
+        while !titles.is_empty() {
+            let copies = titles.swap_remove(draw(titles.len()));
+            let mut available: Vec<Book> = copies
+                .into_iter()
+                .filter(|book| is_available(book, now))
+                .collect();
+            if !available.is_empty() {
+                return Ok(available.swap_remove(draw(available.len())));
+            }
+        }
+    }
+    Err(Error::NotFound {
+        msg: "No available book matches the filter.".to_string(),
+    })
+}
+
+// An index below `len`, derived from the seed and the draw number.
+fn pick(seed: &[u8], draw: u64, len: usize) -> usize {
+    let hash = Sha256::new()
+        .chain_update(seed)
+        .chain_update(draw.to_be_bytes())
+        .finalize();
+    let mut value = [0u8; 16];
+    value.copy_from_slice(&hash[..16]);
+    // With 128 random bits the modulo bias is negligible for any catalog size.
+    (u128::from_be_bytes(value) % len as u128) as usize
+}
@@ -0,0 +1,174 @@
+use crate::auth::require_admin;
+use crate::book::Book;
+use crate::catalog::require_catalog_access;
+use crate::crud;
+use crate::env::{CanisterEnv, Clock};
+use crate::perf;
+use crate::storable::{candid_storable, IndexString};
+use crate::{Error, BOOK_TRANSLATIONS};
+
+// Longest language tag accepted, as allowed by BCP 47 for the common forms.
+const MAX_LANGUAGE_LENGTH: usize = 35;
+
+// Longest translated title and description accepted.
+const MAX_TITLE_LENGTH: usize = 200;
+const MAX_DESCRIPTION_LENGTH: usize = 2000;
+
+// Define the BookTranslation struct to represent a book's title and description in one language.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct BookTranslation {
+    pub book_id: u64,
+    // BCP 47 language tag, lower-cased, e.g. "id" or "en-gb".
+    pub language: String,
+    pub title: String,
+    pub description: Option<String>,
+    pub updated_at: u64,
+}
+
+// Store BookTranslation as Candid, bounded to 4096 bytes.
+candid_storable!(BookTranslation, max_size = 4096);
+
+// Define the payload structure for setting a translation.
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub struct BookTranslationPayload {
+    title: String,
+    description: Option<String>,
+}
+
+// Retrieve every translation of a book, ordered by language.
+#[ic_cdk::query]
+fn get_book_translations(book_id: u64) -> Result<Vec<BookTranslation>, Error> {
+    require_catalog_access()?;
+    crud::find::<Book>(book_id)?;
+    Ok(translations(book_id))
+}
+
+// Set a book's title and description in a language, replacing any earlier translation.
+#[ic_cdk::update]
+fn set_book_translation(
+    book_id: u64,
+    language: String,
+    payload: BookTranslationPayload,
+) -> Result<BookTranslation, Error> {
+    let _perf = perf::measure("set_book_translation");
+    require_admin()?;
+    _set_book_translation(&CanisterEnv, book_id, &language, payload)
+}
+
+// Remove a book's translation into a language.
+#[ic_cdk::update]
+fn remove_book_translation(book_id: u64, language: String) -> Result<BookTranslation, Error> {
+    let _perf = perf::measure("remove_book_translation");
+    require_admin()?;
+    let language = language_tag(&language)?;
+    BOOK_TRANSLATIONS
+        .with(|s| {
+            s.borrow_mut()
+                .remove(&(book_id, IndexString(language.clone())))
+        })
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Book id={} has no \"{}\" translation.", book_id, language),
+        })
+}
+
+// Internal function to set a translation, taking the timestamp from `clock`.
+pub(crate) fn _set_book_translation(
+    clock: &impl Clock,
+    book_id: u64,
+    language: &str,
+    payload: BookTranslationPayload,
+) -> Result<BookTranslation, Error> {
+    let language = language_tag(language)?;
+    let title = payload.title.trim().to_string();
+    if title.is_empty() || title.len() > MAX_TITLE_LENGTH {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A translated title must be 1 to {} characters.",
+                MAX_TITLE_LENGTH
+            ),
+        });
+    }
+    if payload
+        .description
+        .as_ref()
+        .is_some_and(|d| d.len() > MAX_DESCRIPTION_LENGTH)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A translated description can be at most {} characters.",
+                MAX_DESCRIPTION_LENGTH
+            ),
+        });
+    }
+    crud::find::<Book>(book_id)?;
+    let translation = BookTranslation {
+        book_id,
+        language: language.clone(),
+        title,
+        description: payload.description.filter(|d| !d.trim().is_empty()),
+        updated_at: clock.now(),
+    };
+    BOOK_TRANSLATIONS.with(|s| {
+        s.borrow_mut()
+            .insert((book_id, IndexString(language)), translation.clone())
+    });
+    Ok(translation)
+}
+
+// Give a book its title in `language` when it has a translation into it, or into the language
+// without its region, e.g. "id" for "id-id"; otherwise the book keeps its own title.
+pub(crate) fn localize(mut book: Book, language: Option<&str>) -> Book {
+    let Some(language) = language.and_then(|l| language_tag(l).ok()) else {
+        return book;
+    };
+    let primary = language.split('-').next().unwrap_or_default().to_string();
+    let translation = BOOK_TRANSLATIONS.with(|s| {
+        let s = s.borrow();
+        s.get(&(book.id, IndexString(language)))
+            .or_else(|| s.get(&(book.id, IndexString(primary))))
+    });
+    if let Some(translation) = translation {
+        book.title = translation.title;
+    }
+    book
+}
+
+// Remove every translation of a deleted book.
+pub(crate) fn remove_book(book_id: u64) {
+    for translation in translations(book_id) {
+        BOOK_TRANSLATIONS.with(|s| {
+            s.borrow_mut()
+                .remove(&(book_id, IndexString(translation.language)))
+        });
+    }
+}
+
+// A book's translations, ordered by language.
+fn translations(book_id: u64) -> Vec<BookTranslation> {
+    BOOK_TRANSLATIONS.with(|s| {
+        s.borrow()
+            .range((book_id, IndexString(String::new()))..)
+            .take_while(|((id, _), _)| *id == book_id)
+            .map(|(_, translation)| translation)
+            .collect()
+    })
+}
+
+// Check a language tag: letters, digits and dashes, starting with a 2 or 3 letter language.
+// Tags are compared lower-cased.
+fn language_tag(language: &str) -> Result<String, Error> {
+    let tag = language.trim().to_lowercase();
+    let primary = tag.split('-').next().unwrap_or_default();
+    let valid = tag.len() <= MAX_LANGUAGE_LENGTH
+        && (2..=3).contains(&primary.len())
+        && primary.chars().all(|c| c.is_ascii_lowercase())
+        && tag
+            .split('-')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()));
+    if !valid {
+        return Err(Error::InvalidInput {
+            msg: format!("\"{}\" is not a language tag.", language.trim()),
+        });
+    }
+    Ok(tag)
+}
@@ -0,0 +1,109 @@
+use crate::book::{copies_of, title_key, Book};
+use crate::catalog::require_catalog_access;
+use crate::crud;
+use crate::date::DAY;
+use crate::env::{CanisterEnv, Clock};
+use crate::pagination::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::storable::candid_storable;
+use crate::{Error, TRENDING_STORAGE};
+
+// Time after which a loan counts half as much towards a title's trending score.
+const TREND_HALF_LIFE: u64 = 7 * DAY;
+
+// Scores below this have decayed away; such titles are left off the trending list.
+const MIN_TREND_SCORE: f64 = 0.05;
+
+// Define the TrendScore struct to keep a title's time-decayed borrow count, stored under the
+// title's key so every copy counts towards it. It is kept up to date as loans start, so ranking
+// doesn't need to scan the loans.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct TrendScore {
+    // The copy of the title lent most recently.
+    pub book_id: u64,
+    // Decayed borrow count as of `updated_at`.
+    pub score: f64,
+    pub updated_at: u64,
+}
+
+// Store TrendScore as Candid, bounded to 128 bytes.
+candid_storable!(TrendScore, max_size = 128);
+
+// A title on the trending list.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub struct TrendingBook {
+    pub book: Book,
+    // Borrows in the recent past, each weighed by how recent it was.
+    pub score: f64,
+}
+
+// Retrieve the titles borrowed most in the recent past, highest score first.
+#[ic_cdk::query]
+fn get_trending_books(limit: Option<u32>) -> Result<Vec<TrendingBook>, Error> {
+    require_catalog_access()?;
+    Ok(_get_trending_books(&CanisterEnv, limit))
+}
+
+// Internal function to rank titles by their score decayed to `clock`'s time.
+pub(crate) fn _get_trending_books(clock: &impl Clock, limit: Option<u32>) -> Vec<TrendingBook> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+    let now = clock.now();
+    let mut ranked: Vec<(u64, f64)> = TRENDING_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, trend)| (trend.book_id, decayed(&trend, now)))
+            .filter(|&(_, score)| score >= MIN_TREND_SCORE)
+            .collect()
+    });
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    ranked
+        .into_iter()
+        .filter_map(|(book_id, score)| {
+            crud::get::<Book>(&book_id).map(|book| TrendingBook { book, score })
+        })
+        .take(limit)
+        .collect()
+}
+
+// Hook run when a book is lent: add one borrow to its title's score.
+pub(crate) fn record_loan(book: &Book, now: u64) {
+    let key = title_key(&book.title);
+    TRENDING_STORAGE.with(|s| {
+        let mut trends = s.borrow_mut();
+        let score = trends.get(&key).map_or(0.0, |trend| decayed(&trend, now));
+        trends.insert(
+            key,
+            TrendScore {
+                book_id: book.id,
+                score: score + 1.0,
+                updated_at: now,
+            },
+        );
+    });
+}
+
+// Helper function to keep a deleted book's title on the list through another copy, or drop the
+// title once no copy is left.
+pub(crate) fn remove_book(book: &Book) {
+    let key = title_key(&book.title);
+    TRENDING_STORAGE.with(|s| {
+        let mut trends = s.borrow_mut();
+        let Some(mut trend) = trends.get(&key).filter(|trend| trend.book_id == book.id) else {
+            return;
+        };
+        match copies_of(book).into_iter().find(|copy| copy.id != book.id) {
+            Some(copy) => {
+                trend.book_id = copy.id;
+                trends.insert(key, trend);
+            }
+            None => {
+                trends.remove(&key);
+            }
+        }
+    });
+}
+
+// A score decayed from when it was last updated to `now`.
+fn decayed(trend: &TrendScore, now: u64) -> f64 {
+    let elapsed = now.saturating_sub(trend.updated_at) as f64;
+    trend.score * 0.5f64.powf(elapsed / TREND_HALF_LIFE as f64)
+}
@@ -0,0 +1,156 @@
+use sha2::{Digest, Sha256};
+
+use crate::auth::require_admin;
+use crate::env::random_bytes;
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::{Error, ULID_GENERATOR};
+
+// Crockford's base32 alphabet used by the ULID text form.
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+// The random component of a ULID is 80 bits wide.
+const RANDOM_MASK: u128 = (1 << 80) - 1;
+
+// State of the ULID generator; an empty seed means ULIDs are not enabled.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct UlidGenerator {
+    seed: Vec<u8>,
+    last_ms: u64,
+    last_random: u128,
+}
+
+// Store UlidGenerator as Candid.
+candid_storable!(UlidGenerator);
+
+// Enable ULIDs on new records, seeding the generator from the management canister's randomness.
+#[ic_cdk::update]
+async fn enable_ulids() -> Result<(), Error> {
+    let _perf = perf::measure("enable_ulids");
+    require_admin()?;
+    if ulids_enabled() {
+        return Ok(());
+    }
+
+    let seed = random_bytes().await?;
+    ULID_GENERATOR
+        .with(|g| {
+            let mut generator = g.borrow_mut();
+            // Another call may have enabled ULIDs while this one was waiting.
+            if !generator.get().seed.is_empty() {
+                return Ok(generator.get().clone());
+            }
+            generator.set(UlidGenerator {
+                seed,
+                ..Default::default()
+            })
+        })
+        .expect("Cannot store the ULID generator");
+    Ok(())
+}
+
+// Report whether new records are given ULIDs.
+#[ic_cdk::query]
+fn ulids_enabled() -> bool {
+    ULID_GENERATOR.with(|g| !g.borrow().get().seed.is_empty())
+}
+
+// Generate the next ULID for a record created at `now`, or None when ULIDs are not enabled.
+// ULIDs created within the same millisecond are made monotonic by incrementing the random part.
+pub(crate) fn next_ulid(now: u64) -> Option<String> {
+    ULID_GENERATOR.with(|g| {
+        let mut generator = g.borrow().get().clone();
+        if generator.seed.is_empty() {
+            return None;
+        }
+
+        let now_ms = now / 1_000_000;
+        let (ms, random) = if now_ms > generator.last_ms {
+            (now_ms, random_for(&generator.seed, now_ms))
+        } else if generator.last_random < RANDOM_MASK {
+            (generator.last_ms, generator.last_random + 1)
+        } else {
+            let ms = generator.last_ms + 1;
+            (ms, random_for(&generator.seed, ms))
+        };
+
+        generator.last_ms = ms;
+        generator.last_random = random;
+        g.borrow_mut()
+            .set(generator)
+            .expect("Cannot store the ULID generator");
+        Some(encode(ms, random))
+    })
+}
+
+// Derive the 80 random bits for a millisecond from the canister's secret seed.
+fn random_for(seed: &[u8], ms: u64) -> u128 {
+    let digest = Sha256::new()
+        .chain_update(seed)
+        .chain_update(ms.to_be_bytes())
+        .finalize();
+    let mut bytes = [0u8; 16];
+    bytes[6..].copy_from_slice(&digest[..10]);
+    u128::from_be_bytes(bytes)
+}
+
+// Render a 48-bit timestamp and 80-bit random part as the 26-character ULID text form.
+fn encode(ms: u64, random: u128) -> String {
+    let value = ((ms as u128) << 80) | random;
+    (0..26)
+        .rev()
+        .map(|i| ALPHABET[((value >> (i * 5)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2023-11-14 22:13:20 UTC, in nanoseconds.
+    const START: u64 = 1_700_000_000_000_000_000;
+
+    fn enable() {
+        ULID_GENERATOR
+            .with(|g| {
+                g.borrow_mut().set(UlidGenerator {
+                    seed: vec![7; 32],
+                    ..Default::default()
+                })
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn encode_renders_the_timestamp_first() {
+        assert_eq!(encode(0, 0), "00000000000000000000000000");
+        assert_eq!(encode(1, 0), "00000000010000000000000000");
+        assert_eq!(encode(0, RANDOM_MASK), "0000000000ZZZZZZZZZZZZZZZZ");
+    }
+
+    #[test]
+    fn no_ulids_until_enabled() {
+        assert!(next_ulid(START).is_none());
+    }
+
+    #[test]
+    fn ulids_sort_in_creation_order() {
+        enable();
+        let ulids: Vec<String> = [START, START, START, START + 1_000_000]
+            .into_iter()
+            .map(|now| next_ulid(now).unwrap())
+            .collect();
+        assert!(ulids.iter().all(|ulid| ulid.len() == 26));
+        assert!(ulids.windows(2).all(|pair| pair[0] < pair[1]));
+        // Within a millisecond the random part counts up by one.
+        assert_eq!(ulids[0][..10], ulids[2][..10]);
+    }
+
+    #[test]
+    fn clock_going_back_keeps_ulids_increasing() {
+        enable();
+        let first = next_ulid(START).unwrap();
+        let second = next_ulid(START - 1_000_000_000).unwrap();
+        assert!(first < second);
+    }
+}
@@ -0,0 +1,80 @@
+use crate::auth::{self, InitArgs};
+use crate::env::CanisterEnv;
+use crate::{cache, certification, checkpoint, migrations, scheduler, SCHEMA_VERSION};
+
+// Version of the stored data layout that this build of the canister understands.
+pub const CURRENT_SCHEMA_VERSION: u32 = 10;
+
+// Stored value for canisters installed before the schema version was tracked.
+const UNVERSIONED: u32 = 0;
+
+// Layout of the data written by canisters installed before the schema version was tracked.
+const INITIAL_SCHEMA_VERSION: u32 = 1;
+
+// Record the schema version and the initial administrators on a fresh install, and start the
+// background jobs.
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    set_schema_version(CURRENT_SCHEMA_VERSION);
+    auth::bootstrap(&CanisterEnv, args);
+    scheduler::start();
+}
+
+// Refuse to run against data written by a newer release, otherwise migrate to the current version.
+// Everything the canister keeps lives in stable memory, so there is no pre_upgrade hook and
+// nothing to carry over on the heap. What post_upgrade does rebuild is the stored indexes whose
+// layout a migration changes, the aggregate cache, the certified data and the timers.
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    let stored = match stored_schema_version() {
+        UNVERSIONED => INITIAL_SCHEMA_VERSION,
+        version => version,
+    };
+    if stored > CURRENT_SCHEMA_VERSION {
+        // Trapping rolls the upgrade back, leaving the previous code and its data untouched.
+        ic_cdk::trap(&format!(
+            "Stored schema version {} is newer than the supported version {}.",
+            stored, CURRENT_SCHEMA_VERSION
+        ));
+    }
+
+    // A trap in any migration rolls back the whole upgrade, including earlier steps.
+    migrations::run_pending(stored);
+    set_schema_version(CURRENT_SCHEMA_VERSION);
+
+    // Flag anything lost or altered since prepare_upgrade was called.
+    checkpoint::verify(stored != CURRENT_SCHEMA_VERSION);
+
+    // Results cached by the previous code may no longer match what this code computes.
+    cache::invalidate_all();
+
+    // Certified data doesn't survive an upgrade; publish the block log tip and the last
+    // certified state root again.
+    certification::publish();
+
+    // Canisters installed before administrators existed get the upgrading principal as theirs.
+    if !auth::has_admins() {
+        auth::bootstrap(&CanisterEnv, None);
+    }
+
+    // Timers are cleared on upgrade; register the background jobs again.
+    scheduler::start();
+}
+
+// Retrieve the schema version of the stored data.
+#[ic_cdk::query]
+fn get_schema_version() -> u32 {
+    stored_schema_version()
+}
+
+// Internal function to read the persisted schema version.
+pub(crate) fn stored_schema_version() -> u32 {
+    SCHEMA_VERSION.with(|version| *version.borrow().get())
+}
+
+// Helper function to persist the schema version.
+fn set_schema_version(version: u32) {
+    SCHEMA_VERSION
+        .with(|cell| cell.borrow_mut().set(version))
+        .expect("Cannot store the schema version");
+}
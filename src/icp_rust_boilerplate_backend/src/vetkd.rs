@@ -0,0 +1,103 @@
+use candid::Principal;
+use ic_cdk::api::call::{call, call_with_payment128};
+
+use crate::signing;
+use crate::Error;
+
+// Cycles a key derivation costs on a 34-node subnet; whatever isn't used is refunded.
+const VETKD_DERIVE_KEY_CYCLES: u128 = 26_153_846_153;
+
+#[derive(candid::CandidType, Deserialize)]
+enum VetKdCurve {
+    #[serde(rename = "bls12_381_g2")]
+    Bls12381G2,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct VetKdKeyId {
+    curve: VetKdCurve,
+    name: String,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct VetKdPublicKeyArgs {
+    canister_id: Option<Principal>,
+    context: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct VetKdPublicKeyResult {
+    public_key: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct VetKdDeriveKeyArgs {
+    input: Vec<u8>,
+    context: Vec<u8>,
+    transport_public_key: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct VetKdDeriveKeyResult {
+    encrypted_key: Vec<u8>,
+}
+
+// Retrieve the canister's vetKD public key for `context`, against which derived keys are
+// verified.
+pub(crate) async fn public_key(context: &[u8]) -> Result<Vec<u8>, Error> {
+    let args = VetKdPublicKeyArgs {
+        canister_id: None,
+        context: context.to_vec(),
+        key_id: key_id(),
+    };
+    let result: Result<(VetKdPublicKeyResult,), _> = call(
+        Principal::management_canister(),
+        "vetkd_public_key",
+        (args,),
+    )
+    .await;
+    match result {
+        Ok((result,)) => Ok(result.public_key),
+        Err((code, msg)) => Err(Error::Unavailable {
+            msg: format!("Cannot obtain the vetKD public key ({:?}): {}", code, msg),
+        }),
+    }
+}
+
+// Derive the key for `input` within `context`, encrypted to `transport_public_key` so that
+// only the caller holding the matching transport secret can read it.
+pub(crate) async fn derive_encrypted_key(
+    context: &[u8],
+    input: Vec<u8>,
+    transport_public_key: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    let args = VetKdDeriveKeyArgs {
+        input,
+        context: context.to_vec(),
+        transport_public_key,
+        key_id: key_id(),
+    };
+    let result: Result<(VetKdDeriveKeyResult,), _> = call_with_payment128(
+        Principal::management_canister(),
+        "vetkd_derive_key",
+        (args,),
+        VETKD_DERIVE_KEY_CYCLES,
+    )
+    .await;
+    match result {
+        Ok((result,)) => Ok(result.encrypted_key),
+        Err((code, msg)) => Err(Error::Unavailable {
+            msg: format!("Cannot derive a vetKD key ({:?}): {}", code, msg),
+        }),
+    }
+}
+
+// The configured threshold key on BLS12-381, the curve vetKD uses.
+fn key_id() -> VetKdKeyId {
+    VetKdKeyId {
+        curve: VetKdCurve::Bls12381G2,
+        name: signing::key_name(),
+    }
+}
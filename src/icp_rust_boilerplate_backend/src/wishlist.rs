@@ -0,0 +1,201 @@
+use crate::book::Book;
+use crate::consent::{has_consent, ConsentKind};
+use crate::crud;
+use crate::env::{CallerProvider, CanisterEnv, Clock};
+use crate::notification;
+use crate::perf;
+use crate::storable::candid_storable;
+use crate::student::{caller_student, ContactKind, Student};
+use crate::{Error, WISHLIST_STORAGE};
+
+// Most books a student may wishlist.
+const MAX_WISHLIST_SIZE: usize = 100;
+
+// Define the WishlistItem struct to represent a book a student would like to read.
+#[derive(candid::CandidType, Deserialize, Serialize, Clone)]
+pub struct WishlistItem {
+    pub student_id: u64,
+    pub book_id: u64,
+    // Whether the student wants to hear when the book can be borrowed.
+    pub notify: bool,
+    pub added_at: u64,
+    // When the book last came back into circulation, if the student asked to be notified and
+    // gave Communication consent. Cleared once they dismiss it.
+    pub available_since: Option<u64>,
+}
+
+// Store WishlistItem as Candid, bounded to 128 bytes.
+candid_storable!(WishlistItem, max_size = 128);
+
+// Add a book to the caller's wishlist, or change whether they want to be notified about it.
+#[ic_cdk::update]
+fn add_to_wishlist(book_id: u64, notify: bool) -> Result<WishlistItem, Error> {
+    let _perf = perf::measure("add_to_wishlist");
+    _add_to_wishlist(&CanisterEnv, book_id, notify)
+}
+
+// Internal function to add a book to the wishlist of `env`'s caller.
+pub(crate) fn _add_to_wishlist(
+    env: &(impl Clock + CallerProvider),
+    book_id: u64,
+    notify: bool,
+) -> Result<WishlistItem, Error> {
+    let student = caller_student(env)?;
+    crud::find::<Book>(book_id)?;
+    let item = match get(student.id, book_id) {
+        Some(item) => WishlistItem {
+            notify,
+            available_since: item.available_since.filter(|_| notify),
+            ..item
+        },
+        None => {
+            if items(student.id).len() >= MAX_WISHLIST_SIZE {
+                return Err(Error::InvalidInput {
+                    msg: format!("A wishlist can hold at most {} books.", MAX_WISHLIST_SIZE),
+                });
+            }
+            WishlistItem {
+                student_id: student.id,
+                book_id,
+                notify,
+                added_at: env.now(),
+                available_since: None,
+            }
+        }
+    };
+    WISHLIST_STORAGE.with(|s| s.borrow_mut().insert((student.id, book_id), item.clone()));
+    Ok(item)
+}
+
+// Remove a book from the caller's wishlist.
+#[ic_cdk::update]
+fn remove_from_wishlist(book_id: u64) -> Result<WishlistItem, Error> {
+    let _perf = perf::measure("remove_from_wishlist");
+    let student = caller_student(&CanisterEnv)?;
+    WISHLIST_STORAGE
+        .with(|s| s.borrow_mut().remove(&(student.id, book_id)))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("The book id={} is not on your wishlist.", book_id),
+        })
+}
+
+// Retrieve the caller's wishlist, in book ID order.
+#[ic_cdk::query]
+fn get_my_wishlist() -> Result<Vec<WishlistItem>, Error> {
+    let student = caller_student(&CanisterEnv)?;
+    Ok(items(student.id))
+}
+
+// Dismiss the notification that a wishlisted book is available.
+#[ic_cdk::update]
+fn dismiss_wishlist_notification(book_id: u64) -> Result<WishlistItem, Error> {
+    let _perf = perf::measure("dismiss_wishlist_notification");
+    let student = caller_student(&CanisterEnv)?;
+    let Some(mut item) = get(student.id, book_id) else {
+        return Err(Error::NotFound {
+            msg: format!("The book id={} is not on your wishlist.", book_id),
+        });
+    };
+    item.available_since = None;
+    WISHLIST_STORAGE.with(|s| s.borrow_mut().insert((student.id, book_id), item.clone()));
+    Ok(item)
+}
+
+// Hook run when a book comes back into circulation: notify the students who asked to be and
+// agreed to be contacted, on the wishlist and by email.
+pub(crate) fn book_available(book_id: u64, now: u64) {
+    let notified: Vec<u64> = WISHLIST_STORAGE.with(|s| {
+        let mut wishlists = s.borrow_mut();
+        let items: Vec<WishlistItem> = wishlists
+            .iter()
+            .map(|(_, item)| item)
+            .filter(|item| {
+                item.book_id == book_id
+                    && item.notify
+                    && has_consent(item.student_id, ConsentKind::Communication)
+            })
+            .collect();
+        items
+            .into_iter()
+            .map(|mut item| {
+                item.available_since = Some(now);
+                let student_id = item.student_id;
+                wishlists.insert((student_id, book_id), item);
+                student_id
+            })
+            .collect()
+    });
+    let Some(book) = crud::get::<Book>(&book_id) else {
+        return;
+    };
+    for student in notified.iter().filter_map(crud::get::<Student>) {
+        notification::dispatch(
+            now,
+            &student,
+            ContactKind::Email,
+            "A book on your wishlist is available".to_string(),
+            format!("\"{}\" by {} can be borrowed now.", book.title, book.author),
+        );
+    }
+}
+
+// Helper function to drop a deleted student's wishlist.
+pub(crate) fn remove_student(student_id: u64) {
+    remove_where(|&(student, _)| student == student_id);
+}
+
+// Helper function to move the wishlist of the student `from` onto that of the student `to`,
+// returning how many books moved. A book on both keeps `to`'s item. The merged list may go
+// past MAX_WISHLIST_SIZE, which only limits what a student adds.
+pub(crate) fn move_student(from: u64, to: u64) -> u64 {
+    let moved = items(from);
+    WISHLIST_STORAGE.with(|s| {
+        let mut wishlists = s.borrow_mut();
+        for item in &moved {
+            wishlists.remove(&(from, item.book_id));
+            if !wishlists.contains_key(&(to, item.book_id)) {
+                let moved = WishlistItem {
+                    student_id: to,
+                    ..item.clone()
+                };
+                wishlists.insert((to, item.book_id), moved);
+            }
+        }
+    });
+    moved.len() as u64
+}
+
+// Helper function to take a deleted book off every wishlist.
+pub(crate) fn remove_book(book_id: u64) {
+    remove_where(|&(_, book)| book == book_id);
+}
+
+// Remove every item whose key matches `filter`.
+fn remove_where(filter: impl Fn(&(u64, u64)) -> bool) {
+    WISHLIST_STORAGE.with(|s| {
+        let mut wishlists = s.borrow_mut();
+        let keys: Vec<_> = wishlists
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| filter(key))
+            .collect();
+        for key in keys {
+            wishlists.remove(&key);
+        }
+    });
+}
+
+// Internal function to fetch a wishlist item.
+fn get(student_id: u64, book_id: u64) -> Option<WishlistItem> {
+    WISHLIST_STORAGE.with(|s| s.borrow().get(&(student_id, book_id)))
+}
+
+// Every item on a student's wishlist, in book ID order.
+fn items(student_id: u64) -> Vec<WishlistItem> {
+    WISHLIST_STORAGE.with(|s| {
+        s.borrow()
+            .range((student_id, 0)..=(student_id, u64::MAX))
+            .map(|(_, item)| item)
+            .collect()
+    })
+}